@@ -125,5 +125,5 @@ fn decompress_huge_dict(b: &mut Bencher) {
                               \x49\x98\x6f\x10\x19\xc6\xd7\x31\
                               \xeb\x36\x50\xb2\x98\x48\xff\xfe\
                               \xa5\xb0\x00";
-    decompress_bench(&compressed, b);
+    decompress_bench(compressed, b);
 }
@@ -0,0 +1,30 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+use lzma_rs::decompress::raw::{LzmaDecoder, LzmaParams, LzmaProperties};
+use lzma_rs::error::Result;
+
+/// Decode raw LZMA data with a tiny dictionary of up to 255 bytes, taken
+/// from the first byte, and the properties of the second byte.
+fn decode_raw(data: &[u8]) -> Result<Vec<u8>> {
+    let (dict_size, props, mut compressed) = match data {
+        [dict_size, props, compressed @ ..] => (*dict_size as u32, *props as u32, compressed),
+        _ => return Ok(Vec::new()),
+    };
+    let properties = LzmaProperties {
+        lc: props % 9,
+        lp: (props / 9) % 5,
+        pb: (props / 45) % 5,
+    };
+    let params = LzmaParams::new(properties, dict_size, None);
+    let mut decoder = LzmaDecoder::new(params, Some(1 << 20))?;
+
+    let mut decomp: Vec<u8> = Vec::new();
+    decoder.decompress(&mut compressed, &mut decomp)?;
+    Ok(decomp)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _decomp = decode_raw(data);
+});
@@ -0,0 +1,246 @@
+//! Self-benchmark on a corpus of files, to compare this crate with another
+//! compression backend on representative data.
+//!
+//! [`run_benchmarks()`] compresses and decompresses every file of a
+//! directory in each format with the default options, timing each step with
+//! [`Instant`], and checks that the data round-trips.
+
+use crate::codec::{Decode, Encode, Lzma2, LzmaAlone, Xz};
+use crate::{error, Format};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+/// Each step is repeated until it has run for this long, and the fastest
+/// run is kept.
+const MIN_STEP_TIME: Duration = Duration::from_millis(100);
+/// Maximum number of runs of each step.
+const MAX_RUNS: u32 = 10;
+
+/// Results for one file in one format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Measurement {
+    /// File of the corpus.
+    pub file: PathBuf,
+    /// Format of the compressed data.
+    pub format: Format,
+    /// Size of the file, in bytes.
+    pub in_bytes: u64,
+    /// Size of the compressed data, in bytes.
+    pub out_bytes: u64,
+    /// Fastest compression time.
+    pub compress_time: Duration,
+    /// Fastest decompression time.
+    pub decompress_time: Duration,
+}
+
+impl Measurement {
+    /// Compressed size relative to the file size. Empty files have a ratio
+    /// of 0.
+    pub fn ratio(&self) -> f64 {
+        if self.in_bytes == 0 {
+            0.0
+        } else {
+            self.out_bytes as f64 / self.in_bytes as f64
+        }
+    }
+
+    /// Compression speed, in uncompressed bytes per second.
+    pub fn compress_speed(&self) -> f64 {
+        speed(self.in_bytes, self.compress_time)
+    }
+
+    /// Decompression speed, in uncompressed bytes per second.
+    pub fn decompress_speed(&self) -> f64 {
+        speed(self.in_bytes, self.decompress_time)
+    }
+}
+
+fn speed(bytes: u64, time: Duration) -> f64 {
+    bytes as f64 / time.as_secs_f64().max(1e-9)
+}
+
+/// Results of [`run_benchmarks()`], printed as a table by its [`Display`]
+/// implementation.
+///
+/// [`Display`]: fmt::Display
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// One measurement per file and format, in the order of the file names.
+    pub measurements: Vec<Measurement>,
+}
+
+impl Report {
+    /// Sum of the sizes and times of the measurements in `format`, with an
+    /// empty file name.
+    pub fn total(&self, format: Format) -> Measurement {
+        let mut total = Measurement {
+            file: PathBuf::new(),
+            format,
+            in_bytes: 0,
+            out_bytes: 0,
+            compress_time: Duration::ZERO,
+            decompress_time: Duration::ZERO,
+        };
+        for m in self.measurements.iter().filter(|m| m.format == format) {
+            total.in_bytes += m.in_bytes;
+            total.out_bytes += m.out_bytes;
+            total.compress_time += m.compress_time;
+            total.decompress_time += m.decompress_time;
+        }
+        total
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            fmt,
+            "{:<32} {:<6} {:>12} {:>12} {:>7} {:>10} {:>11}",
+            "file", "format", "size", "compressed", "ratio", "comp MB/s", "decomp MB/s"
+        )?;
+        for m in &self.measurements {
+            writeln!(
+                fmt,
+                "{:<32} {:<6} {:>12} {:>12} {:>7.3} {:>10.2} {:>11.2}",
+                m.file.display(),
+                format!("{:?}", m.format),
+                m.in_bytes,
+                m.out_bytes,
+                m.ratio(),
+                m.compress_speed() / 1e6,
+                m.decompress_speed() / 1e6
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Benchmark every regular file directly inside `corpus_dir` in the LZMA,
+/// LZMA2 and `.xz` formats, with the default options.
+///
+/// Files are read into memory first, so that only the codecs are timed.
+/// Each compression and decompression is repeated for about 100 ms, up to
+/// 10 times, and the fastest run is reported. An error is returned if a file
+/// can't be read, or doesn't decompress to its original content.
+pub fn run_benchmarks<P>(corpus_dir: P) -> error::Result<Report>
+where
+    P: AsRef<Path>,
+{
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(corpus_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    let codecs: [(&dyn Encode, &dyn Decode); 3] = [
+        (&LzmaAlone::default(), &LzmaAlone::default()),
+        (&Lzma2, &Lzma2),
+        (&Xz::default(), &Xz::default()),
+    ];
+
+    let mut report = Report::default();
+    for file in files {
+        let data = std::fs::read(&file)?;
+        for &(encoder, decoder) in codecs.iter() {
+            let measurement = measure(&file, &data, encoder, decoder)?;
+            lzma_info!("Benchmark: {:?}", measurement);
+            report.measurements.push(measurement);
+        }
+    }
+    Ok(report)
+}
+
+fn measure(
+    file: &Path,
+    data: &[u8],
+    encoder: &dyn Encode,
+    decoder: &dyn Decode,
+) -> error::Result<Measurement> {
+    let mut compressed = Vec::new();
+    let compress_time = fastest_run(|| {
+        compressed.clear();
+        encoder.encode(&mut &data[..], &mut compressed)
+    })?;
+
+    let mut decompressed = Vec::with_capacity(data.len());
+    let decompress_time = fastest_run(|| {
+        decompressed.clear();
+        decoder.decode(&mut compressed.as_slice(), &mut decompressed)
+    })?;
+    if decompressed != data {
+        return Err(error::Error::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} does not round-trip in format {:?}",
+                file.display(),
+                encoder.format()
+            ),
+        )));
+    }
+
+    Ok(Measurement {
+        file: file.to_path_buf(),
+        format: encoder.format(),
+        in_bytes: data.len() as u64,
+        out_bytes: compressed.len() as u64,
+        compress_time,
+        decompress_time,
+    })
+}
+
+/// Run `step` repeatedly as described in [`run_benchmarks()`], returning the
+/// time of the fastest run.
+fn fastest_run<F>(mut step: F) -> error::Result<Duration>
+where
+    F: FnMut() -> error::Result<()>,
+{
+    let mut fastest = Duration::MAX;
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..MAX_RUNS {
+        let start = Instant::now();
+        step()?;
+        let time = start.elapsed();
+        fastest = fastest.min(time);
+        elapsed += time;
+        if elapsed >= MIN_STEP_TIME {
+            break;
+        }
+    }
+    Ok(fastest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_benchmarks() {
+        let dir = std::env::temp_dir().join(format!("lzma-rs-bench-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("a.txt"), b"hello hello hello hello").unwrap();
+        std::fs::write(dir.join("empty"), b"").unwrap();
+
+        let report = run_benchmarks(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let report = report.unwrap();
+
+        assert_eq!(report.measurements.len(), 6);
+        let formats: Vec<Format> = report.measurements.iter().map(|m| m.format).collect();
+        assert_eq!(formats, [Format::Lzma, Format::Lzma2, Format::Xz].repeat(2));
+        assert_eq!(report.measurements[0].file, dir.join("a.txt"));
+        assert_eq!(report.measurements[0].in_bytes, 23);
+        assert_eq!(report.measurements[3].ratio(), 0.0);
+
+        let total = report.total(Format::Xz);
+        assert_eq!(total.in_bytes, 23);
+        assert_eq!(
+            total.out_bytes,
+            report.measurements[2].out_bytes + report.measurements[5].out_bytes
+        );
+        assert_eq!(report.to_string().lines().count(), 7);
+    }
+}
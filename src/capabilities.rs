@@ -0,0 +1,83 @@
+//! Runtime report of the functionality compiled in the crate.
+
+use crate::xz::{CheckMethod, FilterId};
+use crate::Format;
+
+/// Functionality supported by this build of the crate, returned by
+/// [`capabilities()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Version of the crate.
+    pub version: &'static str,
+    /// Formats that can be decompressed.
+    pub decode_formats: &'static [Format],
+    /// Formats that can be compressed.
+    pub encode_formats: &'static [Format],
+    /// Filters that can be decoded in `.xz` blocks.
+    pub xz_decode_filters: &'static [FilterId],
+    /// Filters that can be written in `.xz` blocks.
+    pub xz_encode_filters: &'static [FilterId],
+    /// Checks of `.xz` blocks verified when decompressing. Streams declaring
+    /// another check fail to decompress.
+    pub xz_decode_checks: &'static [CheckMethod],
+    /// Checks of `.xz` blocks that can be written when compressing.
+    pub xz_encode_checks: &'static [CheckMethod],
+    /// Whether the `stream` feature is enabled.
+    pub stream: bool,
+    /// Whether the `raw_decoder` feature is enabled.
+    pub raw_decoder: bool,
+    /// Whether the `enable_logging` feature is enabled.
+    pub logging: bool,
+    /// Whether the `checked` feature is enabled, asserting internal
+    /// invariants in release builds.
+    pub checked: bool,
+}
+
+const ALL_FORMATS: &[Format] = &[Format::Lzma, Format::Lzma2, Format::Xz];
+const SUPPORTED_CHECKS: &[CheckMethod] =
+    &[CheckMethod::None, CheckMethod::Crc32, CheckMethod::Crc64];
+
+/// Report the formats, filters, checks and optional features supported by
+/// this build, so that callers can negotiate functionality without probing
+/// with test streams.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        decode_formats: ALL_FORMATS,
+        encode_formats: ALL_FORMATS,
+        xz_decode_filters: &[FilterId::Delta, FilterId::Lzma2],
+        xz_encode_filters: &[FilterId::Delta, FilterId::Lzma2],
+        xz_decode_checks: SUPPORTED_CHECKS,
+        xz_encode_checks: SUPPORTED_CHECKS,
+        stream: cfg!(feature = "stream"),
+        raw_decoder: cfg!(feature = "raw_decoder"),
+        logging: cfg!(feature = "enable_logging"),
+        checked: cfg!(feature = "checked"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+
+        // Every advertised check can be written and read back.
+        for &check in capabilities.xz_encode_checks {
+            assert!(capabilities.xz_decode_checks.contains(&check));
+            let options = crate::compress::Options {
+                check,
+                ..Default::default()
+            };
+            let compressed = crate::xz_compress_to_vec(b"abc", &options).unwrap();
+            assert_eq!(crate::xz_decompress_to_vec(&compressed).unwrap(), b"abc");
+        }
+        for &format in capabilities.encode_formats {
+            assert!(capabilities.decode_formats.contains(&format));
+        }
+        assert_eq!(capabilities.stream, cfg!(feature = "stream"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Traits unifying the encoders and decoders of the supported formats.
+//!
+//! Libraries can accept an `impl Decode` (or a `&dyn Decode`) and let their
+//! users pick the container, instead of calling one of the format-specific
+//! functions. Codecs hold no state, so a single `&dyn Decode` can be shared by
+//! several threads.
+
+use crate::{compress, decompress, error, Format};
+use std::io;
+
+/// A compressor for one of the supported formats.
+pub trait Encode: Send + Sync {
+    /// Format of the compressed data.
+    fn format(&self) -> Format;
+
+    /// Compress all the data from `input` into `output`.
+    fn encode(&self, input: &mut dyn io::BufRead, output: &mut dyn io::Write) -> error::Result<()>;
+}
+
+/// A decompressor for one of the supported formats.
+pub trait Decode: Send + Sync {
+    /// Format of the compressed data.
+    fn format(&self) -> Format;
+
+    /// Decompress the data from `input` into `output`.
+    fn decode(&self, input: &mut dyn io::BufRead, output: &mut dyn io::Write) -> error::Result<()>;
+}
+
+/// Codec for the legacy `.lzma` format.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LzmaAlone {
+    /// Options used when compressing.
+    pub compress: compress::Options,
+    /// Options used when decompressing.
+    pub decompress: decompress::Options,
+}
+
+impl Encode for LzmaAlone {
+    fn format(&self) -> Format {
+        Format::Lzma
+    }
+
+    fn encode(
+        &self,
+        mut input: &mut dyn io::BufRead,
+        mut output: &mut dyn io::Write,
+    ) -> error::Result<()> {
+        crate::lzma_compress_with_options(&mut input, &mut output, &self.compress)
+    }
+}
+
+impl Decode for LzmaAlone {
+    fn format(&self) -> Format {
+        Format::Lzma
+    }
+
+    fn decode(
+        &self,
+        mut input: &mut dyn io::BufRead,
+        mut output: &mut dyn io::Write,
+    ) -> error::Result<()> {
+        crate::lzma_decompress_with_options(&mut input, &mut output, &self.decompress)
+    }
+}
+
+/// Codec for raw LZMA2 streams.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lzma2;
+
+impl Encode for Lzma2 {
+    fn format(&self) -> Format {
+        Format::Lzma2
+    }
+
+    fn encode(
+        &self,
+        mut input: &mut dyn io::BufRead,
+        mut output: &mut dyn io::Write,
+    ) -> error::Result<()> {
+        crate::lzma2_compress(&mut input, &mut output)
+    }
+}
+
+impl Decode for Lzma2 {
+    fn format(&self) -> Format {
+        Format::Lzma2
+    }
+
+    fn decode(
+        &self,
+        mut input: &mut dyn io::BufRead,
+        mut output: &mut dyn io::Write,
+    ) -> error::Result<()> {
+        crate::lzma2_decompress(&mut input, &mut output)
+    }
+}
+
+/// Codec for the `.xz` format.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Xz {
+    /// Options used when compressing.
+    pub compress: compress::Options,
+    /// Options used when decompressing.
+    pub decompress: decompress::Options,
+}
+
+impl Encode for Xz {
+    fn format(&self) -> Format {
+        Format::Xz
+    }
+
+    fn encode(
+        &self,
+        mut input: &mut dyn io::BufRead,
+        mut output: &mut dyn io::Write,
+    ) -> error::Result<()> {
+        crate::xz_compress_with_options(&mut input, &mut output, &self.compress)
+    }
+}
+
+impl Decode for Xz {
+    fn format(&self) -> Format {
+        Format::Xz
+    }
+
+    fn decode(
+        &self,
+        mut input: &mut dyn io::BufRead,
+        mut output: &mut dyn io::Write,
+    ) -> error::Result<()> {
+        crate::xz_decompress_with_options(&mut input, &mut output, &self.decompress)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip<C: Encode + Decode>(codec: &C, data: &[u8]) {
+        let mut compressed = Vec::new();
+        codec.encode(&mut &data[..], &mut compressed).unwrap();
+        let mut decompressed = Vec::new();
+        codec
+            .decode(&mut compressed.as_slice(), &mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"Hello world, hello codecs";
+        round_trip(&LzmaAlone::default(), data);
+        round_trip(&Lzma2, data);
+        round_trip(&Xz::default(), data);
+    }
+
+    #[test]
+    fn test_dyn_decode() {
+        let decoders: [&dyn Decode; 3] = [&LzmaAlone::default(), &Lzma2, &Xz::default()];
+        let encoders: [&dyn Encode; 3] = [&LzmaAlone::default(), &Lzma2, &Xz::default()];
+        for (encoder, decoder) in encoders.iter().zip(decoders.iter()) {
+            assert_eq!(encoder.format(), decoder.format());
+            let mut compressed = Vec::new();
+            encoder.encode(&mut &b"abc"[..], &mut compressed).unwrap();
+            let mut decompressed = Vec::new();
+            decoder
+                .decode(&mut compressed.as_slice(), &mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, b"abc");
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Batched decoding of many independent raw LZMA members.
+
+use crate::decode::lzbuffer::{LzBuffer, LzCircularBuffer};
+use crate::decode::lzma::{DecoderState, LzmaParams};
+use crate::decode::rangecoder::RangeDecoder;
+use crate::error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Decodes many independent raw LZMA members, spreading the work across
+/// `parallelism` threads.
+///
+/// Each member is given by its [`LzmaParams`] and its compressed bytes,
+/// without header. Each thread keeps a single decoder state that is reset
+/// between members, so that the probability tables are only allocated once
+/// per thread. If `parallelism` is 0, the available parallelism of the host
+/// is used.
+///
+/// Results are returned in the same order as the members.
+pub fn decode_batch<'a, I>(members: I, parallelism: usize) -> Vec<error::Result<Vec<u8>>>
+where
+    I: IntoIterator<Item = (LzmaParams, &'a [u8])>,
+{
+    let members: Vec<(LzmaParams, &[u8])> = members.into_iter().collect();
+    let parallelism = if parallelism == 0 {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        parallelism
+    };
+    let parallelism = parallelism.min(members.len());
+
+    let mut results: Vec<Option<error::Result<Vec<u8>>>> = Vec::new();
+    results.resize_with(members.len(), || None);
+
+    if parallelism <= 1 {
+        let mut state = None;
+        for (i, (params, input)) in members.iter().enumerate() {
+            results[i] = Some(decode_member(&mut state, params, input));
+        }
+    } else {
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..parallelism)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut state = None;
+                        let mut decoded = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let (params, input) = match members.get(i) {
+                                Some(member) => member,
+                                None => break,
+                            };
+                            decoded.push((i, decode_member(&mut state, params, input)));
+                        }
+                        decoded
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                for (i, result) in worker.join().expect("batch decoding thread panicked") {
+                    results[i] = Some(result);
+                }
+            }
+        });
+    }
+
+    results
+        .into_iter()
+        .map(|result| result.expect("every member is decoded"))
+        .collect()
+}
+
+/// Decodes a single member, reusing the given decoder state if any.
+fn decode_member(
+    state: &mut Option<DecoderState>,
+    params: &LzmaParams,
+    mut input: &[u8],
+) -> error::Result<Vec<u8>> {
+    let state = match state.as_mut() {
+        Some(state) => {
+            state.reset_state(params.properties);
+            state.set_unpacked_size(params.unpacked_size);
+            state
+        }
+        None => state.get_or_insert(DecoderState::new(params.properties, params.unpacked_size)),
+    };
+
+    let mut decoded = Vec::new();
+    let mut output =
+        LzCircularBuffer::from_stream(&mut decoded, params.dict_size as usize, usize::MAX);
+    let mut rangecoder = RangeDecoder::new(&mut input)
+        .map_err(|e| error::Error::Truncated(format!("LZMA stream too short: {}", e)))?;
+    state.process(&mut output, &mut rangecoder)?;
+    output.finish()?;
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decompress::Options;
+
+    #[test]
+    fn test_decode_batch() {
+        let inputs: Vec<Vec<u8>> = (0..20)
+            .map(|i| format!("member {} ", i).repeat(i * 10).into_bytes())
+            .collect();
+        let compressed: Vec<Vec<u8>> = inputs
+            .iter()
+            .map(|input| {
+                let mut compressed = Vec::new();
+                crate::lzma_compress(&mut input.as_slice(), &mut compressed).unwrap();
+                compressed
+            })
+            .collect();
+        let members: Vec<(LzmaParams, &[u8])> = compressed
+            .iter()
+            .map(|member| {
+                let mut member = member.as_slice();
+                let params = LzmaParams::read_header(&mut member, &Options::default()).unwrap();
+                (params, member)
+            })
+            .collect();
+
+        for &parallelism in &[0, 1, 3] {
+            let results = decode_batch(members.iter().cloned(), parallelism);
+            assert_eq!(results.len(), inputs.len());
+            for (result, input) in results.into_iter().zip(&inputs) {
+                assert_eq!(&result.unwrap(), input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_batch_error() {
+        let params = LzmaParams::new(
+            crate::decode::lzma::LzmaProperties {
+                lc: 3,
+                lp: 0,
+                pb: 2,
+            },
+            0x1000,
+            None,
+        );
+        let results = decode_batch(vec![(params, &b"\x00"[..])], 1);
+        assert!(results[0].is_err());
+    }
+}
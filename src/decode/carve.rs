@@ -0,0 +1,155 @@
+//! Scanning of arbitrary data for embedded LZMA and XZ streams.
+
+use crate::decode::lzma::{LzmaDecoder, LzmaParams};
+use crate::decode::{util, xz};
+use crate::decompress::{Options, UnpackedSize, UnsupportedCheck};
+use crate::magic::LzmaSubformat;
+use crate::xz::header::XZ_MAGIC;
+use crate::{error, Format, UncompressedSize};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io;
+
+/// A compressed stream found by [`find_embedded_streams`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmbeddedStream {
+    /// Offset of the stream in the scanned data.
+    pub offset: usize,
+    /// Number of bytes consumed when decoding the stream. For LZMA streams,
+    /// this may be a few bytes short of what the encoder wrote, as the
+    /// decoder doesn't read the whole range coder flush.
+    pub length: usize,
+    /// Format of the stream.
+    pub format: Format,
+    /// "lclppb" properties byte of an LZMA stream, i.e.
+    /// `(pb * 5 + lp) * 9 + lc`.
+    pub properties: Option<u8>,
+    /// Dictionary size declared by an LZMA stream.
+    pub dict_size: Option<u32>,
+    /// Whether an LZMA stream declares its unpacked size in its header or
+    /// ends with a marker.
+    pub lzma_subformat: Option<LzmaSubformat>,
+    /// Size of the decompressed data.
+    pub unpacked_size: UncompressedSize,
+}
+
+/// Scan `data` for plausible LZMA and XZ headers and report the streams that
+/// decode successfully.
+///
+/// Each candidate is decoded into a sink, up to `max_unpacked_size` bytes
+/// which also bounds the dictionary memory; candidates producing more data,
+/// or no data at all, are discarded. Scanning resumes after the end of each
+/// stream found, so streams nested in other streams are not reported.
+///
+/// This is meant for forensic carving: valid streams are found at any
+/// offset, but streams truncated in `data` are not reported.
+pub fn find_embedded_streams(data: &[u8], max_unpacked_size: usize) -> Vec<EmbeddedStream> {
+    let mut streams = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let candidate = &data[offset..];
+        let found = if candidate.starts_with(XZ_MAGIC) {
+            try_xz(candidate, max_unpacked_size)
+        } else if is_plausible_lzma_header(candidate, max_unpacked_size) {
+            try_lzma(candidate, max_unpacked_size)
+        } else {
+            None
+        };
+
+        match found {
+            Some(mut stream) => {
+                stream.offset = offset;
+                lzma_info!("Found embedded stream: {:?}", stream);
+                offset += stream.length.max(1);
+                streams.push(stream);
+            }
+            None => offset += 1,
+        }
+    }
+    streams
+}
+
+/// Check the LZMA header fields against the values written by common
+/// encoders, to avoid attempting to decode at every offset.
+pub(crate) fn is_plausible_lzma_header(candidate: &[u8], max_unpacked_size: usize) -> bool {
+    // 13 bytes of header, then the first byte of the range coder which is
+    // always null.
+    if candidate.len() < 14 || candidate[0] >= 225 || candidate[13] != 0 {
+        return false;
+    }
+
+    // Encoders write dictionary sizes of the form 2^n or 3 * 2^(n-1).
+    let dict_size = LittleEndian::read_u32(&candidate[1..5]);
+    if dict_size < 0x1000 {
+        return false;
+    }
+    let shifted = dict_size >> dict_size.trailing_zeros();
+    if shifted != 1 && shifted != 3 {
+        return false;
+    }
+
+    let unpacked_size = LittleEndian::read_u64(&candidate[5..13]);
+    unpacked_size == u64::MAX || (unpacked_size > 0 && unpacked_size <= max_unpacked_size as u64)
+}
+
+fn try_lzma(candidate: &[u8], max_unpacked_size: usize) -> Option<EmbeddedStream> {
+    let options = Options {
+        unpacked_size: UnpackedSize::ReadFromHeader,
+        memlimit: Some(max_unpacked_size),
+        allow_trailing_data: true,
+        ..Default::default()
+    };
+    let mut input = candidate;
+    let mut count_input = util::CountBufRead::new(&mut input);
+    let mut sink = io::sink();
+    let mut output = util::BoundedWrite::new(&mut sink, max_unpacked_size);
+    let params = decode_lzma(&mut count_input, &mut output, &options).ok()?;
+    if output.count() == 0 {
+        return None;
+    }
+    Some(EmbeddedStream {
+        offset: 0,
+        length: count_input.count(),
+        format: Format::Lzma,
+        properties: Some(candidate[0]),
+        dict_size: Some(params.dict_size),
+        lzma_subformat: LzmaSubformat::from_header(candidate),
+        unpacked_size: UncompressedSize(output.count() as u64),
+    })
+}
+
+fn decode_lzma<R, W>(input: &mut R, output: &mut W, options: &Options) -> error::Result<LzmaParams>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let params = LzmaParams::read_header(input, options)?;
+    let mut decoder = LzmaDecoder::with_options(params, options)?;
+    decoder.decompress(input, output)?;
+    Ok(params)
+}
+
+fn try_xz(candidate: &[u8], max_unpacked_size: usize) -> Option<EmbeddedStream> {
+    let mut input = candidate;
+    let mut count_input = util::CountBufRead::new(&mut input);
+    let mut sink = io::sink();
+    let mut output = util::BoundedWrite::new(&mut sink, max_unpacked_size);
+    xz::decode_single_stream(
+        &mut count_input,
+        &mut output,
+        UnsupportedCheck::Error,
+        &mut |_| (),
+    )
+    .ok()?;
+    if output.count() == 0 {
+        return None;
+    }
+    Some(EmbeddedStream {
+        offset: 0,
+        length: count_input.count(),
+        format: Format::Xz,
+        properties: None,
+        dict_size: None,
+        lzma_subformat: None,
+        unpacked_size: UncompressedSize(output.count() as u64),
+    })
+}
@@ -0,0 +1,159 @@
+//! Decoder for payloads split into independent fixed-size blocks.
+
+use crate::decode::lzbuffer::{LzBuffer, LzCircularBuffer};
+use crate::decode::lzma::{DecoderState, LzmaParams};
+use crate::decode::lzma2::Lzma2Decoder;
+use crate::decode::rangecoder::RangeDecoder;
+use crate::decode::{util, xz};
+use crate::decompress::Options;
+use crate::{error, Format};
+use std::io;
+
+/// Decoder for payloads made of independent blocks, each of which is a
+/// complete LZMA, LZMA2 or XZ stream decompressing to at most `block_size`
+/// bytes.
+///
+/// This is the layout used by filesystem images such as SquashFS or EROFS.
+/// All blocks share the same [`Options`], and the decoder state is reused
+/// from one block to the next to avoid re-allocating the probability tables.
+#[derive(Debug)]
+pub struct FixedBlockDecoder {
+    format: Format,
+    block_size: usize,
+    options: Options,
+    lzma_state: Option<DecoderState>,
+    lzma2_decoder: Lzma2Decoder,
+}
+
+impl FixedBlockDecoder {
+    /// Creates a decoder for blocks of the given `format`, each decompressing
+    /// to at most `block_size` bytes.
+    pub fn new(format: Format, block_size: usize, options: &Options) -> Self {
+        Self {
+            format,
+            block_size,
+            options: *options,
+            lzma_state: None,
+            lzma2_decoder: Lzma2Decoder::new(),
+        }
+    }
+
+    /// Returns the format of the blocks.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the maximum decompressed size of a block.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Decompresses one block into the output, returning the number of bytes
+    /// written.
+    ///
+    /// Decompressing to more than `block_size` bytes is an error.
+    pub fn decompress_block<W: io::Write>(
+        &mut self,
+        block: &[u8],
+        output: &mut W,
+    ) -> error::Result<usize> {
+        let mut input = block;
+        let mut bounded = util::BoundedWrite::new(output, self.block_size);
+        let result = match self.format {
+            Format::Lzma => self.decompress_lzma(&mut input, &mut bounded),
+            Format::Lzma2 => self.lzma2_decoder.decompress(&mut input, &mut bounded),
+            Format::Xz => xz::decode_stream(
+                &mut input,
+                &mut bounded,
+                self.options.unsupported_check,
+                &mut |_| (),
+            )
+            .map(|_| ()),
+        };
+
+        if bounded.exceeded() {
+            return Err(error::Error::LzmaError(format!(
+                "Block decompressed to more than {} bytes",
+                self.block_size
+            )));
+        }
+        result?;
+        Ok(bounded.count())
+    }
+
+    fn decompress_lzma<R, W>(&mut self, input: &mut R, output: &mut W) -> error::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        let params = LzmaParams::read_header(input, &self.options)?;
+        let state = match self.lzma_state.as_mut() {
+            Some(state) => {
+                state.reset_state(params.properties);
+                state.set_unpacked_size(params.unpacked_size);
+                state
+            }
+            None => self
+                .lzma_state
+                .get_or_insert(DecoderState::new(params.properties, params.unpacked_size)),
+        };
+
+        // The dictionary never needs to be larger than a block.
+        let dict_size = std::cmp::min(params.dict_size as usize, self.block_size.max(1));
+        let mut output = LzCircularBuffer::from_stream(
+            output,
+            dict_size,
+            self.options.memlimit.unwrap_or(usize::MAX),
+        );
+        let mut rangecoder = RangeDecoder::new(input)
+            .map_err(|e| error::Error::Truncated(format!("LZMA stream too short: {}", e)))?;
+        state.process(&mut output, &mut rangecoder)?;
+        output.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn compress(format: Format, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut input = data;
+        match format {
+            Format::Lzma => crate::lzma_compress(&mut input, &mut compressed),
+            Format::Lzma2 => crate::lzma2_compress(&mut input, &mut compressed),
+            Format::Xz => crate::xz_compress(&mut input, &mut compressed),
+        }
+        .unwrap();
+        compressed
+    }
+
+    #[test]
+    fn test_decompress_blocks() {
+        let data = include_bytes!("../../tests/files/foo.txt");
+        for &format in &[Format::Lzma, Format::Lzma2, Format::Xz] {
+            let blocks: Vec<Vec<u8>> = data.chunks(4096).map(|b| compress(format, b)).collect();
+            let mut decoder = FixedBlockDecoder::new(format, 4096, &Options::default());
+            let mut output = Vec::new();
+            for block in &blocks {
+                decoder.decompress_block(block, &mut output).unwrap();
+            }
+            assert_eq!(&output[..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_block_too_large() {
+        let block = compress(Format::Lzma, &[0; 100]);
+        let mut decoder = FixedBlockDecoder::new(Format::Lzma, 64, &Options::default());
+        let err = decoder
+            .decompress_block(&block, &mut Vec::new())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("more than 64 bytes"),
+            "error was: {}",
+            err
+        );
+    }
+}
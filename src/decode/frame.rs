@@ -0,0 +1,111 @@
+//! Length-prefixed compressed messages.
+
+use crate::decode::lzbuffer::LzCircularBuffer;
+use crate::decode::lzma2::Lzma2Decoder;
+use crate::decode::util;
+use crate::error;
+use std::io;
+use std::io::Read;
+
+/// Decompressor of the frames written by
+/// [`FrameEncoder`](crate::compress::FrameEncoder), returning one message at
+/// a time.
+///
+/// The dictionary is kept from one frame to the next, so frames must be read
+/// in the order they were written.
+pub struct FrameDecoder<R>
+where
+    R: io::BufRead,
+{
+    /// Input source
+    input: R,
+    /// LZMA2 state, kept across frames
+    lzma2: Lzma2Decoder,
+    /// Dictionary, kept across frames, whose sink collects the current
+    /// message
+    accum: LzCircularBuffer<Vec<u8>>,
+}
+
+impl<R> FrameDecoder<R>
+where
+    R: io::BufRead,
+{
+    /// Initialize the decompressor, reading frames from the `input` source,
+    /// with a dictionary of 8 MiB.
+    pub fn new(input: R) -> Self {
+        Self::with_dict_size(input, 0x0080_0000)
+    }
+
+    /// Initialize the decompressor with the given dictionary size, which
+    /// must be at least the one used by the compressor.
+    pub fn with_dict_size(input: R, dict_size: u32) -> Self {
+        Self {
+            input,
+            lzma2: Lzma2Decoder::new(),
+            accum: LzCircularBuffer::from_stream(Vec::new(), dict_size as usize, usize::MAX),
+        }
+    }
+
+    /// Get a reference to the input source.
+    pub fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    /// Consumes the decompressor and returns the input source.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Read and decompress the next frame, returning `None` if the input
+    /// ends right before it.
+    pub fn read_frame(&mut self) -> error::Result<Option<Vec<u8>>> {
+        if util::is_eof(&mut self.input)? {
+            return Ok(None);
+        }
+        let len = read_frame_len(&mut self.input)?;
+        let mut payload = (&mut self.input).take(len);
+        self.lzma2
+            .decompress_chunks(&mut payload, &mut self.accum)?;
+        if payload.limit() != 0 {
+            return Err(error::Error::LzmaError(format!(
+                "Frame has {} byte(s) after the LZMA2 end marker",
+                payload.limit()
+            )));
+        }
+        self.accum.flush_pending()?;
+        Ok(Some(std::mem::take(self.accum.stream_mut())))
+    }
+}
+
+/// Read the variable-length payload size of a frame.
+fn read_frame_len<R>(input: &mut R) -> error::Result<u64>
+where
+    R: io::Read,
+{
+    let mut len = 0;
+    for i in 0..9 {
+        let mut byte = [0u8];
+        input
+            .read_exact(&mut byte)
+            .map_err(|e| error::Error::Truncated(format!("Frame length: {}", e)))?;
+        len ^= ((byte[0] & 0x7F) as u64) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Err(error::Error::LzmaError(
+        "Frame length is longer than 9 bytes".to_string(),
+    ))
+}
+
+impl<R> std::fmt::Debug for FrameDecoder<R>
+where
+    R: io::BufRead + std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("FrameDecoder")
+            .field("input", &self.input)
+            .field("lzma2", &self.lzma2)
+            .finish()
+    }
+}
@@ -0,0 +1,158 @@
+//! Incremental parsing of LZMA headers.
+
+use crate::decode::lzma::LzmaParams;
+#[cfg(all(test, feature = "raw_decoder"))]
+use crate::decode::lzma::UnpackedSizeField;
+use crate::decompress::{Options, UnpackedSize};
+use crate::error;
+
+/// Length of the properties and dictionary size fields of the header.
+pub(crate) const MIN_HEADER_LEN: usize = 5;
+
+/// Length of the header including the unpacked size field.
+pub(crate) const MAX_HEADER_LEN: usize = MIN_HEADER_LEN + 8;
+
+/// Incremental parser of LZMA headers.
+///
+/// Bytes are fed as they arrive and the parser never consumes more than the
+/// header itself, so that protocols multiplexing LZMA streams with other
+/// traffic can hand the remaining bytes to the decoder, or elsewhere.
+#[derive(Clone, Debug)]
+pub struct HeaderParser {
+    /// Header bytes accumulated so far
+    buf: [u8; MAX_HEADER_LEN],
+    /// Number of bytes in `buf`
+    len: usize,
+    /// Options used to interpret the header
+    options: Options,
+    /// Parameters, once the whole header has been read
+    params: Option<LzmaParams>,
+}
+
+impl HeaderParser {
+    /// Create a parser interpreting the header according to the
+    /// [`unpacked_size`](Options::unpacked_size) option.
+    pub fn new(options: &Options) -> Self {
+        Self {
+            buf: [0; MAX_HEADER_LEN],
+            len: 0,
+            options: *options,
+            params: None,
+        }
+    }
+
+    /// Total length of the header, in bytes.
+    pub fn header_len(&self) -> usize {
+        match self.options.unpacked_size {
+            UnpackedSize::UseProvided(_) => MIN_HEADER_LEN,
+            UnpackedSize::ReadFromHeader | UnpackedSize::ReadHeaderButUseProvided(_) => {
+                MAX_HEADER_LEN
+            }
+        }
+    }
+
+    /// Consume header bytes from `data`, returning how many were consumed.
+    ///
+    /// Once the header is complete, it is parsed and no more bytes are
+    /// consumed. Fails if the header is invalid.
+    pub fn push(&mut self, data: &[u8]) -> error::Result<usize> {
+        if self.params.is_some() {
+            return Ok(0);
+        }
+        let n = std::cmp::min(data.len(), self.header_len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+
+        if self.len == self.header_len() {
+            let mut header = &self.buf[..self.len];
+            self.params = Some(LzmaParams::read_header(&mut header, &self.options)?);
+        }
+        Ok(n)
+    }
+
+    /// Whether the whole header has been read.
+    pub fn is_complete(&self) -> bool {
+        self.params.is_some()
+    }
+
+    /// Number of header bytes consumed so far.
+    #[cfg(feature = "stream")]
+    pub(crate) fn consumed(&self) -> usize {
+        self.len
+    }
+
+    /// Parameters read from the header, once it is complete.
+    pub fn params(&self) -> Option<LzmaParams> {
+        self.params
+    }
+}
+
+#[cfg(all(test, feature = "raw_decoder"))]
+mod test {
+    use super::*;
+    use crate::UncompressedSize;
+
+    #[test]
+    fn test_byte_by_byte() {
+        let header = [
+            0x5D, 0x00, 0x00, 0x80, 0x00, 0x0C, 0, 0, 0, 0, 0, 0, 0, 0xAB,
+        ];
+        let mut parser = HeaderParser::new(&Options::default());
+        for (i, byte) in header.iter().enumerate() {
+            let consumed = parser.push(&[*byte]).unwrap();
+            assert_eq!(consumed, (i < 13) as usize);
+            assert_eq!(parser.is_complete(), i >= 12);
+        }
+        let params = parser.params().unwrap();
+        assert_eq!(params.dict_size(), 0x80_0000);
+        assert_eq!(params.unpacked_size(), Some(UncompressedSize(12)));
+        assert_eq!(params.unpacked_size_field(), UnpackedSizeField::Known(12));
+        let properties = params.properties();
+        assert_eq!((properties.lc, properties.lp, properties.pb), (3, 0, 2));
+    }
+
+    #[test]
+    fn test_provided_size() {
+        let options = Options {
+            unpacked_size: UnpackedSize::UseProvided(Some(42)),
+            ..Default::default()
+        };
+        let mut parser = HeaderParser::new(&options);
+        assert_eq!(parser.header_len(), 5);
+        assert_eq!(parser.push(&[0x5D, 0, 0, 1, 0, 0xFF, 0xFF]).unwrap(), 5);
+        let params = parser.params().unwrap();
+        assert_eq!(params.unpacked_size(), Some(UncompressedSize(42)));
+        assert_eq!(params.unpacked_size_field(), UnpackedSizeField::Absent);
+
+        // The header is written back without the size field.
+        let mut header = Vec::new();
+        params.write_header(&mut header).unwrap();
+        assert_eq!(header, [0x5D, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_unknown_size_round_trip() {
+        let header = [
+            0x5D, 0, 0, 1, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let mut parser = HeaderParser::new(&Options::default());
+        parser.push(&header).unwrap();
+        let params = parser.params().unwrap();
+        assert_eq!(params.unpacked_size(), None);
+        assert_eq!(params.unpacked_size_field(), UnpackedSizeField::Unknown);
+
+        let mut written = Vec::new();
+        params.write_header(&mut written).unwrap();
+        assert_eq!(written, header);
+    }
+
+    #[test]
+    fn test_invalid_properties() {
+        let mut parser = HeaderParser::new(&Options::default());
+        let err = parser.push(&[225; 13]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lzma error: LZMA header invalid properties: 225 must be < 225"
+        );
+    }
+}
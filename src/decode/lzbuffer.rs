@@ -1,4 +1,6 @@
 use crate::error;
+use crate::error::SinkError;
+use std::collections::VecDeque;
 use std::io;
 
 pub trait LzBuffer<W>
@@ -11,7 +13,7 @@ where
     fn last_or(&self, lit: u8) -> u8;
 
     /// Retrieve the n-th last byte.
-    fn last_n(&self, dist: usize) -> error::Result<u8>;
+    fn last_n(&mut self, dist: usize) -> error::Result<u8>;
 
     /// Append a literal.
     fn append_literal(&mut self, lit: u8) -> error::Result<()>;
@@ -19,17 +21,134 @@ where
     /// Fetch an LZ sequence (length, distance) from inside the buffer.
     fn append_lz(&mut self, len: usize, dist: usize) -> error::Result<()>;
 
+    /// Append bytes.
+    fn append_bytes(&mut self, buf: &[u8]) -> error::Result<()>;
+
+    /// Reset the internal dictionary.
+    fn reset(&mut self) -> io::Result<()>;
+
     /// Get a reference to the output sink.
+    #[cfg(feature = "stream")]
     fn get_output(&self) -> &W;
 
     /// Get a mutable reference to the output sink.
+    #[cfg(feature = "stream")]
     fn get_output_mut(&mut self) -> &mut W;
 
     /// Consumes this buffer and flushes any data.
     fn finish(self) -> io::Result<W>;
+}
+
+/// A destination for decompressed bytes which keeps the history that LZ
+/// matches copy from, so that the decoder appends straight to it instead of
+/// going through a dictionary of its own. This lets e.g. audio or telemetry
+/// pipelines decompress into their existing ring buffers without copies.
+///
+/// Matches reach at most the dictionary size back: a buffer dropping older
+/// bytes, as a ring buffer does, must keep at least that many of the last
+/// bytes appended, or matches reaching farther back fail.
+pub trait OutputBuffer {
+    /// Append decompressed bytes.
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Append `len` bytes copied from `dist` bytes back, 1 being the last
+    /// byte appended. The bytes are copied one at a time, so a copy longer
+    /// than `dist` repeats the bytes it appended. The decoder only calls this
+    /// after [`OutputBuffer::history()`] returned a byte for `dist`.
+    fn copy_from_history(&mut self, dist: usize, len: usize) -> io::Result<()>;
+
+    /// The byte `dist` bytes back, 1 being the last byte appended, or `None`
+    /// if the buffer doesn't hold it.
+    fn history(&self, dist: usize) -> Option<u8>;
+
+    /// Called once the stream is decompressed. Does nothing by default.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputBuffer for Vec<u8> {
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn copy_from_history(&mut self, dist: usize, len: usize) -> io::Result<()> {
+        self.reserve(len);
+        let offset = self.len() - dist;
+        for i in offset..offset + len {
+            let x = self[i];
+            self.push(x);
+        }
+        Ok(())
+    }
+
+    fn history(&self, dist: usize) -> Option<u8> {
+        let index = self.len().checked_sub(dist)?;
+        self.get(index).copied()
+    }
+}
+
+impl OutputBuffer for VecDeque<u8> {
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.extend(bytes);
+        Ok(())
+    }
+
+    fn copy_from_history(&mut self, dist: usize, len: usize) -> io::Result<()> {
+        self.reserve(len);
+        let offset = self.len() - dist;
+        for i in offset..offset + len {
+            let x = self[i];
+            self.push_back(x);
+        }
+        Ok(())
+    }
+
+    fn history(&self, dist: usize) -> Option<u8> {
+        let index = self.len().checked_sub(dist)?;
+        self.get(index).copied()
+    }
+}
+
+/// Write all of `buf` to the output `stream`, at most `max_write` bytes at a
+/// time, adding the bytes it accepts to `written`, and wrap its errors in a
+/// [`SinkError`] with that count.
+fn write_sink<W>(
+    stream: &mut W,
+    mut buf: &[u8],
+    max_write: usize,
+    written: &mut u64,
+) -> io::Result<()>
+where
+    W: io::Write,
+{
+    while !buf.is_empty() {
+        let len = std::cmp::min(buf.len(), max_write);
+        match stream.write(&buf[..len]) {
+            Ok(0) => {
+                return Err(SinkError::wrap(
+                    io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer"),
+                    *written,
+                ))
+            }
+            Ok(n) => {
+                *written += n as u64;
+                buf = &buf[n..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(SinkError::wrap(e, *written)),
+        }
+    }
+    Ok(())
+}
 
-    /// Consumes this buffer without flushing any data.
-    fn into_output(self) -> W;
+/// Flush the output `stream`, wrapping its errors like [`write_sink`].
+fn flush_sink<W>(stream: &mut W, written: u64) -> io::Result<()>
+where
+    W: io::Write,
+{
+    stream.flush().map_err(|e| SinkError::wrap(e, written))
 }
 
 /// An accumulating buffer for LZ sequences.
@@ -45,6 +164,8 @@ where
     memlimit: usize,
     /// Total number of bytes sent through the buffer
     len: usize,
+    /// Number of bytes accepted by the output sink
+    written: u64,
 }
 
 impl<W> LzAccumBuffer<W>
@@ -57,22 +178,9 @@ where
             buf: Vec::new(),
             memlimit,
             len: 0,
+            written: 0,
         }
     }
-
-    /// Append bytes.
-    pub fn append_bytes(&mut self, buf: &[u8]) {
-        self.buf.extend_from_slice(buf);
-        self.len += buf.len();
-    }
-
-    /// Reset the internal dictionary.
-    pub fn reset(&mut self) -> io::Result<()> {
-        self.stream.write_all(self.buf.as_slice())?;
-        self.buf.clear();
-        self.len = 0;
-        Ok(())
-    }
 }
 
 impl<W> LzBuffer<W> for LzAccumBuffer<W>
@@ -92,7 +200,7 @@ where
         }
     }
 
-    fn last_n(&self, dist: usize) -> error::Result<u8> {
+    fn last_n(&mut self, dist: usize) -> error::Result<u8> {
         let buf_len = self.buf.len();
         if dist > buf_len {
             return Err(error::Error::LzmaError(format!(
@@ -108,10 +216,7 @@ where
         let new_len = self.len + 1;
 
         if new_len > self.memlimit {
-            Err(error::Error::LzmaError(format!(
-                "exceeded memory limit of {}",
-                self.memlimit
-            )))
+            Err(error::Error::MemoryLimitExceeded(self.memlimit))
         } else {
             self.buf.push(lit);
             self.len = new_len;
@@ -129,33 +234,49 @@ where
             )));
         }
 
-        let mut offset = buf_len - dist;
-        for _ in 0..len {
-            let x = self.buf[offset];
+        let offset = buf_len - dist;
+        for i in offset..offset + len {
+            let x = self.buf[i];
             self.buf.push(x);
-            offset += 1;
         }
         self.len += len;
         Ok(())
     }
 
+    fn append_bytes(&mut self, buf: &[u8]) -> error::Result<()> {
+        let new_len = self.len + buf.len();
+
+        if new_len > self.memlimit {
+            Err(error::Error::MemoryLimitExceeded(self.memlimit))
+        } else {
+            self.buf.extend_from_slice(buf);
+            self.len = new_len;
+            Ok(())
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        write_sink(&mut self.stream, &self.buf, usize::MAX, &mut self.written)?;
+        self.buf.clear();
+        self.len = 0;
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
     fn get_output(&self) -> &W {
         &self.stream
     }
 
+    #[cfg(feature = "stream")]
     fn get_output_mut(&mut self) -> &mut W {
         &mut self.stream
     }
 
     fn finish(mut self) -> io::Result<W> {
-        self.stream.write_all(self.buf.as_slice())?;
-        self.stream.flush()?;
+        write_sink(&mut self.stream, &self.buf, usize::MAX, &mut self.written)?;
+        flush_sink(&mut self.stream, self.written)?;
         Ok(self.stream)
     }
-
-    fn into_output(self) -> W {
-        self.stream
-    }
 }
 
 /// A circular buffer for LZ sequences
@@ -173,23 +294,98 @@ where
     memlimit: usize,
     /// Current position
     cursor: usize,
+    /// Position of the first byte not yet written to the output sink
+    flushed: usize,
     /// Total number of bytes sent through the buffer
     len: usize,
+    /// Number of bytes accepted by the output sink
+    written: u64,
+    /// Maximum number of bytes passed to a single write to the output sink
+    max_write: usize,
 }
 
 impl<W> LzCircularBuffer<W>
 where
     W: io::Write,
 {
+    /// Create a buffer for a dictionary of `dict_size` bytes, writing to
+    /// `stream`. A dictionary of 0 bytes, which some raw parameters declare,
+    /// holds 1 byte: the one being decoded. Matches farther back than the
+    /// dictionary, however long, fail with an error.
     pub fn from_stream(stream: W, dict_size: usize, memlimit: usize) -> Self {
         lzma_info!("Dict size in LZ buffer: {}", dict_size);
+        let dict_size = std::cmp::max(dict_size, 1);
         Self {
             stream,
             buf: Vec::new(),
             dict_size,
             memlimit,
             cursor: 0,
+            flushed: 0,
             len: 0,
+            written: 0,
+            max_write: usize::MAX,
+        }
+    }
+
+    /// Split the writes to the output sink into writes of at most
+    /// `max_write` bytes, which must be positive.
+    pub fn set_max_write(&mut self, max_write: usize) {
+        lzma_check!(max_write > 0);
+        self.max_write = max_write;
+    }
+
+    /// Write the bytes appended so far to the output sink, keeping them in
+    /// the dictionary.
+    pub fn flush_pending(&mut self) -> io::Result<()> {
+        write_sink(
+            &mut self.stream,
+            &self.buf[self.flushed..self.cursor],
+            self.max_write,
+            &mut self.written,
+        )?;
+        self.flushed = self.cursor;
+        Ok(())
+    }
+
+    /// Get a mutable reference to the output sink.
+    pub fn stream_mut(&mut self) -> &mut W {
+        &mut self.stream
+    }
+
+    /// Copy of the dictionary window: the last bytes appended since the last
+    /// reset, up to the dictionary size, oldest first.
+    pub fn dictionary(&self) -> Vec<u8> {
+        if self.len >= self.dict_size {
+            let mut window = self.buf[self.cursor..].to_vec();
+            window.extend_from_slice(&self.buf[..self.cursor]);
+            window
+        } else {
+            self.buf[..self.cursor].to_vec()
+        }
+    }
+
+    /// Fill the empty dictionary with the last bytes of `dict`, up to the
+    /// dictionary size, without writing them to the output sink. Matches
+    /// can then reach into them, and they count in [`LzBuffer::len()`].
+    /// Returns the number of bytes kept.
+    pub fn preset(&mut self, dict: &[u8]) -> error::Result<usize> {
+        lzma_check!(self.len == 0 && self.cursor == 0);
+        let kept = &dict[dict.len().saturating_sub(self.dict_size)..];
+        for (index, &byte) in kept.iter().enumerate() {
+            self.set(index, byte)?;
+        }
+        self.len = kept.len();
+        self.cursor = kept.len() % self.dict_size;
+        self.flushed = self.cursor;
+        Ok(kept.len())
+    }
+
+    /// Allocate the whole dictionary upfront, if it fits within the memory
+    /// limit.
+    pub fn reserve_dict(&mut self) {
+        if self.dict_size <= self.memlimit {
+            self.buf.reserve_exact(self.dict_size - self.buf.len());
         }
     }
 
@@ -204,10 +400,7 @@ where
             if new_len <= self.memlimit {
                 self.buf.resize(new_len, 0);
             } else {
-                return Err(error::Error::LzmaError(format!(
-                    "exceeded memory limit of {}",
-                    self.memlimit
-                )));
+                return Err(error::Error::MemoryLimitExceeded(self.memlimit));
             }
         }
         self.buf[index] = value;
@@ -231,7 +424,7 @@ where
         }
     }
 
-    fn last_n(&self, dist: usize) -> error::Result<u8> {
+    fn last_n(&mut self, dist: usize) -> error::Result<u8> {
         if dist > self.dict_size {
             return Err(error::Error::LzmaError(format!(
                 "Match distance {} is beyond dictionary size {}",
@@ -250,14 +443,21 @@ where
     }
 
     fn append_literal(&mut self, lit: u8) -> error::Result<()> {
+        lzma_check!(self.flushed <= self.cursor && self.cursor < self.dict_size);
         self.set(self.cursor, lit)?;
         self.cursor += 1;
         self.len += 1;
 
         // Flush the circular buffer to the output
         if self.cursor == self.dict_size {
-            self.stream.write_all(self.buf.as_slice())?;
+            write_sink(
+                &mut self.stream,
+                &self.buf[self.flushed..],
+                self.max_write,
+                &mut self.written,
+            )?;
             self.cursor = 0;
+            self.flushed = 0;
         }
 
         Ok(())
@@ -290,24 +490,383 @@ where
         Ok(())
     }
 
+    fn append_bytes(&mut self, buf: &[u8]) -> error::Result<()> {
+        for &x in buf {
+            self.append_literal(x)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.flush_pending()?;
+        self.cursor = 0;
+        self.flushed = 0;
+        self.len = 0;
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
     fn get_output(&self) -> &W {
         &self.stream
     }
 
+    #[cfg(feature = "stream")]
     fn get_output_mut(&mut self) -> &mut W {
         &mut self.stream
     }
 
     fn finish(mut self) -> io::Result<W> {
-        if self.cursor > 0 {
-            self.stream.write_all(&self.buf[0..self.cursor])?;
-        }
-        self.stream.flush()?;
+        self.flush_pending()?;
+        flush_sink(&mut self.stream, self.written)?;
         Ok(self.stream)
     }
+}
+
+impl<W> OutputBuffer for LzCircularBuffer<W>
+where
+    W: io::Write,
+{
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        Ok(self.append_bytes(bytes)?)
+    }
+
+    fn copy_from_history(&mut self, dist: usize, len: usize) -> io::Result<()> {
+        Ok(self.append_lz(len, dist)?)
+    }
+
+    fn history(&self, dist: usize) -> Option<u8> {
+        if dist == 0 || dist > self.dict_size || dist > self.len {
+            return None;
+        }
+        Some(self.get((self.dict_size + self.cursor - dist) % self.dict_size))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_pending()?;
+        flush_sink(&mut self.stream, self.written)
+    }
+}
+
+/// A buffer for LZ sequences appending to an [`OutputBuffer`], which holds
+/// the dictionary. As there is no output sink, the sink type is
+/// [`io::Sink`].
+pub struct LzOutputBuffer<'a, B>
+where
+    B: OutputBuffer + ?Sized,
+{
+    /// Buffer holding the output and the dictionary
+    buf: &'a mut B,
+    /// Farthest distance of a match
+    dict_size: usize,
+    /// Number of bytes appended since the last reset
+    len: usize,
+    sink: io::Sink,
+}
+
+impl<'a, B> LzOutputBuffer<'a, B>
+where
+    B: OutputBuffer + ?Sized,
+{
+    pub fn new(buf: &'a mut B, dict_size: usize) -> Self {
+        Self {
+            buf,
+            dict_size: std::cmp::max(dict_size, 1),
+            len: 0,
+            sink: io::sink(),
+        }
+    }
 
-    fn into_output(self) -> W {
-        self.stream
+    /// Retrieve the byte `dist` bytes back, checking that a match may reach
+    /// it.
+    fn history(&self, dist: usize) -> error::Result<u8> {
+        if dist > self.dict_size {
+            return Err(error::Error::LzmaError(format!(
+                "LZ distance {} is beyond dictionary size {}",
+                dist, self.dict_size
+            )));
+        }
+        if dist > self.len {
+            return Err(error::Error::LzmaError(format!(
+                "LZ distance {} is beyond output size {}",
+                dist, self.len
+            )));
+        }
+        self.buf.history(dist).ok_or_else(|| {
+            error::Error::LzmaError(format!(
+                "LZ distance {} is beyond the history of the output buffer",
+                dist
+            ))
+        })
+    }
+}
+
+impl<'a, B> LzBuffer<io::Sink> for LzOutputBuffer<'a, B>
+where
+    B: OutputBuffer + ?Sized,
+{
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn last_or(&self, lit: u8) -> u8 {
+        if self.len == 0 {
+            lit
+        } else {
+            self.buf.history(1).unwrap_or(lit)
+        }
+    }
+
+    fn last_n(&mut self, dist: usize) -> error::Result<u8> {
+        self.history(dist)
+    }
+
+    fn append_literal(&mut self, lit: u8) -> error::Result<()> {
+        self.buf.append(&[lit])?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn append_lz(&mut self, len: usize, dist: usize) -> error::Result<()> {
+        lzma_debug!("LZ {{ len: {}, dist: {} }}", len, dist);
+        self.history(dist)?;
+        self.buf.copy_from_history(dist, len)?;
+        self.len += len;
+        Ok(())
+    }
+
+    fn append_bytes(&mut self, buf: &[u8]) -> error::Result<()> {
+        self.buf.append(buf)?;
+        self.len += buf.len();
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.len = 0;
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
+    fn get_output(&self) -> &io::Sink {
+        &self.sink
+    }
+
+    #[cfg(feature = "stream")]
+    fn get_output_mut(&mut self) -> &mut io::Sink {
+        &mut self.sink
+    }
+
+    fn finish(self) -> io::Result<io::Sink> {
+        self.buf.flush()?;
+        Ok(self.sink)
+    }
+}
+
+/// Smallest window of [`LzWindowedBuffer`], so that the bytes of a match
+/// read back from the output never overlap the match itself.
+pub const MIN_WINDOW_SIZE: usize = 4096;
+
+/// A buffer for LZ sequences keeping only the most recent part of the
+/// dictionary in memory.
+///
+/// Matches reaching beyond the window are served by reading the bytes back
+/// from the output, which must therefore be seekable and readable, e.g. a
+/// file opened for both reading and writing.
+pub struct LzWindowedBuffer<W>
+where
+    W: io::Read + io::Write + io::Seek,
+{
+    /// Output sink, also holding the history beyond the window
+    stream: W,
+    /// Circular buffer of the most recent bytes
+    buf: Vec<u8>,
+    /// Length of the circular buffer
+    window_size: usize,
+    /// Size of the dictionary of the stream, which bounds the distances
+    dict_size: usize,
+    /// Current position in the circular buffer
+    cursor: usize,
+    /// Position of the first byte not yet written to the output sink
+    flushed: usize,
+    /// Number of bytes sent through the buffer since the last reset
+    len: usize,
+    /// Offset of the first output byte in the output sink
+    start: u64,
+    /// Number of bytes accepted by the output sink
+    written: u64,
+}
+
+impl<W> LzWindowedBuffer<W>
+where
+    W: io::Read + io::Write + io::Seek,
+{
+    /// Create a buffer keeping the last `window_size` bytes of a dictionary
+    /// of `dict_size` bytes in memory, writing to `stream` from its current
+    /// position.
+    pub fn from_stream(mut stream: W, dict_size: usize, window_size: usize) -> io::Result<Self> {
+        let window_size =
+            window_size.clamp(MIN_WINDOW_SIZE, std::cmp::max(dict_size, MIN_WINDOW_SIZE));
+        lzma_info!(
+            "Window of {} byte(s) for a dictionary of {} byte(s)",
+            window_size,
+            dict_size
+        );
+        let start = stream.stream_position()?;
+        Ok(Self {
+            stream,
+            buf: Vec::new(),
+            window_size,
+            dict_size,
+            cursor: 0,
+            flushed: 0,
+            len: 0,
+            start,
+            written: 0,
+        })
+    }
+
+    /// Write the bytes appended so far to the output sink.
+    fn flush_pending(&mut self) -> io::Result<()> {
+        write_sink(
+            &mut self.stream,
+            &self.buf[self.flushed..self.cursor],
+            usize::MAX,
+            &mut self.written,
+        )?;
+        self.flushed = self.cursor;
+        Ok(())
+    }
+
+    fn check_distance(&self, dist: usize) -> error::Result<()> {
+        if dist > self.dict_size {
+            return Err(error::Error::LzmaError(format!(
+                "LZ distance {} is beyond dictionary size {}",
+                dist, self.dict_size
+            )));
+        }
+        if dist > self.len {
+            return Err(error::Error::LzmaError(format!(
+                "LZ distance {} is beyond output size {}",
+                dist, self.len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read `out.len()` bytes of history starting `dist` bytes back from the
+    /// output sink, leaving it positioned at its end.
+    fn read_history(&mut self, dist: usize, out: &mut [u8]) -> io::Result<()> {
+        lzma_check!(out.len() <= dist);
+        self.flush_pending()?;
+        let end = self.start + self.written;
+        self.stream.seek(io::SeekFrom::Start(end - dist as u64))?;
+        self.stream.read_exact(out)?;
+        self.stream.seek(io::SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+impl<W> LzBuffer<W> for LzWindowedBuffer<W>
+where
+    W: io::Read + io::Write + io::Seek,
+{
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn last_or(&self, lit: u8) -> u8 {
+        if self.len == 0 {
+            lit
+        } else {
+            self.buf[(self.window_size + self.cursor - 1) % self.window_size]
+        }
+    }
+
+    fn last_n(&mut self, dist: usize) -> error::Result<u8> {
+        self.check_distance(dist)?;
+        if dist <= self.window_size {
+            return Ok(self.buf[(self.window_size + self.cursor - dist) % self.window_size]);
+        }
+        let mut byte = [0];
+        self.read_history(dist, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn append_literal(&mut self, lit: u8) -> error::Result<()> {
+        lzma_check!(self.flushed <= self.cursor && self.cursor < self.window_size);
+        if self.buf.len() <= self.cursor {
+            self.buf.push(lit);
+        } else {
+            self.buf[self.cursor] = lit;
+        }
+        self.cursor += 1;
+        self.len += 1;
+
+        if self.cursor == self.window_size {
+            write_sink(
+                &mut self.stream,
+                &self.buf[self.flushed..],
+                usize::MAX,
+                &mut self.written,
+            )?;
+            self.cursor = 0;
+            self.flushed = 0;
+        }
+        Ok(())
+    }
+
+    fn append_lz(&mut self, len: usize, dist: usize) -> error::Result<()> {
+        lzma_debug!("LZ {{ len: {}, dist: {} }}", len, dist);
+        self.check_distance(dist)?;
+        if dist <= self.window_size {
+            let mut offset = (self.window_size + self.cursor - dist) % self.window_size;
+            for _ in 0..len {
+                let x = self.buf[offset];
+                self.append_literal(x)?;
+                offset += 1;
+                if offset == self.window_size {
+                    offset = 0
+                }
+            }
+        } else {
+            // The window is longer than any match, so the bytes copied all
+            // precede the current position.
+            let mut bytes = [0; MIN_WINDOW_SIZE];
+            let bytes = &mut bytes[..len];
+            self.read_history(dist, bytes)?;
+            self.append_bytes(bytes)?;
+        }
+        Ok(())
+    }
+
+    fn append_bytes(&mut self, buf: &[u8]) -> error::Result<()> {
+        for &x in buf {
+            self.append_literal(x)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.flush_pending()?;
+        self.cursor = 0;
+        self.flushed = 0;
+        self.len = 0;
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
+    fn get_output(&self) -> &W {
+        &self.stream
+    }
+
+    #[cfg(feature = "stream")]
+    fn get_output_mut(&mut self) -> &mut W {
+        &mut self.stream
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.flush_pending()?;
+        flush_sink(&mut self.stream, self.written)?;
+        Ok(self.stream)
     }
 }
 
@@ -346,4 +905,111 @@ mod test {
         let stream = b.finish().unwrap();
         assert!(stream.unflushed.is_empty());
     }
+
+    #[test]
+    fn flush_pending_keeps_dictionary() {
+        let mut b = LzCircularBuffer::from_stream(Vec::new(), 4, usize::MAX);
+        b.append_bytes(b"abc").unwrap();
+        b.flush_pending().unwrap();
+        assert_eq!(b.stream_mut(), b"abc");
+        // Wrapping around only writes the bytes not flushed yet.
+        b.append_bytes(b"de").unwrap();
+        b.append_lz(2, 4).unwrap();
+        b.flush_pending().unwrap();
+        assert_eq!(b.stream_mut(), b"abcdebc");
+        assert_eq!(b.finish().unwrap(), b"abcdebc");
+    }
+
+    #[test]
+    fn dictionary_window() {
+        let mut b = LzCircularBuffer::from_stream(Vec::new(), 4, usize::MAX);
+        assert_eq!(b.dictionary(), b"");
+        b.append_bytes(b"abc").unwrap();
+        assert_eq!(b.dictionary(), b"abc");
+        b.append_bytes(b"d").unwrap();
+        assert_eq!(b.dictionary(), b"abcd");
+        b.append_bytes(b"efghij").unwrap();
+        assert_eq!(b.dictionary(), b"ghij");
+        b.reset().unwrap();
+        assert_eq!(b.dictionary(), b"");
+    }
+
+    #[test]
+    fn degenerate_dict_sizes() {
+        for &dict_size in [0, 1, 2].iter() {
+            let mut b = LzCircularBuffer::from_stream(Vec::new(), dict_size, usize::MAX);
+            assert_eq!(b.last_or(7), 7);
+            assert!(b.last_n(1).is_err());
+            b.append_literal(b'a').unwrap();
+            assert_eq!(b.last_or(7), b'a');
+            // Matches may be longer than the dictionary.
+            b.append_lz(10, 1).unwrap();
+            b.append_literal(b'b').unwrap();
+            let err = b.append_lz(3, dict_size + 2).unwrap_err().to_string();
+            assert!(err.contains("beyond dictionary size"), "{}", err);
+            assert_eq!(b.dictionary().len(), std::cmp::max(dict_size, 1));
+            assert_eq!(b.finish().unwrap(), b"aaaaaaaaaaab");
+        }
+
+        let mut b = LzCircularBuffer::from_stream(Vec::new(), 0, usize::MAX);
+        assert_eq!(b.preset(b"xyz").unwrap(), 1);
+        b.append_lz(2, 1).unwrap();
+        assert_eq!(b.finish().unwrap(), b"zz");
+    }
+
+    #[test]
+    fn output_buffers_agree() {
+        fn decode<B: OutputBuffer>(buf: &mut B, dict_size: usize) -> error::Result<()> {
+            let mut b = LzOutputBuffer::new(buf, dict_size);
+            b.append_bytes(b"abcd")?;
+            b.append_lz(6, 3)?;
+            b.append_literal(b'e')?;
+            b.append_lz(2, 5)?;
+            b.finish()?;
+            Ok(())
+        }
+
+        let expected = b"abcdbcdbcdedb";
+        let mut vec = Vec::new();
+        decode(&mut vec, 8).unwrap();
+        assert_eq!(vec, expected);
+        let mut deque = VecDeque::new();
+        decode(&mut deque, 8).unwrap();
+        assert_eq!(deque, expected);
+        let mut circular = LzCircularBuffer::from_stream(Vec::new(), 8, usize::MAX);
+        decode(&mut circular, 8).unwrap();
+        assert_eq!(circular.stream_mut(), expected);
+
+        // The distance is checked against the dictionary, and against the
+        // history the buffer holds.
+        let err = decode(&mut Vec::new(), 4).unwrap_err().to_string();
+        assert!(err.contains("beyond dictionary size"), "{}", err);
+        let mut small = LzCircularBuffer::from_stream(Vec::new(), 4, usize::MAX);
+        let err = decode(&mut small, 8).unwrap_err().to_string();
+        assert!(err.contains("beyond the history"), "{}", err);
+    }
+
+    #[test]
+    fn windowed_reads_back_history() {
+        let mut b = LzWindowedBuffer::from_stream(io::Cursor::new(Vec::new()), 0x10000, 0).unwrap();
+        assert_eq!(b.window_size, MIN_WINDOW_SIZE);
+        let data: Vec<u8> = (0..MIN_WINDOW_SIZE * 3)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        b.append_bytes(&data).unwrap();
+
+        let dist = MIN_WINDOW_SIZE * 2 + 10;
+        assert_eq!(b.last_n(dist).unwrap(), data[data.len() - dist]);
+        b.append_lz(100, dist).unwrap();
+        b.append_literal(1).unwrap();
+        assert!(b.append_lz(2, data.len() + 200).is_err());
+
+        let output = b.finish().unwrap().into_inner();
+        assert_eq!(&output[..data.len()], &data[..]);
+        assert_eq!(
+            &output[data.len()..data.len() + 100],
+            &data[data.len() - dist..data.len() - dist + 100]
+        );
+        assert_eq!(output.len(), data.len() + 101);
+    }
 }
@@ -1,16 +1,24 @@
-use crate::decode::lzbuffer::{LzBuffer, LzCircularBuffer};
+use crate::decode::lzbuffer::{
+    LzBuffer, LzCircularBuffer, LzOutputBuffer, LzWindowedBuffer, OutputBuffer,
+};
 use crate::decode::rangecoder::{BitTree, LenDecoder, RangeDecoder};
-use crate::decompress::{Options, UnpackedSize};
+use crate::decompress::{DeclaredSize, LargeDict, Options, UnpackedSize, MAX_DICT_SIZE};
 use crate::error;
+#[cfg(feature = "raw_decoder")]
+use crate::error::OptionsError;
 use crate::util::vec2d::Vec2D;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io;
 
 /// Maximum input data that can be processed in one iteration.
 /// Libhtp uses the following equation to define the maximum number of bits
 /// for the worst case scenario:
 ///   log2((2^11 / 31) ^ 22) + 26 < 134 + 26 = 160
-const MAX_REQUIRED_INPUT: usize = 20;
+pub(crate) const MAX_REQUIRED_INPUT: usize = 20;
+
+/// Number of range decoder operations over which the work per output byte is
+/// averaged before being checked against the work limit.
+const WORK_WINDOW: u64 = 1 << 16;
 
 /// Processing mode for decompression.
 ///
@@ -64,6 +72,109 @@ impl LzmaProperties {
         assert!(self.lp <= 4);
         assert!(self.pb <= 4);
     }
+
+    /// Index of the probabilities of a literal at position `pos` of the
+    /// plaintext after `prev_byte`: the `lp` low bits of the position, then
+    /// the `lc` high bits of the previous byte.
+    pub(crate) fn literal_state(self, pos: u64, prev_byte: u8) -> usize {
+        let pos_bits = (pos & ((1 << self.lp) - 1)) as usize;
+        (pos_bits << self.lc) + (prev_byte as usize >> (8 - self.lc))
+    }
+
+    /// "lclppb" byte encoding the properties, as found in headers.
+    pub(crate) fn to_byte(self) -> u8 {
+        (self.lc + 9 * (self.lp + 5 * self.pb)) as u8
+    }
+}
+
+/// Initial values of the literal and "is match" probabilities, in place of
+/// the 1/2 that the LZMA specification mandates.
+///
+/// Streams only decompress with the initial probabilities they were
+/// compressed with, so this is meant for experiments with trained priors,
+/// not for interoperable data. Probabilities are of a 0 bit, in units of
+/// 1/2048, and must be within `1..=2047`.
+#[cfg(feature = "raw_decoder")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InitialProbs {
+    /// Probabilities of the bits of a literal, used for every literal
+    /// context. Indices follow the literal coder: 1 to 0xFF for plain
+    /// literals, 0x100 to 0x2FF for literals after a match.
+    pub literal: [u16; 0x300],
+    /// Probabilities that the next symbol is a literal, indexed by
+    /// `(state << 4) + pos_state`.
+    pub is_match: [u16; 192],
+}
+
+#[cfg(feature = "raw_decoder")]
+impl Default for InitialProbs {
+    /// The probabilities of the specification.
+    fn default() -> Self {
+        Self {
+            literal: [0x400; 0x300],
+            is_match: [0x400; 192],
+        }
+    }
+}
+
+#[cfg(feature = "raw_decoder")]
+impl InitialProbs {
+    /// Check that every probability is within `1..=2047`.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        let check = |field, probs: &[u16]| match probs.iter().position(|p| !(1..=2047).contains(p))
+        {
+            Some(i) => Err(OptionsError::new(
+                field,
+                format!(
+                    "probability {} at index {} is not within 1..=2047",
+                    probs[i], i
+                ),
+            )),
+            None => Ok(()),
+        };
+        check("literal", &self.literal)?;
+        check("is_match", &self.is_match)
+    }
+
+    /// Overwrite the initial probabilities of a coder.
+    pub(crate) fn apply(&self, literal_probs: &mut Vec2D<u16>, is_match: &mut [u16; 192]) {
+        literal_probs.fill_rows(&self.literal);
+        *is_match = self.is_match;
+    }
+}
+
+/// Unpacked size field of an LZMA header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnpackedSizeField {
+    /// The field holds the size of the unpacked data.
+    Known(u64),
+    /// The field holds the `0xFFFF_FFFF_FFFF_FFFF` marker: the size is unknown
+    /// and the stream ends with an end-of-stream marker.
+    Unknown,
+    /// The header has no unpacked size field. The size, if any, is stored
+    /// elsewhere by the container.
+    Absent,
+}
+
+impl UnpackedSizeField {
+    /// Value of the 8-byte field encoding the unknown size.
+    const UNKNOWN_MARKER: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+
+    /// Length of the field in the header, in bytes.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            UnpackedSizeField::Known(_) | UnpackedSizeField::Unknown => 8,
+            UnpackedSizeField::Absent => 0,
+        }
+    }
+
+    fn from_value(value: u64) -> Self {
+        if value == Self::UNKNOWN_MARKER {
+            UnpackedSizeField::Unknown
+        } else {
+            UnpackedSizeField::Known(value)
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -75,9 +186,24 @@ pub struct LzmaParams {
     pub(crate) dict_size: u32,
     /// The size of the unpacked data.
     pub(crate) unpacked_size: Option<u64>,
+    /// The unpacked size field, as read from or written to the header. It
+    /// may differ from `unpacked_size` when the size is provided separately.
+    pub(crate) unpacked_size_field: UnpackedSizeField,
 }
 
 impl LzmaParams {
+    /// Size of the dictionary to allocate, capped to the unpacked size if
+    /// requested by the options.
+    pub(crate) fn dict_size_for(&self, options: &Options) -> usize {
+        match self.unpacked_size {
+            Some(unpacked_size) if options.cap_dict_to_unpacked_size => {
+                // The buffer must hold at least one byte.
+                std::cmp::min(self.dict_size as u64, unpacked_size.max(1)) as usize
+            }
+            _ => self.dict_size as usize,
+        }
+    }
+
     /// Create an new instance of LZMA parameters.
     #[cfg(feature = "raw_decoder")]
     pub fn new(
@@ -89,14 +215,79 @@ impl LzmaParams {
             properties,
             dict_size,
             unpacked_size,
+            unpacked_size_field: unpacked_size
+                .map_or(UnpackedSizeField::Unknown, UnpackedSizeField::Known),
+        }
+    }
+
+    /// Set how the unpacked size is represented in the header.
+    ///
+    /// A [`Known`](UnpackedSizeField::Known) or
+    /// [`Unknown`](UnpackedSizeField::Unknown) field also sets the unpacked
+    /// size used when decompressing, while an
+    /// [`Absent`](UnpackedSizeField::Absent) field keeps it unchanged.
+    #[cfg(feature = "raw_decoder")]
+    pub fn with_unpacked_size_field(mut self, field: UnpackedSizeField) -> Self {
+        match field {
+            UnpackedSizeField::Known(size) => self.unpacked_size = Some(size),
+            UnpackedSizeField::Unknown => self.unpacked_size = None,
+            UnpackedSizeField::Absent => {}
         }
+        self.unpacked_size_field = field;
+        self
     }
 
-    /// Read LZMA parameters from the LZMA stream header.
+    /// The LZMA "lclppb" decompression properties.
+    #[cfg(feature = "raw_decoder")]
+    pub fn properties(&self) -> LzmaProperties {
+        self.properties
+    }
+
+    /// The dictionary size to use when decompressing.
+    #[cfg(feature = "raw_decoder")]
+    pub fn dict_size(&self) -> u32 {
+        self.dict_size
+    }
+
+    /// The size of the unpacked data, if known.
+    #[cfg(feature = "raw_decoder")]
+    pub fn unpacked_size(&self) -> Option<crate::UncompressedSize> {
+        self.unpacked_size.map(crate::UncompressedSize)
+    }
+
+    /// The unpacked size field of the header.
+    #[cfg(feature = "raw_decoder")]
+    pub fn unpacked_size_field(&self) -> UnpackedSizeField {
+        self.unpacked_size_field
+    }
+
+    /// Write the LZMA stream header for these parameters.
+    pub fn write_header<W>(&self, output: &mut W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        output.write_u8(self.properties.to_byte())?;
+        output.write_u32::<LittleEndian>(self.dict_size)?;
+        match self.unpacked_size_field {
+            UnpackedSizeField::Known(size) => output.write_u64::<LittleEndian>(size),
+            UnpackedSizeField::Unknown => {
+                output.write_u64::<LittleEndian>(UnpackedSizeField::UNKNOWN_MARKER)
+            }
+            UnpackedSizeField::Absent => Ok(()),
+        }
+    }
+
+    /// Read LZMA parameters from the LZMA stream header, after checking the
+    /// `options`.
+    ///
+    /// [`PendingLzma`](crate::decompress::PendingLzma) keeps them together
+    /// with the input, to decompress the stream afterwards.
     pub fn read_header<R>(input: &mut R, options: &Options) -> error::Result<LzmaParams>
     where
         R: io::BufRead,
     {
+        options.validate()?;
+
         // Properties
         let props = input.read_u8().map_err(error::Error::HeaderTooShort)?;
 
@@ -121,6 +312,20 @@ impl LzmaParams {
             .map_err(error::Error::HeaderTooShort)?;
         let dict_size = if dict_size_provided < 0x1000 {
             0x1000
+        } else if dict_size_provided > MAX_DICT_SIZE {
+            match options.large_dict {
+                LargeDict::Clamp => {
+                    lzma_info!("Clamping dict size {}", dict_size_provided);
+                    MAX_DICT_SIZE
+                }
+                LargeDict::Reject => {
+                    return Err(error::Error::LzmaError(format!(
+                        "Dictionary size {} exceeds the maximum of {}",
+                        dict_size_provided, MAX_DICT_SIZE
+                    )))
+                }
+                LargeDict::Allow => dict_size_provided,
+            }
         } else {
             dict_size_provided
         };
@@ -128,33 +333,43 @@ impl LzmaParams {
         lzma_info!("Dict size: {}", dict_size);
 
         // Unpacked size
-        let unpacked_size: Option<u64> = match options.unpacked_size {
-            UnpackedSize::ReadFromHeader => {
-                let unpacked_size_provided = input
+        let unpacked_size_field = match options.unpacked_size {
+            UnpackedSize::ReadFromHeader | UnpackedSize::ReadHeaderButUseProvided(_) => {
+                let value = input
                     .read_u64::<LittleEndian>()
                     .map_err(error::Error::HeaderTooShort)?;
-                let marker_mandatory: bool = unpacked_size_provided == 0xFFFF_FFFF_FFFF_FFFF;
-                if marker_mandatory {
-                    None
-                } else {
-                    Some(unpacked_size_provided)
-                }
+                UnpackedSizeField::from_value(value)
             }
-            UnpackedSize::ReadHeaderButUseProvided(x) => {
-                input
-                    .read_u64::<LittleEndian>()
-                    .map_err(error::Error::HeaderTooShort)?;
-                x
-            }
-            UnpackedSize::UseProvided(x) => x,
+            UnpackedSize::UseProvided(_) => UnpackedSizeField::Absent,
+        };
+        let unpacked_size: Option<u64> = match options.unpacked_size {
+            UnpackedSize::ReadFromHeader => match unpacked_size_field {
+                UnpackedSizeField::Known(0) if options.zero_size_is_unknown => None,
+                UnpackedSizeField::Known(size) => Some(size),
+                UnpackedSizeField::Unknown | UnpackedSizeField::Absent => None,
+            },
+            UnpackedSize::ReadHeaderButUseProvided(x) | UnpackedSize::UseProvided(x) => x,
         };
 
-        lzma_info!("Unpacked size: {:?}", unpacked_size);
+        lzma_info!(
+            "Unpacked size: {:?} (header field: {:?})",
+            unpacked_size,
+            unpacked_size_field
+        );
+        if let (DeclaredSize::Max(max), Some(size)) = (options.declared_size, unpacked_size) {
+            if size > max {
+                return Err(error::Error::LzmaError(format!(
+                    "Declared unpacked size {} exceeds the maximum of {}",
+                    size, max
+                )));
+            }
+        }
 
         let params = LzmaParams {
             properties: LzmaProperties { lc, lp, pb },
             dict_size,
             unpacked_size,
+            unpacked_size_field,
         };
 
         Ok(params)
@@ -182,6 +397,14 @@ pub(crate) struct DecoderState {
     rep: [usize; 4],
     len_decoder: LenDecoder,
     rep_len_decoder: LenDecoder,
+    allow_trailing_data: bool,
+    strict_end: bool,
+    /// Maximum average number of range decoder operations per output byte
+    work_limit: Option<u32>,
+    /// Range decoder operations in the current window
+    window_ops: u64,
+    /// Output length at the start of the current window
+    window_start: usize,
 }
 
 impl DecoderState {
@@ -210,6 +433,11 @@ impl DecoderState {
             rep: [0; 4],
             len_decoder: LenDecoder::new(),
             rep_len_decoder: LenDecoder::new(),
+            allow_trailing_data: false,
+            strict_end: false,
+            work_limit: None,
+            window_ops: 0,
+            window_start: 0,
         }
     }
 
@@ -246,12 +474,64 @@ impl DecoderState {
         self.rep = [0; 4];
         self.len_decoder = LenDecoder::new();
         self.rep_len_decoder = LenDecoder::new();
+        self.window_ops = 0;
+        self.window_start = 0;
     }
 
     pub fn set_unpacked_size(&mut self, unpacked_size: Option<u64>) {
         self.unpacked_size = unpacked_size;
     }
 
+    /// Overwrite the probabilities reset by [`DecoderState::reset_state()`].
+    #[cfg(feature = "raw_decoder")]
+    pub fn set_initial_probs(&mut self, probs: &InitialProbs) {
+        probs.apply(&mut self.literal_probs, &mut self.is_match);
+    }
+
+    pub fn set_allow_trailing_data(&mut self, allow_trailing_data: bool) {
+        self.allow_trailing_data = allow_trailing_data;
+    }
+
+    pub fn set_strict_end(&mut self, strict_end: bool) {
+        self.strict_end = strict_end;
+    }
+
+    /// Number of input bytes held until enough are available to decode the
+    /// next symbol.
+    #[cfg(feature = "stream")]
+    pub(crate) fn buffered_input(&self) -> usize {
+        self.partial_input_buf.position() as usize
+    }
+
+    pub fn set_work_limit(&mut self, work_limit: Option<u32>) {
+        self.work_limit = work_limit;
+    }
+
+    /// Account for `ops` range decoder operations, failing if the average
+    /// work per output byte over the last window exceeds the limit.
+    fn account_work(&mut self, ops: u64, output_len: usize) -> error::Result<()> {
+        let limit = match self.work_limit {
+            Some(limit) => limit as u64,
+            None => return Ok(()),
+        };
+        self.window_ops += ops;
+        if self.window_ops < WORK_WINDOW {
+            return Ok(());
+        }
+        // The output length goes back to zero when the dictionary is reset.
+        let produced = output_len.saturating_sub(self.window_start).max(1) as u64;
+        if self.window_ops > limit.saturating_mul(produced) {
+            return Err(error::Error::LzmaError(format!(
+                "Work limit exceeded: {} range decoder operations for {} output bytes, \
+                 the limit is {} per byte",
+                self.window_ops, produced, limit
+            )));
+        }
+        self.window_ops = 0;
+        self.window_start = output_len;
+        Ok(())
+    }
+
     pub fn process<W: io::Write, LZB: LzBuffer<W>, R: io::BufRead>(
         &mut self,
         output: &mut LZB,
@@ -282,13 +562,10 @@ impl DecoderState {
         update: bool,
     ) -> error::Result<ProcessingStatus> {
         let pos_state = output.len() & ((1 << self.lzma_props.pb) - 1);
+        lzma_check!(self.state < 12, "invalid state {}", self.state);
 
         // Literal
-        if !rangecoder.decode_bit(
-            // TODO: assumes pb = 2 ??
-            &mut self.is_match[(self.state << 4) + pos_state],
-            update,
-        )? {
+        if !rangecoder.decode_bit(&mut self.is_match[(self.state << 4) + pos_state], update)? {
             let byte: u8 = self.decode_literal(output, rangecoder, update)?;
 
             if update {
@@ -372,7 +649,9 @@ impl DecoderState {
             if update {
                 self.rep[0] = rep_0;
                 if self.rep[0] == 0xFFFF_FFFF {
-                    if rangecoder.is_finished_ok()? {
+                    if rangecoder.is_finished_ok()?
+                        || (self.allow_trailing_data && rangecoder.code == 0)
+                    {
                         return Ok(ProcessingStatus::Finished);
                     }
                     return Err(error::Error::LzmaError(String::from(
@@ -439,8 +718,18 @@ impl DecoderState {
         mode: ProcessingMode,
     ) -> error::Result<()> {
         loop {
-            if let Some(unpacked_size) = self.unpacked_size {
-                if output.len() as u64 >= unpacked_size {
+            let size_reached = match self.unpacked_size {
+                // A stream declaring no data may still carry an end-of-stream
+                // marker, as liblzma accepts: decode it, unless the options
+                // reject or ignore what follows the data anyway.
+                Some(0) if output.len() == 0 && !self.strict_end && !self.allow_trailing_data => {
+                    None
+                }
+                Some(unpacked_size) => Some(output.len() as u64 >= unpacked_size),
+                None => None,
+            };
+            if let Some(size_reached) = size_reached {
+                if size_reached {
                     break;
                 }
             } else if match mode {
@@ -482,6 +771,7 @@ impl DecoderState {
 
                 // Update the actual rangecoder
                 rangecoder.set(tmp_rangecoder.range, tmp_rangecoder.code);
+                self.account_work(tmp_rangecoder.ops, output.len())?;
 
                 // Update tmp buffer
                 let end = self.partial_input_buf.position();
@@ -504,7 +794,10 @@ impl DecoderState {
                     return self.read_partial_input_buf(rangecoder);
                 }
 
-                if self.process_next(output, rangecoder)? == ProcessingStatus::Finished {
+                let ops = rangecoder.ops;
+                let res = self.process_next(output, rangecoder)?;
+                self.account_work(rangecoder.ops - ops, output.len())?;
+                if res == ProcessingStatus::Finished {
                     break;
                 };
             }
@@ -518,6 +811,11 @@ impl DecoderState {
                     output.len()
                 )));
             }
+            if mode == ProcessingMode::Finish && self.strict_end && !rangecoder.is_finished_ok()? {
+                return Err(error::Error::LzmaError(String::from(
+                    "Expected end of stream after the unpacked size but more bytes are available",
+                )));
+            }
         }
 
         Ok(())
@@ -530,11 +828,12 @@ impl DecoderState {
         update: bool,
     ) -> error::Result<u8> {
         let def_prev_byte = 0u8;
-        let prev_byte = output.last_or(def_prev_byte) as usize;
+        let prev_byte = output.last_or(def_prev_byte);
 
         let mut result: usize = 1;
-        let lit_state = ((output.len() & ((1 << self.lzma_props.lp) - 1)) << self.lzma_props.lc)
-            + (prev_byte >> (8 - self.lzma_props.lc));
+        let lit_state = self
+            .lzma_props
+            .literal_state(output.len() as u64, prev_byte);
         let probs = &mut self.literal_probs[lit_state];
 
         if self.state >= 7 {
@@ -569,6 +868,7 @@ impl DecoderState {
         let len_state = if length > 3 { 3 } else { length };
 
         let pos_slot = self.pos_slot_decoder[len_state].parse(rangecoder, update)? as usize;
+        lzma_check!(pos_slot < 64, "invalid distance slot {}", pos_slot);
         if pos_slot < 4 {
             return Ok(pos_slot);
         }
@@ -597,6 +897,13 @@ impl DecoderState {
 pub struct LzmaDecoder {
     params: LzmaParams,
     memlimit: usize,
+    /// Maximum size of the writes to the output sink
+    max_write_size: Option<usize>,
+    /// Dictionary size to allocate upfront, if capped by the options
+    preallocated_dict_size: Option<usize>,
+    /// Probabilities to start from instead of those of the specification
+    #[cfg(feature = "raw_decoder")]
+    initial_probs: Option<Box<InitialProbs>>,
     state: DecoderState,
 }
 
@@ -608,10 +915,73 @@ impl LzmaDecoder {
         Ok(Self {
             params,
             memlimit: memlimit.unwrap_or(usize::MAX),
+            max_write_size: None,
+            preallocated_dict_size: None,
+            #[cfg(feature = "raw_decoder")]
+            initial_probs: None,
             state: DecoderState::new(params.properties, params.unpacked_size),
         })
     }
 
+    /// Creates a new decoder honoring the given decompression options.
+    pub(crate) fn with_options(
+        params: LzmaParams,
+        options: &Options,
+    ) -> error::Result<LzmaDecoder> {
+        let mut decoder = Self::new(params, options.memlimit)?;
+        decoder.set_options(options);
+        Ok(decoder)
+    }
+
+    /// Creates a new decoder honoring the given decompression options, using
+    /// the probability tables of `state`, which must have been reset to the
+    /// properties of `params`.
+    pub(crate) fn with_state(
+        params: LzmaParams,
+        options: &Options,
+        mut state: DecoderState,
+    ) -> LzmaDecoder {
+        state.set_unpacked_size(params.unpacked_size);
+        let mut decoder = Self {
+            params,
+            memlimit: options.memlimit.unwrap_or(usize::MAX),
+            max_write_size: None,
+            preallocated_dict_size: None,
+            #[cfg(feature = "raw_decoder")]
+            initial_probs: None,
+            state,
+        };
+        decoder.set_options(options);
+        decoder
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.state
+            .set_allow_trailing_data(options.allow_trailing_data);
+        self.state.set_strict_end(options.strict_end);
+        self.state.set_work_limit(options.max_work_per_byte);
+        self.max_write_size = options.max_write_size;
+        if options.cap_dict_to_unpacked_size {
+            self.preallocated_dict_size = Some(self.params.dict_size_for(options));
+        }
+    }
+
+    /// Starts decoding from the given initial probabilities, which must be
+    /// those the stream was compressed with, instead of those of the
+    /// specification. They also apply after [`LzmaDecoder::reset()`].
+    #[cfg(feature = "raw_decoder")]
+    pub fn set_initial_probs(&mut self, probs: &InitialProbs) -> error::Result<()> {
+        probs.validate()?;
+        self.state.set_initial_probs(probs);
+        self.initial_probs = Some(Box::new(probs.clone()));
+        Ok(())
+    }
+
+    /// Returns the decoder state, for reuse by another decoder.
+    pub(crate) fn into_state(self) -> DecoderState {
+        self.state
+    }
+
     /// Performs the equivalent of replacing this decompression state with a
     /// freshly allocated copy.
     ///
@@ -624,6 +994,9 @@ impl LzmaDecoder {
     #[cfg(feature = "raw_decoder")]
     pub fn reset(&mut self, unpacked_size: Option<Option<u64>>) {
         self.state.reset_state(self.params.properties);
+        if let Some(probs) = &self.initial_probs {
+            self.state.set_initial_probs(probs);
+        }
 
         if let Some(unpacked_size) = unpacked_size {
             self.state.set_unpacked_size(unpacked_size);
@@ -637,13 +1010,116 @@ impl LzmaDecoder {
         input: &mut R,
         output: &mut W,
     ) -> error::Result<()> {
-        let mut output =
-            LzCircularBuffer::from_stream(output, self.params.dict_size as usize, self.memlimit);
+        let mut output = self.buffer(output);
+        self.process(input, &mut output)?;
+        output.finish()?;
+        Ok(())
+    }
 
-        let mut rangecoder = RangeDecoder::new(input)
-            .map_err(|e| error::Error::LzmaError(format!("LZMA stream too short: {}", e)))?;
-        self.state.process(&mut output, &mut rangecoder)?;
+    /// Decompresses the input data by appending to `output`, which holds the
+    /// dictionary instead of an internal buffer. The memory limit doesn't
+    /// apply.
+    pub fn decompress_into_buffer<B, R>(
+        &mut self,
+        input: &mut R,
+        output: &mut B,
+    ) -> error::Result<()>
+    where
+        B: OutputBuffer + ?Sized,
+        R: io::BufRead,
+    {
+        let mut output = LzOutputBuffer::new(output, self.params.dict_size as usize);
+        self.process(input, &mut output)?;
+        output.finish()?;
+        Ok(())
+    }
+
+    /// Decompresses like [`LzmaDecoder::decompress()`], also returning a copy
+    /// of the dictionary window at the end of the stream.
+    pub(crate) fn decompress_with_dictionary<W: io::Write, R: io::BufRead>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> error::Result<Vec<u8>> {
+        let mut output = self.buffer(output);
+        self.process(input, &mut output)?;
+        let dictionary = output.dictionary();
+        output.finish()?;
+        Ok(dictionary)
+    }
+
+    /// Decompresses like [`LzmaDecoder::decompress()`], with the dictionary
+    /// first filled with `preset_dict`, which must be the one the stream was
+    /// compressed with.
+    pub(crate) fn decompress_with_preset_dict<W: io::Write, R: io::BufRead>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        preset_dict: &[u8],
+    ) -> error::Result<()> {
+        // A dictionary capped to the unpacked size must also hold the preset.
+        let dict_size = self.params.dict_size as usize;
+        self.preallocated_dict_size = self
+            .preallocated_dict_size
+            .map(|size| std::cmp::min(size.saturating_add(preset_dict.len()), dict_size));
+        let mut output = self.buffer(output);
+        let preset_len = output.preset(preset_dict)?;
+        // The preset bytes count as output for the position and size checks.
+        self.state.set_unpacked_size(
+            self.params
+                .unpacked_size
+                .map(|size| size.saturating_add(preset_len as u64)),
+        );
+        self.process(input, &mut output)?;
+        output.finish()?;
+        Ok(())
+    }
+
+    /// Decompresses like [`LzmaDecoder::decompress()`], keeping only the last
+    /// `window_size` bytes of the dictionary in memory and reading older
+    /// history back from `output`.
+    pub(crate) fn decompress_windowed<W, R>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        window_size: usize,
+    ) -> error::Result<()>
+    where
+        W: io::Read + io::Write + io::Seek,
+        R: io::BufRead,
+    {
+        let mut output =
+            LzWindowedBuffer::from_stream(output, self.params.dict_size as usize, window_size)?;
+        self.process(input, &mut output)?;
         output.finish()?;
         Ok(())
     }
+
+    /// Dictionary buffer writing to `output`.
+    fn buffer<W: io::Write>(&self, output: W) -> LzCircularBuffer<W> {
+        let mut output = match self.preallocated_dict_size {
+            Some(dict_size) => {
+                let mut output = LzCircularBuffer::from_stream(output, dict_size, self.memlimit);
+                output.reserve_dict();
+                output
+            }
+            None => {
+                LzCircularBuffer::from_stream(output, self.params.dict_size as usize, self.memlimit)
+            }
+        };
+        if let Some(max_write_size) = self.max_write_size {
+            output.set_max_write(max_write_size);
+        }
+        output
+    }
+
+    fn process<W: io::Write, LZB: LzBuffer<W>, R: io::BufRead>(
+        &mut self,
+        input: &mut R,
+        output: &mut LZB,
+    ) -> error::Result<()> {
+        let mut rangecoder = RangeDecoder::new(input)
+            .map_err(|e| error::Error::Truncated(format!("LZMA stream too short: {}", e)))?;
+        self.state.process(output, &mut rangecoder)
+    }
 }
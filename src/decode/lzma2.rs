@@ -1,5 +1,6 @@
 use crate::decode::lzbuffer::LzBuffer;
 use crate::decode::lzma::{DecoderState, LzmaProperties};
+use crate::decode::options::MAX_DICT_SIZE;
 use crate::decode::{lzbuffer, rangecoder};
 use crate::error;
 use byteorder::{BigEndian, ReadBytesExt};
@@ -10,6 +11,8 @@ use std::io::Read;
 /// Raw decoder for LZMA2.
 pub struct Lzma2Decoder {
     lzma_state: DecoderState,
+    /// Dictionary size, if known from the container.
+    dict_size: Option<u32>,
 }
 
 impl Default for Lzma2Decoder {
@@ -30,9 +33,37 @@ impl Lzma2Decoder {
                 },
                 None,
             ),
+            dict_size: None,
         }
     }
 
+    /// Creates a new decoder for a stream whose dictionary size is known, for
+    /// example from the XZ filter properties. This bounds the memory used
+    /// while decompressing to the dictionary size.
+    pub(crate) fn with_dict_size(dict_size: u32) -> Lzma2Decoder {
+        Lzma2Decoder {
+            dict_size: Some(dict_size),
+            ..Self::new()
+        }
+    }
+
+    /// Creates a new decoder using the probability tables of `state`.
+    ///
+    /// The tables are reset but keep their size: the first LZMA chunk of a
+    /// stream sets the properties anyway.
+    pub(crate) fn with_state(mut state: DecoderState) -> Lzma2Decoder {
+        state.reset_state(state.lzma_props);
+        Lzma2Decoder {
+            lzma_state: state,
+            dict_size: None,
+        }
+    }
+
+    /// Returns the decoder state, for reuse by another decoder.
+    pub(crate) fn into_state(self) -> DecoderState {
+        self.lzma_state
+    }
+
     /// Performs the equivalent of replacing this decompression state with a
     /// freshly allocated copy.
     ///
@@ -54,8 +85,79 @@ impl Lzma2Decoder {
         input: &mut R,
         output: &mut W,
     ) -> error::Result<()> {
-        let mut accum = lzbuffer::LzAccumBuffer::from_stream(output, usize::MAX);
+        match self.dict_size {
+            Some(dict_size) => {
+                let accum =
+                    lzbuffer::LzCircularBuffer::from_stream(output, dict_size as usize, usize::MAX);
+                self.decompress_into(input, accum)
+            }
+            None => {
+                let accum = lzbuffer::LzAccumBuffer::from_stream(output, usize::MAX);
+                self.decompress_into(input, accum)
+            }
+        }
+    }
 
+    /// Decompresses like [`Lzma2Decoder::decompress()`] with a circular
+    /// dictionary of `dict_size` bytes, also returning a copy of the
+    /// dictionary window at the end of the stream.
+    pub(crate) fn decompress_with_dictionary<W: io::Write, R: io::BufRead>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        dict_size: u32,
+    ) -> error::Result<Vec<u8>> {
+        let mut accum =
+            lzbuffer::LzCircularBuffer::from_stream(output, dict_size as usize, usize::MAX);
+        self.decompress_chunks(input, &mut accum)?;
+        let dictionary = accum.dictionary();
+        accum.finish()?;
+        Ok(dictionary)
+    }
+
+    /// Decompresses like [`Lzma2Decoder::decompress()`], keeping only the last
+    /// `window_size` bytes of the dictionary in memory and reading older
+    /// history back from `output`. Without a known dictionary size, matches
+    /// may reach as far as [`MAX_DICT_SIZE`].
+    pub(crate) fn decompress_windowed<W, R>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        window_size: usize,
+    ) -> error::Result<()>
+    where
+        W: io::Read + io::Write + io::Seek,
+        R: io::BufRead,
+    {
+        let dict_size = self.dict_size.unwrap_or(MAX_DICT_SIZE);
+        let accum =
+            lzbuffer::LzWindowedBuffer::from_stream(output, dict_size as usize, window_size)?;
+        self.decompress_into(input, accum)
+    }
+
+    fn decompress_into<W, R, LZB>(&mut self, input: &mut R, mut accum: LZB) -> error::Result<()>
+    where
+        W: io::Write,
+        R: io::BufRead,
+        LZB: LzBuffer<W>,
+    {
+        self.decompress_chunks(input, &mut accum)?;
+        accum.finish()?;
+        Ok(())
+    }
+
+    /// Decompress the chunks up to the end marker into `accum`, which keeps
+    /// its dictionary for chunks that follow.
+    pub(crate) fn decompress_chunks<W, R, LZB>(
+        &mut self,
+        input: &mut R,
+        accum: &mut LZB,
+    ) -> error::Result<()>
+    where
+        W: io::Write,
+        R: io::BufRead,
+        LZB: LzBuffer<W>,
+    {
         loop {
             let status = input.read_u8().map_err(|e| {
                 error::Error::LzmaError(format!("LZMA2 expected new status: {}", e))
@@ -68,28 +170,27 @@ impl Lzma2Decoder {
                 break;
             } else if status == 1 {
                 // uncompressed reset dict
-                Self::parse_uncompressed(&mut accum, input, true)?;
+                Self::parse_uncompressed(accum, input, true)?;
             } else if status == 2 {
                 // uncompressed no reset
-                Self::parse_uncompressed(&mut accum, input, false)?;
+                Self::parse_uncompressed(accum, input, false)?;
             } else {
-                self.parse_lzma(&mut accum, input, status)?;
+                self.parse_lzma(accum, input, status)?;
             }
         }
-
-        accum.finish()?;
         Ok(())
     }
 
-    fn parse_lzma<R, W>(
+    fn parse_lzma<R, W, LZB>(
         &mut self,
-        accum: &mut lzbuffer::LzAccumBuffer<W>,
+        accum: &mut LZB,
         input: &mut R,
         status: u8,
     ) -> error::Result<()>
     where
         R: io::BufRead,
         W: io::Write,
+        LZB: LzBuffer<W>,
     {
         if status & 0x80 == 0 {
             return Err(error::Error::LzmaError(format!(
@@ -188,18 +289,19 @@ impl Lzma2Decoder {
 
         let mut taken = input.take(packed_size);
         let mut rangecoder = rangecoder::RangeDecoder::new(&mut taken)
-            .map_err(|e| error::Error::LzmaError(format!("LZMA input too short: {}", e)))?;
+            .map_err(|e| error::Error::Truncated(format!("LZMA input too short: {}", e)))?;
         self.lzma_state.process(accum, &mut rangecoder)
     }
 
-    fn parse_uncompressed<R, W>(
-        accum: &mut lzbuffer::LzAccumBuffer<W>,
+    fn parse_uncompressed<R, W, LZB>(
+        accum: &mut LZB,
         input: &mut R,
         reset_dict: bool,
     ) -> error::Result<()>
     where
         R: io::BufRead,
         W: io::Write,
+        LZB: LzBuffer<W>,
     {
         let unpacked_size = input
             .read_u16::<BigEndian>()
@@ -223,8 +325,72 @@ impl Lzma2Decoder {
                 unpacked_size, e
             ))
         })?;
-        accum.append_bytes(buf.as_slice());
+        accum.append_bytes(buf.as_slice())
+    }
+}
 
-        Ok(())
+/// Scan the chunk headers of an LZMA2 stream, without decompressing it, and
+/// return the uncompressed offsets at which the dictionary is reset.
+///
+/// Decompression can start at any of these offsets, independently of the
+/// preceding data. The scan stops after the end-of-stream marker.
+pub fn dict_reset_points<R>(input: &mut R) -> error::Result<Vec<u64>>
+where
+    R: io::BufRead,
+{
+    let mut points = vec![];
+    let mut offset: u64 = 0;
+    loop {
+        let status = input
+            .read_u8()
+            .map_err(|e| error::Error::LzmaError(format!("LZMA2 expected new status: {}", e)))?;
+
+        let (reset_dict, unpacked_size, skip) = match status {
+            0 => break,
+            1 | 2 => {
+                let unpacked_size = input.read_u16::<BigEndian>().map_err(|e| {
+                    error::Error::LzmaError(format!("LZMA2 expected unpacked size: {}", e))
+                })? as u64
+                    + 1;
+                (status == 1, unpacked_size, unpacked_size)
+            }
+            0x80..=0xFF => {
+                let unpacked_size = input.read_u16::<BigEndian>().map_err(|e| {
+                    error::Error::LzmaError(format!("LZMA2 expected unpacked size: {}", e))
+                })?;
+                let unpacked_size = ((((status & 0x1F) as u64) << 16) | (unpacked_size as u64)) + 1;
+                let packed_size = input.read_u16::<BigEndian>().map_err(|e| {
+                    error::Error::LzmaError(format!("LZMA2 expected packed size: {}", e))
+                })? as u64
+                    + 1;
+                // Properties byte, then the compressed data.
+                let props_size = (status >= 0xC0) as u64;
+                (status >= 0xE0, unpacked_size, props_size + packed_size)
+            }
+            _ => {
+                return Err(error::Error::LzmaError(format!(
+                    "LZMA2 invalid status {}, must be 0, 1, 2 or >= 128",
+                    status
+                )))
+            }
+        };
+
+        if reset_dict {
+            points.push(offset);
+        } else if points.is_empty() {
+            return Err(error::Error::LzmaError(
+                "LZMA2 first chunk must reset the dictionary".to_string(),
+            ));
+        }
+
+        let skipped = io::copy(&mut input.take(skip), &mut io::sink())?;
+        if skipped != skip {
+            return Err(error::Error::LzmaError(format!(
+                "LZMA2 chunk truncated: expected {} bytes but got {}",
+                skip, skipped
+            )));
+        }
+        offset += unpacked_size;
     }
+    Ok(points)
 }
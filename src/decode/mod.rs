@@ -1,12 +1,25 @@
 //! Decoding logic.
 
+pub mod carve;
+pub mod fixed_block;
+pub mod frame;
 pub mod lzbuffer;
 pub mod lzma;
 pub mod lzma2;
 pub mod options;
+pub mod pending;
+pub mod pool;
 pub mod rangecoder;
 pub mod util;
+pub mod verify;
 pub mod xz;
 
+#[cfg(feature = "raw_decoder")]
+pub mod batch;
+#[cfg(any(feature = "raw_decoder", feature = "stream"))]
+pub mod header_parser;
+
+#[cfg(feature = "stream")]
+pub mod reader;
 #[cfg(feature = "stream")]
 pub mod stream;
@@ -1,3 +1,5 @@
+use crate::error::OptionsError;
+
 /// Options to tweak decompression behavior.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub struct Options {
@@ -6,6 +8,14 @@ pub struct Options {
     ///
     /// The default is [`UnpackedSize::ReadFromHeader`].
     pub unpacked_size: UnpackedSize,
+    /// Determines whether an unpacked size of 0 read from the header means
+    /// that the size is unknown, as written by some legacy encoders instead
+    /// of `0xFFFF_FFFF_FFFF_FFFF`. Such streams end with an end-of-stream
+    /// marker, which empty streams declaring a size of 0 lack, so those fail
+    /// to decode with this option.
+    ///
+    /// The default is false (a size of 0 means an empty stream).
+    pub zero_size_is_unknown: bool,
     /// Defines whether the dictionary's dynamic size should be limited during
     /// decompression.
     ///
@@ -17,6 +27,192 @@ pub struct Options {
     ///
     /// The default is false (always do completion check).
     pub allow_incomplete: bool,
+    /// Determines whether bytes following the end-of-stream marker are left
+    /// unread instead of being rejected.
+    ///
+    /// This option does not apply to the `stream` API.
+    ///
+    /// The default is false (no data may follow the end-of-stream marker).
+    pub allow_trailing_data: bool,
+    /// Determines whether, when the unpacked size is known, the input must end
+    /// right after the last decoded byte. This rejects streams carrying an
+    /// end-of-stream marker as well as any trailing data.
+    ///
+    /// This option does not apply to the `stream` API.
+    ///
+    /// The default is false (an end-of-stream marker may follow the data).
+    pub strict_end: bool,
+    /// Determines whether the dictionary is capped to the unpacked size when
+    /// the latter is known and smaller than the dictionary size declared in
+    /// the header.
+    ///
+    /// When enabled, the dictionary buffer is allocated upfront with exactly
+    /// that capped size (if within [`memlimit`](Options::memlimit)) instead of
+    /// growing on demand, which avoids reallocations and over-allocation for
+    /// small streams declaring a huge dictionary. Memory estimates relying on
+    /// the declared dictionary size no longer apply.
+    ///
+    /// The default is false (the buffer grows as needed up to the declared
+    /// dictionary size).
+    pub cap_dict_to_unpacked_size: bool,
+    /// Maximum number of range decoder operations (decoded bits) per output
+    /// byte, averaged over windows of 65536 operations.
+    ///
+    /// Decoding fails with an error once a window exceeds this budget, which
+    /// bounds the CPU time spent on crafted streams relative to the size of
+    /// their output. Valid LZMA data takes about 9 operations per literal
+    /// byte and up to about 21 per byte for the shortest distant matches, so
+    /// a limit below 32 may reject legitimate streams.
+    ///
+    /// This option does not apply to LZMA2 and `.xz` streams.
+    ///
+    /// The default is `None` (no limit).
+    pub max_work_per_byte: Option<u32>,
+    /// Maximum number of bytes passed to a single `write()` call on the
+    /// output sink.
+    ///
+    /// The dictionary is otherwise written out in writes of up to the
+    /// dictionary size, which some sinks such as pipes or FUSE file systems
+    /// handle poorly. It must not be zero.
+    ///
+    /// This option does not apply to LZMA2 and `.xz` streams.
+    ///
+    /// The default is `None` (no limit).
+    pub max_write_size: Option<usize>,
+    /// Defines how much the unpacked size declared in the header, or
+    /// provided by [`unpacked_size`](Options::unpacked_size), is trusted for
+    /// allocations.
+    ///
+    /// The default is [`DeclaredSize::Hint`].
+    pub declared_size: DeclaredSize,
+    /// Defines how dictionary sizes above [`MAX_DICT_SIZE`] declared in the
+    /// header are handled.
+    ///
+    /// The default is [`LargeDict::Clamp`].
+    pub large_dict: LargeDict,
+    /// Defines how `.xz` streams whose check method isn't supported by this
+    /// crate, currently SHA-256, are handled.
+    ///
+    /// This option only applies to `.xz` streams.
+    ///
+    /// The default is [`UnsupportedCheck::Error`].
+    pub unsupported_check: UnsupportedCheck,
+}
+
+impl Options {
+    /// Check the combination of options, as the decompressors do before
+    /// reading any data.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.strict_end && self.allow_trailing_data {
+            return Err(OptionsError::conflict(
+                "strict_end",
+                "allow_trailing_data",
+                "Trailing data can't be both rejected and allowed".to_string(),
+            ));
+        }
+        if self.max_write_size == Some(0) {
+            return Err(OptionsError::new(
+                "max_write_size",
+                "Write size limit must be positive".to_string(),
+            ));
+        }
+        if self.max_work_per_byte == Some(0) {
+            return Err(OptionsError::new(
+                "max_work_per_byte",
+                "Work limit must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Options for LZMA streams embedded in Linux kernel and initramfs images.
+    ///
+    /// Such streams often have a zero or garbage unpacked size in their
+    /// header, and are followed by padding or unrelated data. These options
+    /// ignore the unpacked size from the header, stop at the end-of-stream
+    /// marker and leave any trailing bytes unread. Use
+    /// [`lzma_decompress_counted`](crate::lzma_decompress_counted) to find
+    /// out where the stream ended.
+    pub fn kernel_image() -> Self {
+        Self {
+            unpacked_size: UnpackedSize::ReadHeaderButUseProvided(None),
+            allow_trailing_data: true,
+            ..Default::default()
+        }
+    }
+
+    /// Options for LZMA streams embedded in firmware images, such as UEFI
+    /// capsules.
+    ///
+    /// The unpacked size is provided by an external header and the header's
+    /// own size field is ignored. The stream must decode to exactly that size
+    /// and end right there: end-of-stream markers and trailing bytes are
+    /// rejected. The dictionary may not grow beyond `dict_limit` bytes.
+    pub fn firmware(unpacked_size: u64, dict_limit: usize) -> Self {
+        Self {
+            unpacked_size: UnpackedSize::ReadHeaderButUseProvided(Some(unpacked_size)),
+            memlimit: Some(dict_limit),
+            strict_end: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Policies for the unpacked size declared by a stream, which may be
+/// arbitrarily large in untrusted input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DeclaredSize {
+    /// Pre-allocate the output of
+    /// [`lzma_decompress_to_vec`](crate::lzma_decompress_to_vec) from the
+    /// declared size, up to 64 MiB and the
+    /// [`memlimit`](Options::memlimit), growing it further as data is decoded.
+    #[default]
+    Hint,
+    /// Like [`DeclaredSize::Hint`], but reject streams declaring more than
+    /// the given number of bytes before decoding anything.
+    Max(u64),
+    /// Don't use the declared size for allocations: the output grows only as
+    /// data is decoded. The size is still checked against the decoded data.
+    Ignore,
+}
+
+/// Largest dictionary size used by default, 1.5 GiB: the largest one that
+/// the xz-utils encoder accepts. Headers can declare more, up to 4 GiB - 1
+/// for the dictionary size field of LZMA headers and the LZMA2 property of
+/// `.xz` filters alike, but xz-utils never writes such sizes.
+pub const MAX_DICT_SIZE: u32 = 0x6000_0000;
+
+/// Policies for dictionary sizes above [`MAX_DICT_SIZE`] declared in LZMA
+/// headers.
+///
+/// xz-utils never writes such sizes, but other producers do. Allocating
+/// them as declared fails right away on 32-bit platforms, and elsewhere
+/// depends on the available memory once the buffer has grown that far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LargeDict {
+    /// Use a dictionary of [`MAX_DICT_SIZE`] bytes instead. Streams whose
+    /// matches all stay within that distance decode as usual, which includes
+    /// any stream of at most that size; others fail at the first match
+    /// reaching farther.
+    #[default]
+    Clamp,
+    /// Reject the stream before decoding anything.
+    Reject,
+    /// Use the declared size, up to 4 GiB.
+    Allow,
+}
+
+/// Policies for `.xz` streams whose integrity checks can't be verified
+/// because their check method isn't supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnsupportedCheck {
+    /// Fail at the first block.
+    #[default]
+    Error,
+    /// Decompress without verifying the checks, logging a warning.
+    Warn,
+    /// Decompress without verifying the checks.
+    Ignore,
 }
 
 /// Alternatives for defining the unpacked size of the decoded data.
@@ -26,6 +222,10 @@ pub enum UnpackedSize {
     /// the header. If the bytes are `0xFFFF_FFFF_FFFF_FFFF`, assume that
     /// there is an end-of-payload marker in the file.
     /// If not, read the 8 bytes as a little-endian encoded u64.
+    ///
+    /// A size of zero yields no data without decoding anything, but an
+    /// end-of-stream marker following the range coder initialization is
+    /// consumed, as liblzma does, and any other symbol there is an error.
     #[default]
     ReadFromHeader,
     /// Assume that there are 8 bytes representing the unpacked size present in
@@ -51,8 +251,17 @@ mod test {
         assert_eq!(
             Options {
                 unpacked_size: UnpackedSize::ReadFromHeader,
+                zero_size_is_unknown: false,
                 memlimit: None,
                 allow_incomplete: false,
+                allow_trailing_data: false,
+                strict_end: false,
+                cap_dict_to_unpacked_size: false,
+                max_work_per_byte: None,
+                max_write_size: None,
+                declared_size: DeclaredSize::Hint,
+                large_dict: LargeDict::Clamp,
+                unsupported_check: UnsupportedCheck::Error,
             },
             Options::default()
         );
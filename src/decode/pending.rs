@@ -0,0 +1,88 @@
+//! LZMA streams whose header is read before deciding to decode them.
+
+use crate::decode::lzma::{LzmaDecoder, LzmaParams};
+use crate::decompress::Options;
+use crate::{error, UncompressedSize};
+use std::io;
+
+/// LZMA stream whose header has been read, ready to be decompressed from
+/// the same reader.
+///
+/// This lets callers inspect the parameters of a stream, e.g. to reject
+/// dictionaries or unpacked sizes that are too large for them, before
+/// decoding anything, without having to rewind the input. It works on
+/// non-seekable sources such as sockets and pipes.
+pub struct PendingLzma<R>
+where
+    R: io::BufRead,
+{
+    input: R,
+    params: LzmaParams,
+    options: Options,
+}
+
+impl<R> PendingLzma<R>
+where
+    R: io::BufRead,
+{
+    /// Read the header of the LZMA stream starting at the current position
+    /// of `input`, after checking the `options`, which also apply to the
+    /// decompression.
+    pub fn new(mut input: R, options: &Options) -> error::Result<Self> {
+        let params = LzmaParams::read_header(&mut input, options)?;
+        Ok(Self {
+            input,
+            params,
+            options: *options,
+        })
+    }
+
+    /// Parameters read from the header.
+    #[cfg(feature = "raw_decoder")]
+    #[cfg_attr(docsrs, doc(cfg(raw_decoder)))]
+    pub fn params(&self) -> &LzmaParams {
+        &self.params
+    }
+
+    /// Dictionary size used to decompress, after applying the
+    /// [`large_dict`](Options::large_dict) policy to the one declared in the
+    /// header.
+    pub fn dict_size(&self) -> u32 {
+        self.params.dict_size
+    }
+
+    /// Size of the decompressed data, if known from the header or the
+    /// options.
+    pub fn unpacked_size(&self) -> Option<UncompressedSize> {
+        self.params.unpacked_size.map(UncompressedSize)
+    }
+
+    /// Decompress the rest of the stream into `output`, returning the input
+    /// positioned after the data consumed.
+    pub fn decompress<W>(mut self, output: &mut W) -> error::Result<R>
+    where
+        W: io::Write,
+    {
+        let mut decoder = LzmaDecoder::with_options(self.params, &self.options)?;
+        decoder.decompress(&mut self.input, output)?;
+        Ok(self.input)
+    }
+
+    /// Give up on decompressing the stream, returning the input positioned
+    /// right after the header.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R> std::fmt::Debug for PendingLzma<R>
+where
+    R: io::BufRead,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("PendingLzma")
+            .field("params", &self.params)
+            .field("options", &self.options)
+            .finish()
+    }
+}
@@ -0,0 +1,184 @@
+//! Pool of decoder states shared by concurrent decompressions.
+
+use crate::decode::lzma::{DecoderState, LzmaDecoder, LzmaParams, LzmaProperties};
+use crate::decode::lzma2::Lzma2Decoder;
+use crate::decode::xz;
+use crate::decompress::Options;
+use crate::{error, Format};
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Pool of decoder states, for services decompressing many payloads on
+/// several threads.
+///
+/// Allocating and initializing the probability tables is a large part of the
+/// cost of decompressing a short stream. The pool keeps the tables of
+/// finished decompressions and hands them out, reset, to the following ones:
+/// LZMA streams preferably get tables of the size their header requires, and
+/// LZMA2 streams take any.
+///
+/// LZMA streams are decompressed with the [`Options`] of the pool, and LZMA2
+/// streams like by [`lzma2_decompress`](crate::lzma2_decompress). XZ streams
+/// are decompressed without reusing tables, and of the options only
+/// [`unsupported_check`](Options::unsupported_check) applies to them.
+#[derive(Debug)]
+pub struct DecompressorPool {
+    options: Options,
+    max_idle: usize,
+    idle: Mutex<Vec<DecoderState>>,
+}
+
+impl DecompressorPool {
+    /// Creates an empty pool decompressing with the given options, keeping the
+    /// states of at most `max_idle` finished decompressions.
+    pub fn new(options: &Options, max_idle: usize) -> Self {
+        Self {
+            options: *options,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the options used to decompress LZMA streams.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Returns the number of decoder states waiting for reuse.
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Drops the decoder states waiting for reuse.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Decompresses a stream of the given format from `input` into `output`,
+    /// reusing the decoder state of a previous decompression if available.
+    pub fn decompress<R, W>(
+        &self,
+        format: Format,
+        input: &mut R,
+        output: &mut W,
+    ) -> error::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        match format {
+            Format::Lzma => {
+                let params = LzmaParams::read_header(input, &self.options)?;
+                let state = match self.take(Some(params.properties)) {
+                    Some(mut state) => {
+                        state.reset_state(params.properties);
+                        state
+                    }
+                    None => DecoderState::new(params.properties, params.unpacked_size),
+                };
+                let mut decoder = LzmaDecoder::with_state(params, &self.options, state);
+                let result = decoder.decompress(input, output);
+                self.put(decoder.into_state());
+                result
+            }
+            Format::Lzma2 => {
+                let mut decoder = match self.take(None) {
+                    Some(state) => Lzma2Decoder::with_state(state),
+                    None => Lzma2Decoder::new(),
+                };
+                let result = decoder.decompress(input, output);
+                self.put(decoder.into_state());
+                result
+            }
+            Format::Xz => {
+                xz::decode_stream(input, output, self.options.unsupported_check, &mut |_| ())
+                    .map(|_| ())
+            }
+        }
+    }
+
+    /// Takes an idle state, preferably with literal tables sized for
+    /// `properties`.
+    fn take(&self, properties: Option<LzmaProperties>) -> Option<DecoderState> {
+        let mut idle = self.lock();
+        let literal_bits = properties.map(|p| p.lc + p.lp);
+        let index = idle
+            .iter()
+            .rposition(|state| Some(state.lzma_props.lc + state.lzma_props.lp) == literal_bits)
+            .or_else(|| idle.len().checked_sub(1))?;
+        Some(idle.swap_remove(index))
+    }
+
+    fn put(&self, state: DecoderState) {
+        let mut idle = self.lock();
+        if idle.len() < self.max_idle {
+            idle.push(state);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<DecoderState>> {
+        // The states are reset before reuse, so a panic while holding the
+        // lock leaves nothing inconsistent.
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reuse_states() {
+        let data = include_bytes!("../../tests/files/foo.txt");
+        let pool = DecompressorPool::new(&Options::default(), 2);
+        for &format in &[Format::Lzma, Format::Lzma2, Format::Xz] {
+            let mut compressed = Vec::new();
+            let mut input = &data[..];
+            match format {
+                Format::Lzma => crate::lzma_compress(&mut input, &mut compressed),
+                Format::Lzma2 => crate::lzma2_compress(&mut input, &mut compressed),
+                Format::Xz => crate::xz_compress(&mut input, &mut compressed),
+            }
+            .unwrap();
+            for _ in 0..3 {
+                let mut output = Vec::new();
+                pool.decompress(format, &mut compressed.as_slice(), &mut output)
+                    .unwrap();
+                assert_eq!(&output[..], &data[..]);
+                assert_eq!(pool.idle(), 1);
+            }
+        }
+
+        // A failed decompression gives its state back too.
+        let mut output = Vec::new();
+        let corrupt = [0x5d, 0, 0, 0x80, 0, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0xff];
+        assert!(pool
+            .decompress(Format::Lzma, &mut &corrupt[..], &mut output)
+            .is_err());
+        assert_eq!(pool.idle(), 1);
+
+        pool.clear();
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_reuse_states_work_limit() {
+        // The work counted for a stream doesn't carry over to the next one.
+        let compressed = include_bytes!("../../tests/files/foo.txt.lzma");
+        let options = Options {
+            max_work_per_byte: Some(32),
+            ..Default::default()
+        };
+        let pool = DecompressorPool::new(&options, 1);
+        for _ in 0..3 {
+            let mut output = Vec::new();
+            pool.decompress(Format::Lzma, &mut &compressed[..], &mut output)
+                .unwrap();
+            assert_eq!(
+                &output[..],
+                &include_bytes!("../../tests/files/foo.txt")[..]
+            );
+        }
+        assert_eq!(pool.idle(), 1);
+    }
+}
@@ -4,24 +4,30 @@ use crate::util::const_assert;
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io;
 
+/// Range decoder reading the bits of an LZMA-style range-coded stream.
 pub struct RangeDecoder<'a, R>
 where
     R: 'a + io::BufRead,
 {
-    pub stream: &'a mut R,
-    pub range: u32,
-    pub code: u32,
+    pub(crate) stream: &'a mut R,
+    pub(crate) range: u32,
+    pub(crate) code: u32,
+    /// Number of bits decoded so far, as a measure of the work done
+    pub(crate) ops: u64,
 }
 
 impl<'a, R> RangeDecoder<'a, R>
 where
     R: io::BufRead,
 {
+    /// Initialize the decoder from the first 5 bytes of the range-coded data
+    /// in `stream`.
     pub fn new(stream: &'a mut R) -> io::Result<Self> {
         let mut dec = Self {
             stream,
             range: 0xFFFF_FFFF,
             code: 0,
+            ops: 0,
         };
         let _ = dec.stream.read_u8()?;
         dec.code = dec.stream.read_u32::<BigEndian>()?;
@@ -29,30 +35,33 @@ where
         Ok(dec)
     }
 
-    pub fn from_parts(stream: &'a mut R, range: u32, code: u32) -> Self {
+    pub(crate) fn from_parts(stream: &'a mut R, range: u32, code: u32) -> Self {
         Self {
             stream,
             range,
             code,
+            ops: 0,
         }
     }
 
-    pub fn set(&mut self, range: u32, code: u32) {
+    pub(crate) fn set(&mut self, range: u32, code: u32) {
         self.range = range;
         self.code = code;
     }
 
-    pub fn read_into(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+    pub(crate) fn read_into(&mut self, dst: &mut [u8]) -> io::Result<usize> {
         self.stream.read(dst)
     }
 
+    /// Whether the range-coded data ended cleanly: the code is zero and the
+    /// stream has no more bytes.
     #[inline]
     pub fn is_finished_ok(&mut self) -> io::Result<bool> {
         Ok(self.code == 0 && self.is_eof()?)
     }
 
     #[inline]
-    pub fn is_eof(&mut self) -> io::Result<bool> {
+    pub(crate) fn is_eof(&mut self) -> io::Result<bool> {
         util::is_eof(self.stream)
     }
 
@@ -65,11 +74,17 @@ where
 
             lzma_debug!("+ {{ range: {:08x}, code: {:08x} }}", self.range, self.code);
         }
+        lzma_check!(
+            self.range >= 0x0100_0000,
+            "range {:08x} not normalized",
+            self.range
+        );
         Ok(())
     }
 
     #[inline]
     fn get_bit(&mut self) -> error::Result<bool> {
+        self.ops += 1;
         self.range >>= 1;
 
         let bit = self.code >= self.range;
@@ -81,7 +96,7 @@ where
         Ok(bit)
     }
 
-    pub fn get(&mut self, count: usize) -> error::Result<u32> {
+    pub(crate) fn get(&mut self, count: usize) -> error::Result<u32> {
         let mut result = 0u32;
         for _ in 0..count {
             result = (result << 1) ^ (self.get_bit()? as u32)
@@ -89,8 +104,16 @@ where
         Ok(result)
     }
 
+    /// Decode a bit with the adaptive probability `prob` of it being zero,
+    /// out of 2048, updating the probability if `update` is set.
     #[inline]
     pub fn decode_bit(&mut self, prob: &mut u16, update: bool) -> io::Result<bool> {
+        lzma_check!(
+            *prob > 0 && *prob < 0x800,
+            "probability {} out of bounds",
+            prob
+        );
+        self.ops += 1;
         let bound: u32 = (self.range >> 11) * (*prob as u32);
 
         lzma_trace!(
@@ -125,6 +148,7 @@ where
         probs: &mut [u16],
         update: bool,
     ) -> io::Result<u32> {
+        lzma_check!(probs.len() >= 1 << num_bits);
         let mut tmp: u32 = 1;
         for _ in 0..num_bits {
             let bit = self.decode_bit(&mut probs[tmp as usize], update)?;
@@ -133,13 +157,14 @@ where
         Ok(tmp - (1 << num_bits))
     }
 
-    pub fn parse_reverse_bit_tree(
+    pub(crate) fn parse_reverse_bit_tree(
         &mut self,
         num_bits: usize,
         probs: &mut [u16],
         offset: usize,
         update: bool,
     ) -> io::Result<u32> {
+        lzma_check!(probs.len() >= offset + (1 << num_bits));
         let mut result = 0u32;
         let mut tmp: usize = 1;
         for i in 0..num_bits {
@@ -199,6 +224,23 @@ impl<const PROBS_ARRAY_LEN: usize> BitTree<PROBS_ARRAY_LEN> {
     }
 }
 
+impl<'a, R> std::fmt::Debug for RangeDecoder<'a, R>
+where
+    R: io::BufRead,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RangeDecoder")
+            .field("range", &self.range)
+            .field("code", &self.code)
+            .finish()
+    }
+}
+
+/// Decoder of LZMA match lengths, minus the minimum match length of 2.
+///
+/// Lengths below 8 and below 16 are coded with 3-bit trees depending on the
+/// position state, and longer ones with a shared 8-bit tree, for a maximum
+/// of 271.
 #[derive(Debug)]
 pub struct LenDecoder {
     choice: u16,
@@ -209,6 +251,7 @@ pub struct LenDecoder {
 }
 
 impl LenDecoder {
+    /// Create a decoder with all probabilities at one half.
     pub fn new() -> Self {
         LenDecoder {
             choice: 0x400,
@@ -253,6 +296,8 @@ impl LenDecoder {
         }
     }
 
+    /// Decode a length in the context of `pos_state`, which must be below
+    /// 16. The probabilities are only updated if `update` is set.
     pub fn decode<R: io::BufRead>(
         &mut self,
         rangecoder: &mut RangeDecoder<R>,
@@ -268,3 +313,9 @@ impl LenDecoder {
         }
     }
 }
+
+impl Default for LenDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
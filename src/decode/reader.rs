@@ -0,0 +1,443 @@
+//! Pull-style decompression through the [`io::Read`] interface.
+
+use crate::decode::options::UnsupportedCheck;
+use crate::decode::stream::Stream;
+use crate::decode::{util, xz};
+use crate::decompress::Options;
+use crate::error;
+use crate::xz::check::CheckDigest;
+use crate::xz::index::IndexRecord;
+use crate::xz::{header, StreamFlags};
+use byteorder::ReadBytesExt;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+/// Lzma decompressor reading compressed bytes from an [`io::BufRead`] source
+/// and returning decompressed bytes through the [`io::Read`] interface.
+///
+/// The reader implements [`io::BufRead`] on top of its buffer of
+/// decompressed bytes, so it doesn't need to be wrapped in a
+/// [`io::BufReader`] to be read line by line.
+///
+/// It also implements [`io::Seek`], but only forward: seeking skips
+/// the decompressed bytes in between without copying them out, and seeking
+/// backward or relative to the end fails. Seeking past the end succeeds and
+/// further reads return no data, as with files.
+///
+/// Once the compressed data turns out to be invalid or truncated, every
+/// further read fails with the same error. Errors of the input source are
+/// returned as they are and reading can be retried.
+#[cfg_attr(docsrs, doc(cfg(stream)))]
+pub struct LzmaReader<R>
+where
+    R: io::BufRead,
+{
+    /// Compressed input source
+    input: R,
+    /// Push decoder, or `None` once the end of the input has been reached
+    stream: Option<Stream<Vec<u8>>>,
+    /// Decompressed bytes, of which those before `pos` have been returned
+    buf: Vec<u8>,
+    /// Read position in `buf`
+    pos: usize,
+    /// Offset of the next byte to return in the decompressed data
+    offset: u64,
+    /// Error that ended decompression, returned again by further reads
+    error: Option<(io::ErrorKind, String)>,
+}
+
+impl<R> LzmaReader<R>
+where
+    R: io::BufRead,
+{
+    /// Initialize the decompressor, reading compressed bytes from `input`.
+    pub fn new(input: R) -> Self {
+        Self::new_with_options(&Options::default(), input)
+    }
+
+    /// Initialize the decompressor with the given `options`.
+    pub fn new_with_options(options: &Options, input: R) -> Self {
+        Self {
+            input,
+            stream: Some(Stream::new_with_options(options, Vec::new())),
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+            error: None,
+        }
+    }
+
+    /// Get a reference to the input source.
+    pub fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    /// Consumes the decompressor and returns the input source.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Decompress more data until some is available in `buf`, or the end
+    /// of the stream is reached.
+    fn fill(&mut self) -> io::Result<()> {
+        if let Some((kind, message)) = &self.error {
+            return Err(io::Error::new(*kind, message.clone()));
+        }
+        while self.pos == self.buf.len() {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+            let input = self.input.fill_buf()?;
+            let n = if input.is_empty() {
+                0
+            } else {
+                poison(&mut self.error, stream.write(input))?
+            };
+            if n == 0 {
+                // Either the input or the stream reached its end, in the
+                // latter case leaving the rest of the input unread.
+                let stream = self.stream.take().unwrap();
+                self.buf = poison(&mut self.error, stream.finish().map_err(io::Error::from))?;
+                self.pos = 0;
+                continue;
+            }
+            self.input.consume(n);
+            poison(&mut self.error, stream.flush_pending())?;
+            let output = stream.get_output_mut().unwrap();
+            if !output.is_empty() {
+                self.buf.clear();
+                std::mem::swap(&mut self.buf, output);
+                self.pos = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Skip up to `n` decompressed bytes, returning how many were skipped.
+    fn skip(&mut self, mut n: u64) -> io::Result<u64> {
+        let start = n;
+        while n > 0 {
+            self.fill()?;
+            let available = (self.buf.len() - self.pos) as u64;
+            if available == 0 {
+                break;
+            }
+            let step = available.min(n);
+            self.pos += step as usize;
+            n -= step;
+        }
+        Ok(start - n)
+    }
+}
+
+impl<R> Read for LzmaReader<R>
+where
+    R: io::BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill()?;
+        let n = (&self.buf[self.pos..]).read(buf)?;
+        self.pos += n;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> BufRead for LzmaReader<R>
+where
+    R: io::BufRead,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill()?;
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buf.len() - self.pos);
+        self.pos += amt;
+        self.offset += amt as u64;
+    }
+}
+
+impl<R> Seek for LzmaReader<R>
+where
+    R: io::BufRead,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = seek_target("LzmaReader", self.offset, pos)?;
+        self.skip(target - self.offset)?;
+        self.offset = target;
+        Ok(target)
+    }
+}
+
+impl<R> std::fmt::Debug for LzmaReader<R>
+where
+    R: io::BufRead + std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("LzmaReader")
+            .field("input", &self.input)
+            .field("stream", &self.stream)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+/// Xz decompressor reading compressed bytes from an [`io::BufRead`] source
+/// and returning decompressed bytes through the [`io::Read`] interface.
+///
+/// Blocks are decompressed one at a time into a buffer, so the memory used
+/// grows with the uncompressed size of the largest block. Files written by
+/// `xz` in single-threaded mode hold a single block, which is then buffered
+/// whole; prefer [`LzmaReader`] or
+/// [`xz_decompress`](crate::xz_decompress) for those.
+///
+/// Like [`LzmaReader`], the reader implements [`io::BufRead`] and forward
+/// [`io::Seek`]. Since a block can't be resumed halfway, any error, of the
+/// input source included, ends decompression and is returned again by
+/// further reads.
+#[cfg_attr(docsrs, doc(cfg(stream)))]
+pub struct XzReader<R>
+where
+    R: io::BufRead,
+{
+    /// Compressed input source
+    input: R,
+    /// How to handle check methods that can't be verified
+    unsupported_check: UnsupportedCheck,
+    /// Position in the stream
+    state: XzState,
+    /// Decompressed bytes of the current block, of which those before `pos`
+    /// have been returned
+    buf: Vec<u8>,
+    /// Read position in `buf`
+    pos: usize,
+    /// Offset of the next byte to return in the decompressed data
+    offset: u64,
+    /// Error that ended decompression, returned again by further reads
+    error: Option<(io::ErrorKind, String)>,
+}
+
+#[derive(Debug)]
+enum XzState {
+    /// The stream header is still to be read.
+    Header,
+    /// The next block or the index is to be read.
+    Blocks {
+        stream_flags: StreamFlags,
+        records: Vec<IndexRecord>,
+    },
+    /// The footer has been read and checked.
+    Done,
+}
+
+impl<R> XzReader<R>
+where
+    R: io::BufRead,
+{
+    /// Initialize the decompressor, reading compressed bytes from `input`.
+    pub fn new(input: R) -> Self {
+        Self::new_with_options(&Options::default(), input)
+    }
+
+    /// Initialize the decompressor with the given `options`, of which only
+    /// [`unsupported_check`](Options::unsupported_check) applies.
+    pub fn new_with_options(options: &Options, input: R) -> Self {
+        Self {
+            input,
+            unsupported_check: options.unsupported_check,
+            state: XzState::Header,
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+            error: None,
+        }
+    }
+
+    /// Get a reference to the input source.
+    pub fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    /// Consumes the decompressor and returns the input source.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Decompress blocks until some data is available in `buf`, or the end
+    /// of the stream is reached.
+    fn fill(&mut self) -> io::Result<()> {
+        if let Some((kind, message)) = &self.error {
+            return Err(io::Error::new(*kind, message.clone()));
+        }
+        while self.pos == self.buf.len() {
+            if let XzState::Done = self.state {
+                break;
+            }
+            let result = self.advance();
+            poison(&mut self.error, result.map_err(io::Error::from))?;
+        }
+        Ok(())
+    }
+
+    /// Read the next part of the stream: its header, a block or the index
+    /// and footer.
+    fn advance(&mut self) -> error::Result<()> {
+        match &mut self.state {
+            XzState::Header => {
+                let header = header::StreamHeader::parse(&mut self.input)?;
+                let check_method = header.stream_flags.check_method;
+                if !CheckDigest::is_supported(check_method)
+                    && self.unsupported_check == UnsupportedCheck::Warn
+                {
+                    lzma_warn!(
+                        "XZ check method {:?} is not supported, the data is not verified",
+                        check_method
+                    );
+                }
+                self.state = XzState::Blocks {
+                    stream_flags: header.stream_flags,
+                    records: Vec::new(),
+                };
+            }
+            XzState::Blocks {
+                stream_flags,
+                records,
+            } => {
+                let mut count_input = util::CountBufRead::new(&mut self.input);
+                let header_size = count_input.read_u8()?;
+                if header_size == 0 {
+                    xz::check_index(&mut count_input, records)?;
+                    let index_size = count_input.count();
+                    xz::check_footer(&mut self.input, *stream_flags, index_size)?;
+                    if !util::is_eof(&mut self.input)? {
+                        return Err(error::Error::XzError(
+                            "Unexpected data after last XZ block".to_string(),
+                        ));
+                    }
+                    self.state = XzState::Done;
+                } else {
+                    self.buf.clear();
+                    self.pos = 0;
+                    xz::read_block(
+                        &mut count_input,
+                        &mut self.buf,
+                        stream_flags.check_method,
+                        self.unsupported_check,
+                        records,
+                        header_size,
+                    )?;
+                }
+            }
+            XzState::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Skip up to `n` decompressed bytes, returning how many were skipped.
+    fn skip(&mut self, mut n: u64) -> io::Result<u64> {
+        let start = n;
+        while n > 0 {
+            self.fill()?;
+            let available = (self.buf.len() - self.pos) as u64;
+            if available == 0 {
+                break;
+            }
+            let step = available.min(n);
+            self.pos += step as usize;
+            n -= step;
+        }
+        Ok(start - n)
+    }
+}
+
+impl<R> Read for XzReader<R>
+where
+    R: io::BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill()?;
+        let n = (&self.buf[self.pos..]).read(buf)?;
+        self.pos += n;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> BufRead for XzReader<R>
+where
+    R: io::BufRead,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill()?;
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buf.len() - self.pos);
+        self.pos += amt;
+        self.offset += amt as u64;
+    }
+}
+
+impl<R> Seek for XzReader<R>
+where
+    R: io::BufRead,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = seek_target("XzReader", self.offset, pos)?;
+        self.skip(target - self.offset)?;
+        self.offset = target;
+        Ok(target)
+    }
+}
+
+impl<R> std::fmt::Debug for XzReader<R>
+where
+    R: io::BufRead + std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("XzReader")
+            .field("input", &self.input)
+            .field("state", &self.state)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+/// Record the error of `result`, if any, so that further reads return it.
+fn poison<T>(error: &mut Option<(io::ErrorKind, String)>, result: io::Result<T>) -> io::Result<T> {
+    if let Err(e) = &result {
+        *error = Some((e.kind(), e.to_string()));
+    }
+    result
+}
+
+/// Resolve `pos` to an offset no smaller than `offset`, as readers can only
+/// seek forward.
+fn seek_target(name: &str, offset: u64, pos: SeekFrom) -> io::Result<u64> {
+    let target = match pos {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::Current(n) => {
+            if n >= 0 {
+                offset.checked_add(n as u64)
+            } else {
+                offset.checked_sub(n.unsigned_abs())
+            }
+        }
+        SeekFrom::End(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} can't seek relative to the end", name),
+            ))
+        }
+    };
+    match target {
+        Some(target) if target >= offset => Ok(target),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can only seek forward", name),
+        )),
+    }
+}
@@ -114,3 +114,63 @@ where
         self.count += amt;
     }
 }
+
+/// An [`io::Write`] counting the bytes written and refusing to write more than
+/// a given limit.
+pub struct BoundedWrite<'a, W>
+where
+    W: 'a + io::Write,
+{
+    /// Underlying writer
+    write: &'a mut W,
+    /// Number of bytes written
+    count: usize,
+    /// Maximum number of bytes to write
+    limit: usize,
+    /// Whether a write was refused because of the limit
+    exceeded: bool,
+}
+
+impl<'a, W> BoundedWrite<'a, W>
+where
+    W: io::Write,
+{
+    pub fn new(write: &'a mut W, limit: usize) -> Self {
+        Self {
+            write,
+            count: 0,
+            limit,
+            exceeded: false,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<'a, W> io::Write for BoundedWrite<'a, W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.limit - self.count {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("output exceeds limit of {} bytes", self.limit),
+            ));
+        }
+        let result = self.write.write(buf)?;
+        self.count += result;
+        Ok(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
@@ -0,0 +1,144 @@
+//! Comparison of a compressed stream against its expected content.
+
+use crate::decode::lzma2::Lzma2Decoder;
+use crate::decode::options::MAX_DICT_SIZE;
+use crate::{error, Format};
+use std::io;
+
+/// Size of the pieces in which the decompressed data is compared.
+const COMPARE_BUF_SIZE: usize = 0x10000;
+
+/// Decompress `compressed` in the given `format` and compare the result with
+/// the data read from `plain`, returning the offset of the first byte that
+/// differs, or `None` if both are identical.
+///
+/// If one side is a prefix of the other, the divergence is reported at the
+/// end of the shorter one. Data is compared as it is decompressed, so memory
+/// usage is bounded by the dictionary size rather than by the size of the
+/// content. Invalid compressed data is reported as an error.
+///
+/// Raw LZMA2 streams don't declare their dictionary size, so it may grow up
+/// to [`MAX_DICT_SIZE`] for them: use [`verify_lzma2_matches`] with the size
+/// known from the container to bound it further.
+pub fn verify_matches<R, P>(
+    compressed: &mut R,
+    plain: &mut P,
+    format: Format,
+) -> error::Result<Option<u64>>
+where
+    R: io::BufRead,
+    P: io::Read,
+{
+    match format {
+        Format::Lzma => verify_with(plain, |output| crate::lzma_decompress(compressed, output)),
+        Format::Lzma2 => verify_lzma2_matches(compressed, plain, MAX_DICT_SIZE),
+        Format::Xz => verify_with(plain, |output| crate::xz_decompress(compressed, output)),
+    }
+}
+
+/// Like [`verify_matches`] for raw LZMA2 data compressed with a dictionary of
+/// at most `dict_size` bytes, which bounds the memory used. Streams whose
+/// matches reach farther back are reported as errors.
+pub fn verify_lzma2_matches<R, P>(
+    compressed: &mut R,
+    plain: &mut P,
+    dict_size: u32,
+) -> error::Result<Option<u64>>
+where
+    R: io::BufRead,
+    P: io::Read,
+{
+    verify_with(plain, |output| {
+        Lzma2Decoder::with_dict_size(dict_size).decompress(compressed, output)
+    })
+}
+
+/// Compare the output of `decompress` with the data read from `plain`.
+fn verify_with<P, F>(plain: &mut P, decompress: F) -> error::Result<Option<u64>>
+where
+    P: io::Read,
+    F: FnOnce(&mut CompareWrite<'_, P>) -> error::Result<()>,
+{
+    let mut compare = CompareWrite {
+        plain,
+        buf: Vec::new(),
+        offset: 0,
+        divergence: None,
+    };
+    let result = decompress(&mut compare);
+    if let Some(offset) = compare.divergence {
+        return Ok(Some(offset));
+    }
+    result?;
+
+    // The plain data must end with the decompressed data.
+    let mut byte = [0u8];
+    if read_full(compare.plain, &mut byte)? != 0 {
+        return Ok(Some(compare.offset));
+    }
+    Ok(None)
+}
+
+/// An [`io::Write`] comparing the bytes written with a reader, and failing at
+/// the first difference.
+struct CompareWrite<'a, P>
+where
+    P: io::Read,
+{
+    /// Expected data
+    plain: &'a mut P,
+    /// Buffer for the expected data, of at most [`COMPARE_BUF_SIZE`] bytes
+    buf: Vec<u8>,
+    /// Number of identical bytes so far
+    offset: u64,
+    /// Offset of the first difference, if any
+    divergence: Option<u64>,
+}
+
+impl<'a, P> io::Write for CompareWrite<'a, P>
+where
+    P: io::Read,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        for piece in data.chunks(COMPARE_BUF_SIZE) {
+            if self.buf.len() < piece.len() {
+                self.buf.resize(piece.len(), 0);
+            }
+            let expected = &mut self.buf[..piece.len()];
+            let len = read_full(self.plain, expected)?;
+            let same = expected[..len]
+                .iter()
+                .zip(piece)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if same < piece.len() {
+                self.divergence = Some(self.offset + same as u64);
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "decompressed data differs from the expected data",
+                ));
+            }
+            self.offset += piece.len() as u64;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Read until `buf` is full or the end of `input` is reached, returning the
+/// number of bytes read.
+fn read_full<R: io::Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(len) => n += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
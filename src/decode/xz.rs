@@ -1,28 +1,119 @@
 //! Decoder for the `.xz` file format.
 
 use crate::decode::lzma2::Lzma2Decoder;
+use crate::decode::options::UnsupportedCheck;
 use crate::decode::util;
-use crate::error;
-use crate::xz::crc::{CRC32, CRC64};
-use crate::xz::{footer, header, CheckMethod, StreamFlags};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use crate::xz::check::{Check, CheckDigest};
+use crate::xz::crc::CRC32;
+use crate::xz::delta::DeltaState;
+use crate::xz::index::{self, IndexRecord};
+use crate::xz::{footer, header, CheckMethod, FilterId, StreamFlags};
+use crate::{error, CompressedSize, UncompressedSize};
+use byteorder::{LittleEndian, ReadBytesExt};
 use std::io;
 use std::io::Read;
 
-#[derive(Debug)]
-struct Record {
-    unpadded_size: u64,
-    unpacked_size: u64,
+/// Summary of a decompressed `.xz` stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XzSummary {
+    /// Check method of the stream.
+    pub check_method: CheckMethod,
+    /// Whether the checks of the blocks were left unverified because the
+    /// check method isn't supported, as allowed by the
+    /// [`unsupported_check`](crate::decompress::Options::unsupported_check)
+    /// option.
+    pub check_skipped: bool,
 }
 
-pub fn decode_stream<R, W>(input: &mut R, output: &mut W) -> error::Result<()>
+/// Header of an `.xz` block, see sect. 3.1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XzBlockHeader {
+    /// Size of the block header in bytes.
+    pub header_size: u64,
+    /// Filters of the block, in encoding order.
+    pub filters: Vec<FilterId>,
+    /// Compressed size declared by the header, if any.
+    pub packed_size: Option<CompressedSize>,
+    /// Uncompressed size declared by the header, if any.
+    pub unpacked_size: Option<UncompressedSize>,
+}
+
+/// A block of an `.xz` stream, as passed to the callback of
+/// [`xz_decompress_with_block_callback`](crate::xz_decompress_with_block_callback)
+/// once the block is decompressed and verified.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XzBlockInfo {
+    /// Number of the block in the stream, starting from 0.
+    pub number: usize,
+    /// Header of the block.
+    pub header: XzBlockHeader,
+    /// Sizes of the block, as listed in the index.
+    pub record: IndexRecord,
+    /// Offset of the block from the start of the stream.
+    pub compressed_offset: u64,
+    /// Offset of the decompressed data of the block in the decompressed
+    /// data of the stream.
+    pub uncompressed_offset: u64,
+}
+
+impl XzBlockInfo {
+    /// Number of bytes of the stream read up to the end of this block,
+    /// padding included.
+    pub fn compressed_total(&self) -> u64 {
+        self.compressed_offset + self.record.padded_size().0
+    }
+
+    /// Number of bytes decompressed up to the end of this block.
+    pub fn uncompressed_total(&self) -> u64 {
+        self.uncompressed_offset + self.record.unpacked_size.0
+    }
+}
+
+pub fn decode_stream<R, W>(
+    input: &mut R,
+    output: &mut W,
+    unsupported_check: UnsupportedCheck,
+    on_block: &mut dyn FnMut(&XzBlockInfo),
+) -> error::Result<XzSummary>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let summary = decode_single_stream(input, output, unsupported_check, on_block)?;
+    if !util::is_eof(input)? {
+        return Err(error::Error::XzError(
+            "Unexpected data after last XZ block".to_string(),
+        ));
+    }
+    Ok(summary)
+}
+
+/// Decode one stream, up to its footer, leaving any following data unread.
+/// `on_block` is called after each block.
+pub(crate) fn decode_single_stream<R, W>(
+    input: &mut R,
+    output: &mut W,
+    unsupported_check: UnsupportedCheck,
+    on_block: &mut dyn FnMut(&XzBlockInfo),
+) -> error::Result<XzSummary>
 where
     R: io::BufRead,
     W: io::Write,
 {
     let header = header::StreamHeader::parse(input)?;
+    let check_method = header.stream_flags.check_method;
+    let check_skipped =
+        !CheckDigest::is_supported(check_method) && unsupported_check != UnsupportedCheck::Error;
+    if check_skipped && unsupported_check == UnsupportedCheck::Warn {
+        lzma_warn!(
+            "XZ check method {:?} is not supported, the data is not verified",
+            check_method
+        );
+    }
 
-    let mut records: Vec<Record> = vec![];
+    let mut records: Vec<IndexRecord> = vec![];
+    let mut compressed_offset = header::XZ_HEADER_SIZE;
+    let mut uncompressed_offset = 0;
     let index_size = loop {
         let mut count_input = util::CountBufRead::new(input);
         let header_size = count_input.read_u8()?;
@@ -35,148 +126,102 @@ where
             break index_size;
         }
 
-        read_block(
+        let header = read_block(
             &mut count_input,
             output,
-            header.stream_flags.check_method,
+            check_method,
+            unsupported_check,
             &mut records,
             header_size,
         )?;
+        let block = XzBlockInfo {
+            number: records.len() - 1,
+            header,
+            record: records[records.len() - 1],
+            compressed_offset,
+            uncompressed_offset,
+        };
+        compressed_offset = block.compressed_total();
+        uncompressed_offset = block.uncompressed_total();
+        on_block(&block);
     };
 
-    let crc32 = input.read_u32::<LittleEndian>()?;
-    let mut digest = CRC32.digest();
-    {
-        let mut digested = util::CrcDigestRead::new(input, &mut digest);
-        let backward_size = digested.read_u32::<LittleEndian>()?;
-        if index_size as u32 != (backward_size + 1) << 2 {
-            return Err(error::Error::XzError(format!(
-                "Invalid index size: expected {} but got {}",
-                (backward_size + 1) << 2,
-                index_size
-            )));
-        }
-
-        let stream_flags = {
-            let field = digested.read_u16::<BigEndian>()?;
-            StreamFlags::parse(field)?
-        };
+    check_footer(input, header.stream_flags, index_size)?;
 
-        if header.stream_flags != stream_flags {
-            return Err(error::Error::XzError(format!(
-                "Flags in header ({:?}) does not match footer ({:?})",
-                header.stream_flags, stream_flags
-            )));
-        }
-    }
+    Ok(XzSummary {
+        check_method,
+        check_skipped,
+    })
+}
 
-    let digest_crc32 = digest.finalize();
-    if crc32 != digest_crc32 {
+/// Parse the stream footer and check it against the stream flags of the
+/// header and the size of the index.
+pub(crate) fn check_footer<R>(
+    input: &mut R,
+    stream_flags: StreamFlags,
+    index_size: usize,
+) -> error::Result<()>
+where
+    R: io::BufRead,
+{
+    let footer = footer::StreamFooter::parse(input)?;
+    if index_size as u64 != footer.index_size() {
         return Err(error::Error::XzError(format!(
-            "Invalid footer CRC32: expected 0x{:08x} but got 0x{:08x}",
-            crc32, digest_crc32
+            "Invalid index size: expected {} but got {}",
+            footer.index_size(),
+            index_size
         )));
     }
 
-    if !util::read_tag(input, footer::XZ_MAGIC_FOOTER)? {
+    if stream_flags != footer.stream_flags {
         return Err(error::Error::XzError(format!(
-            "Invalid footer magic, expected {:?}",
-            footer::XZ_MAGIC_FOOTER
+            "Flags in header ({:?}) does not match footer ({:?})",
+            stream_flags, footer.stream_flags
         )));
     }
-
-    if !util::is_eof(input)? {
-        return Err(error::Error::XzError(
-            "Unexpected data after last XZ block".to_string(),
-        ));
-    }
     Ok(())
 }
 
-fn check_index<R>(
+pub(crate) fn check_index<R>(
     count_input: &mut util::CountBufRead<'_, R>,
-    records: &[Record],
+    records: &[IndexRecord],
 ) -> error::Result<()>
 where
     R: io::BufRead,
 {
-    let mut digest = CRC32.digest();
-    let index_tag = 0u8;
-    digest.update(&[index_tag]);
-    {
-        let mut digested = util::CrcDigestRead::new(count_input, &mut digest);
+    let index_records = index::parse_records(count_input)?;
+    if index_records.len() != records.len() {
+        return Err(error::Error::XzError(format!(
+            "Expected {} records but got {} records",
+            index_records.len(),
+            records.len()
+        )));
+    }
+
+    for (i, (record, index_record)) in records.iter().zip(&index_records).enumerate() {
+        lzma_info!("XZ index checking record {}: {:?}", i, record);
 
-        let num_records = get_multibyte(&mut digested)?;
-        if num_records != records.len() as u64 {
+        if index_record.unpadded_size != record.unpadded_size {
             return Err(error::Error::XzError(format!(
-                "Expected {} records but got {} records",
-                num_records,
-                records.len()
+                "Invalid index for record {}: unpadded size ({}) does not match index ({})",
+                i, record.unpadded_size, index_record.unpadded_size
             )));
         }
 
-        for (i, record) in records.iter().enumerate() {
-            lzma_info!("XZ index checking record {}: {:?}", i, record);
-
-            let unpadded_size = get_multibyte(&mut digested)?;
-            if unpadded_size != record.unpadded_size {
-                return Err(error::Error::XzError(format!(
-                    "Invalid index for record {}: unpadded size ({}) does not match index ({})",
-                    i, record.unpadded_size, unpadded_size
-                )));
-            }
-
-            let unpacked_size = get_multibyte(&mut digested)?;
-            if unpacked_size != record.unpacked_size {
-                return Err(error::Error::XzError(format!(
-                    "Invalid index for record {}: unpacked size ({}) does not match index ({})",
-                    i, record.unpacked_size, unpacked_size
-                )));
-            }
-        }
-    };
-    // TODO: create padding parser function
-    let count = count_input.count();
-    let padding_size = ((count ^ 0x03) + 1) & 0x03;
-    lzma_info!(
-        "XZ index: {} byte(s) read, {} byte(s) of padding",
-        count,
-        padding_size
-    );
-
-    {
-        let mut digested = util::CrcDigestRead::new(count_input, &mut digest);
-        for _ in 0..padding_size {
-            let byte = digested.read_u8()?;
-            if byte != 0 {
-                return Err(error::Error::XzError(
-                    "Invalid index padding, must be null bytes".to_string(),
-                ));
-            }
+        if index_record.unpacked_size != record.unpacked_size {
+            return Err(error::Error::XzError(format!(
+                "Invalid index for record {}: unpacked size ({}) does not match index ({})",
+                i, record.unpacked_size, index_record.unpacked_size
+            )));
         }
-    };
-
-    let digest_crc32 = digest.finalize();
-    lzma_info!("XZ index checking digest 0x{:08x}", digest_crc32);
-
-    let crc32 = count_input.read_u32::<LittleEndian>()?;
-    if crc32 != digest_crc32 {
-        return Err(error::Error::XzError(format!(
-            "Invalid index CRC32: expected 0x{:08x} but got 0x{:08x}",
-            crc32, digest_crc32
-        )));
     }
 
     Ok(())
 }
 
-#[derive(Debug)]
-enum FilterId {
-    Lzma2,
-}
-
 fn get_filter_id(id: u64) -> error::Result<FilterId> {
     match id {
+        0x03 => Ok(FilterId::Delta),
         0x21 => Ok(FilterId::Lzma2),
         _ => Err(error::Error::XzError(format!("Unknown filter id {}", id))),
     }
@@ -189,29 +234,32 @@ struct Filter {
 
 struct BlockHeader {
     filters: Vec<Filter>,
-    packed_size: Option<u64>,
-    unpacked_size: Option<u64>,
+    packed_size: Option<CompressedSize>,
+    unpacked_size: Option<UncompressedSize>,
 }
 
-fn read_block<R, W>(
+pub(crate) fn read_block<R, W>(
     count_input: &mut util::CountBufRead<'_, R>,
     output: &mut W,
     check_method: CheckMethod,
-    records: &mut Vec<Record>,
+    unsupported_check: UnsupportedCheck,
+    records: &mut Vec<IndexRecord>,
     header_size: u8,
-) -> error::Result<bool>
+) -> error::Result<XzBlockHeader>
 where
     R: io::BufRead,
     W: io::Write,
 {
     let mut digest = CRC32.digest();
     digest.update(&[header_size]);
-    let header_size = ((header_size as u64) << 2) - 1;
+    let header_size = (header_size as u64 + 1) << 2;
 
     let block_header = {
-        let mut taken = count_input.take(header_size);
+        // The size byte is read, and the CRC32 is read below.
+        let fields_size = header_size - 5;
+        let mut taken = count_input.take(fields_size);
         let mut digested = io::BufReader::new(util::CrcDigestRead::new(&mut taken, &mut digest));
-        read_block_header(&mut digested, header_size)?
+        read_block_header(&mut digested, fields_size)?
     };
 
     let crc32 = count_input.read_u32::<LittleEndian>()?;
@@ -223,37 +271,36 @@ where
         )));
     }
 
-    let mut tmpbuf: Vec<u8> = Vec::new();
-    let filters = block_header.filters;
-    for (i, filter) in filters.iter().enumerate() {
-        if i == 0 {
-            // TODO: use SubBufRead on input if packed_size is known?
-            let packed_size = decode_filter(count_input, &mut tmpbuf, filter)?;
-            if let Some(expected_packed_size) = block_header.packed_size {
-                if (packed_size as u64) != expected_packed_size {
-                    return Err(error::Error::XzError(format!(
-                        "Invalid compressed size: expected {} but got {}",
-                        expected_packed_size, packed_size
-                    )));
-                }
+    let mut check_output = CheckWrite::new(output, check_method);
+    {
+        // Filters are listed in encoding order, so the data decoded by the
+        // last filter goes through the other ones in reverse order.
+        let (last, others) = match block_header.filters.split_last() {
+            Some(split) => split,
+            None => unreachable!("block headers have at least one filter"),
+        };
+        let mut filtered: Box<dyn io::Write + '_> = Box::new(&mut check_output);
+        for filter in others {
+            filtered = filter_output(filtered, filter)?;
+        }
+
+        // TODO: use SubBufRead on input if packed_size is known?
+        let packed_size = decode_filter(count_input, &mut filtered, last)?;
+        if let Some(expected_packed_size) = block_header.packed_size {
+            if CompressedSize(packed_size as u64) != expected_packed_size {
+                return Err(error::Error::XzError(format!(
+                    "Invalid compressed size: expected {} but got {}",
+                    expected_packed_size, packed_size
+                )));
             }
-        } else {
-            let mut newbuf: Vec<u8> = Vec::new();
-            decode_filter(
-                &mut io::BufReader::new(tmpbuf.as_slice()),
-                &mut newbuf,
-                filter,
-            )?;
-            // TODO: does this move or copy?
-            tmpbuf = newbuf;
         }
     }
 
-    let unpacked_size = tmpbuf.len();
-    lzma_info!("XZ block decompressed to {} byte(s)", tmpbuf.len());
+    let unpacked_size = check_output.count();
+    lzma_info!("XZ block decompressed to {} byte(s)", unpacked_size);
 
     if let Some(expected_unpacked_size) = block_header.unpacked_size {
-        if (unpacked_size as u64) != expected_unpacked_size {
+        if UncompressedSize(unpacked_size as u64) != expected_unpacked_size {
             return Err(error::Error::XzError(format!(
                 "Invalid decompressed size: expected {} but got {}",
                 expected_unpacked_size, unpacked_size
@@ -277,34 +324,98 @@ where
             ));
         }
     }
-    validate_block_check(count_input, tmpbuf.as_slice(), check_method)?;
-
-    output.write_all(tmpbuf.as_slice())?;
-    records.push(Record {
-        unpadded_size: (count_input.count() - padding_size) as u64,
-        unpacked_size: unpacked_size as u64,
+    validate_block_check(
+        count_input,
+        check_output.finalize(),
+        check_method,
+        unsupported_check,
+    )?;
+
+    records.push(IndexRecord {
+        unpadded_size: CompressedSize((count_input.count() - padding_size) as u64),
+        unpacked_size: UncompressedSize(unpacked_size as u64),
     });
 
-    let finished = false;
-    Ok(finished)
+    Ok(XzBlockHeader {
+        header_size,
+        filters: block_header
+            .filters
+            .iter()
+            .map(|filter| filter.filter_id)
+            .collect(),
+        packed_size: block_header.packed_size,
+        unpacked_size: block_header.unpacked_size,
+    })
+}
+
+/// An [`io::Write`] counting the bytes written and computing the block check
+/// on them.
+struct CheckWrite<'a, W>
+where
+    W: 'a + io::Write,
+{
+    /// Underlying writer
+    write: &'a mut W,
+    /// Hasher
+    digest: CheckDigest,
+    /// Number of bytes written
+    count: usize,
 }
 
-/// Verify block checksum against the "Block Check" field.
+impl<'a, W> CheckWrite<'a, W>
+where
+    W: io::Write,
+{
+    fn new(write: &'a mut W, check_method: CheckMethod) -> Self {
+        Self {
+            write,
+            digest: CheckDigest::new(check_method),
+            count: 0,
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    fn finalize(self) -> Option<Check> {
+        self.digest.finalize()
+    }
+}
+
+impl<'a, W> io::Write for CheckWrite<'a, W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.write.write(buf)?;
+        self.digest.update(&buf[..result]);
+        self.count += result;
+        Ok(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// Verify block checksum against the "Block Check" field. A `None` check
+/// stands for an unsupported check method.
 ///
 /// See spec section 3.4 for details.
 fn validate_block_check<R>(
     input: &mut R,
-    buf: &[u8],
+    check: Option<Check>,
     check_method: CheckMethod,
+    unsupported_check: UnsupportedCheck,
 ) -> error::Result<()>
 where
     R: io::BufRead,
 {
-    match check_method {
-        CheckMethod::None => (),
-        CheckMethod::Crc32 => {
+    match check {
+        Some(Check::None) => (),
+        Some(Check::Crc32(digest_crc32)) => {
             let crc32 = input.read_u32::<LittleEndian>()?;
-            let digest_crc32 = CRC32.checksum(buf);
             if crc32 != digest_crc32 {
                 return Err(error::Error::XzError(format!(
                     "Invalid block CRC32, expected 0x{:08x} but got 0x{:08x}",
@@ -312,9 +423,8 @@ where
                 )));
             }
         }
-        CheckMethod::Crc64 => {
+        Some(Check::Crc64(digest_crc64)) => {
             let crc64 = input.read_u64::<LittleEndian>()?;
-            let digest_crc64 = CRC64.checksum(buf);
             if crc64 != digest_crc64 {
                 return Err(error::Error::XzError(format!(
                     "Invalid block CRC64, expected 0x{:016x} but got 0x{:016x}",
@@ -322,16 +432,80 @@ where
                 )));
             }
         }
-        // TODO
-        CheckMethod::Sha256 => {
-            return Err(error::Error::XzError(
-                "Unsupported SHA-256 checksum (not yet implemented)".to_string(),
-            ));
+        None => {
+            if unsupported_check == UnsupportedCheck::Error {
+                return Err(error::Error::XzError(format!(
+                    "Unsupported check method {:?}",
+                    check_method
+                )));
+            }
+            let mut skipped = [0; 64];
+            input.read_exact(&mut skipped[..check_method.check_size()])?;
         }
     }
     Ok(())
 }
 
+/// Wrap `output` to revert a filter that isn't the last one of the chain.
+fn filter_output<'a>(
+    output: Box<dyn io::Write + 'a>,
+    filter: &Filter,
+) -> error::Result<Box<dyn io::Write + 'a>> {
+    match filter.filter_id {
+        FilterId::Delta => Ok(Box::new(DeltaWrite {
+            write: output,
+            state: DeltaState::new(delta_distance(&filter.props)?),
+            buf: Vec::new(),
+        })),
+        FilterId::Lzma2 => Err(error::Error::XzError(
+            "Filter Lzma2 must be the last filter".to_string(),
+        )),
+    }
+}
+
+/// An [`io::Write`] reverting the delta filter on the bytes written.
+struct DeltaWrite<W>
+where
+    W: io::Write,
+{
+    /// Underlying writer
+    write: W,
+    /// Filter state
+    state: DeltaState,
+    /// Buffer for the unfiltered bytes
+    buf: Vec<u8>,
+}
+
+impl<W> io::Write for DeltaWrite<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(buf);
+        self.state.decode(&mut self.buf);
+        // Bytes are unfiltered once, so they must all be passed on.
+        self.write.write_all(&self.buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// Decode the distance from the delta filter properties, see spec section
+/// 5.3.2.
+fn delta_distance(props: &[u8]) -> error::Result<usize> {
+    match props {
+        [distance] => Ok(*distance as usize + 1),
+        _ => Err(error::Error::XzError(format!(
+            "Invalid properties for filter {:?}",
+            FilterId::Delta
+        ))),
+    }
+}
+
 fn decode_filter<R, W>(input: &mut R, output: &mut W, filter: &Filter) -> error::Result<usize>
 where
     R: io::BufRead,
@@ -339,6 +513,9 @@ where
 {
     let mut count_input = util::CountBufRead::new(input);
     match filter.filter_id {
+        FilterId::Delta => Err(error::Error::XzError(
+            "Filter Delta can't be the last filter".to_string(),
+        )),
         FilterId::Lzma2 => {
             if filter.props.len() != 1 {
                 return Err(error::Error::XzError(format!(
@@ -346,13 +523,26 @@ where
                     filter.filter_id
                 )));
             }
-            // TODO: properties??
-            Lzma2Decoder::new().decompress(&mut count_input, output)?;
+            let dict_size = lzma2_dict_size(filter.props[0])?;
+            Lzma2Decoder::with_dict_size(dict_size).decompress(&mut count_input, output)?;
             Ok(count_input.count())
         }
     }
 }
 
+/// Decode the dictionary size from the LZMA2 filter properties, see spec
+/// section 5.3.1.
+fn lzma2_dict_size(props: u8) -> error::Result<u32> {
+    match props {
+        0..=39 => Ok((2 | (props as u32 & 1)) << (props / 2 + 11)),
+        40 => Ok(0xFFFF_FFFF),
+        _ => Err(error::Error::XzError(format!(
+            "Invalid LZMA2 dictionary size property {}, must be <= 40",
+            props
+        ))),
+    }
+}
+
 fn read_block_header<R>(input: &mut R, header_size: u64) -> error::Result<BlockHeader>
 where
     R: io::BufRead,
@@ -380,13 +570,13 @@ where
     }
 
     let packed_size = if has_packed_size {
-        Some(get_multibyte(input)?)
+        Some(CompressedSize(get_multibyte(input)?))
     } else {
         None
     };
 
     let unpacked_size = if has_unpacked_size {
-        Some(get_multibyte(input)?)
+        Some(UncompressedSize(get_multibyte(input)?))
     } else {
         None
     };
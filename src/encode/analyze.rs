@@ -0,0 +1,243 @@
+//! Analysis of the input to pick a filter for `.xz` blocks.
+
+use crate::compress::Filter;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+/// Minimum number of bytes to consider a delta filter.
+const MIN_DELTA_SAMPLE: usize = 256;
+
+/// Largest delta distance tried.
+const MAX_DELTA_DISTANCE: usize = 16;
+
+/// Number of fractional bits of the entropies, which are computed in fixed
+/// point so that they, and the output of the encoders that rely on them, are
+/// the same on every host.
+pub(crate) const ENTROPY_FRAC_BITS: u32 = 16;
+
+/// Result of [`analyze`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Analysis {
+    /// Executable detected from its header.
+    pub executable: Option<Executable>,
+    /// Delta distance that makes the bytes noticeably more predictable, if
+    /// any.
+    pub delta_distance: Option<u16>,
+}
+
+impl Analysis {
+    /// Filter to apply given this analysis.
+    ///
+    /// Executables would benefit from a BCJ filter, which isn't supported
+    /// yet, so no filter is applied to them.
+    pub fn filter(&self) -> Filter {
+        match (self.executable, self.delta_distance) {
+            (None, Some(distance)) => Filter::Delta(distance),
+            _ => Filter::None,
+        }
+    }
+}
+
+/// Container of an executable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutableFormat {
+    /// Executable and Linkable Format, used on Unix systems.
+    Elf,
+    /// Portable Executable format, used on Windows.
+    Pe,
+}
+
+/// Executable detected by [`analyze`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Executable {
+    /// Container format.
+    pub format: ExecutableFormat,
+    /// Machine type from the header, i.e. `e_machine` for ELF (e.g. 0x3E for
+    /// x86-64) or `Machine` for PE (e.g. 0x8664 for x86-64).
+    pub machine: u16,
+}
+
+/// Analyze a sample from the start of the data to compress, to decide which
+/// filter would improve compression.
+///
+/// This detects ELF and PE executables from their header, and otherwise
+/// compares the byte entropy of the sample before and after delta filtering
+/// with small distances.
+pub fn analyze(sample: &[u8]) -> Analysis {
+    let executable = detect_executable(sample);
+    let delta_distance = if executable.is_none() {
+        best_delta_distance(sample)
+    } else {
+        None
+    };
+    Analysis {
+        executable,
+        delta_distance,
+    }
+}
+
+fn detect_executable(sample: &[u8]) -> Option<Executable> {
+    if sample.len() >= 20 && sample.starts_with(b"\x7FELF") {
+        // EI_DATA gives the endianness of the following fields.
+        let machine = match sample[5] {
+            1 => LittleEndian::read_u16(&sample[18..20]),
+            2 => BigEndian::read_u16(&sample[18..20]),
+            _ => return None,
+        };
+        return Some(Executable {
+            format: ExecutableFormat::Elf,
+            machine,
+        });
+    }
+
+    if sample.len() >= 0x40 && sample.starts_with(b"MZ") {
+        let pe_offset = LittleEndian::read_u32(&sample[0x3C..0x40]) as usize;
+        let pe_header = sample.get(pe_offset..pe_offset.checked_add(6)?)?;
+        if pe_header.starts_with(b"PE\0\0") {
+            return Some(Executable {
+                format: ExecutableFormat::Pe,
+                machine: LittleEndian::read_u16(&pe_header[4..6]),
+            });
+        }
+    }
+    None
+}
+
+fn best_delta_distance(sample: &[u8]) -> Option<u16> {
+    if sample.len() < MIN_DELTA_SAMPLE {
+        return None;
+    }
+    let raw_bits = entropy_bits(sample.iter().copied());
+    let (distance, delta_bits) = (1..=MAX_DELTA_DISTANCE)
+        .map(|distance| {
+            let deltas = sample
+                .iter()
+                .zip(&sample[distance..])
+                .map(|(&before, &byte)| byte.wrapping_sub(before));
+            (distance, entropy_bits(deltas))
+        })
+        .fold((0, u64::MAX), |best, candidate| {
+            if candidate.1 < best.1 {
+                candidate
+            } else {
+                best
+            }
+        });
+    // Only worth it if clearly better, as LZMA2 already captures some
+    // regularities.
+    if 4 * delta_bits < 3 * raw_bits {
+        Some(distance as u16)
+    } else {
+        None
+    }
+}
+
+/// Order-0 entropy of the bytes, in bits per byte with
+/// [`ENTROPY_FRAC_BITS`] fractional bits.
+pub(crate) fn entropy_bits<I: Iterator<Item = u8>>(bytes: I) -> u64 {
+    let mut counts = [0u64; 256];
+    let mut total = 0u64;
+    for byte in bytes {
+        counts[byte as usize] += 1;
+        total += 1;
+    }
+    if total == 0 {
+        return 0;
+    }
+    // Sum of count * log2(total / count), divided by the total.
+    let log2_total = log2_fixed(total);
+    let bits: u64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| count * (log2_total - log2_fixed(count)))
+        .sum();
+    bits / total
+}
+
+/// Base-2 logarithm of `x`, which must not be zero, with
+/// [`ENTROPY_FRAC_BITS`] fractional bits, computed one bit at a time by
+/// squaring the mantissa.
+fn log2_fixed(x: u64) -> u64 {
+    debug_assert!(x > 0);
+    let int = 63 - x.leading_zeros();
+    // Mantissa in [1, 2), with 31 fractional bits.
+    let mut mantissa = if int >= 31 {
+        x >> (int - 31)
+    } else {
+        x << (31 - int)
+    };
+    let mut log = (int as u64) << ENTROPY_FRAC_BITS;
+    for bit in (0..ENTROPY_FRAC_BITS).rev() {
+        mantissa = (mantissa * mantissa) >> 31;
+        if mantissa >= 1 << 32 {
+            mantissa >>= 1;
+            log |= 1 << bit;
+        }
+    }
+    log
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entropy_bits() {
+        let one = 1 << ENTROPY_FRAC_BITS;
+        assert_eq!(log2_fixed(1), 0);
+        assert_eq!(log2_fixed(1 << 40), 40 * one);
+        // log2(3) = 1.58496...
+        assert_eq!(log2_fixed(3), 103_872);
+
+        assert_eq!(entropy_bits([7u8; 100].iter().copied()), 0);
+        assert_eq!(entropy_bits(b"abab".iter().copied()), one);
+        assert_eq!(entropy_bits(0..=255), 8 * one);
+    }
+
+    #[test]
+    fn test_executables() {
+        let mut elf = vec![0u8; 64];
+        elf[..6].copy_from_slice(b"\x7FELF\x02\x01");
+        elf[18] = 0x3E;
+        let analysis = analyze(&elf);
+        assert_eq!(
+            analysis.executable,
+            Some(Executable {
+                format: ExecutableFormat::Elf,
+                machine: 0x3E
+            })
+        );
+        assert_eq!(analysis.filter(), Filter::None);
+
+        let mut pe = vec![0u8; 0x100];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3C] = 0x80;
+        pe[0x80..0x86].copy_from_slice(b"PE\0\0\x64\x86");
+        assert_eq!(
+            analyze(&pe).executable,
+            Some(Executable {
+                format: ExecutableFormat::Pe,
+                machine: 0x8664
+            })
+        );
+
+        // Truncated or out-of-bounds headers.
+        assert_eq!(analyze(b"\x7FELF").executable, None);
+        pe[0x3C] = 0xFF;
+        assert_eq!(analyze(&pe).executable, None);
+    }
+
+    #[test]
+    fn test_delta() {
+        // 16-bit samples of a slow signal.
+        let samples: Vec<u8> = (0..4000u32)
+            .flat_map(|i| ((i * 7 + i * i / 1000) as u16).to_le_bytes())
+            .collect();
+        let analysis = analyze(&samples);
+        assert_eq!(analysis.delta_distance, Some(2));
+        assert_eq!(analysis.filter(), Filter::Delta(2));
+
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(20);
+        assert_eq!(analyze(&text).filter(), Filter::None);
+        assert_eq!(analyze(&samples[..100]).filter(), Filter::None);
+    }
+}
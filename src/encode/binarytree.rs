@@ -0,0 +1,276 @@
+//! Binary-tree match finder, the counterpart of liblzma's bt4.
+
+use crate::encode::matchfinder::{hash, Match, HASH_LEN, MAX_MATCH_LEN, MIN_MATCH_LEN};
+
+/// Number of tree nodes visited per lookup.
+const DEFAULT_DEPTH: usize = 48;
+/// Length beyond which a match is taken without looking further.
+const DEFAULT_NICE_LEN: usize = 64;
+const MAX_CYCLIC_SIZE: u64 = 1 << 31;
+
+/// Match finder keeping, for each hash of the first [`HASH_LEN`] bytes, a
+/// binary search tree of the positions ordered by the bytes that follow.
+///
+/// Each lookup descends the tree towards the current position, so the
+/// candidates visited share ever longer prefixes with it, and re-roots the
+/// tree at the current position on the way. This finds longer matches than
+/// a hash chain of the same depth, at about twice the memory and time.
+///
+/// Positions are stored on 32 bits, as offsets plus one from `base`, zero
+/// meaning none, and shifted down before they overflow.
+pub(crate) struct BinaryTree {
+    /// Root of the tree of each hash.
+    head: Vec<u32>,
+    hash_bits: u32,
+    /// Left and right children of each position, indexed modulo
+    /// `cyclic_size`. Grown lazily, so small inputs stay cheap.
+    son: Vec<u32>,
+    /// Number of positions kept in the trees, one more than the dictionary
+    /// size so that matches can reach as far as it. It's capped at 2 GiB to
+    /// leave room for the offsets, which stays beyond the dictionary sizes
+    /// that decoders accept by default.
+    cyclic_size: u64,
+    /// Position that the stored offsets are relative to
+    base: u64,
+    depth: usize,
+    nice_len: usize,
+}
+
+impl BinaryTree {
+    pub fn new(dict_size: u32, hash_bits: u32) -> Self {
+        Self {
+            head: vec![0; 1 << hash_bits],
+            hash_bits,
+            son: Vec::new(),
+            cyclic_size: std::cmp::min(dict_size as u64 + 1, MAX_CYCLIC_SIZE),
+            base: 0,
+            depth: DEFAULT_DEPTH,
+            nice_len: DEFAULT_NICE_LEN,
+        }
+    }
+
+    /// Number of bytes allocated at most for the given dictionary size and
+    /// hash bits.
+    pub fn mem_usage(dict_size: u32, hash_bits: u32) -> u64 {
+        (4 << hash_bits) + 8 * std::cmp::min(dict_size as u64 + 1, MAX_CYCLIC_SIZE)
+    }
+
+    /// Forget all the positions, keeping the allocations.
+    pub fn reset(&mut self) {
+        self.head.fill(0);
+        self.son.clear();
+        self.base = 0;
+    }
+
+    /// Stop the lookups after `depth` candidates, or at a match of
+    /// `nice_len` bytes.
+    pub fn with_limits(mut self, nice_len: usize, depth: usize) -> Self {
+        self.nice_len = nice_len;
+        self.depth = depth;
+        self
+    }
+
+    /// Record position `pos`, whose bytes start at `window[at]`. Positions
+    /// with fewer than [`HASH_LEN`] bytes available are skipped.
+    pub fn insert(&mut self, window: &[u8], at: usize, pos: u64) {
+        self.update(window, at, pos, |_| ());
+    }
+
+    /// Find the longest match for position `pos`, whose bytes start at
+    /// `window[at]`, then record that position.
+    pub fn find(&mut self, window: &[u8], at: usize, pos: u64) -> Option<Match> {
+        let best = self.update(window, at, pos, |_| ())?;
+        Some(self.extend(window, at, best))
+    }
+
+    /// Append to `matches` the ever longer matches met on the way to the
+    /// longest one, then record position `pos`.
+    pub fn find_all(&mut self, window: &[u8], at: usize, pos: u64, matches: &mut Vec<Match>) {
+        let start = matches.len();
+        self.update(window, at, pos, |m| matches.push(m));
+        if let Some(last) = matches[start..].last_mut() {
+            *last = self.extend(window, at, *last);
+        }
+    }
+
+    /// Extend `best`, found by `update()`, beyond the nice length.
+    fn extend(&self, window: &[u8], at: usize, mut best: Match) -> Match {
+        // The trees only compare up to the nice length.
+        let current = &window[at..std::cmp::min(window.len(), at + MAX_MATCH_LEN)];
+        if best.len < current.len() && best.len == self.nice_len {
+            let start = at - best.dist as usize;
+            best.len += window[start + best.len..]
+                .iter()
+                .zip(&current[best.len..])
+                .take_while(|(a, b)| a == b)
+                .count();
+        }
+        best
+    }
+
+    /// Insert position `pos` in its tree, returning the longest match up to
+    /// the nice length met on the way and passing each match longer than the
+    /// previous ones to `on_match`.
+    fn update<F>(&mut self, window: &[u8], at: usize, pos: u64, mut on_match: F) -> Option<Match>
+    where
+        F: FnMut(Match),
+    {
+        let current = &window[at..];
+        if current.len() < HASH_LEN {
+            return None;
+        }
+        let len_limit = std::cmp::min(current.len(), self.nice_len);
+        self.normalize(pos);
+
+        let h = hash(current, self.hash_bits);
+        let mut cur_match = self.head[h];
+        self.head[h] = (pos - self.base + 1) as u32;
+
+        let cyclic_pos = (pos % self.cyclic_size) as usize;
+        if self.son.len() < 2 * cyclic_pos + 2 {
+            self.son.resize(2 * cyclic_pos + 2, 0);
+        }
+        // Slots of the current node's children still to be filled in: the
+        // smaller and the greater positions of the subtree being split.
+        let mut ptr0 = 2 * cyclic_pos + 1;
+        let mut ptr1 = 2 * cyclic_pos;
+        // Lengths of the prefixes shared with the nodes of each side.
+        let mut len0 = 0;
+        let mut len1 = 0;
+        let mut best: Option<Match> = None;
+
+        for _ in 0..self.depth {
+            if cur_match == 0 {
+                break;
+            }
+            let cand_pos = self.base + cur_match as u64 - 1;
+            let delta = pos - cand_pos;
+            if delta >= self.cyclic_size {
+                break;
+            }
+            let pair = 2 * (cand_pos % self.cyclic_size) as usize;
+            lzma_check!(delta as usize <= at && pair + 1 < self.son.len());
+            let candidate = &window[at - delta as usize..];
+
+            let mut len = std::cmp::min(len0, len1);
+            while len < len_limit && candidate[len] == current[len] {
+                len += 1;
+            }
+            if len > best.map_or(MIN_MATCH_LEN - 1, |m| m.len) {
+                let m = Match {
+                    len,
+                    dist: delta as u32,
+                };
+                on_match(m);
+                best = Some(m);
+            }
+            if len == len_limit {
+                // The candidate is equal as far as the trees compare: the
+                // current position takes its place.
+                self.son[ptr1] = self.son[pair];
+                self.son[ptr0] = self.son[pair + 1];
+                return best;
+            }
+
+            if candidate[len] < current[len] {
+                self.son[ptr1] = cur_match;
+                ptr1 = pair + 1;
+                cur_match = self.son[ptr1];
+                len1 = len;
+            } else {
+                self.son[ptr0] = cur_match;
+                ptr0 = pair;
+                cur_match = self.son[ptr0];
+                len0 = len;
+            }
+        }
+
+        self.son[ptr0] = 0;
+        self.son[ptr1] = 0;
+        best
+    }
+
+    /// Move `base` forward before the offset of `pos` overflows, forgetting
+    /// the positions beyond the dictionary.
+    fn normalize(&mut self, pos: u64) {
+        if pos - self.base < u32::MAX as u64 - 1 {
+            return;
+        }
+        let shift = pos - self.base - self.cyclic_size;
+        for offset in self.head.iter_mut().chain(self.son.iter_mut()) {
+            *offset = (*offset as u64).saturating_sub(shift) as u32;
+        }
+        self.base += shift;
+    }
+}
+
+impl std::fmt::Debug for BinaryTree {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("BinaryTree")
+            .field("cyclic_size", &self.cyclic_size)
+            .field("hash_bits", &self.hash_bits)
+            .field("depth", &self.depth)
+            .field("nice_len", &self.nice_len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_longest() {
+        let data = b"abcdefabcdxyzabcdefg";
+        let mut finder = BinaryTree::new(0x1000, 12);
+        for at in 0..13 {
+            finder.insert(data, at, at as u64);
+        }
+        assert_eq!(finder.find(data, 13, 13), Some(Match { len: 6, dist: 13 }));
+        assert_eq!(finder.find(data, 14, 14), Some(Match { len: 5, dist: 13 }));
+    }
+
+    #[test]
+    fn test_find_all() {
+        let data = b"abcdefabcdxyzabcdeXabcdef";
+        let mut finder = BinaryTree::new(0x1000, 12);
+        for at in 0..19 {
+            finder.insert(data, at, at as u64);
+        }
+        let mut matches = vec![];
+        finder.find_all(data, 19, 19, &mut matches);
+        assert_eq!(
+            matches,
+            [Match { len: 5, dist: 6 }, Match { len: 6, dist: 19 }]
+        );
+    }
+
+    #[test]
+    fn test_beyond_nice_len() {
+        let data = vec![7u8; 1000];
+        let mut finder = BinaryTree::new(0x1000, 12);
+        finder.insert(&data, 0, 0);
+        assert_eq!(
+            finder.find(&data, 1, 1),
+            Some(Match {
+                len: MAX_MATCH_LEN,
+                dist: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let data = b"abcdabcdabcd";
+        let mut finder = BinaryTree::new(0x1000, 12);
+        let start = u32::MAX as u64 - 4;
+        for at in 0..8 {
+            finder.insert(data, at, start + at as u64);
+        }
+        assert!(finder.base > 0);
+        assert_eq!(
+            finder.find(data, 8, start + 8),
+            Some(Match { len: 4, dist: 4 })
+        );
+    }
+}
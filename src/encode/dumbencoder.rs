@@ -1,140 +1,1649 @@
-use crate::compress::{Options, UnpackedSize};
-use crate::encode::rangecoder;
-use byteorder::{LittleEndian, WriteBytesExt};
-use std::io;
+use crate::compress::{
+    EncodeMode, MatchFinder, Options, UnknownSizeSentinel, UnpackedSize, DICT_SIZE_MIN,
+};
+#[cfg(feature = "raw_decoder")]
+use crate::decode::lzma::InitialProbs;
+use crate::decode::lzma::{LzmaParams, LzmaProperties, UnpackedSizeField};
+use crate::encode::matchfinder::{Finder, Match, HASH_BITS_RANGE, MAX_MATCH_LEN};
+use crate::encode::optimum::{self, Node, Optimum, Step};
+use crate::encode::rangecoder::{self, BitTree, LenEncoder};
+use crate::encode::stats::CompressStats;
+#[cfg(feature = "raw_decoder")]
+use crate::error::OptionsError;
+use crate::util::vec2d::Vec2D;
+use crate::{error, CompressedSize, UncompressedSize};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-pub struct Encoder<'a, W>
+/// LZMA compressor writing to an output sink, which can be driven
+/// step by step on non-blocking streams.
+///
+/// Encoded bytes are buffered until the sink accepts them, so that an error
+/// from either the input or the sink never leaves the range coder in an
+/// inconsistent state.
+pub struct Encoder<W>
 where
-    W: 'a + io::Write,
+    W: io::Write,
 {
-    rangecoder: rangecoder::RangeEncoder<'a, W>,
-    literal_probs: [[u16; 0x300]; 8],
-    is_match: [u16; 4], // true = LZ, false = literal
+    /// Output sink
+    output: W,
+    /// Range coder, buffering the encoded bytes not yet written to the sink
+    rangecoder: rangecoder::RangeEncoder<Vec<u8>>,
+    /// Parameters written in the header of each stream
+    params: LzmaParams,
+    properties: LzmaProperties,
+    literal_probs: Vec2D<u16>,
+    is_match: [u16; 192], // true = LZ, false = literal
+    is_rep: [u16; 12],
+    is_rep_g0: [u16; 12],
+    is_rep_g1: [u16; 12],
+    is_rep_g2: [u16; 12],
+    is_rep_0long: [u16; 192],
+    pos_slot_encoder: [BitTree<{ 1 << 6 }>; 4],
+    align_encoder: BitTree<{ 1 << 4 }>,
+    pos_encoders: [u16; 115],
+    len_encoder: LenEncoder,
+    rep_len_encoder: LenEncoder,
+    state: usize,
+    /// Last four match distances, minus one, most recent first
+    rep: [u32; 4],
+    match_finder: Finder,
+    /// Length of the matches taken without looking further by the optimal
+    /// parser
+    nice_len: usize,
+    /// Longest match to emit
+    max_match_len: usize,
+    /// Working memory of the optimal parser, in [`EncodeMode::Normal`] only
+    optimum: Option<Box<Optimum>>,
+    /// Whether to look one byte ahead before taking a match, in
+    /// [`EncodeMode::Lazy`]
+    lazy: bool,
+    /// Whether to encode literals only, in [`EncodeMode::Store`]
+    store: bool,
+    /// Match found at the next byte to encode by the lookahead of the lazy
+    /// parser, which already recorded that position in the match finder
+    next_match: Option<Option<Match>>,
+    /// Uncompressed data from `window_start` on: the dictionary, followed
+    /// by the bytes read but not yet encoded
+    window: Vec<u8>,
+    /// Position of the first byte of `window` in the uncompressed data
+    window_start: u64,
+    dict_size: u32,
     unpacked_size: UnpackedSize,
+    /// Whether to write the end-of-stream marker even if the size is known
+    end_marker: bool,
+    /// Number of bytes encoded
+    input_len: u64,
+    /// Whether the end of the stream has been encoded
+    finished: bool,
+    /// Statistics of the compression so far
+    stats: CompressStats,
+    /// Callback reporting the progress of the compression
+    progress: Option<Progress>,
+    /// Flag cancelling the compression once set
+    cancel: Option<Arc<AtomicBool>>,
+    /// Initial probabilities replacing those of the specification, restored
+    /// by [`Encoder::reset()`]
+    #[cfg(feature = "raw_decoder")]
+    initial_probs: Option<Box<InitialProbs>>,
+}
+
+/// Progress callback of an encoder, see [`Encoder::set_progress()`].
+struct Progress {
+    /// Number of input bytes between calls
+    interval: u64,
+    /// Numbers of input and output bytes at the last call
+    reported: Option<(u64, u64)>,
+    callback: Box<dyn FnMut(u64, u64) + Send + Sync>,
+}
+
+/// Upper bound on the number of bytes of range-coded data per input byte.
+///
+/// The adaptive probabilities never drop below 31/2048, so a bit costs at
+/// most log2(2048 / 31) < 6.05 bits, and a literal (9 bits) at most 54.5 bits.
+/// Matches cover at least 3 bytes for at most 15 such bits and 26 direct
+/// ones, so they cost less per byte.
+const MAX_BYTES_PER_LITERAL: u64 = 7;
+
+/// Upper bound on the number of bytes taken by the end-of-stream marker (117
+/// bits at most), the range coder flush (5 bytes) and its initial cache byte.
+const MAX_TRAILER_SIZE: u64 = 24;
+
+/// Maximum number of bytes read from the input at once, bounding the
+/// window beyond the dictionary.
+const MAX_READ_SIZE: usize = 1 << 20;
+
+/// Magic bytes and version starting the states saved by
+/// [`Encoder::save_state()`].
+const STATE_MAGIC: &[u8; 5] = b"LZES\x01";
+
+/// Number of bytes allocated at most by the compressor of `options`, not
+/// counting the encoded bytes waiting for the output sink.
+pub(crate) fn mem_usage(options: &Options) -> u64 {
+    let dict_size = options.dict_size as u64;
+    let window =
+        dict_size + std::cmp::max(dict_size / 8, MAX_READ_SIZE as u64) + MAX_READ_SIZE as u64;
+    let literal_probs = (0x300 << (options.lc + options.lp)) * std::mem::size_of::<u16>() as u64;
+    let optimum = match options.mode {
+        EncodeMode::Normal => Optimum::mem_usage(options.pb),
+        EncodeMode::Store | EncodeMode::Fast | EncodeMode::Lazy => 0,
+    };
+    let match_finder = match options.mode {
+        EncodeMode::Store => 0,
+        EncodeMode::Fast | EncodeMode::Lazy | EncodeMode::Normal => {
+            crate::encode::matchfinder::mem_usage(options)
+        }
+    };
+    std::mem::size_of::<Encoder<io::Sink>>() as u64
+        + window
+        + literal_probs
+        + optimum
+        + match_finder
 }
 
-const LC: u32 = 3;
-const LP: u32 = 0;
-const PB: u32 = 2;
+/// Options of the match finder, which is unused and as small as possible in
+/// [`EncodeMode::Store`].
+fn finder_options(options: &Options) -> Options {
+    let options = Options {
+        nice_len: nice_len(options) as u32,
+        ..*options
+    };
+    match options.mode {
+        EncodeMode::Store => Options {
+            match_finder: MatchFinder::Hc4,
+            hash_bits: *HASH_BITS_RANGE.start(),
+            ..options
+        },
+        EncodeMode::Fast | EncodeMode::Lazy | EncodeMode::Normal => options,
+    }
+}
+
+/// Nice length of `options`, capped to the maximum match length.
+fn nice_len(options: &Options) -> usize {
+    std::cmp::min(options.nice_len, options.max_match_len) as usize
+}
 
-impl<'a, W> Encoder<'a, W>
+/// Matches of the minimum length beyond this distance cost more than the
+/// literals they replace.
+const MAX_SHORT_MATCH_DIST: u32 = 0x1000;
+
+/// Upper bound on the size of the LZMA stream written for `len` bytes of
+/// input with the given `options`, header included.
+pub fn compress_bound(len: u64, options: &Options) -> u64 {
+    let header_size = 5 + unpacked_size_field(options).encoded_len() as u64;
+    len.saturating_mul(MAX_BYTES_PER_LITERAL)
+        .saturating_add(header_size + MAX_TRAILER_SIZE)
+}
+
+/// Range-code `data` with fresh probabilities, without header nor end
+/// marker: the payload of an LZMA2 chunk that resets the dictionary and the
+/// state. Statistics other than the duration and the sizes are added to
+/// `stats`. Only the match finder settings, the mode and the literal coder
+/// properties of `options` apply.
+pub(crate) fn encode_chunk(
+    data: &[u8],
+    options: &Options,
+    stats: &mut CompressStats,
+) -> io::Result<Vec<u8>> {
+    let options = Options {
+        unpacked_size: UnpackedSize::SkipWritingToHeader,
+        // Matches can't reach beyond the chunk.
+        dict_size: std::cmp::max(data.len() as u32, DICT_SIZE_MIN),
+        match_finder: options.match_finder,
+        nice_len: options.nice_len,
+        max_match_len: options.max_match_len,
+        depth: options.depth,
+        mode: options.mode,
+        lc: options.lc,
+        lp: options.lp,
+        pb: options.pb,
+        ..Default::default()
+    };
+    let mut encoder = Encoder::chunked(&options)?;
+    let packed = encoder.encode_next_chunk(data)?;
+    stats.literals += encoder.stats.literals;
+    stats.matches += encoder.stats.matches;
+    stats.rep_matches += encoder.stats.rep_matches;
+    Ok(packed)
+}
+
+impl Encoder<io::Sink> {
+    /// Initialize an encoder range-coding the payloads of consecutive LZMA2
+    /// chunks with [`Encoder::encode_next_chunk()`], without header nor end
+    /// marker.
+    pub(crate) fn chunked(options: &Options) -> io::Result<Self> {
+        let options = Options {
+            unpacked_size: UnpackedSize::SkipWritingToHeader,
+            ..*options
+        };
+        let mut encoder = Self::from_stream(io::sink(), &options)?;
+        encoder.rangecoder.get_mut().clear();
+        Ok(encoder)
+    }
+
+    /// Range-code `data` as the payload of the next LZMA2 chunk, which
+    /// keeps the dictionary, the probabilities and the state of the
+    /// previous ones unless [`Encoder::reset_probs()`] was called.
+    pub(crate) fn encode_next_chunk(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.window.extend_from_slice(data);
+        self.stats.in_bytes.0 += data.len() as u64;
+        self.encode_window(true)?;
+        self.rangecoder.finish()?;
+        let packed = std::mem::take(self.rangecoder.get_mut());
+        self.rangecoder = rangecoder::RangeEncoder::new(Vec::new());
+        Ok(packed)
+    }
+}
+
+/// Unpacked size field to write in the header.
+fn unpacked_size_field(options: &Options) -> UnpackedSizeField {
+    match options.unpacked_size {
+        UnpackedSize::WriteToHeader(Some(size)) => UnpackedSizeField::Known(size),
+        UnpackedSize::WriteToHeader(None) => match options.unknown_size_sentinel {
+            UnknownSizeSentinel::Max => UnpackedSizeField::Unknown,
+            UnknownSizeSentinel::Zero => UnpackedSizeField::Known(0),
+        },
+        UnpackedSize::SkipWritingToHeader => UnpackedSizeField::Absent,
+    }
+}
+
+impl<W> Encoder<W>
 where
     W: io::Write,
 {
-    pub fn from_stream(stream: &'a mut W, options: &Options) -> io::Result<Self> {
-        let dict_size = 0x0080_0000;
-
-        // Properties
-        let props = (LC + 9 * (LP + 5 * PB)) as u8;
-        lzma_info!("Properties {{ lc: {}, lp: {}, pb: {} }}", LC, LP, PB);
-        stream.write_u8(props)?;
-
-        // Dictionary
-        lzma_info!("Dict size: {}", dict_size);
-        stream.write_u32::<LittleEndian>(dict_size)?;
-
-        // Unpacked size
-        match &options.unpacked_size {
-            UnpackedSize::WriteToHeader(unpacked_size) => {
-                let value: u64 = match unpacked_size {
-                    None => {
-                        lzma_info!("Unpacked size: unknown");
-                        0xFFFF_FFFF_FFFF_FFFF
-                    }
-                    Some(x) => {
-                        lzma_info!("Unpacked size: {}", x);
-                        *x
-                    }
-                };
-                stream.write_u64::<LittleEndian>(value)?;
-            }
-            UnpackedSize::SkipWritingToHeader => {}
+    /// Initialize the compressor with the given `options`. Nothing is written
+    /// to the `output` sink until [`Encoder::process()`] is called.
+    pub fn new(output: W, options: &Options) -> error::Result<Self> {
+        options.validate()?;
+        Ok(Self::from_stream(output, options)?)
+    }
+
+    /// Initialize the compressor to write the header of `params` as is: its
+    /// properties, dictionary size and unpacked size field, e.g. to match an
+    /// external specification. The other settings come from `options`, whose
+    /// `lc`, `lp`, `pb`, `dict_size`, `fit_dict_size`, `unpacked_size` and
+    /// `unknown_size_sentinel` are ignored.
+    ///
+    /// The stream ends with an end-of-stream marker if the unpacked size of
+    /// `params` is unknown, even if the header has no size field. As with
+    /// [`UnpackedSize::WriteToHeader`], a known size isn't checked against
+    /// the input.
+    #[cfg(feature = "raw_decoder")]
+    pub fn with_params(output: W, params: LzmaParams, options: &Options) -> error::Result<Self> {
+        let options = Options {
+            lc: params.properties.lc,
+            lp: params.properties.lp,
+            pb: params.properties.pb,
+            dict_size: params.dict_size,
+            fit_dict_size: false,
+            unpacked_size: match params.unpacked_size_field {
+                UnpackedSizeField::Known(size) => UnpackedSize::WriteToHeader(Some(size)),
+                UnpackedSizeField::Unknown => UnpackedSize::WriteToHeader(None),
+                UnpackedSizeField::Absent => UnpackedSize::SkipWritingToHeader,
+            },
+            unknown_size_sentinel: UnknownSizeSentinel::Max,
+            end_marker: options.end_marker || params.unpacked_size.is_none(),
+            ..*options
         };
+        Self::new(output, &options)
+    }
 
+    pub(crate) fn from_stream(stream: W, options: &Options) -> io::Result<Self> {
+        let properties = LzmaProperties {
+            lc: options.lc,
+            lp: options.lp,
+            pb: options.pb,
+        };
+        Self::with_properties(stream, options, properties)
+    }
+
+    /// Initialize the encoder with arbitrary "lclppb" properties, including
+    /// the extreme ones (e.g. `lc = 8` or `pb = 4`) that other encoders
+    /// rarely emit.
+    pub(crate) fn with_properties(
+        stream: W,
+        options: &Options,
+        properties: LzmaProperties,
+    ) -> io::Result<Self> {
+        properties.validate();
+        let options = &match options.unpacked_size {
+            UnpackedSize::WriteToHeader(Some(len)) => options.fit_to(len),
+            _ => *options,
+        };
+        let unpacked_size_field = unpacked_size_field(options);
+        let params = LzmaParams {
+            properties,
+            dict_size: options.dict_size,
+            unpacked_size: match options.unpacked_size {
+                UnpackedSize::WriteToHeader(size) => size,
+                UnpackedSize::SkipWritingToHeader => None,
+            },
+            unpacked_size_field,
+        };
+        lzma_info!("Encoder parameters: {:?}", params);
+        let mut pending = Vec::new();
+        params.write_header(&mut pending)?;
+
+        let LzmaProperties { lc, lp, .. } = properties;
         let encoder = Encoder {
-            rangecoder: rangecoder::RangeEncoder::new(stream),
-            literal_probs: [[0x400; 0x300]; 8],
-            is_match: [0x400; 4],
+            output: stream,
+            rangecoder: rangecoder::RangeEncoder::new(pending),
+            params,
+            properties,
+            literal_probs: Vec2D::init(0x400, (1 << (lc + lp), 0x300)),
+            is_match: [0x400; 192],
+            is_rep: [0x400; 12],
+            is_rep_g0: [0x400; 12],
+            is_rep_g1: [0x400; 12],
+            is_rep_g2: [0x400; 12],
+            is_rep_0long: [0x400; 192],
+            pos_slot_encoder: [
+                BitTree::new(),
+                BitTree::new(),
+                BitTree::new(),
+                BitTree::new(),
+            ],
+            align_encoder: BitTree::new(),
+            pos_encoders: [0x400; 115],
+            len_encoder: LenEncoder::new(),
+            rep_len_encoder: LenEncoder::new(),
+            state: 0,
+            rep: [0; 4],
+            match_finder: Finder::new(&finder_options(options)),
+            nice_len: nice_len(options),
+            max_match_len: options.max_match_len as usize,
+            optimum: match options.mode {
+                EncodeMode::Store | EncodeMode::Fast | EncodeMode::Lazy => None,
+                EncodeMode::Normal => Some(Box::new(Optimum::new(properties.pb))),
+            },
+            lazy: options.mode == EncodeMode::Lazy,
+            store: options.mode == EncodeMode::Store,
+            next_match: None,
+            window: Vec::new(),
+            window_start: 0,
+            dict_size: options.dict_size,
             unpacked_size: options.unpacked_size,
+            end_marker: options.end_marker,
+            input_len: 0,
+            finished: false,
+            stats: CompressStats::default(),
+            progress: None,
+            cancel: None,
+            #[cfg(feature = "raw_decoder")]
+            initial_probs: None,
         };
 
         Ok(encoder)
     }
 
-    pub fn process<R>(mut self, input: R) -> io::Result<()>
+    /// Fill the dictionary with the last bytes of `preset_dict`, up to the
+    /// dictionary size, so that the first bytes of the input can be encoded
+    /// as matches into it. The stream then only decompresses with the same
+    /// preset dictionary.
+    ///
+    /// Panics if called after [`Encoder::process()`].
+    pub fn set_preset_dict(&mut self, preset_dict: &[u8]) {
+        assert!(
+            self.input_len == 0 && self.window.is_empty(),
+            "preset dictionary set after the start of the compression"
+        );
+        let kept = &preset_dict[preset_dict.len().saturating_sub(self.dict_size as usize)..];
+        self.window.extend_from_slice(kept);
+        for at in 0..kept.len() {
+            self.match_finder.insert(&self.window, at, at as u64);
+        }
+        self.input_len = kept.len() as u64;
+    }
+
+    /// Start from the given initial probabilities instead of those of the
+    /// specification. The stream then only decompresses with the same ones,
+    /// see [`LzmaDecoder::set_initial_probs()`](crate::decompress::raw::LzmaDecoder::set_initial_probs).
+    /// Like those of the decoder, they also apply after [`Encoder::reset()`].
+    ///
+    /// Fails with [`Error::InvalidOptions`](error::Error::InvalidOptions) if
+    /// some input has already been processed.
+    #[cfg(feature = "raw_decoder")]
+    pub fn set_initial_probs(&mut self, probs: &InitialProbs) -> error::Result<()> {
+        if self.stats.in_bytes.get() != 0 {
+            return Err(OptionsError::new(
+                "initial_probs",
+                "set after the start of the compression".to_string(),
+            )
+            .into());
+        }
+        probs.validate()?;
+        probs.apply(&mut self.literal_probs, &mut self.is_match);
+        self.initial_probs = Some(Box::new(probs.clone()));
+        Ok(())
+    }
+
+    /// Start a new stream with the same options, written to the same sink
+    /// after the current one, keeping the allocations of the probability
+    /// tables, of the match finder and of the buffers. This is cheaper than
+    /// creating a new compressor for each of many small inputs; the
+    /// compressed bytes of each one can be taken from the sink in between,
+    /// e.g. with `std::mem::take(encoder.get_mut())` on a [`Vec`].
+    ///
+    /// The current stream is dropped if not finished, including the bytes
+    /// not yet written to the sink. The preset dictionary, if set, isn't
+    /// kept, while the initial probabilities are, as with
+    /// [`LzmaDecoder::reset()`](crate::decompress::raw::LzmaDecoder::reset).
+    pub fn reset(&mut self) {
+        let mut pending = std::mem::take(self.rangecoder.get_mut());
+        pending.clear();
+        self.params
+            .write_header(&mut pending)
+            .expect("writing to a Vec never fails");
+        self.rangecoder = rangecoder::RangeEncoder::new(pending);
+        self.reset_probs();
+        #[cfg(feature = "raw_decoder")]
+        if let Some(probs) = &self.initial_probs {
+            probs.apply(&mut self.literal_probs, &mut self.is_match);
+        }
+
+        self.match_finder.reset();
+        self.next_match = None;
+        self.window.clear();
+        self.window_start = 0;
+        self.input_len = 0;
+        self.finished = false;
+        self.stats = CompressStats::default();
+        if let Some(progress) = &mut self.progress {
+            progress.reported = None;
+        }
+    }
+
+    /// Reset the probabilities, the state and the repeated distances to
+    /// those of the start of a stream, keeping the dictionary, as an LZMA2
+    /// chunk resetting the state does.
+    pub(crate) fn reset_probs(&mut self) {
+        self.literal_probs.fill(0x400);
+        self.is_match = [0x400; 192];
+        self.is_rep = [0x400; 12];
+        self.is_rep_g0 = [0x400; 12];
+        self.is_rep_g1 = [0x400; 12];
+        self.is_rep_g2 = [0x400; 12];
+        self.is_rep_0long = [0x400; 192];
+        self.pos_slot_encoder = [
+            BitTree::new(),
+            BitTree::new(),
+            BitTree::new(),
+            BitTree::new(),
+        ];
+        self.align_encoder = BitTree::new();
+        self.pos_encoders = [0x400; 115];
+        self.len_encoder = LenEncoder::new();
+        self.rep_len_encoder = LenEncoder::new();
+        self.state = 0;
+        self.rep = [0; 4];
+        if let Some(opt) = &mut self.optimum {
+            opt.prices.invalidate();
+            opt.path.clear();
+        }
+    }
+
+    /// Call `callback` with the number of bytes read from the input and
+    /// written to the sink so far, as in [`Encoder::stats()`], as
+    /// [`Encoder::process()`] goes through every `interval` bytes of input,
+    /// and once more at the end of the stream.
+    ///
+    /// The input is encoded in chunks of up to 1 MiB, so the calls are at
+    /// most as frequent as the chunks, whatever the interval. The callback
+    /// is kept by [`Encoder::reset()`]. It lives on the compressor rather
+    /// than in the [`Options`], which are plain `Copy` values.
+    pub fn set_progress<F>(&mut self, interval: u64, callback: F)
     where
-        R: io::Read,
+        F: FnMut(u64, u64) + Send + Sync + 'static,
     {
-        let mut prev_byte = 0u8;
-        let mut input_len = 0;
+        self.progress = Some(Progress {
+            interval,
+            reported: None,
+            callback: Box::new(callback),
+        });
+    }
 
-        for (out_len, byte_result) in input.bytes().enumerate() {
-            let byte = byte_result?;
-            let pos_state = out_len & 3;
-            input_len = out_len;
+    /// Check `flag` before each chunk of input that [`Encoder::process()`]
+    /// encodes, failing with [`Error::Cancelled`](error::Error::Cancelled),
+    /// wrapped in an [`io::Error`], once it is set, e.g. from another thread.
+    ///
+    /// The input is encoded in chunks of up to 1 MiB, so a compression stops
+    /// within the time taken by one chunk. The stream is left unfinished;
+    /// the compressor can be used again after [`Encoder::reset()`] and
+    /// clearing the flag.
+    pub fn set_cancel(&mut self, flag: Arc<AtomicBool>) {
+        self.cancel = Some(flag);
+    }
 
-            // Literal
-            self.rangecoder
-                .encode_bit(&mut self.is_match[pos_state], false)?;
+    /// Serialize the state of the compression, to resume it later with
+    /// [`Encoder::restore_state()`], e.g. in another process after a
+    /// restart. It holds the probabilities, the last distances, the
+    /// registers of the range coder and the encoded bytes not yet written
+    /// to the sink, and the window of uncompressed data, i.e. about the
+    /// dictionary size. The sink itself is not part of it: the caller saves
+    /// the output written so far alongside.
+    ///
+    /// The format is specific to this version of the crate.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(self.window.len() + 0x8000);
+        self.write_state(&mut state)
+            .expect("writing to a Vec never fails");
+        state
+    }
 
-            self.encode_literal(byte, prev_byte)?;
-            prev_byte = byte;
+    /// Resume a compression saved by [`Encoder::save_state()`], writing to
+    /// `output` the bytes that followed those written at the time, then the
+    /// rest of the stream as [`Encoder::process()`] goes on.
+    ///
+    /// The `options` must be those of the saved compressor. The match
+    /// finder is rebuilt from the dictionary, so the rest of the stream may
+    /// differ slightly from that of an uninterrupted run, while decoding to
+    /// the same data. The statistics restart from the saved counts, with no
+    /// duration.
+    pub fn restore_state(output: W, options: &Options, state: &[u8]) -> error::Result<Self> {
+        let mut encoder = Self::new(output, options)?;
+        let mut input = state;
+        encoder
+            .read_state(&mut input)
+            .and_then(|()| match input.len() {
+                0 => Ok(()),
+                n => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} trailing bytes", n),
+                )),
+            })
+            .map_err(|e| error::Error::LzmaError(format!("Invalid encoder state: {}", e)))?;
+        Ok(encoder)
+    }
+
+    /// Probabilities of the encoder, in a fixed order.
+    fn probs(&self) -> Vec<&[u16]> {
+        let mut probs: Vec<&[u16]> = vec![
+            self.literal_probs.as_slice(),
+            &self.is_match,
+            &self.is_rep,
+            &self.is_rep_g0,
+            &self.is_rep_g1,
+            &self.is_rep_g2,
+            &self.is_rep_0long,
+        ];
+        probs.extend(self.pos_slot_encoder.iter().map(BitTree::probs));
+        probs.push(self.align_encoder.probs());
+        probs.push(&self.pos_encoders);
+        probs.extend(self.len_encoder.probs());
+        probs.extend(self.rep_len_encoder.probs());
+        probs
+    }
+
+    /// Probabilities of the encoder, in the order of [`Encoder::probs()`].
+    fn probs_mut(&mut self) -> Vec<&mut [u16]> {
+        let mut probs: Vec<&mut [u16]> = vec![
+            self.literal_probs.as_mut_slice(),
+            &mut self.is_match,
+            &mut self.is_rep,
+            &mut self.is_rep_g0,
+            &mut self.is_rep_g1,
+            &mut self.is_rep_g2,
+            &mut self.is_rep_0long,
+        ];
+        probs.extend(self.pos_slot_encoder.iter_mut().map(BitTree::probs_mut));
+        probs.push(self.align_encoder.probs_mut());
+        probs.push(&mut self.pos_encoders);
+        probs.extend(self.len_encoder.probs_mut());
+        probs.extend(self.rep_len_encoder.probs_mut());
+        probs
+    }
+
+    fn write_state(&self, output: &mut Vec<u8>) -> io::Result<()> {
+        output.write_all(STATE_MAGIC)?;
+        let LzmaProperties { lc, lp, pb } = self.properties;
+        output.write_u8(((pb * 5 + lp) * 9 + lc) as u8)?;
+        output.write_u32::<LittleEndian>(self.dict_size)?;
+
+        output.write_u8(self.state as u8)?;
+        for &rep in self.rep.iter() {
+            output.write_u32::<LittleEndian>(rep)?;
+        }
+        let (low, range, cache, cachesz) = self.rangecoder.registers();
+        output.write_u64::<LittleEndian>(low)?;
+        output.write_u32::<LittleEndian>(range)?;
+        output.write_u8(cache)?;
+        output.write_u64::<LittleEndian>(cachesz)?;
+        let pending = self.rangecoder.get_ref();
+        output.write_u64::<LittleEndian>(pending.len() as u64)?;
+        output.write_all(pending)?;
+        for probs in self.probs() {
+            for &prob in probs {
+                output.write_u16::<LittleEndian>(prob)?;
+            }
         }
 
-        self.finish(input_len + 1)
+        output.write_u64::<LittleEndian>(self.window_start)?;
+        output.write_u64::<LittleEndian>(self.input_len)?;
+        output.write_u64::<LittleEndian>(self.window.len() as u64)?;
+        output.write_all(&self.window)?;
+        match self.next_match {
+            None => output.write_u8(0)?,
+            Some(None) => output.write_u8(1)?,
+            Some(Some(m)) => {
+                output.write_u8(2)?;
+                output.write_u32::<LittleEndian>(m.len as u32)?;
+                output.write_u32::<LittleEndian>(m.dist)?;
+            }
+        }
+        output.write_u8(self.finished as u8)?;
+
+        let stats = &self.stats;
+        for &count in [
+            stats.in_bytes.0,
+            stats.out_bytes.0,
+            stats.literals,
+            stats.matches,
+            stats.rep_matches,
+        ]
+        .iter()
+        {
+            output.write_u64::<LittleEndian>(count)?;
+        }
+        Ok(())
     }
 
-    fn finish(&mut self, input_len: usize) -> io::Result<()> {
-        match self.unpacked_size {
-            UnpackedSize::SkipWritingToHeader | UnpackedSize::WriteToHeader(Some(_)) => {}
-            UnpackedSize::WriteToHeader(None) => {
-                // Write end-of-stream marker
-                let pos_state = input_len & 3;
+    fn read_state(&mut self, input: &mut &[u8]) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut magic = [0; 5];
+        input.read_exact(&mut magic)?;
+        if &magic != STATE_MAGIC {
+            return Err(invalid(format!("unknown magic {:02x?}", magic)));
+        }
+        let props = input.read_u8()?;
+        let dict_size = input.read_u32::<LittleEndian>()?;
+        let LzmaProperties { lc, lp, pb } = self.properties;
+        if props as u32 != (pb * 5 + lp) * 9 + lc || dict_size != self.dict_size {
+            return Err(invalid(format!(
+                "saved with properties {:#04x} and dictionary size {}, other than the options",
+                props, dict_size
+            )));
+        }
 
-                // Match
-                self.rangecoder
-                    .encode_bit(&mut self.is_match[pos_state], true)?;
-                // New distance
-                self.rangecoder.encode_bit(&mut 0x400, false)?;
+        self.state = input.read_u8()? as usize;
+        if self.state >= 12 {
+            return Err(invalid(format!("state {} out of range", self.state)));
+        }
+        for rep in self.rep.iter_mut() {
+            *rep = input.read_u32::<LittleEndian>()?;
+        }
+        let low = input.read_u64::<LittleEndian>()?;
+        let range = input.read_u32::<LittleEndian>()?;
+        let cache = input.read_u8()?;
+        let cachesz = input.read_u64::<LittleEndian>()?;
+        if range < 1 << 24 || low >> 33 != 0 {
+            return Err(invalid(format!(
+                "range coder registers {:#x} {:#x} out of range",
+                low, range
+            )));
+        }
+        self.rangecoder.set_registers((low, range, cache, cachesz));
+        let pending = read_bytes(input)?;
+        *self.rangecoder.get_mut() = pending;
+        for probs in self.probs_mut() {
+            for prob in probs.iter_mut() {
+                *prob = input.read_u16::<LittleEndian>()?;
+                if *prob == 0 || *prob >= 0x800 {
+                    return Err(invalid(format!("probability {} out of range", *prob)));
+                }
+            }
+        }
+
+        self.window_start = input.read_u64::<LittleEndian>()?;
+        self.input_len = input.read_u64::<LittleEndian>()?;
+        self.window = read_bytes(input)?;
+        let encoded = self.input_len.checked_sub(self.window_start);
+        if encoded.map_or(true, |encoded| encoded > self.window.len() as u64) {
+            return Err(invalid(format!(
+                "position {} outside of the window",
+                self.input_len
+            )));
+        }
+        self.next_match = match input.read_u8()? {
+            0 => None,
+            1 => Some(None),
+            2 => {
+                let len = input.read_u32::<LittleEndian>()? as usize;
+                let dist = input.read_u32::<LittleEndian>()?;
+                if !(2..=MAX_MATCH_LEN).contains(&len) || dist == 0 {
+                    return Err(invalid(format!("match of {} bytes at {}", len, dist)));
+                }
+                Some(Some(Match { len, dist }))
+            }
+            tag => return Err(invalid(format!("unknown lookahead tag {}", tag))),
+        };
+        self.finished = input.read_u8()? != 0;
+
+        self.stats.in_bytes = UncompressedSize(input.read_u64::<LittleEndian>()?);
+        self.stats.out_bytes = CompressedSize(input.read_u64::<LittleEndian>()?);
+        self.stats.literals = input.read_u64::<LittleEndian>()?;
+        self.stats.matches = input.read_u64::<LittleEndian>()?;
+        self.stats.rep_matches = input.read_u64::<LittleEndian>()?;
+
+        // The positions within reach of the next matches, and the one the
+        // lookahead of the lazy parser already recorded.
+        let end = self.input_len + self.next_match.is_some() as u64;
+        let start = std::cmp::max(
+            self.window_start,
+            self.input_len.saturating_sub(self.dict_size as u64),
+        );
+        for pos in start..end {
+            let at = (pos - self.window_start) as usize;
+            if at < self.window.len() {
+                self.match_finder.insert(&self.window, at, pos);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    /// Get a mutable reference to the output sink.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Consumes the compressor and returns the output sink. Bytes not yet
+    /// written to the sink by [`Encoder::process()`] are lost.
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    /// Statistics of the compression so far. Bytes encoded but not yet
+    /// written to the sink are not counted in `out_bytes`.
+    pub fn stats(&self) -> CompressStats {
+        self.stats
+    }
+
+    /// Compress all the data from `input`, then write the end of the stream.
+    ///
+    /// The input is consumed in the chunks returned by `fill_buf()`, copied
+    /// at once into the window of the encoder, never byte by byte. Data in
+    /// memory is best passed as a slice, `&mut &data[..]`, which hands it
+    /// out without any intermediate buffer, and advances past the consumed
+    /// bytes.
+    ///
+    /// Errors of the input or of the output sink, such as
+    /// [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::Interrupted`], are
+    /// returned as is, with all the data consumed from `input` so far
+    /// accounted for: calling `process()` again with the same `input` resumes
+    /// the compression, producing the same bytes as an uninterrupted run.
+    pub fn process<R>(&mut self, input: &mut R) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        let start = Instant::now();
+        let result = self.process_input(input);
+        self.stats.duration += start.elapsed();
+        result
+    }
+
+    /// Compress all of `data`, then write the end of the stream, as
+    /// [`Encoder::process()`] does with `&mut &data[..]`.
+    ///
+    /// After an error, the compression resumes by passing the bytes not yet
+    /// consumed, `&data[encoder.stats().in_bytes.get() as usize..]` if the
+    /// stream was started from this slice.
+    pub fn process_slice(&mut self, data: &[u8]) -> io::Result<()> {
+        self.process(&mut &data[..])
+    }
+
+    fn process_input<R>(&mut self, input: &mut R) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        loop {
+            self.write_pending()?;
+            self.report_progress();
+            if self.finished {
+                return Ok(());
+            }
+            if let Some(cancel) = &self.cancel {
+                if cancel.load(Ordering::Relaxed) {
+                    lzma_info!("Compression cancelled after {} bytes", self.input_len);
+                    return Err(error::Error::Cancelled.into());
+                }
+            }
+
+            let buf = input.fill_buf()?;
+            if buf.is_empty() {
+                self.encode_window(true)?;
+                self.finish()?;
+                self.finished = true;
+                continue;
+            }
+            let len = std::cmp::min(buf.len(), MAX_READ_SIZE);
+            self.window.extend_from_slice(&buf[..len]);
+            input.consume(len);
+            self.stats.in_bytes.0 += len as u64;
+            self.encode_window(false)?;
+        }
+    }
+
+    /// Call the progress callback if the input went through its interval
+    /// since the last call, or if the stream is finished.
+    fn report_progress(&mut self) {
+        let progress = match &mut self.progress {
+            Some(progress) => progress,
+            None => return,
+        };
+        let in_bytes = self.stats.in_bytes.get();
+        let out_bytes = self.stats.out_bytes.get();
+        let last_in = progress.reported.map_or(0, |(last_in, _)| last_in);
+        let due = if self.finished {
+            progress.reported != Some((in_bytes, out_bytes))
+        } else {
+            in_bytes >= last_in.saturating_add(progress.interval)
+        };
+        if due {
+            progress.reported = Some((in_bytes, out_bytes));
+            (progress.callback)(in_bytes, out_bytes);
+        }
+    }
+
+    /// Write the buffered bytes to the output sink, keeping those that
+    /// couldn't be written.
+    fn write_pending(&mut self) -> io::Result<()> {
+        let pending = self.rangecoder.get_mut();
+        // The written bytes are dropped once at the end, as dropping them
+        // after each short write would move the rest every time.
+        let mut written = 0;
+        let result = loop {
+            if written == pending.len() {
+                break Ok(());
+            }
+            match self.output.write(&pending[written..]) {
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write encoded data",
+                    ))
+                }
+                Ok(n) => {
+                    written += n;
+                    self.stats.out_bytes.0 += n as u64;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        pending.drain(..written);
+        result
+    }
+
+    /// Encode the bytes of the window not yet encoded, as far as the parser
+    /// of the mode allows unless `at_end`, then drop the bytes that are no
+    /// longer needed.
+    fn encode_window(&mut self, at_end: bool) -> io::Result<()> {
+        match self.optimum.take() {
+            None if self.store => self.encode_literals()?,
+            None => self.encode_greedy(at_end)?,
+            Some(mut opt) => {
+                let result = self.encode_optimal(&mut opt, at_end);
+                self.optimum = Some(opt);
+                result?
+            }
+        }
+
+        // Drop the bytes beyond the dictionary, in batches to amortize the
+        // move of the remaining ones.
+        let history = self.input_len - self.window_start;
+        let slack = std::cmp::max(self.dict_size as u64 / 8, MAX_READ_SIZE as u64);
+        if history > self.dict_size as u64 + slack {
+            let drop = history - self.dict_size as u64;
+            self.window.drain(..drop as usize);
+            self.window_start += drop;
+        }
+        Ok(())
+    }
 
-                // Dummy len, as small as possible (len = 0)
-                for _ in 0..4 {
-                    self.rangecoder.encode_bit(&mut 0x400, false)?;
+    /// Encode the bytes of the window taking the longest match at each
+    /// position, or in lazy mode unless a longer one starts at the next
+    /// position. Unless `at_end`, the last [`MAX_MATCH_LEN`] bytes are kept
+    /// back, one more in lazy mode, so that the matches starting before
+    /// them can be found in full once more data is read.
+    fn encode_greedy(&mut self, at_end: bool) -> io::Result<()> {
+        let keep = if at_end {
+            0
+        } else {
+            MAX_MATCH_LEN + self.lazy as usize
+        };
+        loop {
+            let at = (self.input_len - self.window_start) as usize;
+            lzma_check!(at <= self.window.len());
+            if self.window.len() - at <= keep {
+                break;
+            }
+            let found = match self.next_match.take() {
+                Some(found) => found,
+                None => self.find_match(at, self.input_len),
+            };
+            let (rep_index, rep_len) = self.longest_rep(at);
+
+            // Repeated distances are cheaper to encode, so they win against
+            // slightly longer matches, the more so as the match is far.
+            let use_rep = rep_len >= 2
+                && match found {
+                    None => true,
+                    Some(m) => {
+                        rep_len + 1 >= m.len
+                            || (rep_len + 2 >= m.len && m.dist > 0x200)
+                            || (rep_len + 3 >= m.len && m.dist > 0x8000)
+                    }
+                };
+            let main_len = match found {
+                _ if use_rep => rep_len,
+                Some(m) => m.len,
+                None => 0,
+            };
+            if self.lazy && main_len >= 2 && main_len < self.nice_len && at + 1 < self.window.len()
+            {
+                let next = self.find_match(at + 1, self.input_len + 1);
+                let longer = next.map_or(false, |n| {
+                    n.len > main_len + 1
+                        || (n.len > main_len
+                            && !use_rep
+                            && found.map_or(false, |m| n.dist < m.dist))
+                });
+                self.next_match = Some(next);
+                if longer {
+                    self.encode_literal(at)?;
+                    self.input_len += 1;
+                    self.stats.literals += 1;
+                    continue;
+                }
+            }
+
+            let len = if use_rep {
+                self.encode_rep_match(rep_index, rep_len)?;
+                self.stats.rep_matches += 1;
+                rep_len
+            } else if let Some(m) = found {
+                self.encode_match(m.dist - 1, m.len)?;
+                m.len
+            } else if self.is_short_rep(at) {
+                self.encode_short_rep()?;
+                self.stats.rep_matches += 1;
+                1
+            } else {
+                self.encode_literal(at)?;
+                self.input_len += 1;
+                self.stats.literals += 1;
+                continue;
+            };
+
+            // The lookahead already recorded the next position.
+            let first = if self.next_match.take().is_some() {
+                2
+            } else {
+                1
+            };
+            for i in first..len {
+                self.match_finder
+                    .insert(&self.window, at + i, self.input_len + i as u64);
+            }
+            self.input_len += len as u64;
+            self.stats.matches += 1;
+        }
+        Ok(())
+    }
+
+    /// Encode all the bytes of the window not yet encoded as literals.
+    fn encode_literals(&mut self) -> io::Result<()> {
+        loop {
+            let at = (self.input_len - self.window_start) as usize;
+            if at == self.window.len() {
+                return Ok(());
+            }
+            self.encode_literal(at)?;
+            self.input_len += 1;
+            self.stats.literals += 1;
+        }
+    }
+
+    /// Longest match for the byte at index `at` of the window and position
+    /// `pos`, unless too short for its distance, recording that position.
+    fn find_match(&mut self, at: usize, pos: u64) -> Option<Match> {
+        self.match_finder
+            .find(&self.window, at, pos)
+            .map(|m| Match {
+                len: std::cmp::min(m.len, self.max_match_len),
+                ..m
+            })
+            .filter(|m| m.len > 3 || m.dist <= MAX_SHORT_MATCH_DIST)
+    }
+
+    /// Encode the bytes of the window with the optimal parser. Unless
+    /// `at_end`, the last [`optimum::OPT_LOOKAHEAD`] bytes are kept back, so
+    /// that a whole parse can be done with the matches found in full.
+    fn encode_optimal(&mut self, opt: &mut Optimum, at_end: bool) -> io::Result<()> {
+        let keep = if at_end { 0 } else { optimum::OPT_LOOKAHEAD };
+        loop {
+            let at = (self.input_len - self.window_start) as usize;
+            lzma_check!(at <= self.window.len());
+            if self.window.len() - at <= keep {
+                break;
+            }
+            opt.prices.update(
+                &self.len_encoder,
+                &self.rep_len_encoder,
+                &self.pos_slot_encoder,
+                &self.pos_encoders,
+                &self.align_encoder,
+                self.properties.pb,
+            );
+
+            let found = self.parse(opt, at);
+            let mut len = 0;
+            while let Some(step) = opt.path.pop() {
+                match step {
+                    Step::Literal => {
+                        self.encode_literal(at + len)?;
+                        self.stats.literals += 1;
+                    }
+                    Step::ShortRep => {
+                        self.encode_short_rep()?;
+                        self.stats.matches += 1;
+                        self.stats.rep_matches += 1;
+                    }
+                    Step::Rep { index, len } => {
+                        opt.prices.rep_encoded(self.pos_state());
+                        self.encode_rep_match(index, len)?;
+                        self.stats.matches += 1;
+                        self.stats.rep_matches += 1;
+                    }
+                    Step::Match { rep0, len } => {
+                        opt.prices.match_encoded(self.pos_state(), rep0);
+                        self.encode_match(rep0, len)?;
+                        self.stats.matches += 1;
+                    }
+                }
+                len += step.len();
+                self.input_len += step.len() as u64;
+            }
+            for i in found..len {
+                let pos = self.window_start + (at + i) as u64;
+                self.match_finder.insert(&self.window, at + i, pos);
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the cheapest steps to encode the bytes from index `at` of the
+    /// window, as priced by `opt.prices` and the current probabilities,
+    /// leaving them in `opt.path`. Returns the number of positions passed
+    /// to the match finder, which the steps may go beyond.
+    ///
+    /// The nodes are processed in order, each one relaxing the nodes that
+    /// its literal and matches reach, until the last node reached or a match
+    /// of at least the nice length, which is taken as is.
+    fn parse(&mut self, opt: &mut Optimum, at: usize) -> usize {
+        let avail_total = self.window.len() - at;
+        opt.reset(self.state, self.rep);
+        let pos_mask = (1 << self.properties.pb) - 1;
+        let mut i = 0;
+        loop {
+            if i > 0 {
+                if i == opt.frontier() {
+                    opt.backtrack(i);
+                    return i;
+                }
+                opt.settle(i);
+            }
+            let a = at + i;
+            let pos = self.window_start + a as u64;
+            opt.matches.clear();
+            self.match_finder
+                .find_all(&self.window, a, pos, &mut opt.matches);
+            // The first match as long as the maximum stands for the longer
+            // ones, truncated.
+            if let Some(n) = opt.matches.iter().position(|m| m.len >= self.max_match_len) {
+                opt.matches.truncate(n + 1);
+                opt.matches[n].len = self.max_match_len;
+            }
+            let avail = std::cmp::min(avail_total - i, self.max_match_len);
+            let Node {
+                price, state, rep, ..
+            } = opt.nodes[i];
+            let current = &self.window[a..a + avail];
+
+            let mut rep_lens = [0; 4];
+            for (len, &rep) in rep_lens.iter_mut().zip(rep.iter()) {
+                let dist = rep as usize + 1;
+                // The distances are zero at the start of the stream.
+                if dist <= a {
+                    *len = self.window[a - dist..]
+                        .iter()
+                        .zip(current)
+                        .take_while(|(a, b)| a == b)
+                        .count();
                 }
+            }
+            // The first of the longest ones.
+            let rep_index = (0..4).rev().max_by_key(|&j| rep_lens[j]).unwrap_or(0);
+            let rep_len = rep_lens[rep_index];
+            let main = opt.matches.last().copied();
+            let main_len = main.map_or(0, |m| m.len);
+            if std::cmp::max(rep_len, main_len) >= self.nice_len {
+                opt.path.push(match main {
+                    Some(m) if m.len > rep_len => Step::Match {
+                        rep0: m.dist - 1,
+                        len: m.len,
+                    },
+                    _ => Step::Rep {
+                        index: rep_index,
+                        len: rep_len,
+                    },
+                });
+                opt.backtrack(i);
+                return i + 1;
+            }
+
+            // Past the end of the parse, only close the paths still open.
+            let reach = if i < optimum::OPT_LEN {
+                avail
+            } else {
+                std::cmp::min(avail, opt.frontier() - i)
+            };
+            let pos_state = (pos & pos_mask) as usize;
+            let is_match = self.is_match[(state << 4) + pos_state];
 
-                // Distance marker = 0xFFFFFFFF
-                // pos_slot = 63
-                for _ in 0..6 {
-                    self.rangecoder.encode_bit(&mut 0x400, true)?;
+            let literal_price = price
+                + rangecoder::bit_price(is_match, false)
+                + self.literal_price(a, state, rep[0]);
+            opt.relax(i + 1, literal_price, Step::Literal);
+
+            let match_price = price + rangecoder::bit_price(is_match, true);
+            let rep_price = match_price + rangecoder::bit_price(self.is_rep[state], true);
+            let rep0_long = self.is_rep_0long[(state << 4) + pos_state];
+            let g0 = self.is_rep_g0[state];
+            if (rep[0] as usize) < a && current[0] == self.window[a - rep[0] as usize - 1] {
+                let price = rep_price
+                    + rangecoder::bit_price(g0, false)
+                    + rangecoder::bit_price(rep0_long, false);
+                opt.relax(i + 1, price, Step::ShortRep);
+            }
+
+            for (index, &len) in rep_lens.iter().enumerate() {
+                let len = std::cmp::min(len, reach);
+                if len < 2 {
+                    continue;
+                }
+                let g1 = self.is_rep_g1[state];
+                let g2 = self.is_rep_g2[state];
+                let prefix_price = rep_price
+                    + match index {
+                        0 => {
+                            rangecoder::bit_price(g0, false)
+                                + rangecoder::bit_price(rep0_long, true)
+                        }
+                        1 => rangecoder::bit_price(g0, true) + rangecoder::bit_price(g1, false),
+                        _ => {
+                            rangecoder::bit_price(g0, true)
+                                + rangecoder::bit_price(g1, true)
+                                + rangecoder::bit_price(g2, index == 3)
+                        }
+                    };
+                for l in 2..=len {
+                    let price = prefix_price + opt.prices.rep_len(pos_state, l);
+                    opt.relax(i + l, price, Step::Rep { index, len: l });
                 }
-                // num_direct_bits = 30
-                // result = 3 << 30 = C000_0000
-                //        + 3FFF_FFF0  (26 bits)
-                //        + F          ( 4 bits)
-                for _ in 0..30 {
-                    self.rangecoder.encode_bit(&mut 0x400, true)?;
+            }
+
+            let new_price = match_price + rangecoder::bit_price(self.is_rep[state], false);
+            let mut start = 2;
+            for k in 0..opt.matches.len() {
+                let m = opt.matches[k];
+                let len = std::cmp::min(m.len, reach);
+                for l in start..=len {
+                    let price = new_price
+                        + opt.prices.match_len(pos_state, l)
+                        + opt.prices.distance(m.dist - 1, l);
+                    opt.relax(
+                        i + l,
+                        price,
+                        Step::Match {
+                            rep0: m.dist - 1,
+                            len: l,
+                        },
+                    );
                 }
-                //        = FFFF_FFFF
+                start = std::cmp::max(start, len + 1);
             }
+            i += 1;
+        }
+    }
+
+    /// Index in `rep` and length of the longest match at a repeated
+    /// distance for the byte at index `at` of the window, or a length of 0
+    /// if none is at least 2 bytes long.
+    fn longest_rep(&self, at: usize) -> (usize, usize) {
+        let current = &self.window[at..std::cmp::min(self.window.len(), at + self.max_match_len)];
+        let mut best = (0, 0);
+        for (index, &rep) in self.rep.iter().enumerate() {
+            let dist = rep as usize + 1;
+            // The distances are zero at the start of the stream.
+            if dist > at {
+                continue;
+            }
+            let len = self.window[at - dist..]
+                .iter()
+                .zip(current)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.1 {
+                best = (index, len);
+            }
+        }
+        if best.1 < 2 {
+            best.1 = 0;
+        }
+        best
+    }
+
+    /// Whether the byte at index `at` of the window repeats the byte at
+    /// distance `rep[0]`, and is cheaper to encode as a short rep than as a
+    /// literal with the current probabilities.
+    fn is_short_rep(&self, at: usize) -> bool {
+        let dist = self.rep[0] as usize + 1;
+        if dist > at || self.window[at] != self.window[at - dist] {
+            return false;
+        }
+        let state = self.state;
+        let pos_state = self.pos_state();
+        let is_match = self.is_match[(state << 4) + pos_state];
+        let short_rep_price = rangecoder::bit_price(is_match, true)
+            + rangecoder::bit_price(self.is_rep[state], true)
+            + rangecoder::bit_price(self.is_rep_g0[state], false)
+            + rangecoder::bit_price(self.is_rep_0long[(state << 4) + pos_state], false);
+        // A short rep also makes the next literals matched ones, which
+        // costs a bit more on text: require it to save at least one bit.
+        short_rep_price + 16
+            < rangecoder::bit_price(is_match, false) + self.literal_price(at, state, self.rep[0])
+    }
+
+    /// Cost of the literal tree bits of the byte at index `at` of the
+    /// window in `state`, after a match at distance `rep0 + 1` if the state
+    /// is one after a match, in sixteenths of a bit.
+    fn literal_price(&self, at: usize, state: usize, rep0: u32) -> u32 {
+        let byte = self.window[at];
+        let prev_byte = if at == 0 { 0 } else { self.window[at - 1] };
+        let lit_state = self
+            .properties
+            .literal_state(self.window_start + at as u64, prev_byte);
+        let probs = &self.literal_probs[lit_state];
+
+        let mut price = 0;
+        let mut result: usize = 1;
+        let mut i = 0;
+        if state >= 7 {
+            let match_byte = self.window[at - rep0 as usize - 1];
+            while i < 8 {
+                let match_bit = ((match_byte >> (7 - i)) & 1) as usize;
+                let bit = ((byte >> (7 - i)) & 1) != 0;
+                price += rangecoder::bit_price(probs[((1 + match_bit) << 8) + result], bit);
+                result = (result << 1) ^ (bit as usize);
+                i += 1;
+                if match_bit != bit as usize {
+                    break;
+                }
+            }
+        }
+        while i < 8 {
+            let bit = ((byte >> (7 - i)) & 1) != 0;
+            price += rangecoder::bit_price(probs[result], bit);
+            result = (result << 1) ^ (bit as usize);
+            i += 1;
+        }
+        price
+    }
+
+    fn pos_state(&self) -> usize {
+        (self.input_len & ((1 << self.properties.pb) - 1)) as usize
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let size_known = match self.unpacked_size {
+            UnpackedSize::SkipWritingToHeader | UnpackedSize::WriteToHeader(Some(_)) => true,
+            UnpackedSize::WriteToHeader(None) => false,
+        };
+        if !size_known || self.end_marker {
+            // End-of-stream marker: a match of the minimum length at
+            // distance 0xFFFF_FFFF.
+            self.encode_match(0xFFFF_FFFF, 2)?;
         }
 
         // Flush range coder
         self.rangecoder.finish()
     }
 
-    fn encode_literal(&mut self, byte: u8, prev_byte: u8) -> io::Result<()> {
-        let prev_byte = prev_byte as usize;
+    /// Encode a match of `len` bytes at distance `rep0 + 1`.
+    fn encode_match(&mut self, rep0: u32, len: usize) -> io::Result<()> {
+        lzma_check!((2..=MAX_MATCH_LEN).contains(&len), "invalid length {}", len);
+        let pos_state = self.pos_state();
+        self.rangecoder
+            .encode_bit(&mut self.is_match[(self.state << 4) + pos_state], true)?;
+        // New distance
+        self.rangecoder
+            .encode_bit(&mut self.is_rep[self.state], false)?;
+
+        self.len_encoder
+            .encode(&mut self.rangecoder, pos_state, (len - 2) as u32)?;
+        self.state = if self.state < 7 { 7 } else { 10 };
+        self.encode_distance(rep0, len - 2)?;
+
+        self.rep[3] = self.rep[2];
+        self.rep[2] = self.rep[1];
+        self.rep[1] = self.rep[0];
+        self.rep[0] = rep0;
+        Ok(())
+    }
+
+    /// Encode a single byte repeated at distance `rep[0]`.
+    fn encode_short_rep(&mut self) -> io::Result<()> {
+        let pos_state = self.pos_state();
+        let state = self.state;
+        self.rangecoder
+            .encode_bit(&mut self.is_match[(state << 4) + pos_state], true)?;
+        self.rangecoder.encode_bit(&mut self.is_rep[state], true)?;
+        self.rangecoder
+            .encode_bit(&mut self.is_rep_g0[state], false)?;
+        self.rangecoder
+            .encode_bit(&mut self.is_rep_0long[(state << 4) + pos_state], false)?;
+        self.state = if state < 7 { 9 } else { 11 };
+        Ok(())
+    }
+
+    /// Encode a match of `len` bytes at the distance `rep[index]`, which
+    /// becomes the most recent one.
+    fn encode_rep_match(&mut self, index: usize, len: usize) -> io::Result<()> {
+        lzma_check!((2..=MAX_MATCH_LEN).contains(&len), "invalid length {}", len);
+        let pos_state = self.pos_state();
+        let state = self.state;
+        self.rangecoder
+            .encode_bit(&mut self.is_match[(state << 4) + pos_state], true)?;
+        self.rangecoder.encode_bit(&mut self.is_rep[state], true)?;
+        self.rangecoder
+            .encode_bit(&mut self.is_rep_g0[state], index != 0)?;
+        if index == 0 {
+            // Not a single byte (short rep)
+            self.rangecoder
+                .encode_bit(&mut self.is_rep_0long[(state << 4) + pos_state], true)?;
+        } else {
+            self.rangecoder
+                .encode_bit(&mut self.is_rep_g1[state], index != 1)?;
+            if index != 1 {
+                self.rangecoder
+                    .encode_bit(&mut self.is_rep_g2[state], index != 2)?;
+            }
+            let dist = self.rep[index];
+            self.rep.copy_within(0..index, 1);
+            self.rep[0] = dist;
+        }
+
+        self.rep_len_encoder
+            .encode(&mut self.rangecoder, pos_state, (len - 2) as u32)?;
+        self.state = if state < 7 { 8 } else { 11 };
+        Ok(())
+    }
+
+    fn encode_distance(&mut self, distance: u32, length: usize) -> io::Result<()> {
+        let len_state = if length > 3 { 3 } else { length };
+
+        if distance < 4 {
+            return self.pos_slot_encoder[len_state].encode(&mut self.rangecoder, distance);
+        }
+        // Two slots per bit length, told apart by the bit after the
+        // leading one.
+        let top_bit = 31 - distance.leading_zeros();
+        let pos_slot = 2 * top_bit + ((distance >> (top_bit - 1)) & 1);
+        self.pos_slot_encoder[len_state].encode(&mut self.rangecoder, pos_slot)?;
+
+        let num_direct_bits = ((pos_slot >> 1) - 1) as usize;
+        let base = (2 ^ (pos_slot & 1)) << num_direct_bits;
+        let reduced = distance - base;
+
+        if pos_slot < 14 {
+            self.rangecoder.encode_reverse_bit_tree(
+                num_direct_bits,
+                &mut self.pos_encoders,
+                (base - pos_slot) as usize,
+                reduced,
+            )
+        } else {
+            self.rangecoder
+                .encode_direct_bits(num_direct_bits - 4, reduced >> 4)?;
+            self.align_encoder
+                .encode_reverse(&mut self.rangecoder, reduced & 0xF)
+        }
+    }
+
+    /// Encode the byte at index `at` of the window as a literal.
+    fn encode_literal(&mut self, at: usize) -> io::Result<()> {
+        let pos_state = self.pos_state();
+        self.rangecoder
+            .encode_bit(&mut self.is_match[(self.state << 4) + pos_state], false)?;
+
+        let byte = self.window[at];
+        let prev_byte = if at == 0 { 0 } else { self.window[at - 1] };
 
         let mut result: usize = 1;
-        let lit_state = prev_byte >> 5;
+        let lit_state = self.properties.literal_state(self.input_len, prev_byte);
         let probs = &mut self.literal_probs[lit_state];
 
-        for i in 0..8 {
+        let mut i = 0;
+        if self.state >= 7 {
+            // After a match, the byte at distance rep0 + 1 serves as context
+            // until the first bit that differs from it.
+            lzma_check!((self.rep[0] as usize) < at);
+            let match_byte = self.window[at - self.rep[0] as usize - 1];
+            while i < 8 {
+                let match_bit = ((match_byte >> (7 - i)) & 1) as usize;
+                let bit = ((byte >> (7 - i)) & 1) != 0;
+                self.rangecoder
+                    .encode_bit(&mut probs[((1 + match_bit) << 8) + result], bit)?;
+                result = (result << 1) ^ (bit as usize);
+                i += 1;
+                if match_bit != bit as usize {
+                    break;
+                }
+            }
+        }
+        while i < 8 {
             let bit = ((byte >> (7 - i)) & 1) != 0;
             self.rangecoder.encode_bit(&mut probs[result], bit)?;
             result = (result << 1) ^ (bit as usize);
+            i += 1;
         }
 
+        self.state = if self.state < 4 {
+            0
+        } else if self.state < 10 {
+            self.state - 3
+        } else {
+            self.state - 6
+        };
         Ok(())
     }
 }
+
+/// Read a byte string prefixed with its length.
+fn read_bytes(input: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = input.read_u64::<LittleEndian>()?;
+    if len > input.len() as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (bytes, rest) = input.split_at(len as usize);
+    *input = rest;
+    Ok(bytes.to_vec())
+}
+
+impl<W> std::fmt::Debug for Encoder<W>
+where
+    W: io::Write + std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Encoder")
+            .field("output", &self.output)
+            .field("properties", &self.properties)
+            .field("match_finder", &self.match_finder)
+            .field("unpacked_size", &self.unpacked_size)
+            .field("input_len", &self.input_len)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Extreme but legal "lclppb" combinations.
+    const CORNER_CASES: [(u32, u32, u32); 5] =
+        [(8, 0, 2), (0, 4, 2), (3, 0, 4), (8, 4, 4), (0, 0, 0)];
+
+    #[test]
+    fn test_longest_rep() {
+        let mut encoder = Encoder::new(Vec::new(), &Options::default()).unwrap();
+        encoder.window.extend_from_slice(b"abcdXbcdYabcdZ");
+        encoder.rep = [0, 4, 8, 2];
+        // At "abcdZ", only "abcd" 9 bytes back (rep 8) matches.
+        assert_eq!(encoder.longest_rep(9), (2, 4));
+        // No repeated distance reaches before the start.
+        encoder.rep = [20, 30, 40, 50];
+        assert_eq!(encoder.longest_rep(9), (0, 0));
+    }
+
+    #[test]
+    fn test_short_rep() {
+        let mut encoder = Encoder::new(Vec::new(), &Options::default()).unwrap();
+        encoder.window.extend_from_slice(b"abcdefghaXcY");
+        encoder.input_len = 8;
+        encoder.rep = [7, 0, 0, 0];
+        assert!(encoder.is_short_rep(8));
+        encoder.input_len = 9;
+        assert!(!encoder.is_short_rep(9));
+        // The distance reaches before the start.
+        encoder.rep = [20, 0, 0, 0];
+        assert!(!encoder.is_short_rep(10));
+    }
+
+    #[test]
+    fn test_greedy_prefers_reps() {
+        let mut state = 0x2545_f491_u32;
+        let mut window: Vec<u8> = (0..6000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        window[..7].copy_from_slice(b"ABCDEFG");
+        window[3000..3006].copy_from_slice(b"ABCDEx");
+        window.extend_from_slice(b"ABCDEF!");
+
+        let mut encoder = Encoder::new(Vec::new(), &Options::default()).unwrap();
+        encoder.window = window;
+        for at in 0..6000 {
+            encoder.match_finder.insert(&encoder.window, at, at as u64);
+        }
+        encoder.input_len = 6000;
+        encoder.rep = [2999, 0, 0, 0];
+        encoder.encode_greedy(true).unwrap();
+        // 5 bytes at the repeated distance beat 6 bytes 6000 bytes back.
+        assert_eq!(encoder.stats.matches, 1);
+        assert_eq!(encoder.stats.rep_matches, 1);
+        assert_eq!(encoder.rep[0], 2999);
+    }
+
+    #[test]
+    fn test_encode_distances() {
+        // Matches at the edges of every distance slot reachable within the
+        // data, decoded back by the decoder.
+        let len = 1 << 17;
+        let mut expected: Vec<u8> = (0..len as u64).map(|i| (i * i % 251) as u8).collect();
+        let mut compressed = Vec::new();
+        let mut encoder = Encoder::new(&mut compressed, &Options::default()).unwrap();
+        encoder.window.extend_from_slice(&expected);
+        for at in 0..len {
+            encoder.encode_literal(at).unwrap();
+            encoder.input_len += 1;
+        }
+
+        let mut dists = vec![1, 2, 3];
+        for bit in 2..17 {
+            dists.extend_from_slice(&[1 << bit, (1 << bit) + 1, (3 << (bit - 1)) - 1]);
+        }
+        for (i, &dist) in dists.iter().enumerate() {
+            let match_len = 2 + i % 8;
+            for _ in 0..match_len {
+                expected.push(expected[expected.len() - dist]);
+            }
+            encoder.encode_match(dist as u32 - 1, match_len).unwrap();
+            encoder.input_len += match_len as u64;
+        }
+        encoder.finish().unwrap();
+        encoder.write_pending().unwrap();
+        drop(encoder);
+
+        let mut decompressed = Vec::new();
+        crate::lzma_decompress(&mut compressed.as_slice(), &mut decompressed).unwrap();
+        assert_eq!(decompressed, expected);
+    }
+
+    #[test]
+    fn test_literal_state() {
+        let properties = LzmaProperties {
+            lc: 3,
+            lp: 0,
+            pb: 2,
+        };
+        assert_eq!(properties.literal_state(5, 0xFF), 7);
+        let properties = LzmaProperties {
+            lc: 1,
+            lp: 2,
+            pb: 0,
+        };
+        assert_eq!(properties.literal_state(7, 0x80), 7);
+        assert_eq!(properties.literal_state(4, 0x7F), 0);
+        let properties = LzmaProperties {
+            lc: 0,
+            lp: 4,
+            pb: 0,
+        };
+        assert_eq!(properties.literal_state(0x1234, 0xFF), 4);
+        let properties = LzmaProperties {
+            lc: 8,
+            lp: 0,
+            pb: 0,
+        };
+        assert_eq!(properties.literal_state(9, 0xAB), 0xAB);
+    }
+
+    #[test]
+    fn test_corner_case_properties() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * i % 251) as u8).collect();
+        for &(lc, lp, pb) in CORNER_CASES.iter() {
+            let properties = LzmaProperties { lc, lp, pb };
+            let mut compressed = Vec::new();
+            let mut encoder =
+                Encoder::with_properties(&mut compressed, &Options::default(), properties).unwrap();
+            encoder.process(&mut &data[..]).unwrap();
+            assert_eq!(compressed[0] as u32, lc + 9 * (lp + 5 * pb));
+
+            let mut decompressed = Vec::new();
+            crate::lzma_decompress(&mut compressed.as_slice(), &mut decompressed).unwrap();
+            assert_eq!(decompressed, data, "lc={} lp={} pb={}", lc, lp, pb);
+        }
+    }
+}
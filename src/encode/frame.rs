@@ -0,0 +1,151 @@
+//! Length-prefixed compressed messages.
+
+use crate::compress::Options;
+use crate::decode::lzma::LzmaProperties;
+use crate::encode::{dumbencoder, lzma2, xz};
+use crate::error;
+use std::io;
+
+/// Compressor of a sequence of messages, each written as a separate frame
+/// that [`FrameDecoder`](crate::decompress::FrameDecoder) reads back one at a
+/// time.
+///
+/// A frame is the length of its payload as a variable-length integer (7 bits
+/// per byte, least significant first), followed by the message compressed as
+/// LZMA2 chunks and an end marker. Only the first chunk of the first frame
+/// resets the dictionary, and the probabilities are carried over as well:
+/// later frames may refer to the content of the previous messages, which
+/// helps with short messages sharing a lot of content, such as RPC requests.
+/// Chunks that don't shrink once compressed are stored.
+pub struct FrameEncoder<W>
+where
+    W: io::Write,
+{
+    /// Output sink
+    output: W,
+    /// Encoder of the chunks, whose dictionary and state are kept across
+    /// frames
+    encoder: dumbencoder::Encoder<io::Sink>,
+    /// Literal coder properties, written in the first compressed chunk
+    properties: LzmaProperties,
+    /// Whether the dictionary has been reset by a first chunk
+    started: bool,
+    /// Whether the properties have been written by a compressed chunk
+    props_written: bool,
+    /// Whether the next compressed chunk must reset the state, after a
+    /// chunk that was stored
+    reset_state: bool,
+    /// Payload of the frame being written
+    payload: Vec<u8>,
+}
+
+impl<W> FrameEncoder<W>
+where
+    W: io::Write,
+{
+    /// Initialize the compressor, writing frames to the `output` sink, with
+    /// the default options and their dictionary of 8 MiB.
+    pub fn new(output: W) -> Self {
+        Self::with_options(output, &Options::default()).expect("default options are valid")
+    }
+
+    /// Initialize the compressor with the given `options`, of which only the
+    /// dictionary size, the match finder settings, the mode and the literal
+    /// coder properties apply. The frames must be read with a dictionary at
+    /// least as large, see
+    /// [`FrameDecoder::with_dict_size()`](crate::decompress::FrameDecoder::with_dict_size).
+    pub fn with_options(output: W, options: &Options) -> error::Result<Self> {
+        options.validate()?;
+        Ok(Self {
+            output,
+            encoder: dumbencoder::Encoder::chunked(options)?,
+            properties: LzmaProperties {
+                lc: options.lc,
+                lp: options.lp,
+                pb: options.pb,
+            },
+            started: false,
+            props_written: false,
+            reset_state: false,
+            payload: Vec::new(),
+        })
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    /// Consumes the compressor and returns the output sink.
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    /// Compress `message` as the next frame. The frame is written to the
+    /// output sink at once, so that it can be sent right away.
+    pub fn write_frame(&mut self, message: &[u8]) -> error::Result<()> {
+        self.payload.clear();
+        for chunk in message.chunks(lzma2::MAX_UNCOMPRESSED_CHUNK_SIZE) {
+            let packed = self.encoder.encode_next_chunk(chunk)?;
+            let status = if !self.started {
+                // reset dict, state and properties
+                0xE0
+            } else if !self.props_written {
+                // reset state and properties
+                0xC0
+            } else if self.reset_state {
+                // reset state
+                0xA0
+            } else {
+                // no reset
+                0x80
+            };
+            let header_size = if status >= 0xC0 { 6 } else { 5 };
+            if packed.len() <= lzma2::MAX_PACKED_SIZE
+                && packed.len() + header_size < chunk.len() + 3
+            {
+                lzma2::write_compressed_chunk(
+                    &mut self.payload,
+                    status,
+                    chunk.len(),
+                    self.properties,
+                    &packed,
+                )?;
+                self.props_written = true;
+                self.reset_state = false;
+            } else {
+                if self.started {
+                    lzma2::write_uncompressed_chunk_no_reset(&mut self.payload, chunk)?;
+                } else {
+                    lzma2::write_uncompressed_chunk(&mut self.payload, chunk)?;
+                }
+                // The chunk is still in the dictionary, but the decoder
+                // didn't go through the probabilities updated by its
+                // encoding.
+                self.encoder.reset_probs();
+                self.reset_state = true;
+            }
+            self.started = true;
+        }
+        lzma2::write_end(&mut self.payload)?;
+
+        xz::write_multibyte(&mut self.output, self.payload.len() as u64)?;
+        self.output.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+impl<W> std::fmt::Debug for FrameEncoder<W>
+where
+    W: io::Write + std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("FrameEncoder")
+            .field("output", &self.output)
+            .field("properties", &self.properties)
+            .field("started", &self.started)
+            .field("props_written", &self.props_written)
+            .field("reset_state", &self.reset_state)
+            .finish()
+    }
+}
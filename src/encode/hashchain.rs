@@ -0,0 +1,219 @@
+//! Hash-chain match finder.
+
+use crate::encode::matchfinder::{hash, Match, HASH_LEN, MAX_MATCH_LEN, MIN_MATCH_LEN};
+
+/// Number of candidates examined per lookup.
+const DEFAULT_DEPTH: usize = 12;
+/// Length beyond which a match is taken without looking further.
+const DEFAULT_NICE_LEN: usize = 64;
+
+/// Match finder chaining together the positions whose first [`HASH_LEN`]
+/// bytes hash to the same value.
+///
+/// Positions are absolute offsets in the uncompressed data, so the caller
+/// is free to discard the beginning of its window once it's more than a
+/// dictionary away.
+pub(crate) struct HashChain {
+    /// Most recent position of each hash, plus one, or zero if none.
+    head: Vec<u64>,
+    hash_bits: u32,
+    /// Distance from each position to the previous one with the same hash,
+    /// or zero if none, indexed modulo the dictionary size. Grown lazily up
+    /// to the dictionary size, so small inputs stay cheap.
+    prev: Vec<u32>,
+    /// Maximum distance of a match
+    dict_size: u32,
+    depth: usize,
+    nice_len: usize,
+}
+
+impl HashChain {
+    pub fn new(dict_size: u32, hash_bits: u32) -> Self {
+        Self {
+            head: vec![0; 1 << hash_bits],
+            hash_bits,
+            prev: Vec::new(),
+            dict_size,
+            depth: DEFAULT_DEPTH,
+            nice_len: DEFAULT_NICE_LEN,
+        }
+    }
+
+    /// Number of bytes allocated at most for the given dictionary size and
+    /// hash bits.
+    pub fn mem_usage(dict_size: u32, hash_bits: u32) -> u64 {
+        (8 << hash_bits) + 4 * dict_size as u64
+    }
+
+    /// Forget all the positions, keeping the allocations.
+    pub fn reset(&mut self) {
+        self.head.fill(0);
+        self.prev.clear();
+    }
+
+    /// Stop the lookups after `depth` candidates, or at a match of
+    /// `nice_len` bytes.
+    pub fn with_limits(mut self, nice_len: usize, depth: usize) -> Self {
+        self.nice_len = nice_len;
+        self.depth = depth;
+        self
+    }
+
+    /// Record position `pos`, whose bytes start at `window[at]`. Positions
+    /// with fewer than [`HASH_LEN`] bytes available are skipped.
+    pub fn insert(&mut self, window: &[u8], at: usize, pos: u64) {
+        if window.len() - at < HASH_LEN {
+            return;
+        }
+        let h = hash(&window[at..], self.hash_bits);
+        let delta = match self.head[h] {
+            0 => 0,
+            last => {
+                let delta = pos - (last - 1);
+                if delta <= self.dict_size as u64 {
+                    delta as u32
+                } else {
+                    0
+                }
+            }
+        };
+        let index = (pos % self.dict_size as u64) as usize;
+        if index >= self.prev.len() {
+            self.prev.resize(index + 1, 0);
+        }
+        self.prev[index] = delta;
+        self.head[h] = pos + 1;
+    }
+
+    /// Find the longest match for position `pos`, whose bytes start at
+    /// `window[at]`, then record that position.
+    pub fn find(&mut self, window: &[u8], at: usize, pos: u64) -> Option<Match> {
+        self.search(window, at, pos, |_| ())
+    }
+
+    /// Append to `matches` the ever longer matches met while looking for
+    /// the longest one, then record position `pos`.
+    pub fn find_all(&mut self, window: &[u8], at: usize, pos: u64, matches: &mut Vec<Match>) {
+        self.search(window, at, pos, |m| matches.push(m));
+    }
+
+    /// Look up the candidates of position `pos`, passing each match longer
+    /// than the previous ones to `on_match`, then record that position.
+    fn search<F>(&mut self, window: &[u8], at: usize, pos: u64, mut on_match: F) -> Option<Match>
+    where
+        F: FnMut(Match),
+    {
+        let avail = std::cmp::min(window.len() - at, MAX_MATCH_LEN);
+        if avail < HASH_LEN {
+            return None;
+        }
+        let current = &window[at..at + avail];
+        let window_start = pos - at as u64;
+
+        let mut best: Option<Match> = None;
+        let mut candidate = self.head[hash(current, self.hash_bits)];
+        for _ in 0..self.depth {
+            if candidate == 0 {
+                break;
+            }
+            let cand_pos = candidate - 1;
+            let dist = pos - cand_pos;
+            if dist > self.dict_size as u64 || cand_pos < window_start {
+                break;
+            }
+            let start = (cand_pos - window_start) as usize;
+            lzma_check!(start < at);
+            let best_len = best.map_or(MIN_MATCH_LEN - 1, |m| m.len);
+            // Check the byte that would make the match longer first.
+            if window[start + best_len] == current[best_len] {
+                let len = window[start..]
+                    .iter()
+                    .zip(current)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    let m = Match {
+                        len,
+                        dist: dist as u32,
+                    };
+                    on_match(m);
+                    best = Some(m);
+                    if len >= self.nice_len || len == avail {
+                        break;
+                    }
+                }
+            }
+            let delta = self.prev[(cand_pos % self.dict_size as u64) as usize];
+            if delta == 0 {
+                break;
+            }
+            candidate = cand_pos - delta as u64 + 1;
+        }
+
+        self.insert(window, at, pos);
+        best
+    }
+}
+
+impl std::fmt::Debug for HashChain {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("HashChain")
+            .field("dict_size", &self.dict_size)
+            .field("hash_bits", &self.hash_bits)
+            .field("depth", &self.depth)
+            .field("nice_len", &self.nice_len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_longest() {
+        let data = b"abcdefabcxyzabcdefg";
+        let mut finder = HashChain::new(0x1000, 12);
+        for at in 0..12 {
+            finder.insert(data, at, at as u64);
+        }
+        assert_eq!(finder.find(data, 12, 12), Some(Match { len: 6, dist: 12 }));
+        assert_eq!(finder.find(data, 13, 13), Some(Match { len: 5, dist: 12 }));
+    }
+
+    #[test]
+    fn test_find_all() {
+        let data = b"abcdefabcdxyzabcdeXabcdef";
+        let mut finder = HashChain::new(0x1000, 12);
+        for at in 0..19 {
+            finder.insert(data, at, at as u64);
+        }
+        let mut matches = vec![];
+        finder.find_all(data, 19, 19, &mut matches);
+        assert_eq!(
+            matches,
+            [Match { len: 5, dist: 6 }, Match { len: 6, dist: 19 }]
+        );
+    }
+
+    #[test]
+    fn test_dict_size_limit() {
+        let data = b"abcd____abcd";
+        let mut finder = HashChain::new(4, 12);
+        for at in 0..8 {
+            finder.insert(data, at, at as u64);
+        }
+        assert_eq!(finder.find(data, 8, 8), None);
+    }
+
+    #[test]
+    fn test_window_offset() {
+        // The window starts at absolute position 100.
+        let data = b"xyzwxyzw";
+        let mut finder = HashChain::new(0x1000, 12);
+        for at in 0..4 {
+            finder.insert(data, at, 100 + at as u64);
+        }
+        assert_eq!(finder.find(data, 4, 104), Some(Match { len: 4, dist: 4 }));
+    }
+}
@@ -1,26 +1,227 @@
+use crate::compress::Options;
+use crate::decode::lzma::LzmaProperties;
+use crate::encode::stats::CompressStats;
+use crate::encode::{analyze, dumbencoder};
+use crate::error;
+use crate::xz::check::CheckDigest;
+use crate::xz::{Check, CheckMethod};
 use byteorder::{BigEndian, WriteBytesExt};
 use std::io;
 
+/// Maximum size of the contents of an uncompressed chunk.
+pub const MAX_UNCOMPRESSED_CHUNK_SIZE: usize = 0x10000;
+
+/// Maximum size of the range-coded data of a compressed chunk.
+pub(crate) const MAX_PACKED_SIZE: usize = 0x10000;
+
+/// Order-0 entropy, in bits per byte, above which a chunk is stored right
+/// away: 7.9 bits in fixed point. Compressed or encrypted data is close to 8
+/// bits per byte, so trying to range-code it would only waste time.
+const STORE_ENTROPY_BITS: u64 = (79 << analyze::ENTROPY_FRAC_BITS) / 10;
+
 pub fn encode_stream<R, W>(input: &mut R, output: &mut W) -> io::Result<()>
 where
     R: io::BufRead,
     W: io::Write,
 {
-    let mut buf = vec![0u8; 0x10000];
+    encode_stream_with_digest(
+        input,
+        output,
+        &Options::default(),
+        &mut CheckDigest::None,
+        &mut Default::default(),
+    )
+}
+
+/// Encode the stream with the match finder and mode of `options`, recording
+/// statistics except the duration and the output size.
+pub fn encode_stream_with_stats<R, W>(
+    input: &mut R,
+    output: &mut W,
+    options: &Options,
+    stats: &mut CompressStats,
+) -> io::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    encode_stream_with_digest(input, output, options, &mut CheckDigest::None, stats)
+}
+
+/// Encode the stream, computing the given check of the uncompressed data in
+/// the same pass.
+pub fn encode_stream_with_check<R, W>(
+    input: &mut R,
+    output: &mut W,
+    check_method: CheckMethod,
+) -> error::Result<Check>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    if !CheckDigest::is_supported(check_method) {
+        return Err(error::OptionsError::new(
+            "check_method",
+            "Unsupported SHA-256 checksum (not yet implemented)".to_string(),
+        )
+        .into());
+    }
+    let mut digest = CheckDigest::new(check_method);
+    encode_stream_with_digest(
+        input,
+        output,
+        &Options::default(),
+        &mut digest,
+        &mut Default::default(),
+    )?;
+    Ok(digest
+        .finalize()
+        .expect("supported check methods always produce a check"))
+}
+
+fn encode_stream_with_digest<R, W>(
+    input: &mut R,
+    output: &mut W,
+    options: &Options,
+    digest: &mut CheckDigest,
+    stats: &mut CompressStats,
+) -> io::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut buf = vec![0u8; MAX_UNCOMPRESSED_CHUNK_SIZE];
     loop {
-        let n = input.read(&mut buf)?;
+        // Fill whole chunks, so that short reads don't add chunk headers.
+        let mut n = 0;
+        while n < buf.len() {
+            match input.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(len) => n += len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
         if n == 0 {
-            // status = EOF
-            output.write_u8(0)?;
+            write_end(output)?;
             break;
         }
 
-        // status = uncompressed reset dict
-        output.write_u8(1)?;
-        // unpacked size
-        output.write_u16::<BigEndian>((n - 1) as u16)?;
-        // contents
-        output.write_all(&buf[..n])?;
+        digest.update(&buf[..n]);
+        write_chunk(output, &buf[..n], options, stats)?;
+        stats.in_bytes.0 += n as u64;
+        stats.chunks += 1;
     }
     Ok(())
 }
+
+/// Upper bound on the size of the LZMA2 stream written for `len` bytes of
+/// input: each chunk of up to 64 KiB has a 3-byte header if stored, and is
+/// only compressed if that's smaller, and the stream ends with a 1-byte
+/// marker.
+pub fn compress_bound(len: u64) -> u64 {
+    let chunk_size = MAX_UNCOMPRESSED_CHUNK_SIZE as u64;
+    let chunks = len / chunk_size + (len % chunk_size != 0) as u64;
+    len.saturating_add(3 * chunks).saturating_add(1)
+}
+
+/// Write a chunk of at most 64 KiB resetting the dictionary, compressed with
+/// the match finder and mode of `options` or stored, adding the literals and
+/// matches to `stats` if it was compressed.
+///
+/// Each chunk is sized on its own: data that looks incompressible from its
+/// byte distribution is stored without trying to compress it, and data that
+/// doesn't shrink once compressed is stored as well. This keeps mixed inputs,
+/// such as archives of already compressed files, fast to encode while text
+/// and other redundant data is compressed.
+pub fn write_chunk<W>(
+    output: &mut W,
+    buf: &[u8],
+    options: &Options,
+    stats: &mut CompressStats,
+) -> io::Result<()>
+where
+    W: io::Write,
+{
+    debug_assert!(!buf.is_empty() && buf.len() <= MAX_UNCOMPRESSED_CHUNK_SIZE);
+    if analyze::entropy_bits(buf.iter().copied()) < STORE_ENTROPY_BITS {
+        let mut chunk_stats = CompressStats::default();
+        let packed = dumbencoder::encode_chunk(buf, options, &mut chunk_stats)?;
+        // A compressed chunk has a 6-byte header, against 3 when stored.
+        if packed.len() <= MAX_PACKED_SIZE && packed.len() + 6 < buf.len() + 3 {
+            let properties = LzmaProperties {
+                lc: options.lc,
+                lp: options.lp,
+                pb: options.pb,
+            };
+            // status = compressed, reset dict, state and properties
+            write_compressed_chunk(output, 0xE0, buf.len(), properties, &packed)?;
+            stats.literals += chunk_stats.literals;
+            stats.matches += chunk_stats.matches;
+            stats.rep_matches += chunk_stats.rep_matches;
+            return Ok(());
+        }
+    }
+    write_uncompressed_chunk(output, buf)
+}
+
+/// Write a compressed chunk of `unpacked_size` bytes, with the resets of the
+/// `status` byte, from 0x80 (no reset) to 0xE0 (reset dict, state and
+/// properties). The `properties` are only written if they are reset.
+pub(crate) fn write_compressed_chunk<W>(
+    output: &mut W,
+    status: u8,
+    unpacked_size: usize,
+    properties: LzmaProperties,
+    packed: &[u8],
+) -> io::Result<()>
+where
+    W: io::Write,
+{
+    debug_assert!(status & 0x9F == 0x80);
+    let unpacked_size = unpacked_size - 1;
+    output.write_u8(status | (unpacked_size >> 16) as u8)?;
+    output.write_u16::<BigEndian>(unpacked_size as u16)?;
+    output.write_u16::<BigEndian>((packed.len() - 1) as u16)?;
+    if status >= 0xC0 {
+        output.write_u8(properties.to_byte())?;
+    }
+    output.write_all(packed)
+}
+
+/// Write an uncompressed chunk, resetting the dictionary.
+pub fn write_uncompressed_chunk<W>(output: &mut W, buf: &[u8]) -> io::Result<()>
+where
+    W: io::Write,
+{
+    debug_assert!(!buf.is_empty() && buf.len() <= MAX_UNCOMPRESSED_CHUNK_SIZE);
+    // status = uncompressed reset dict
+    output.write_u8(1)?;
+    // unpacked size
+    output.write_u16::<BigEndian>((buf.len() - 1) as u16)?;
+    // contents
+    output.write_all(buf)
+}
+
+/// Write an uncompressed chunk, keeping the dictionary of the previous chunks.
+pub fn write_uncompressed_chunk_no_reset<W>(output: &mut W, buf: &[u8]) -> io::Result<()>
+where
+    W: io::Write,
+{
+    debug_assert!(!buf.is_empty() && buf.len() <= MAX_UNCOMPRESSED_CHUNK_SIZE);
+    // status = uncompressed no reset
+    output.write_u8(2)?;
+    // unpacked size
+    output.write_u16::<BigEndian>((buf.len() - 1) as u16)?;
+    // contents
+    output.write_all(buf)
+}
+
+/// Write the end of stream marker.
+pub fn write_end<W>(output: &mut W) -> io::Result<()>
+where
+    W: io::Write,
+{
+    // status = EOF
+    output.write_u8(0)
+}
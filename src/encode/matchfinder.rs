@@ -0,0 +1,131 @@
+//! Match finders of the LZMA encoder.
+
+use crate::compress::{MatchFinder, Options};
+use crate::encode::binarytree::BinaryTree;
+use crate::encode::hashchain::HashChain;
+
+/// Minimum length of the matches returned.
+pub(crate) const MIN_MATCH_LEN: usize = 3;
+/// Maximum length of an LZMA match.
+pub(crate) const MAX_MATCH_LEN: usize = 273;
+/// Number of bytes hashed to look up the candidates. Hashing 3 bytes finds
+/// more short matches, but makes the lookups much longer on text.
+pub(crate) const HASH_LEN: usize = 4;
+
+/// Bounds on the number of bits of the hashes, which are sized according
+/// to the dictionary unless set in the options.
+const MIN_HASH_BITS: u32 = 12;
+const MAX_HASH_BITS: u32 = 20;
+
+/// Bounds on the number of bits of the hashes set in the options.
+pub(crate) const HASH_BITS_RANGE: std::ops::RangeInclusive<u32> = 8..=24;
+
+/// Longest match found for a position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Match {
+    pub len: usize,
+    /// Distance to the matched bytes, at least 1.
+    pub dist: u32,
+}
+
+/// Number of bits of the hash for the given dictionary size, about one hash
+/// value per position of the dictionary.
+pub(crate) fn hash_bits(dict_size: u32) -> u32 {
+    let dict_bits = 32 - dict_size.leading_zeros();
+    dict_bits.clamp(MIN_HASH_BITS, MAX_HASH_BITS)
+}
+
+/// Number of bits of the hashes used with `options`.
+fn options_hash_bits(options: &Options) -> u32 {
+    match options.hash_bits {
+        0 => hash_bits(options.dict_size),
+        bits => bits,
+    }
+}
+
+/// Number of bytes allocated at most by the finder of `options`.
+pub(crate) fn mem_usage(options: &Options) -> u64 {
+    let hash_bits = options_hash_bits(options);
+    match options.match_finder {
+        MatchFinder::Hc4 => HashChain::mem_usage(options.dict_size, hash_bits),
+        MatchFinder::Bt4 => BinaryTree::mem_usage(options.dict_size, hash_bits),
+    }
+}
+
+/// Hash of the first [`HASH_LEN`] bytes of `bytes` on `bits` bits.
+pub(crate) fn hash(bytes: &[u8], bits: u32) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - bits)) as usize
+}
+
+/// Match finder selected by [`MatchFinder`].
+///
+/// Both finders index positions by absolute offset in the uncompressed
+/// data. `window[..at]` must contain the bytes preceding position `pos` as
+/// far back as the dictionary or the beginning of the data, and every
+/// position must be passed once, in order, to either `find()` or `insert()`.
+#[derive(Debug)]
+pub(crate) enum Finder {
+    HashChain(HashChain),
+    BinaryTree(BinaryTree),
+}
+
+impl Finder {
+    /// Create the finder of `options`, with its dictionary size and limits.
+    pub fn new(options: &Options) -> Self {
+        let nice_len = options.nice_len as usize;
+        let depth = options.depth as usize;
+        let hash_bits = options_hash_bits(options);
+        match options.match_finder {
+            MatchFinder::Hc4 => {
+                // Automatic depths as in liblzma.
+                let depth = if depth == 0 { 4 + nice_len / 4 } else { depth };
+                Finder::HashChain(
+                    HashChain::new(options.dict_size, hash_bits).with_limits(nice_len, depth),
+                )
+            }
+            MatchFinder::Bt4 => {
+                let depth = if depth == 0 { 16 + nice_len / 2 } else { depth };
+                Finder::BinaryTree(
+                    BinaryTree::new(options.dict_size, hash_bits).with_limits(nice_len, depth),
+                )
+            }
+        }
+    }
+
+    /// Find the longest match for position `pos`, whose bytes start at
+    /// `window[at]`, then record that position.
+    pub fn find(&mut self, window: &[u8], at: usize, pos: u64) -> Option<Match> {
+        match self {
+            Finder::HashChain(finder) => finder.find(window, at, pos),
+            Finder::BinaryTree(finder) => finder.find(window, at, pos),
+        }
+    }
+
+    /// Append to `matches` the matches for position `pos` found while
+    /// looking for the longest one, ever longer and usually farther, then
+    /// record that position.
+    pub fn find_all(&mut self, window: &[u8], at: usize, pos: u64, matches: &mut Vec<Match>) {
+        match self {
+            Finder::HashChain(finder) => finder.find_all(window, at, pos, matches),
+            Finder::BinaryTree(finder) => finder.find_all(window, at, pos, matches),
+        }
+    }
+
+    /// Forget all the positions, to start over with new data.
+    pub fn reset(&mut self) {
+        match self {
+            Finder::HashChain(finder) => finder.reset(),
+            Finder::BinaryTree(finder) => finder.reset(),
+        }
+    }
+
+    /// Record position `pos`, whose bytes start at `window[at]`, without
+    /// looking for a match.
+    pub fn insert(&mut self, window: &[u8], at: usize, pos: u64) {
+        match self {
+            Finder::HashChain(finder) => finder.insert(window, at, pos),
+            Finder::BinaryTree(finder) => finder.insert(window, at, pos),
+        }
+    }
+}
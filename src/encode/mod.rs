@@ -1,8 +1,17 @@
 //! Encoding logic.
 
+pub mod analyze;
+pub mod binarytree;
 pub mod dumbencoder;
+pub mod frame;
+pub mod hashchain;
 pub mod lzma2;
+pub mod matchfinder;
+pub mod optimum;
 pub mod options;
-mod rangecoder;
-mod util;
+pub mod parallel;
+pub mod rangecoder;
+pub mod slices;
+pub mod stats;
+pub mod util;
 pub mod xz;
@@ -0,0 +1,444 @@
+//! Price-based optimal parsing of the LZMA encoder, after liblzma's
+//! `lzma_lzma_optimum_normal()`.
+//!
+//! The prices are estimates of the number of bits each symbol would take
+//! with the current probabilities, in sixteenths of a bit. The parser finds
+//! the cheapest sequence of steps over a stretch of the input as a shortest
+//! path, where each position is reached from an earlier one by a literal or
+//! a match.
+
+use crate::encode::matchfinder::{Match, MAX_MATCH_LEN};
+use crate::encode::rangecoder::{self, BitTree, LenEncoder};
+use crate::util::vec2d::Vec2D;
+
+/// Maximum number of positions parsed at once. The matches starting before
+/// that may reach up to [`MAX_MATCH_LEN`] bytes further.
+pub(crate) const OPT_LEN: usize = 4096;
+
+/// Number of bytes needed beyond the current position to parse [`OPT_LEN`]
+/// positions with all their matches found in full.
+pub(crate) const OPT_LOOKAHEAD: usize = OPT_LEN + 2 * MAX_MATCH_LEN;
+
+/// Number of lengths encoded with a position state before the prices of
+/// its lengths are computed again, as many as there are lengths.
+const LEN_PRICE_UPDATE: u32 = MAX_MATCH_LEN as u32 - 1;
+
+/// Number of new distances encoded before the distance prices are computed
+/// again.
+const DIST_PRICE_UPDATE: u32 = 128;
+
+/// Number of distances encoded with their 4 low bits aligned before the
+/// prices of these bits are computed again.
+const ALIGN_PRICE_UPDATE: u32 = 16;
+
+/// Distances below this have their full price tabulated.
+const FULL_DISTANCES: usize = 128;
+
+/// Distance slots from this one on encode all but the 4 low bits directly.
+const END_POS_MODEL_INDEX: u32 = 14;
+
+/// Way to encode the bytes between two positions of the parse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Step {
+    Literal,
+    /// Single byte at the distance `rep[0]`
+    ShortRep,
+    /// Match at the distance `rep[index]`
+    Rep {
+        index: usize,
+        len: usize,
+    },
+    /// Match at the distance `rep0 + 1`
+    Match {
+        rep0: u32,
+        len: usize,
+    },
+}
+
+impl Step {
+    /// Number of bytes covered.
+    pub fn len(self) -> usize {
+        match self {
+            Step::Literal | Step::ShortRep => 1,
+            Step::Rep { len, .. } | Step::Match { len, .. } => len,
+        }
+    }
+
+    /// State and repeated distances after this step.
+    fn apply(self, state: usize, rep: [u32; 4]) -> (usize, [u32; 4]) {
+        match self {
+            Step::Literal => {
+                let state = if state < 4 {
+                    0
+                } else if state < 10 {
+                    state - 3
+                } else {
+                    state - 6
+                };
+                (state, rep)
+            }
+            Step::ShortRep => (if state < 7 { 9 } else { 11 }, rep),
+            Step::Rep { index, .. } => {
+                let mut rep = rep;
+                rep[..=index].rotate_right(1);
+                (if state < 7 { 8 } else { 11 }, rep)
+            }
+            Step::Match { rep0, .. } => (
+                if state < 7 { 7 } else { 10 },
+                [rep0, rep[0], rep[1], rep[2]],
+            ),
+        }
+    }
+}
+
+/// Position of the parse, with the cheapest way found so far to reach it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Node {
+    pub price: u32,
+    /// Last step of the cheapest way, from the node `step.len()` before
+    pub step: Step,
+    /// State and repeated distances on reaching the node, only set once all
+    /// the nodes before it have been processed
+    pub state: usize,
+    pub rep: [u32; 4],
+}
+
+impl Node {
+    const UNREACHED: Node = Node {
+        price: u32::MAX,
+        step: Step::Literal,
+        state: 0,
+        rep: [0; 4],
+    };
+}
+
+/// Prices of the lengths and distances, which are too many to compute for
+/// every parse. Each table is computed again once enough symbols were
+/// encoded with its probabilities for them to drift, as liblzma does.
+pub(crate) struct Prices {
+    /// Price of each match length minus 2, per position state
+    match_len: Vec2D<u32>,
+    /// Same for the repeated matches
+    rep_len: Vec2D<u32>,
+    /// Price of each distance slot and of its direct bits, per length state
+    slot: [[u32; 64]; 4],
+    /// Full price of the distances below [`FULL_DISTANCES`], minus one, per
+    /// length state
+    full: [[u32; FULL_DISTANCES]; 4],
+    /// Price of the 4 low bits of the distances of the last slots
+    align: [u32; 16],
+    /// Lengths left to encode per position state before the match and
+    /// repeated match length prices are stale
+    match_len_left: [u32; 16],
+    rep_len_left: [u32; 16],
+    /// New distances left to encode before the distance prices are stale
+    dist_left: u32,
+    /// Aligned distances left to encode before the align prices are stale
+    align_left: u32,
+}
+
+impl Prices {
+    pub fn new(pb: u32) -> Self {
+        Self {
+            match_len: Vec2D::init(0, (1 << pb, MAX_MATCH_LEN - 1)),
+            rep_len: Vec2D::init(0, (1 << pb, MAX_MATCH_LEN - 1)),
+            slot: [[0; 64]; 4],
+            full: [[0; FULL_DISTANCES]; 4],
+            align: [0; 16],
+            match_len_left: [0; 16],
+            rep_len_left: [0; 16],
+            dist_left: 0,
+            align_left: 0,
+        }
+    }
+
+    /// Mark all the prices as stale, after a reset of the probabilities.
+    pub fn invalidate(&mut self) {
+        self.match_len_left = [0; 16];
+        self.rep_len_left = [0; 16];
+        self.dist_left = 0;
+        self.align_left = 0;
+    }
+
+    /// Account for a match of `distance + 1` encoded at `pos_state`.
+    pub fn match_encoded(&mut self, pos_state: usize, distance: u32) {
+        self.match_len_left[pos_state] = self.match_len_left[pos_state].saturating_sub(1);
+        self.dist_left = self.dist_left.saturating_sub(1);
+        if pos_slot(distance) >= END_POS_MODEL_INDEX {
+            self.align_left = self.align_left.saturating_sub(1);
+        }
+    }
+
+    /// Account for a repeated match encoded at `pos_state`.
+    pub fn rep_encoded(&mut self, pos_state: usize) {
+        self.rep_len_left[pos_state] = self.rep_len_left[pos_state].saturating_sub(1);
+    }
+
+    /// Compute the stale prices from the current probabilities of the
+    /// encoder.
+    pub fn update(
+        &mut self,
+        len_encoder: &LenEncoder,
+        rep_len_encoder: &LenEncoder,
+        pos_slot_encoder: &[BitTree<{ 1 << 6 }>; 4],
+        pos_encoders: &[u16],
+        align_encoder: &BitTree<{ 1 << 4 }>,
+        pb: u32,
+    ) {
+        for pos_state in 0..1 << pb {
+            if self.match_len_left[pos_state] == 0 {
+                let match_len = &mut self.match_len[pos_state];
+                for (value, price) in match_len.iter_mut().enumerate() {
+                    *price = len_encoder.price(pos_state, value as u32);
+                }
+                self.match_len_left[pos_state] = LEN_PRICE_UPDATE;
+            }
+            if self.rep_len_left[pos_state] == 0 {
+                let rep_len = &mut self.rep_len[pos_state];
+                for (value, price) in rep_len.iter_mut().enumerate() {
+                    *price = rep_len_encoder.price(pos_state, value as u32);
+                }
+                self.rep_len_left[pos_state] = LEN_PRICE_UPDATE;
+            }
+        }
+
+        if self.align_left == 0 {
+            for (value, price) in self.align.iter_mut().enumerate() {
+                *price = align_encoder.reverse_price(value as u32);
+            }
+            self.align_left = ALIGN_PRICE_UPDATE;
+        }
+
+        if self.dist_left > 0 {
+            return;
+        }
+        self.dist_left = DIST_PRICE_UPDATE;
+        for (len_state, slot_encoder) in pos_slot_encoder.iter().enumerate() {
+            let slot = &mut self.slot[len_state];
+            for (pos_slot, price) in slot.iter_mut().enumerate() {
+                *price = slot_encoder.price(pos_slot as u32);
+                if pos_slot as u32 >= END_POS_MODEL_INDEX {
+                    *price += rangecoder::direct_bits_price(num_direct_bits(pos_slot as u32) - 4);
+                }
+            }
+            for distance in 0..FULL_DISTANCES as u32 {
+                let pos_slot = pos_slot(distance);
+                let mut price = slot[pos_slot as usize];
+                if pos_slot >= 4 {
+                    let num_direct_bits = num_direct_bits(pos_slot);
+                    let base = (2 ^ (pos_slot & 1)) << num_direct_bits;
+                    price += rangecoder::reverse_bit_tree_price(
+                        num_direct_bits,
+                        pos_encoders,
+                        (base - pos_slot) as usize,
+                        distance - base,
+                    );
+                }
+                self.full[len_state][distance as usize] = price;
+            }
+        }
+    }
+
+    /// Price of the length of a match of `len` bytes.
+    pub fn match_len(&self, pos_state: usize, len: usize) -> u32 {
+        self.match_len[pos_state][len - 2]
+    }
+
+    /// Price of the length of a repeated match of `len` bytes.
+    pub fn rep_len(&self, pos_state: usize, len: usize) -> u32 {
+        self.rep_len[pos_state][len - 2]
+    }
+
+    /// Price of the distance `distance + 1` of a match of `len` bytes.
+    pub fn distance(&self, distance: u32, len: usize) -> u32 {
+        let len_state = std::cmp::min(len - 2, 3);
+        if (distance as usize) < FULL_DISTANCES {
+            self.full[len_state][distance as usize]
+        } else {
+            self.slot[len_state][pos_slot(distance) as usize]
+                + self.align[(distance & 0xF) as usize]
+        }
+    }
+}
+
+impl std::fmt::Debug for Prices {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Prices").finish()
+    }
+}
+
+/// Distance slot of `distance`, minus one: two slots per bit length, told
+/// apart by the bit after the leading one.
+fn pos_slot(distance: u32) -> u32 {
+    if distance < 4 {
+        return distance;
+    }
+    let top_bit = 31 - distance.leading_zeros();
+    2 * top_bit + ((distance >> (top_bit - 1)) & 1)
+}
+
+/// Number of bits of the distances of `pos_slot` below the two leading ones.
+fn num_direct_bits(pos_slot: u32) -> usize {
+    ((pos_slot >> 1) - 1) as usize
+}
+
+/// Working memory of the optimal parser, kept from one parse to the next.
+#[derive(Debug)]
+pub(crate) struct Optimum {
+    pub prices: Prices,
+    /// Nodes of the current parse, one per position from the first one
+    pub nodes: Vec<Node>,
+    /// Matches of the current position
+    pub matches: Vec<Match>,
+    /// Steps found by the last parse, from the last one
+    pub path: Vec<Step>,
+}
+
+impl Optimum {
+    /// Number of bytes allocated at most for the position bits `pb`.
+    pub fn mem_usage(pb: u32) -> u64 {
+        let len_prices = 2 * (1 << pb) * (MAX_MATCH_LEN - 1) * std::mem::size_of::<u32>();
+        let nodes = (OPT_LEN + MAX_MATCH_LEN + 1) * std::mem::size_of::<Node>();
+        let matches = MAX_MATCH_LEN * std::mem::size_of::<Match>();
+        let path = OPT_LEN * std::mem::size_of::<Step>();
+        (std::mem::size_of::<Optimum>() + len_prices + nodes + matches + path) as u64
+    }
+
+    pub fn new(pb: u32) -> Self {
+        Self {
+            prices: Prices::new(pb),
+            nodes: Vec::new(),
+            matches: Vec::new(),
+            path: Vec::new(),
+        }
+    }
+
+    /// Start a parse from `state` and `rep`.
+    pub fn reset(&mut self, state: usize, rep: [u32; 4]) {
+        self.nodes.clear();
+        self.nodes.push(Node {
+            price: 0,
+            step: Step::Literal,
+            state,
+            rep,
+        });
+        self.path.clear();
+    }
+
+    /// Index of the farthest node reached so far.
+    pub fn frontier(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    /// Record `step` from an earlier node as the cheapest way to node
+    /// `index` if its total `price` is lower.
+    pub fn relax(&mut self, index: usize, price: u32, step: Step) {
+        if index >= self.nodes.len() {
+            self.nodes.resize(index + 1, Node::UNREACHED);
+        }
+        let node = &mut self.nodes[index];
+        if price < node.price {
+            node.price = price;
+            node.step = step;
+        }
+    }
+
+    /// Set the state and the repeated distances of node `index` from the
+    /// cheapest way to reach it.
+    pub fn settle(&mut self, index: usize) {
+        let node = self.nodes[index];
+        let prev = self.nodes[index - node.step.len()];
+        let (state, rep) = node.step.apply(prev.state, prev.rep);
+        self.nodes[index].state = state;
+        self.nodes[index].rep = rep;
+    }
+
+    /// Push the steps of the cheapest way to node `end` to `path`, from the
+    /// last one.
+    pub fn backtrack(&mut self, end: usize) {
+        let mut index = end;
+        while index > 0 {
+            let step = self.nodes[index].step;
+            self.path.push(step);
+            index -= step.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pos_slot() {
+        assert_eq!(pos_slot(0), 0);
+        assert_eq!(pos_slot(3), 3);
+        assert_eq!(pos_slot(4), 4);
+        assert_eq!(pos_slot(6), 5);
+        assert_eq!(pos_slot(127), 13);
+        assert_eq!(pos_slot(128), 14);
+        assert_eq!(pos_slot(0xFFFF_FFFF), 63);
+    }
+
+    #[test]
+    fn test_step_apply() {
+        let rep = [1, 2, 3, 4];
+        assert_eq!(Step::Literal.apply(8, rep), (5, rep));
+        assert_eq!(
+            Step::Rep { index: 2, len: 5 }.apply(0, rep),
+            (8, [3, 1, 2, 4])
+        );
+        assert_eq!(
+            Step::Match { rep0: 9, len: 5 }.apply(10, rep),
+            (10, [9, 1, 2, 3])
+        );
+        assert_eq!(Step::ShortRep.apply(7, rep), (11, rep));
+    }
+
+    #[test]
+    fn test_stale_prices() {
+        let len_encoder = LenEncoder::new();
+        let pos_slot_encoder = [
+            BitTree::new(),
+            BitTree::new(),
+            BitTree::new(),
+            BitTree::new(),
+        ];
+        let pos_encoders = [0x400; 115];
+        let align_encoder = BitTree::new();
+        let mut prices = Prices::new(2);
+        let update = |prices: &mut Prices| {
+            prices.update(
+                &len_encoder,
+                &len_encoder,
+                &pos_slot_encoder,
+                &pos_encoders,
+                &align_encoder,
+                2,
+            )
+        };
+
+        update(&mut prices);
+        assert_eq!(prices.match_len_left[..4], [LEN_PRICE_UPDATE; 4]);
+        assert_eq!(prices.match_len(0, 2), len_encoder.price(0, 0));
+
+        for _ in 0..DIST_PRICE_UPDATE {
+            prices.match_encoded(1, 10);
+        }
+        prices.rep_encoded(3);
+        assert_eq!(prices.dist_left, 0);
+        assert_eq!(prices.align_left, ALIGN_PRICE_UPDATE);
+        assert_eq!(
+            prices.match_len_left[1],
+            LEN_PRICE_UPDATE - DIST_PRICE_UPDATE
+        );
+        assert_eq!(prices.rep_len_left[3], LEN_PRICE_UPDATE - 1);
+
+        prices.match_encoded(0, 1000);
+        assert_eq!(prices.align_left, ALIGN_PRICE_UPDATE - 1);
+        update(&mut prices);
+        assert_eq!(prices.dist_left, DIST_PRICE_UPDATE);
+        assert_eq!(prices.align_left, ALIGN_PRICE_UPDATE - 1);
+        assert_eq!(prices.rep_len_left[3], LEN_PRICE_UPDATE - 1);
+    }
+}
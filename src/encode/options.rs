@@ -1,9 +1,388 @@
-/// Options for the `lzma_compress` function.
-#[derive(Clone, Copy, Debug, Default)]
+use crate::encode::{dumbencoder, matchfinder};
+use crate::error::{self, OptionsError};
+use crate::xz::CheckMethod;
+
+/// Smallest dictionary size accepted by the compressors, as in xz-utils.
+pub const DICT_SIZE_MIN: u32 = 0x1000;
+
+/// Options for the `lzma_compress`, `lzma2_compress_with_options` and
+/// `xz_compress_with_options` functions.
+#[derive(Clone, Copy, Debug)]
 pub struct Options {
     /// Defines whether the unpacked size should be written to the header.
-    /// The default is [`UnpackedSize::WriteToHeader(None)`].
+    /// The default is `UnpackedSize::WriteToHeader(None)`.
     pub unpacked_size: UnpackedSize,
+    /// Maximum number of bytes to write to the output. Compression aborts
+    /// with an [`OutputSizeExceeded`](crate::error::OutputSizeExceeded)
+    /// error as soon as a write would go beyond this budget, leaving a
+    /// truncated stream in the output. The default is `None`, i.e. no limit.
+    pub max_output_size: Option<u64>,
+    /// Dictionary size declared in the LZMA header or in the LZMA2 filter
+    /// properties of `.xz` blocks, which the decoder needs to allocate. For
+    /// `.xz`, it is rounded up to the next size that can be encoded. It must
+    /// be at least [`DICT_SIZE_MIN`]. The default is 8 MiB.
+    pub dict_size: u32,
+    /// Whether to shrink the dictionary to the input when its length is
+    /// known, i.e. for LZMA streams with a known unpacked size and for
+    /// slices compressed by `lzma_compress_to_vec`, `xz_compress_to_vec` and
+    /// `xz_compress_parallel`. A smaller dictionary lowers the memory that
+    /// the decoder allocates, without changing what the encoder can find.
+    /// See [`dict_size_for()`](Options::dict_size_for). The default is
+    /// false.
+    pub fit_dict_size: bool,
+    /// Integrity check of each `.xz` block. SHA-256 is not supported yet.
+    /// The default is `CheckMethod::None`.
+    pub check: CheckMethod,
+    /// Number of uncompressed bytes per `.xz` block, or `None` to write all
+    /// the data in a single block. Smaller blocks allow extracting parts of
+    /// the stream, at the cost of a few bytes per block. It must not be
+    /// zero. The default is `None`.
+    pub block_size: Option<u64>,
+    /// Filter applied to the data of `.xz` blocks before compression. The
+    /// default is `Filter::None`.
+    pub filter: Filter,
+    /// Algorithm looking for repeated data. The default is
+    /// [`MatchFinder::Hc4`].
+    pub match_finder: MatchFinder,
+    /// Length of the matches that the match finder takes without looking
+    /// for longer ones, in 3..=273. Longer nice lengths find longer matches
+    /// on redundant data, at the cost of speed. The default is 64.
+    pub nice_len: u32,
+    /// Longest match the encoder emits, in 2..=273, for decoders with small
+    /// buffers or bounded work per match. Longer repetitions are split into
+    /// several matches. The nice length is capped to it. The default is 273,
+    /// the longest match LZMA can encode.
+    pub max_match_len: u32,
+    /// Maximum number of candidates the match finder examines per position,
+    /// i.e. the length of the hash chains followed, or 0 to derive it from the
+    /// nice length as liblzma does: `4 + nice_len / 4` for
+    /// [`MatchFinder::Hc4`] and `16 + nice_len / 2` for
+    /// [`MatchFinder::Bt4`]. The default is 0.
+    pub depth: u32,
+    /// Number of bits of the hashes indexing the match finder, in 8..=24, or
+    /// 0 to derive it from the dictionary size: about one hash value per
+    /// byte of dictionary, with 12 to 20 bits. Each bit doubles the hash
+    /// table, of 8 bytes per value for [`MatchFinder::Hc4`] and 4 bytes for
+    /// [`MatchFinder::Bt4`]. Fewer bits save memory, at the cost of more
+    /// candidates sharing a hash and crowding out the better ones. See
+    /// [`match_finder_mem_usage()`](Options::match_finder_mem_usage). The
+    /// default is 0.
+    pub hash_bits: u32,
+    /// How the encoder picks between literals and matches. The default is
+    /// [`EncodeMode::Fast`].
+    pub mode: EncodeMode,
+    /// Number of high bits of the previous byte used as context to encode
+    /// literals, in 0..=8. The default is 3.
+    pub lc: u32,
+    /// Number of low bits of the position used as context to encode
+    /// literals, in 0..=4. LZMA2 and `.xz` streams also require `lc + lp`
+    /// to be at most 4. The default is 0.
+    pub lp: u32,
+    /// Number of low bits of the position used as context to encode matches
+    /// and literals, in 0..=4. Data made of 2^`pb`-byte records compresses
+    /// better with a matching `pb`. The default is 2.
+    pub pb: u32,
+    /// Value written in the LZMA header when the unpacked size is unknown,
+    /// i.e. with `UnpackedSize::WriteToHeader(None)`. The default is
+    /// [`UnknownSizeSentinel::Max`].
+    pub unknown_size_sentinel: UnknownSizeSentinel,
+    /// Whether LZMA streams end with an end-of-stream marker even when the
+    /// unpacked size is known, for consumers that rely on the marker
+    /// regardless of the size field. Streams of unknown size always end
+    /// with one, and LZMA2 and `.xz` streams never do. The default is false.
+    pub end_marker: bool,
+    /// Whether the output only depends on the input and the options, byte
+    /// for byte, e.g. for reproducible builds. Otherwise, when
+    /// [`block_size`](Options::block_size) isn't set,
+    /// [`xz_compress_parallel`](crate::xz_compress_parallel) splits inputs
+    /// too small to keep every thread busy into smaller blocks, and the
+    /// output depends on the number of threads. The default is true.
+    pub deterministic: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            unpacked_size: UnpackedSize::default(),
+            max_output_size: None,
+            dict_size: 0x0080_0000,
+            fit_dict_size: false,
+            check: CheckMethod::None,
+            block_size: None,
+            filter: Filter::None,
+            match_finder: MatchFinder::default(),
+            nice_len: 64,
+            max_match_len: 273,
+            depth: 0,
+            hash_bits: 0,
+            mode: EncodeMode::default(),
+            lc: 3,
+            lp: 0,
+            pb: 2,
+            unknown_size_sentinel: UnknownSizeSentinel::default(),
+            end_marker: false,
+            deterministic: true,
+        }
+    }
+}
+
+impl Options {
+    /// Options of the compression level `preset`, from 0 (fastest) to 9
+    /// (smallest output), tuned like the presets of xz-utils.
+    ///
+    /// The dictionary grows from 256 KiB at level 0 to 64 MiB at level 9.
+    /// Levels 0 to 3 use the fast mode with hash chains, searching deeper as
+    /// the level increases. Levels 4 to 9 use the normal mode with binary
+    /// trees and longer nice lengths. Unlike xz-utils, level 0 uses hash
+    /// chains of 4 bytes, as no 3-byte match finder is available.
+    ///
+    /// The other options keep their default values. The preset must be at
+    /// most 9.
+    pub fn with_preset(preset: u32) -> error::Result<Options> {
+        let dict_size = match preset {
+            0 => 0x0004_0000,
+            1 => 0x0010_0000,
+            2 => 0x0020_0000,
+            3 | 4 => 0x0040_0000,
+            5 | 6 => 0x0080_0000,
+            7 => 0x0100_0000,
+            8 => 0x0200_0000,
+            9 => 0x0400_0000,
+            _ => {
+                return Err(OptionsError::new(
+                    "preset",
+                    format!("Preset {} must be in 0..=9", preset),
+                )
+                .into())
+            }
+        };
+        let (mode, match_finder, nice_len, depth) = match preset {
+            0 => (EncodeMode::Fast, MatchFinder::Hc4, 128, 4),
+            1 => (EncodeMode::Fast, MatchFinder::Hc4, 128, 8),
+            2 => (EncodeMode::Fast, MatchFinder::Hc4, 273, 24),
+            3 => (EncodeMode::Fast, MatchFinder::Hc4, 273, 48),
+            4 => (EncodeMode::Normal, MatchFinder::Bt4, 16, 0),
+            5 => (EncodeMode::Normal, MatchFinder::Bt4, 32, 0),
+            _ => (EncodeMode::Normal, MatchFinder::Bt4, 64, 0),
+        };
+        Ok(Options {
+            dict_size,
+            match_finder,
+            nice_len,
+            depth,
+            mode,
+            ..Options::default()
+        })
+    }
+
+    /// Options matching the `.xz` output of `xz -T1 -<preset>` from
+    /// xz-utils 5.x, for presets 0 to 9: CRC64 check, no filter other than
+    /// LZMA2, a single block and the settings of
+    /// [`with_preset()`](Options::with_preset).
+    ///
+    /// The output is byte-identical to xz-utils only where both store the
+    /// data as is: for empty input, and for incompressible input (e.g.
+    /// already compressed or random data) of up to about 60 KiB, which fits
+    /// in one chunk. Matching the compressed chunks is out of scope, for the
+    /// fast mode of presets 0 to 3 as well as for the normal mode of the
+    /// others: the match finders and parsers of this crate pick different
+    /// matches than those of liblzma, and following them choice by choice
+    /// would tie the encoder to one xz-utils release. Note that since 5.4,
+    /// `xz` compresses with multiple threads by default and then records the
+    /// block sizes in the block header, hence the `-T1`.
+    ///
+    /// The preset must be at most 9; the `-e` variants are not distinguished
+    /// as they only affect the compressed chunks.
+    pub fn xz_utils(preset: u32) -> error::Result<Options> {
+        Ok(Options {
+            check: CheckMethod::Crc64,
+            ..Options::with_preset(preset)?
+        })
+    }
+
+    /// Approximate number of bytes allocated by the match finder with these
+    /// options: its hash table, and the links between the positions of the
+    /// dictionary, which grow as data is compressed up to the dictionary
+    /// size.
+    pub fn match_finder_mem_usage(&self) -> u64 {
+        matchfinder::mem_usage(self)
+    }
+
+    /// Approximate number of bytes allocated by the LZMA compressor with these
+    /// options, like `lzma_raw_encoder_memusage()` of liblzma, to check
+    /// against a memory budget before compressing: the window of input
+    /// bytes, which holds the dictionary, the match finder, see
+    /// [`match_finder_mem_usage()`](Options::match_finder_mem_usage), and the
+    /// probability and price tables.
+    ///
+    /// Most of it is allocated as data is compressed, so small inputs take
+    /// much less.
+    pub fn mem_usage(&self) -> u64 {
+        dumbencoder::mem_usage(self)
+    }
+
+    /// Dictionary size used for `len` bytes of input: with
+    /// [`fit_dict_size`](Options::fit_dict_size), the next power of two
+    /// holding the input, but at least [`DICT_SIZE_MIN`] and at most
+    /// [`dict_size`](Options::dict_size). Otherwise, `dict_size` as is.
+    pub fn dict_size_for(&self, len: u64) -> u32 {
+        if !self.fit_dict_size || len >= self.dict_size as u64 {
+            return self.dict_size;
+        }
+        let fitted = std::cmp::max(len as u32, DICT_SIZE_MIN).next_power_of_two();
+        std::cmp::min(fitted, self.dict_size)
+    }
+
+    /// These options with the dictionary size for `len` bytes of input.
+    pub(crate) fn fit_to(&self, len: u64) -> Options {
+        Options {
+            dict_size: self.dict_size_for(len),
+            ..*self
+        }
+    }
+
+    /// Check the options, as the compressors do before writing anything.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.dict_size < DICT_SIZE_MIN {
+            return Err(OptionsError::new(
+                "dict_size",
+                format!(
+                    "Dictionary size {} is below the minimum of {}",
+                    self.dict_size, DICT_SIZE_MIN
+                ),
+            ));
+        }
+        if !(3..=273).contains(&self.nice_len) {
+            return Err(OptionsError::new(
+                "nice_len",
+                format!("Nice length {} must be in 3..=273", self.nice_len),
+            ));
+        }
+        if !(2..=273).contains(&self.max_match_len) {
+            return Err(OptionsError::new(
+                "max_match_len",
+                format!(
+                    "Maximum match length {} must be in 2..=273",
+                    self.max_match_len
+                ),
+            ));
+        }
+        if self.hash_bits != 0 && !matchfinder::HASH_BITS_RANGE.contains(&self.hash_bits) {
+            return Err(OptionsError::new(
+                "hash_bits",
+                format!("Hash bits {} must be 0 or in 8..=24", self.hash_bits),
+            ));
+        }
+        if self.lc > 8 {
+            return Err(OptionsError::new(
+                "lc",
+                format!("Literal context bits {} must be in 0..=8", self.lc),
+            ));
+        }
+        if self.lp > 4 {
+            return Err(OptionsError::new(
+                "lp",
+                format!("Literal position bits {} must be in 0..=4", self.lp),
+            ));
+        }
+        if self.pb > 4 {
+            return Err(OptionsError::new(
+                "pb",
+                format!("Position bits {} must be in 0..=4", self.pb),
+            ));
+        }
+        if self.check == CheckMethod::Sha256 {
+            return Err(OptionsError::new(
+                "check",
+                "Unsupported SHA-256 checksum (not yet implemented)".to_string(),
+            ));
+        }
+        if let Filter::Delta(distance) = self.filter {
+            if !(1..=256).contains(&distance) {
+                return Err(OptionsError::new(
+                    "filter",
+                    format!("Delta distance {} must be in 1..=256", distance),
+                ));
+            }
+        }
+        if self.block_size == Some(0) {
+            return Err(OptionsError::new(
+                "block_size",
+                "Block size must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the literal coder properties against the stricter limit of
+    /// LZMA2, as the LZMA2 and `.xz` compressors do.
+    pub(crate) fn validate_lzma2(&self) -> Result<(), OptionsError> {
+        if self.lc + self.lp > 4 {
+            return Err(OptionsError::new(
+                "lc",
+                format!(
+                    "Literal context and position bits {} + {} exceed 4 in LZMA2",
+                    self.lc, self.lp
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Filter applied to the data before LZMA2 compression in `.xz` blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// No filter.
+    None,
+    /// Delta filter, replacing each byte with its difference to the byte at
+    /// the given distance before, in 1..=256. This helps with arrays of
+    /// fixed-size numbers, such as uncompressed audio samples.
+    Delta(u16),
+    /// Pick the filter of each block from the
+    /// [`analyze`](crate::compress::analyze) result on its first bytes.
+    Auto,
+}
+
+/// Match finder of the encoder, named after their liblzma counterparts.
+///
+/// Both index the positions by a hash of their first 4 bytes, so matches of
+/// 3 bytes are rarely found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MatchFinder {
+    /// Hash chains, listing the positions with the same hash from the most
+    /// recent one. Fast, and uses 4 bytes per byte of dictionary.
+    #[default]
+    Hc4,
+    /// Binary trees of the positions with the same hash, sorted by the bytes
+    /// that follow. Finds longer matches for a better ratio, at about half
+    /// the speed, and uses 8 bytes per byte of dictionary.
+    Bt4,
+}
+
+/// Parsing strategy of the encoder, named after the modes of liblzma.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EncodeMode {
+    /// No parsing: encode every byte as a literal, without looking for
+    /// matches. The output is a valid stream, only compressed by the
+    /// probabilities of the literals, written at the highest speed and with
+    /// no match finder memory, e.g. to generate test streams or to frame
+    /// data that is already compressed.
+    Store,
+    /// Greedy parsing: take the longest match at each position, preferring
+    /// repeated distances when they are nearly as long.
+    #[default]
+    Fast,
+    /// Lazy parsing: like [`EncodeMode::Fast`], but before taking a match,
+    /// look up the match at the next byte, and emit a literal instead if
+    /// that one is longer. Text comes out about 2% smaller than with greedy
+    /// parsing, at four fifths of the speed.
+    Lazy,
+    /// Optimal parsing: estimate the cost in bits of the literals, matches
+    /// and repeated matches from the current probabilities, and pick the
+    /// cheapest sequence over up to 4 KiB ahead. Redundant data comes out
+    /// about a tenth smaller, at a third to a fifth of the speed.
+    Normal,
 }
 
 /// Alternatives for handling unpacked size.
@@ -22,6 +401,21 @@ pub enum UnpackedSize {
     SkipWritingToHeader,
 }
 
+/// Value of the unpacked size field of an LZMA header whose size is unknown.
+/// The stream ends with an end-of-stream marker either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnknownSizeSentinel {
+    /// `0xFFFF_FFFF_FFFF_FFFF`, as specified and written by xz-utils and the
+    /// LZMA SDK.
+    #[default]
+    Max,
+    /// Zero, which some legacy consumers expect instead. Other decoders read
+    /// it as an empty stream: decompress such streams with
+    /// [`zero_size_is_unknown`](crate::decompress::Options::zero_size_is_unknown)
+    /// set.
+    Zero,
+}
+
 impl Default for UnpackedSize {
     fn default() -> UnpackedSize {
         UnpackedSize::WriteToHeader(None)
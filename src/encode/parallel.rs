@@ -0,0 +1,106 @@
+//! Parallel `.xz` compression of in-memory data.
+
+use crate::compress::Options;
+use crate::encode::{util, xz};
+use crate::error;
+use crate::xz::index::IndexRecord;
+use crate::xz::StreamFlags;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Smallest shard size used when [`Options::block_size`] is not set.
+const MIN_SHARD_SIZE: usize = 1 << 20;
+
+/// Smallest shard size used to keep every thread busy, when
+/// [`Options::deterministic`] is not set.
+const MIN_THREAD_SHARD_SIZE: usize = 1 << 18;
+
+/// Blocks and index records of a compressed shard.
+type Shard = (Vec<u8>, Vec<IndexRecord>);
+
+/// Compress `data` to an `.xz` stream on `parallelism` threads, as
+/// documented on [`xz_compress_parallel`](crate::xz_compress_parallel).
+pub fn compress_parallel(
+    data: &[u8],
+    options: &Options,
+    parallelism: usize,
+) -> error::Result<Vec<u8>> {
+    options.validate()?;
+    options.validate_lzma2()?;
+    let options = options.fit_to(data.len() as u64);
+    let parallelism = if parallelism == 0 {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        parallelism
+    };
+    let shard_size = match options.block_size {
+        Some(block_size) => std::cmp::min(block_size, usize::MAX as u64) as usize,
+        None => {
+            let shard_size = std::cmp::max(3 * options.dict_size as usize, MIN_SHARD_SIZE);
+            if options.deterministic {
+                shard_size
+            } else {
+                let per_thread = (data.len() + parallelism - 1) / parallelism;
+                shard_size.min(per_thread.max(MIN_THREAD_SHARD_SIZE))
+            }
+        }
+    };
+    let options = Options {
+        block_size: Some(shard_size as u64),
+        ..options
+    };
+    let shards: Vec<&[u8]> = data.chunks(shard_size).collect();
+    let parallelism = parallelism.min(shards.len());
+
+    let mut results: Vec<Option<error::Result<Shard>>> = Vec::new();
+    results.resize_with(shards.len(), || None);
+
+    if parallelism <= 1 {
+        for (i, shard) in shards.iter().enumerate() {
+            results[i] = Some(xz::encode_blocks(shard, &options));
+        }
+    } else {
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..parallelism)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut encoded = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let shard = match shards.get(i) {
+                                Some(shard) => shard,
+                                None => break,
+                            };
+                            encoded.push((i, xz::encode_blocks(shard, &options)));
+                        }
+                        encoded
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                for (i, result) in worker.join().expect("parallel compression thread panicked") {
+                    results[i] = Some(result);
+                }
+            }
+        });
+    }
+
+    let stream_flags = StreamFlags {
+        check_method: options.check,
+    };
+    let mut output = util::LimitWrite::new(Vec::new(), options.max_output_size);
+    xz::write_header(&mut output, stream_flags)?;
+    let mut records = Vec::with_capacity(shards.len());
+    for result in results {
+        let (blocks, shard_records) = result.expect("every shard is compressed")?;
+        lzma_info!("Parallel: shard of {} bytes", blocks.len());
+        output.write_all(&blocks)?;
+        records.extend(shard_records);
+    }
+    let index_size = xz::write_index(&mut output, &records)?;
+    xz::write_footer(&mut output, stream_flags, index_size)?;
+    Ok(output.into_inner())
+}
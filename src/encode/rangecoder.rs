@@ -1,26 +1,106 @@
 use byteorder::WriteBytesExt;
 use std::io;
 
-#[cfg(test)]
 use crate::util::const_assert;
 
-pub struct RangeEncoder<'a, W>
+/// Number of fractional bits of the prices.
+const PRICE_SHIFT_BITS: u32 = 4;
+/// Number of low bits of the probabilities ignored by the price table.
+const PRICE_REDUCING_BITS: u32 = 4;
+
+/// Price of a bit of each probability of it, rounded to the table step.
+const PRICES: [u32; 0x800 >> PRICE_REDUCING_BITS] = price_table();
+
+/// Compute `-log2(p / 2048)` in sixteenths of a bit for the middle of each
+/// step of the probabilities, with integers only, as liblzma does: squaring
+/// the probability four times yields four more bits of its logarithm.
+const fn price_table() -> [u32; 0x800 >> PRICE_REDUCING_BITS] {
+    let mut table = [0; 0x800 >> PRICE_REDUCING_BITS];
+    let mut i = (1 << PRICE_REDUCING_BITS) / 2;
+    while i < 0x800 {
+        let mut w: u32 = i;
+        let mut bit_count = 0;
+        let mut j = 0;
+        while j < PRICE_SHIFT_BITS {
+            w *= w;
+            bit_count <<= 1;
+            while w >= 1 << 16 {
+                w >>= 1;
+                bit_count += 1;
+            }
+            j += 1;
+        }
+        table[(i >> PRICE_REDUCING_BITS) as usize] = (11 << PRICE_SHIFT_BITS) - 15 - bit_count;
+        i += 1 << PRICE_REDUCING_BITS;
+    }
+    table
+}
+
+/// Approximate cost of encoding `bit` with the probability `prob` of it
+/// being zero, out of 2048, in sixteenths of a bit.
+pub(crate) fn bit_price(prob: u16, bit: bool) -> u32 {
+    let p = if bit { 0x800 - prob } else { prob };
+    PRICES[(p >> PRICE_REDUCING_BITS) as usize]
+}
+
+/// Cost of `num_bits` bits encoded with fixed probabilities of one half.
+pub(crate) fn direct_bits_price(num_bits: usize) -> u32 {
+    (num_bits as u32) << PRICE_SHIFT_BITS
+}
+
+/// Cost of encoding `value` with `encode_bit_tree()`.
+fn bit_tree_price(num_bits: usize, probs: &[u16], value: u32) -> u32 {
+    let mut price = 0;
+    let mut tmp: usize = 1;
+    for i in 0..num_bits {
+        let bit = ((value >> (num_bits - i - 1)) & 1) != 0;
+        price += bit_price(probs[tmp], bit);
+        tmp = (tmp << 1) ^ (bit as usize);
+    }
+    price
+}
+
+/// Cost of encoding `value` with `encode_reverse_bit_tree()`.
+pub(crate) fn reverse_bit_tree_price(
+    num_bits: usize,
+    probs: &[u16],
+    offset: usize,
+    mut value: u32,
+) -> u32 {
+    let mut price = 0;
+    let mut tmp: usize = 1;
+    for _ in 0..num_bits {
+        let bit = (value & 1) != 0;
+        value >>= 1;
+        price += bit_price(probs[offset + tmp], bit);
+        tmp = (tmp << 1) ^ (bit as usize);
+    }
+    price
+}
+
+/// Range encoder writing LZMA-style range-coded data.
+pub struct RangeEncoder<W>
 where
-    W: 'a + io::Write,
+    W: io::Write,
 {
-    stream: &'a mut W,
+    stream: W,
     range: u32,
     low: u64,
+    /// First byte not yet written, which a carry may still increment
     cache: u8,
-    cachesz: u32,
+    /// Number of bytes held back: the cache byte followed by bytes of 0xFF,
+    /// which a carry turns into zeros. The run has no bound in theory, so
+    /// it's counted in 64 bits for outputs beyond 4 GiB.
+    cachesz: u64,
 }
 
-impl<'a, W> RangeEncoder<'a, W>
+impl<W> RangeEncoder<W>
 where
     W: io::Write,
 {
+    /// Initialize the encoder, writing to `stream`.
     #[allow(clippy::let_and_return)]
-    pub fn new(stream: &'a mut W) -> Self {
+    pub fn new(stream: W) -> Self {
         let enc = Self {
             stream,
             range: 0xFFFF_FFFF,
@@ -32,19 +112,38 @@ where
         enc
     }
 
+    /// Get a reference to the underlying stream.
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the underlying stream.
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.stream
+    }
+
+    /// Registers of the encoder: `low`, `range`, the cache byte and the
+    /// number of bytes held back.
+    pub(crate) fn registers(&self) -> (u64, u32, u8, u64) {
+        (self.low, self.range, self.cache, self.cachesz)
+    }
+
+    /// Restore registers returned by [`RangeEncoder::registers()`].
+    pub(crate) fn set_registers(&mut self, (low, range, cache, cachesz): (u64, u32, u8, u64)) {
+        self.low = low;
+        self.range = range;
+        self.cache = cache;
+        self.cachesz = cachesz;
+    }
+
     fn write_low(&mut self) -> io::Result<()> {
         if self.low < 0xFF00_0000 || self.low > 0xFFFF_FFFF {
-            let mut tmp = self.cache;
-            loop {
-                let byte = tmp.wrapping_add((self.low >> 32) as u8);
-                self.stream.write_u8(byte)?;
-                lzma_debug!("> byte: {:02x}", byte);
-                tmp = 0xFF;
-                self.cachesz -= 1;
-                if self.cachesz == 0 {
-                    break;
-                }
-            }
+            let carry = (self.low >> 32) as u8;
+            let byte = self.cache.wrapping_add(carry);
+            self.stream.write_u8(byte)?;
+            lzma_debug!("> byte: {:02x}", byte);
+            self.write_run(0xFF_u8.wrapping_add(carry), self.cachesz - 1)?;
+            self.cachesz = 0;
             self.cache = (self.low >> 24) as u8;
         }
 
@@ -53,6 +152,22 @@ where
         Ok(())
     }
 
+    /// Write `count` times the same byte.
+    fn write_run(&mut self, byte: u8, mut count: u64) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        lzma_debug!("> {} byte(s): {:02x}", count, byte);
+        let buf = [byte; 4096];
+        while count > 0 {
+            let len = std::cmp::min(count, buf.len() as u64) as usize;
+            self.stream.write_all(&buf[..len])?;
+            count -= len as u64;
+        }
+        Ok(())
+    }
+
+    /// Flush the pending bits, ending the range-coded data.
     pub fn finish(&mut self) -> io::Result<()> {
         for _ in 0..5 {
             self.write_low()?;
@@ -82,10 +197,22 @@ where
             );
         }
         lzma_trace!("  {{ range: {:08x}, low: {:010x} }}", self.range, self.low);
+        lzma_check!(
+            self.range >= 0x0100_0000,
+            "range {:08x} not normalized",
+            self.range
+        );
         Ok(())
     }
 
+    /// Encode a bit with the adaptive probability `prob` of it being zero,
+    /// out of 2048, and update the probability.
     pub fn encode_bit(&mut self, prob: &mut u16, bit: bool) -> io::Result<()> {
+        lzma_check!(
+            *prob > 0 && *prob < 0x800,
+            "probability {} out of bounds",
+            prob
+        );
         let bound: u32 = (self.range >> 11) * (*prob as u32);
         lzma_trace!(
             "  bound: {:08x}, prob: {:04x}, bit: {}",
@@ -106,14 +233,13 @@ where
         self.normalize()
     }
 
-    #[cfg(test)]
     fn encode_bit_tree(
         &mut self,
         num_bits: usize,
         probs: &mut [u16],
         value: u32,
     ) -> io::Result<()> {
-        debug_assert!(value.leading_zeros() as usize + num_bits >= 32);
+        lzma_check!(value.leading_zeros() as usize + num_bits >= 32);
         let mut tmp: usize = 1;
         for i in 0..num_bits {
             let bit = ((value >> (num_bits - i - 1)) & 1) != 0;
@@ -123,15 +249,27 @@ where
         Ok(())
     }
 
-    #[cfg(test)]
-    pub fn encode_reverse_bit_tree(
+    /// Encode the `num_bits` low bits of `value`, most significant first,
+    /// with fixed probabilities of one half.
+    pub(crate) fn encode_direct_bits(&mut self, num_bits: usize, value: u32) -> io::Result<()> {
+        for i in (0..num_bits).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += self.range as u64;
+            }
+            self.normalize()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn encode_reverse_bit_tree(
         &mut self,
         num_bits: usize,
         probs: &mut [u16],
         offset: usize,
         mut value: u32,
     ) -> io::Result<()> {
-        debug_assert!(value.leading_zeros() as usize + num_bits >= 32);
+        lzma_check!(value.leading_zeros() as usize + num_bits >= 32);
         let mut tmp: usize = 1;
         for _ in 0..num_bits {
             let bit = (value & 1) != 0;
@@ -143,13 +281,11 @@ where
     }
 }
 
-#[cfg(test)]
 #[derive(Debug, Clone)]
-pub struct BitTree<const PROBS_ARRAY_LEN: usize> {
+pub(crate) struct BitTree<const PROBS_ARRAY_LEN: usize> {
     probs: [u16; PROBS_ARRAY_LEN],
 }
 
-#[cfg(test)]
 impl<const PROBS_ARRAY_LEN: usize> BitTree<PROBS_ARRAY_LEN> {
     pub fn new() -> Self {
         // The validity of PROBS_ARRAY_LEN is checked at compile-time with a macro
@@ -179,7 +315,7 @@ impl<const PROBS_ARRAY_LEN: usize> BitTree<PROBS_ARRAY_LEN> {
     // NUM_BITS can be derived by the number of trailing zeroes.
     const NUM_BITS: usize = PROBS_ARRAY_LEN.trailing_zeros() as usize;
 
-    pub fn encode<W: io::Write>(
+    pub(crate) fn encode<W: io::Write>(
         &mut self,
         rangecoder: &mut RangeEncoder<W>,
         value: u32,
@@ -187,16 +323,36 @@ impl<const PROBS_ARRAY_LEN: usize> BitTree<PROBS_ARRAY_LEN> {
         rangecoder.encode_bit_tree(Self::NUM_BITS, &mut self.probs, value)
     }
 
-    pub fn encode_reverse<W: io::Write>(
+    pub(crate) fn encode_reverse<W: io::Write>(
         &mut self,
         rangecoder: &mut RangeEncoder<W>,
         value: u32,
     ) -> io::Result<()> {
         rangecoder.encode_reverse_bit_tree(Self::NUM_BITS, &mut self.probs, 0, value)
     }
+
+    pub(crate) fn probs(&self) -> &[u16] {
+        &self.probs
+    }
+
+    pub(crate) fn probs_mut(&mut self) -> &mut [u16] {
+        &mut self.probs
+    }
+
+    /// Cost of encoding `value` with [`BitTree::encode()`].
+    pub(crate) fn price(&self, value: u32) -> u32 {
+        bit_tree_price(Self::NUM_BITS, &self.probs, value)
+    }
+
+    /// Cost of encoding `value` with [`BitTree::encode_reverse()`].
+    pub(crate) fn reverse_price(&self, value: u32) -> u32 {
+        reverse_bit_tree_price(Self::NUM_BITS, &self.probs, 0, value)
+    }
 }
 
-#[cfg(test)]
+/// Encoder of LZMA match lengths, minus the minimum match length of 2, the
+/// counterpart of [`LenDecoder`](crate::decompress::raw::LenDecoder).
+#[derive(Debug)]
 pub struct LenEncoder {
     choice: u16,
     choice2: u16,
@@ -205,8 +361,8 @@ pub struct LenEncoder {
     high_coder: BitTree<{ 1 << 8 }>,
 }
 
-#[cfg(test)]
 impl LenEncoder {
+    /// Create an encoder with all probabilities at one half.
     pub fn new() -> Self {
         LenEncoder {
             choice: 0x400,
@@ -251,6 +407,32 @@ impl LenEncoder {
         }
     }
 
+    /// Probabilities of the encoder, in a fixed order.
+    pub(crate) fn probs(&self) -> Vec<&[u16]> {
+        let mut probs = vec![
+            std::slice::from_ref(&self.choice),
+            std::slice::from_ref(&self.choice2),
+        ];
+        probs.extend(self.low_coder.iter().map(BitTree::probs));
+        probs.extend(self.mid_coder.iter().map(BitTree::probs));
+        probs.push(self.high_coder.probs());
+        probs
+    }
+
+    /// Probabilities of the encoder, in the order of [`LenEncoder::probs()`].
+    pub(crate) fn probs_mut(&mut self) -> Vec<&mut [u16]> {
+        let mut probs = vec![
+            std::slice::from_mut(&mut self.choice),
+            std::slice::from_mut(&mut self.choice2),
+        ];
+        probs.extend(self.low_coder.iter_mut().map(BitTree::probs_mut));
+        probs.extend(self.mid_coder.iter_mut().map(BitTree::probs_mut));
+        probs.push(self.high_coder.probs_mut());
+        probs
+    }
+
+    /// Encode `value`, which must be below 272, in the context of
+    /// `pos_state`, which must be below 16.
     pub fn encode<W: io::Write>(
         &mut self,
         rangecoder: &mut RangeEncoder<W>,
@@ -271,6 +453,39 @@ impl LenEncoder {
 
         self.high_coder.encode(rangecoder, value - 16)
     }
+
+    /// Cost of encoding `value` in the context of `pos_state`, in sixteenths
+    /// of a bit.
+    pub(crate) fn price(&self, pos_state: usize, value: u32) -> u32 {
+        if value < 8 {
+            return bit_price(self.choice, false) + self.low_coder[pos_state].price(value);
+        }
+        let price = bit_price(self.choice, true);
+        if value < 16 {
+            return price
+                + bit_price(self.choice2, false)
+                + self.mid_coder[pos_state].price(value - 8);
+        }
+        price + bit_price(self.choice2, true) + self.high_coder.price(value - 16)
+    }
+}
+
+impl Default for LenEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W> std::fmt::Debug for RangeEncoder<W>
+where
+    W: io::Write,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RangeEncoder")
+            .field("range", &self.range)
+            .field("low", &self.low)
+            .finish()
+    }
 }
 
 #[cfg(test)]
@@ -300,6 +515,17 @@ mod test {
         assert!(decoder.is_finished_ok().unwrap());
     }
 
+    #[test]
+    fn test_bit_price() {
+        assert_eq!(bit_price(0x400, false), 16);
+        assert_eq!(bit_price(0x400, true), 16);
+        for prob in (0x100..0x700).step_by(7) {
+            let exact = -(prob as f64 / 2048.0).log2() * 16.0;
+            let price = bit_price(prob, false) as f64;
+            assert!((price - exact).abs() < 1.5, "{} {} {}", prob, price, exact);
+        }
+    }
+
     #[test]
     fn test_encode_decode_zeros() {
         encode_decode(0x400, &[false; 10000]);
@@ -310,6 +536,54 @@ mod test {
         encode_decode(0x400, &[true; 10000]);
     }
 
+    /// Sink checking that it receives one byte followed by a run of another.
+    struct RunSink {
+        first: Option<u8>,
+        run: u8,
+        len: u64,
+    }
+
+    impl io::Write for RunSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut run = buf;
+            if self.first.is_none() {
+                self.first = Some(buf[0]);
+                run = &buf[1..];
+            }
+            if let (Some(&first), Some(&last)) = (run.first(), run.last()) {
+                assert_eq!((first, last), (self.run, self.run));
+            }
+            self.len += run.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_carry_beyond_4gib() {
+        // A carry reaching the cache byte after more than 4 GiB of held
+        // back 0xFF bytes, as a long enough output could produce.
+        let run_len = (1 << 32) + 5;
+        let mut encoder = RangeEncoder::new(RunSink {
+            first: None,
+            run: 0x00,
+            len: 0,
+        });
+        encoder.cache = 0x12;
+        encoder.cachesz = run_len + 1;
+        encoder.low = 0x1_00AB_CDEF;
+        encoder.write_low().unwrap();
+
+        let sink = encoder.get_mut();
+        assert_eq!(sink.first, Some(0x13));
+        assert_eq!(sink.len, run_len);
+        assert_eq!(encoder.cache, 0x00);
+        assert_eq!(encoder.cachesz, 1);
+    }
+
     fn encode_decode_bittree<const PROBS_LEN: usize>(values: &[u32]) {
         let mut buf: Vec<u8> = Vec::new();
 
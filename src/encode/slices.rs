@@ -0,0 +1,104 @@
+//! Input made of several byte slices.
+
+use std::io;
+
+/// [`io::BufRead`] over a sequence of byte slices, such as the pieces of a
+/// rope or scatter buffers, to compress them as one stream without
+/// concatenating them first.
+///
+/// Each slice is handed out as is by `fill_buf()`, and empty slices are
+/// skipped, so the compressors see a contiguous input.
+///
+/// The match finder doesn't need to know about the segments: the compressor
+/// copies every input, contiguous or not, into its window of recent bytes,
+/// and matches are searched for there, including matches across the
+/// boundaries of the slices. There is no match finder working on the
+/// segments in place: the copy is one pass over the input, cheap next to the
+/// hashing and the match searches, and the contiguous window keeps them
+/// simple and fast. Other segmented buffers only need to implement
+/// [`io::BufRead`] the same way, handing out their chunks in order, as e.g.
+/// the `reader()` adapter of the `bytes` crate's `Buf` does.
+pub struct SliceReader<'a, I>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    /// Remaining slices
+    slices: I,
+    /// Unread part of the current slice
+    current: &'a [u8],
+}
+
+impl<'a, I> SliceReader<'a, I>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    /// Read the concatenation of the given `slices`.
+    pub fn new<T>(slices: T) -> Self
+    where
+        T: IntoIterator<IntoIter = I>,
+    {
+        Self {
+            slices: slices.into_iter(),
+            current: &[],
+        }
+    }
+}
+
+impl<'a, I> io::Read for SliceReader<'a, I>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = io::Read::read(&mut io::BufRead::fill_buf(self)?, buf)?;
+        io::BufRead::consume(self, n);
+        Ok(n)
+    }
+}
+
+impl<'a, I> io::BufRead for SliceReader<'a, I>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.current.is_empty() {
+            match self.slices.next() {
+                Some(slice) => self.current = slice,
+                None => break,
+            }
+        }
+        Ok(self.current)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.current = &self.current[amt..];
+    }
+}
+
+impl<'a, I> std::fmt::Debug for SliceReader<'a, I>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("SliceReader")
+            .field("current", &self.current.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_concatenation() {
+        let slices: [&[u8]; 4] = [b"ab", b"", b"c", b"defg"];
+        let mut reader = SliceReader::new(slices.iter().copied());
+        let mut buf = [0u8; 3];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ab");
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"cdefg");
+    }
+}
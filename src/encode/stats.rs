@@ -0,0 +1,39 @@
+//! Statistics of a compression run.
+
+use crate::{CompressedSize, UncompressedSize};
+use std::time::Duration;
+
+/// Summary of a compression run, returned by
+/// [`compress_with_stats`](crate::compress_with_stats) and the encoders'
+/// `stats()` methods.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressStats {
+    /// Number of uncompressed bytes read.
+    pub in_bytes: UncompressedSize,
+    /// Number of compressed bytes written, headers included.
+    pub out_bytes: CompressedSize,
+    /// Number of bytes encoded as LZMA literals. Uncompressed LZMA2 chunks
+    /// contain neither literals nor matches.
+    pub literals: u64,
+    /// Number of LZMA matches, including repeated ones.
+    pub matches: u64,
+    /// Number of repeated matches among `matches`: matches at one of the
+    /// last four distances, including the single-byte ones.
+    pub rep_matches: u64,
+    /// Number of LZMA2 chunks, zero for LZMA.
+    pub chunks: u64,
+    /// Time spent in the encoder.
+    pub duration: Duration,
+}
+
+impl CompressStats {
+    /// Compressed size relative to the uncompressed size, above 1 for
+    /// incompressible data. Empty inputs have a ratio of 0.
+    pub fn ratio(&self) -> f64 {
+        if self.in_bytes.0 == 0 {
+            0.0
+        } else {
+            self.out_bytes.0 as f64 / self.in_bytes.0 as f64
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::error::OutputSizeExceeded;
 use std::io;
 
 /// An [`io::Write`] computing a digest on the bytes written.
@@ -74,3 +75,65 @@ where
         self.write.flush()
     }
 }
+
+/// An [`io::Write`] refusing writes beyond an optional limit.
+#[derive(Debug)]
+pub struct LimitWrite<W>
+where
+    W: io::Write,
+{
+    /// Underlying writer
+    write: W,
+    /// Number of bytes written
+    count: u64,
+    /// Maximum number of bytes to write
+    limit: Option<u64>,
+}
+
+impl<W> LimitWrite<W>
+where
+    W: io::Write,
+{
+    pub fn new(write: W, limit: Option<u64>) -> Self {
+        Self {
+            write,
+            count: 0,
+            limit,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.write
+    }
+
+    pub fn into_inner(self) -> W {
+        self.write
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W> io::Write for LimitWrite<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(limit) = self.limit {
+            if buf.len() as u64 > limit - self.count {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    OutputSizeExceeded { limit },
+                ));
+            }
+        }
+        let result = self.write.write(buf)?;
+        self.count += result as u64;
+        Ok(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
@@ -1,34 +1,522 @@
-use crate::decode;
-use crate::encode::{lzma2, util};
+use crate::compress::{Filter, Options};
+use crate::encode::stats::CompressStats;
+use crate::encode::{analyze, lzma2, util};
+use crate::xz::check::CheckDigest;
 use crate::xz::crc::CRC32;
-use crate::xz::{footer, header, CheckMethod, StreamFlags};
+use crate::xz::delta::DeltaState;
+use crate::xz::index::IndexRecord;
+use crate::xz::{footer, header, FilterId, StreamFlags};
+use crate::{error, CompressedSize, UncompressedSize};
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::io;
 use std::io::Write;
+use std::time::Instant;
 
-pub fn encode_stream<R, W>(input: &mut R, output: &mut W) -> io::Result<()>
+pub fn encode_stream<R, W>(input: &mut R, output: &mut W, options: &Options) -> error::Result<()>
 where
     R: io::BufRead,
     W: io::Write,
 {
-    let stream_flags = StreamFlags {
-        check_method: CheckMethod::None,
+    let mut writer = XzWriter::new(output, options)?;
+    io::copy(input, &mut writer)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Upper bound on the size of the index, without records, and footer:
+/// indicator, record count, padding, CRC32 and a 12-byte footer.
+const MAX_TRAILER_SIZE: u64 = 1 + 9 + 3 + 4 + 12;
+
+/// Upper bound on the size of an index record: two sizes of at most 9 bytes.
+const MAX_RECORD_SIZE: u64 = 18;
+
+/// Upper bound on the size of the `.xz` stream written for `len` bytes of
+/// input with the given `options`.
+pub fn compress_bound(len: u64, options: &Options) -> u64 {
+    // Block header, block padding and check.
+    let block_overhead = 12 + 3 + options.check.check_size() as u64 + MAX_RECORD_SIZE;
+    let (blocks, data_bound) = match options.block_size {
+        Some(block_size) if block_size > 0 && len > 0 => {
+            let blocks = (len - 1) / block_size + 1;
+            let last = len - (blocks - 1) * block_size;
+            let full = lzma2::compress_bound(block_size).saturating_mul(blocks - 1);
+            (blocks, full.saturating_add(lzma2::compress_bound(last)))
+        }
+        _ => (1, lzma2::compress_bound(len)),
     };
+    // Stream header, blocks, index and footer.
+    12u64
+        .saturating_add(data_bound)
+        .saturating_add(blocks.saturating_mul(block_overhead))
+        .saturating_add(MAX_TRAILER_SIZE)
+}
 
-    // Header
-    write_header(output, stream_flags)?;
+/// Smallest LZMA2 dictionary size property that can hold `dict_size`, see
+/// spec section 5.3.1.
+fn lzma2_dict_size_props(dict_size: u32) -> u8 {
+    (0..40)
+        .find(|&props: &u8| (2 | (props as u32 & 1)) << (props / 2 + 11) >= dict_size)
+        .unwrap_or(40)
+}
 
-    // Block
-    let (unpadded_size, unpacked_size) = write_block(input, output)?;
+/// XZ compressor that can process multiple chunks of data using the
+/// [`io::Write`] interface.
+///
+/// The data is split in blocks of [`Options::block_size`] bytes, or written
+/// as a single block by default. Additional block boundaries can be forced
+/// with [`XzWriter::with_block_list()`]. Call [`XzWriter::finish()`] or
+/// [`XzWriter::try_finish()`] to write the index and footer once all the data
+/// has been written.
+///
+/// If the writer is dropped without being finished, it attempts to finish the
+/// stream, ignoring any error: call one of these methods to detect errors.
+/// After a failed write to the sink, the stream is left in an inconsistent
+/// state and the writer is poisoned: later writes and finishing fail instead
+/// of producing a corrupt stream.
+pub struct XzWriter<W>
+where
+    W: io::Write,
+{
+    /// Output sink, only taken by [`XzWriter::finish()`]
+    output: Option<util::LimitWrite<W>>,
+    /// Stream flags written in the header and footer
+    stream_flags: StreamFlags,
+    /// LZMA2 dictionary size property written in block headers
+    dict_size_props: u8,
+    /// Maximum number of uncompressed bytes per block
+    block_size: Option<u64>,
+    /// Filter applied before LZMA2
+    filter: Filter,
+    /// State of the delta filter of the current block, if any
+    delta: Option<DeltaState>,
+    /// Options of the compressed chunks, of which only the match finder and
+    /// the mode apply
+    chunk_options: Options,
+    /// Uncompressed offsets at which to start a new block, in decreasing
+    /// order so that the next one is last
+    block_list: Vec<u64>,
+    /// Pending data of the current LZMA2 chunk
+    chunk: Vec<u8>,
+    /// Check of the uncompressed data of the current block
+    check: CheckDigest,
+    /// Number of bytes written for the current block so far, zero until the
+    /// block header is written along with the first chunk
+    unpadded_size: usize,
+    /// Number of uncompressed bytes in the current block
+    unpacked_size: usize,
+    /// Records of the blocks written so far
+    records: Vec<IndexRecord>,
+    /// Number of uncompressed bytes in the blocks written so far
+    records_unpacked_size: u64,
+    /// Whether the index and footer have been written
+    finished: bool,
+    /// Whether writing to the sink failed, leaving a partial stream
+    poisoned: bool,
+    /// Statistics of the compression so far, except the output size tracked
+    /// by the sink
+    stats: CompressStats,
+}
 
-    // Index
-    let index_size = write_index(output, unpadded_size, unpacked_size)?;
+impl<W> XzWriter<W>
+where
+    W: io::Write,
+{
+    /// Initialize the compressor with the given `options`, writing the stream
+    /// header to the `output` sink.
+    pub fn new(output: W, options: &Options) -> error::Result<Self> {
+        options.validate()?;
+        options.validate_lzma2()?;
 
-    // Footer
-    write_footer(output, stream_flags, index_size)
+        let mut writer = Self::headerless(output, options);
+        let result = write_header(sink(&mut writer.output), writer.stream_flags);
+        writer.poisoned = result.is_err();
+        result?;
+        Ok(writer)
+    }
+
+    /// Initialize the compressor without writing the stream header, for
+    /// [`encode_blocks()`].
+    fn headerless(output: W, options: &Options) -> Self {
+        let stream_flags = StreamFlags {
+            check_method: options.check,
+        };
+        Self {
+            output: Some(util::LimitWrite::new(output, options.max_output_size)),
+            stream_flags,
+            dict_size_props: lzma2_dict_size_props(options.dict_size),
+            block_size: options.block_size,
+            filter: options.filter,
+            delta: None,
+            chunk_options: *options,
+            chunk: Vec::with_capacity(lzma2::MAX_UNCOMPRESSED_CHUNK_SIZE),
+            check: CheckDigest::new(options.check),
+            unpadded_size: 0,
+            unpacked_size: 0,
+            records: vec![],
+            records_unpacked_size: 0,
+            block_list: vec![],
+            finished: false,
+            poisoned: false,
+            stats: CompressStats::default(),
+        }
+    }
+
+    /// Initialize the compressor like [`XzWriter::new()`], also starting a
+    /// new block at each of the uncompressed offsets in `block_list`, which
+    /// must be increasing.
+    ///
+    /// This aligns blocks with application-level records, so that they can
+    /// be extracted separately later on. Offsets beyond the end of the data
+    /// are ignored. Note that [`xz_compress_bound`](crate::xz_compress_bound)
+    /// doesn't account for these additional blocks.
+    pub fn with_block_list(
+        output: W,
+        options: &Options,
+        block_list: &[u64],
+    ) -> error::Result<Self> {
+        if block_list.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(error::OptionsError::new(
+                "block_list",
+                "Block list offsets must be strictly increasing".to_string(),
+            )
+            .into());
+        }
+        let mut writer = Self::new(output, options)?;
+        // A block always starts at offset zero.
+        writer.block_list = block_list
+            .iter()
+            .rev()
+            .copied()
+            .filter(|&offset| offset > 0)
+            .collect();
+        Ok(writer)
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_ref(&self) -> &W {
+        self.output
+            .as_ref()
+            .expect("the output is only taken by finish()")
+            .get_ref()
+    }
+
+    /// Consumes the compressor and returns the output sink, after writing the
+    /// end of the last block, the index and the footer.
+    ///
+    /// Without any data, the stream has no block at all.
+    pub fn finish(mut self) -> error::Result<W> {
+        self.try_finish()?;
+        let output = self
+            .output
+            .take()
+            .expect("the output is only taken by finish()");
+        Ok(output.into_inner())
+    }
+
+    /// Write the end of the last block, the index and the footer, keeping
+    /// the output sink in place, e.g. to inspect it with
+    /// [`XzWriter::get_ref()`].
+    ///
+    /// Once this succeeds, calling it again does nothing, and writing more
+    /// data fails. If it fails with a poisoned writer, the stream can't be
+    /// completed.
+    pub fn try_finish(&mut self) -> error::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.check_poisoned()?;
+        let start = Instant::now();
+        let result = self.write_trailer();
+        self.stats.duration += start.elapsed();
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Compress the data buffered so far as a shorter LZMA2 chunk and flush
+    /// the output sink, so that the receiver can decode everything written
+    /// up to now, e.g. to answer a request without closing the stream.
+    ///
+    /// LZMA2 chunks end their range-coded data, unlike plain LZMA streams,
+    /// whose range decoder reads a few bytes ahead that depend on the data
+    /// not yet compressed. [`LzmaEncoder`](crate::compress::LzmaEncoder)
+    /// has no such flush for this reason. As each chunk resets the
+    /// dictionary, a flush costs the matches that a full chunk would have
+    /// found in the data that follows, and a chunk header of up to 3 bytes
+    /// that [`xz_compress_bound()`](crate::xz_compress_bound) doesn't count.
+    pub fn flush_sync(&mut self) -> io::Result<()> {
+        self.check_poisoned()?;
+        if self.finished {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "XzWriter is already finished",
+            ));
+        }
+        let start = Instant::now();
+        let result = self
+            .end_chunk()
+            .and_then(|()| sink(&mut self.output).flush());
+        self.stats.duration += start.elapsed();
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result
+    }
+
+    /// Statistics of the compression so far. Data buffered for the pending
+    /// chunk counts as read but not yet written.
+    pub fn stats(&self) -> CompressStats {
+        CompressStats {
+            out_bytes: CompressedSize(self.output.as_ref().map_or(0, |output| output.count())),
+            ..self.stats
+        }
+    }
+
+    /// Whether a previous write to the sink failed, leaving a partial stream
+    /// that can't be finished.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    fn check_poisoned(&self) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "XzWriter is poisoned by a previous write error",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write the pending data, the end of the last block, the index and the
+    /// footer.
+    fn write_trailer(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.write_block_end()?;
+
+        let output = sink(&mut self.output);
+        // Index
+        let index_size = write_index(output, &self.records)?;
+
+        // Footer
+        write_footer(output, self.stream_flags, index_size)?;
+        output.flush()
+    }
+
+    /// Number of uncompressed bytes written so far, including the pending
+    /// chunk.
+    fn position(&self) -> u64 {
+        self.records_unpacked_size + (self.unpacked_size + self.chunk.len()) as u64
+    }
+
+    /// Number of bytes that can be added to the pending chunk without
+    /// exceeding the chunk or block size, or crossing a forced boundary.
+    fn chunk_room(&self) -> usize {
+        let mut room = (lzma2::MAX_UNCOMPRESSED_CHUNK_SIZE - self.chunk.len()) as u64;
+        if let Some(block_size) = self.block_size {
+            let used = (self.unpacked_size + self.chunk.len()) as u64;
+            room = std::cmp::min(room, block_size - used);
+        }
+        if let Some(&boundary) = self.block_list.last() {
+            room = std::cmp::min(room, boundary - self.position());
+        }
+        room as usize
+    }
+
+    /// Write the pending chunk, if any.
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        if self.unpadded_size == 0 {
+            let filter = match self.filter {
+                Filter::Auto => analyze::analyze(&self.chunk).filter(),
+                filter => filter,
+            };
+            let delta_distance = match filter {
+                Filter::Delta(distance) => Some(distance),
+                Filter::None | Filter::Auto => None,
+            };
+            self.delta = delta_distance.map(|distance| DeltaState::new(distance as usize));
+            self.unpadded_size =
+                write_block_header(sink(&mut self.output), self.dict_size_props, delta_distance)?;
+        }
+        self.check.update(&self.chunk);
+        if let Some(delta) = &mut self.delta {
+            delta.encode(&mut self.chunk);
+        }
+        let mut count_output = util::CountWrite::new(sink(&mut self.output));
+        lzma2::write_chunk(
+            &mut count_output,
+            &self.chunk,
+            &self.chunk_options,
+            &mut self.stats,
+        )?;
+        self.stats.chunks += 1;
+        self.unpadded_size += count_output.count();
+        self.unpacked_size += self.chunk.len();
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Write the full pending chunk, and end the block if it reached its size
+    /// or a forced boundary.
+    fn end_chunk(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        let block_full = self.block_size == Some(self.unpacked_size as u64);
+        let at_boundary = self.block_list.last() == Some(&self.position());
+        if at_boundary {
+            self.block_list.pop();
+        }
+        if block_full || at_boundary {
+            self.write_block_end()?;
+        }
+        Ok(())
+    }
+
+    /// Terminate the LZMA2 stream of the current block, if any, pad the block
+    /// and record it for the index.
+    fn write_block_end(&mut self) -> io::Result<()> {
+        if self.unpadded_size == 0 {
+            return Ok(());
+        }
+        self.delta = None;
+        let output = sink(&mut self.output);
+        lzma2::write_end(output)?;
+        self.unpadded_size += 1;
+        lzma_info!(
+            "Unpadded size = {}, unpacked_size = {}",
+            self.unpadded_size,
+            self.unpacked_size
+        );
+
+        let padding_size = ((self.unpadded_size ^ 0x03) + 1) & 0x03;
+        let padding = vec![0; padding_size];
+        output.write_all(padding.as_slice())?;
+
+        let check = std::mem::replace(
+            &mut self.check,
+            CheckDigest::new(self.stream_flags.check_method),
+        )
+        .finalize()
+        .expect("unsupported check methods are rejected on creation");
+        let check = check.to_bytes();
+        sink(&mut self.output).write_all(&check)?;
+
+        self.records.push(IndexRecord {
+            unpadded_size: CompressedSize((self.unpadded_size + check.len()) as u64),
+            unpacked_size: UncompressedSize(self.unpacked_size as u64),
+        });
+        self.records_unpacked_size += self.unpacked_size as u64;
+        self.unpadded_size = 0;
+        self.unpacked_size = 0;
+        Ok(())
+    }
+}
+
+impl<W> io::Write for XzWriter<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_poisoned()?;
+        if self.finished {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "XzWriter is already finished",
+            ));
+        }
+        let start = Instant::now();
+        let n = std::cmp::min(buf.len(), self.chunk_room());
+        self.chunk.extend_from_slice(&buf[..n]);
+        self.stats.in_bytes.0 += n as u64;
+        if self.chunk_room() == 0 {
+            let result = self.end_chunk();
+            if result.is_err() {
+                self.poisoned = true;
+            }
+            result?;
+        }
+        self.stats.duration += start.elapsed();
+        Ok(n)
+    }
+
+    /// Flushes the output sink. Pending data is kept until a full chunk is
+    /// available or [`XzWriter::finish()`] is called, see
+    /// [`XzWriter::flush_sync()`] to compress it right away.
+    fn flush(&mut self) -> io::Result<()> {
+        sink(&mut self.output).flush()
+    }
+}
+
+impl<W> Drop for XzWriter<W>
+where
+    W: io::Write,
+{
+    fn drop(&mut self) {
+        if self.output.is_some() && !self.poisoned {
+            let _ = self.try_finish();
+        }
+    }
 }
 
-fn write_header<W>(output: &mut W, stream_flags: StreamFlags) -> io::Result<()>
+impl<W> std::fmt::Debug for XzWriter<W>
+where
+    W: io::Write + std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("XzWriter")
+            .field(
+                "output",
+                &self.output.as_ref().map(|output| output.get_ref()),
+            )
+            .field("stream_flags", &self.stream_flags)
+            .field("dict_size_props", &self.dict_size_props)
+            .field("block_size", &self.block_size)
+            .field("block_list", &self.block_list)
+            .field("chunk", &self.chunk.len())
+            .field("unpadded_size", &self.unpadded_size)
+            .field("unpacked_size", &self.unpacked_size)
+            .field("records", &self.records)
+            .field("finished", &self.finished)
+            .field("poisoned", &self.poisoned)
+            .finish()
+    }
+}
+
+/// Compress `data` into `.xz` blocks with the given `options`, returning
+/// the blocks and their records, without the stream header nor the index.
+///
+/// The options must have been validated.
+pub(crate) fn encode_blocks(
+    data: &[u8],
+    options: &Options,
+) -> error::Result<(Vec<u8>, Vec<IndexRecord>)> {
+    let mut writer = XzWriter::headerless(Vec::new(), options);
+    writer.write_all(data)?;
+    writer.write_chunk()?;
+    writer.write_block_end()?;
+    // The caller writes the index and footer.
+    writer.finished = true;
+    let records = std::mem::take(&mut writer.records);
+    Ok((writer.finish()?, records))
+}
+
+/// Output sink of an [`XzWriter`] that hasn't been consumed by
+/// [`XzWriter::finish()`].
+fn sink<W>(output: &mut Option<util::LimitWrite<W>>) -> &mut util::LimitWrite<W>
+where
+    W: io::Write,
+{
+    output
+        .as_mut()
+        .expect("the output is only taken by finish()")
+}
+
+pub(crate) fn write_header<W>(output: &mut W, stream_flags: StreamFlags) -> io::Result<()>
 where
     W: io::Write,
 {
@@ -43,7 +531,11 @@ where
     Ok(())
 }
 
-fn write_footer<W>(output: &mut W, stream_flags: StreamFlags, index_size: usize) -> io::Result<()>
+pub(crate) fn write_footer<W>(
+    output: &mut W,
+    stream_flags: StreamFlags,
+    index_size: usize,
+) -> io::Result<()>
 where
     W: io::Write,
 {
@@ -64,54 +556,48 @@ where
     Ok(())
 }
 
-fn write_block<R, W>(input: &mut R, output: &mut W) -> io::Result<(usize, usize)>
+/// Write the block header, with a delta filter before LZMA2 if a distance is
+/// given, returning its size.
+fn write_block_header<W>(
+    output: &mut W,
+    dict_size_props: u8,
+    delta_distance: Option<u16>,
+) -> io::Result<usize>
 where
-    R: io::BufRead,
     W: io::Write,
 {
-    let (unpadded_size, unpacked_size) = {
-        let mut count_output = util::CountWrite::new(output);
-
-        // Block header
-        let mut digest = CRC32.digest();
-        {
-            let mut digested = util::CrcDigestWrite::new(&mut count_output, &mut digest);
-            let header_size = 8;
-            digested.write_u8((header_size >> 2) as u8)?;
-            let flags = 0x00; // 1 filter, no (un)packed size provided
-            digested.write_u8(flags)?;
-            let filter_id = 0x21; // LZMA2
-            digested.write_u8(filter_id)?;
-            let size_of_properties = 1;
+    let mut count_output = util::CountWrite::new(output);
+
+    let mut digest = CRC32.digest();
+    {
+        let mut digested = util::CrcDigestWrite::new(&mut count_output, &mut digest);
+        let header_size = 8;
+        digested.write_u8((header_size >> 2) as u8)?;
+        // Number of filters minus one, no (un)packed size provided
+        let flags = delta_distance.is_some() as u8;
+        digested.write_u8(flags)?;
+        let size_of_properties = 1;
+        if let Some(distance) = delta_distance {
+            digested.write_u8(FilterId::Delta as u8)?;
             digested.write_u8(size_of_properties)?;
-            let properties = 22; // TODO
-            digested.write_u8(properties)?;
-            let padding = [0, 0, 0];
-            digested.write_all(&padding)?;
-        }
-        let crc32 = digest.finalize();
-        count_output.write_u32::<LittleEndian>(crc32)?;
-
-        // Block
-        let mut count_input = decode::util::CountBufRead::new(input);
-        lzma2::encode_stream(&mut count_input, &mut count_output)?;
-        (count_output.count(), count_input.count())
-    };
-    lzma_info!(
-        "Unpadded size = {}, unpacked_size = {}",
-        unpadded_size,
-        unpacked_size
-    );
-
-    let padding_size = ((unpadded_size ^ 0x03) + 1) & 0x03;
-    let padding = vec![0; padding_size];
-    output.write_all(padding.as_slice())?;
-    // Checksum = None (cf. above)
-
-    Ok((unpadded_size, unpacked_size))
+            digested.write_u8((distance - 1) as u8)?;
+        }
+        digested.write_u8(FilterId::Lzma2 as u8)?;
+        digested.write_u8(size_of_properties)?;
+        digested.write_u8(dict_size_props)?;
+        // Both filters fit in the header without padding.
+        let padding_size = if delta_distance.is_some() { 0 } else { 3 };
+        let padding = vec![0; padding_size];
+        digested.write_all(&padding)?;
+    }
+    let crc32 = digest.finalize();
+    count_output.write_u32::<LittleEndian>(crc32)?;
+
+    Ok(count_output.count())
 }
 
-fn write_index<W>(output: &mut W, unpadded_size: usize, unpacked_size: usize) -> io::Result<usize>
+/// Write an index listing the given block records, returning its size.
+pub(crate) fn write_index<W>(output: &mut W, records: &[IndexRecord]) -> io::Result<usize>
 where
     W: io::Write,
 {
@@ -121,11 +607,11 @@ where
     {
         let mut digested = util::CrcDigestWrite::new(&mut count_output, &mut digest);
         digested.write_u8(0)?; // No more block
-        let num_records = 1;
-        write_multibyte(&mut digested, num_records)?;
-
-        write_multibyte(&mut digested, unpadded_size as u64)?;
-        write_multibyte(&mut digested, unpacked_size as u64)?;
+        write_multibyte(&mut digested, records.len() as u64)?;
+        for record in records {
+            write_multibyte(&mut digested, record.unpadded_size.0)?;
+            write_multibyte(&mut digested, record.unpacked_size.0)?;
+        }
     }
 
     // Padding
@@ -143,7 +629,7 @@ where
     Ok(count_output.count())
 }
 
-fn write_multibyte<W>(output: &mut W, mut value: u64) -> io::Result<()>
+pub(crate) fn write_multibyte<W>(output: &mut W, mut value: u64) -> io::Result<()>
 where
     W: io::Write,
 {
@@ -14,14 +14,74 @@ pub enum Error {
     LzmaError(String),
     /// XZ error.
     XzError(String),
+    /// Input ending before the end of the stream.
+    Truncated(String),
+    /// Buffer needed by the decompressor exceeding the memory limit, in
+    /// bytes.
+    MemoryLimitExceeded(usize),
+    /// Invalid or unsupported options, detected before processing any data.
+    InvalidOptions(OptionsError),
+    /// Compression cancelled through the flag set with
+    /// [`LzmaEncoder::set_cancel()`](crate::compress::LzmaEncoder::set_cancel).
+    Cancelled,
 }
 
 /// Library result alias.
 pub type Result<T> = result::Result<T, Error>;
 
+/// I/O errors wrapping an [`Error`], such as those of the encoders and of
+/// the readers, are unwrapped to it. Other ones are kept whole.
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
-        Error::IoError(e)
+        if e.get_ref().map_or(false, |inner| inner.is::<Error>()) {
+            *e.into_inner()
+                .and_then(|inner| inner.downcast::<Error>().ok())
+                .expect("checked to wrap an Error")
+        } else {
+            Error::IoError(e)
+        }
+    }
+}
+
+impl From<OptionsError> for Error {
+    fn from(e: OptionsError) -> Error {
+        Error::InvalidOptions(e)
+    }
+}
+
+impl Error {
+    /// Kind of the [`io::Error`] that this error converts to.
+    ///
+    /// I/O errors keep their kind. Truncated headers and streams map to
+    /// [`io::ErrorKind::UnexpectedEof`], exceeded memory limits to
+    /// [`io::ErrorKind::OutOfMemory`], other LZMA and XZ errors to
+    /// [`io::ErrorKind::InvalidData`], invalid options to
+    /// [`io::ErrorKind::InvalidInput`] and cancellations to
+    /// [`io::ErrorKind::Other`], as [`io::ErrorKind::Interrupted`] would be
+    /// retried.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            Error::IoError(e) => e.kind(),
+            Error::HeaderTooShort(_) => io::ErrorKind::UnexpectedEof,
+            Error::LzmaError(_) | Error::XzError(_) => io::ErrorKind::InvalidData,
+            Error::Truncated(_) => io::ErrorKind::UnexpectedEof,
+            Error::MemoryLimitExceeded(_) => io::ErrorKind::OutOfMemory,
+            Error::InvalidOptions(_) => io::ErrorKind::InvalidInput,
+            Error::Cancelled => io::ErrorKind::Other,
+        }
+    }
+}
+
+/// I/O errors are unwrapped as is. Other errors are wrapped whole in an
+/// [`io::Error`] of their [`io_kind()`](Error::io_kind), from which they can
+/// be recovered with `err.into_inner().and_then(|e|
+/// e.downcast::<Error>().ok())`.
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::IoError(e) => e,
+            error => io::Error::new(error.io_kind(), error),
+        }
     }
 }
 
@@ -32,6 +92,12 @@ impl Display for Error {
             Error::HeaderTooShort(e) => write!(fmt, "header too short: {}", e),
             Error::LzmaError(e) => write!(fmt, "lzma error: {}", e),
             Error::XzError(e) => write!(fmt, "xz error: {}", e),
+            Error::Truncated(e) => write!(fmt, "truncated input: {}", e),
+            Error::MemoryLimitExceeded(limit) => {
+                write!(fmt, "exceeded memory limit of {}", limit)
+            }
+            Error::InvalidOptions(e) => write!(fmt, "invalid options: {}", e),
+            Error::Cancelled => write!(fmt, "cancelled"),
         }
     }
 }
@@ -40,14 +106,139 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::IoError(e) | Error::HeaderTooShort(e) => Some(e),
-            Error::LzmaError(_) | Error::XzError(_) => None,
+            Error::LzmaError(_)
+            | Error::XzError(_)
+            | Error::Truncated(_)
+            | Error::MemoryLimitExceeded(_)
+            | Error::InvalidOptions(_)
+            | Error::Cancelled => None,
+        }
+    }
+}
+
+/// Invalid option, or combination of options, naming the fields involved.
+///
+/// It is returned by `validate()` on the compression and decompression
+/// options, which the compressors and decompressors call before processing
+/// any data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionsError {
+    /// Name of the invalid field.
+    pub field: &'static str,
+    /// Name of the field that `field` conflicts with, if the problem lies in
+    /// their combination rather than in `field` alone.
+    pub conflicts_with: Option<&'static str>,
+    /// Description of the problem.
+    pub reason: String,
+}
+
+impl OptionsError {
+    pub(crate) fn new(field: &'static str, reason: String) -> Self {
+        Self {
+            field,
+            conflicts_with: None,
+            reason,
+        }
+    }
+
+    pub(crate) fn conflict(
+        field: &'static str,
+        conflicts_with: &'static str,
+        reason: String,
+    ) -> Self {
+        Self {
+            field,
+            conflicts_with: Some(conflicts_with),
+            reason,
+        }
+    }
+}
+
+impl Display for OptionsError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.conflicts_with {
+            Some(other) => write!(
+                fmt,
+                "`{}` conflicts with `{}`: {}",
+                self.field, other, self.reason
+            ),
+            None => write!(fmt, "`{}`: {}", self.field, self.reason),
         }
     }
 }
 
+impl std::error::Error for OptionsError {}
+
+/// Error returned by the compressors, wrapped in an [`io::Error`] as it is
+/// raised by the output sink, when the output would exceed the
+/// [`max_output_size`](crate::compress::Options::max_output_size) option.
+///
+/// It can be recognized with
+/// `err.get_ref().map_or(false, |e| e.is::<OutputSizeExceeded>())` on the
+/// [`io::Error`] of an [`Error::IoError`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputSizeExceeded {
+    /// Configured maximum output size, in bytes.
+    pub limit: u64,
+}
+
+impl Display for OutputSizeExceeded {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "compressed output exceeds limit of {} bytes",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for OutputSizeExceeded {}
+
+/// Error of the output sink of a decompressor, wrapped in an [`io::Error`]
+/// of the same kind, with the number of decompressed bytes that the sink
+/// accepted before failing.
+///
+/// The output up to that offset is valid: after running out of disk space,
+/// for example, decompression can be restarted once space is freed, skipping
+/// that many bytes instead of rewriting them.
+///
+/// It can be retrieved with
+/// `err.get_ref().and_then(|e| e.downcast_ref::<SinkError>())` on the
+/// [`io::Error`] of an [`Error::IoError`].
+#[derive(Debug)]
+pub struct SinkError {
+    /// Number of decompressed bytes written to the sink before the failure.
+    pub written: u64,
+    /// Error returned by the sink.
+    pub source: io::Error,
+}
+
+impl SinkError {
+    pub(crate) fn wrap(source: io::Error, written: u64) -> io::Error {
+        io::Error::new(source.kind(), SinkError { written, source })
+    }
+}
+
+impl Display for SinkError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "output sink failed after {} bytes: {}",
+            self.written, self.source
+        )
+    }
+}
+
+impl std::error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Error;
+    use super::{Error, OptionsError};
+    use std::io;
 
     #[test]
     fn test_display() {
@@ -67,5 +258,54 @@ mod test {
             Error::XzError("this is an error".to_string()).to_string(),
             "xz error: this is an error"
         );
+        assert_eq!(
+            Error::InvalidOptions(OptionsError::new("field", "this is an error".to_string()))
+                .to_string(),
+            "invalid options: `field`: this is an error"
+        );
+        assert_eq!(
+            Error::InvalidOptions(OptionsError::conflict(
+                "field",
+                "other",
+                "this is an error".to_string()
+            ))
+            .to_string(),
+            "invalid options: `field` conflicts with `other`: this is an error"
+        );
+    }
+
+    #[test]
+    fn test_into_io_error() {
+        let e = io::Error::new(io::ErrorKind::WouldBlock, "busy");
+        let e: io::Error = Error::IoError(e).into();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(e.to_string(), "busy");
+
+        let e: io::Error = Error::MemoryLimitExceeded(10).into();
+        assert_eq!(e.kind(), io::ErrorKind::OutOfMemory);
+        let e: io::Error = Error::Truncated("LZMA stream too short: eof".to_string()).into();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        // The kind doesn't depend on the message.
+        let e: io::Error = Error::LzmaError("LZMA stream too short".to_string()).into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e: io::Error = Error::HeaderTooShort(io::ErrorKind::UnexpectedEof.into()).into();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e: io::Error = Error::InvalidOptions(OptionsError::new("field", String::new())).into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let e: io::Error = Error::XzError("Invalid index".to_string()).into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let inner = e.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*inner, Error::XzError(ref e) if e == "Invalid index"));
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let e = io::Error::from(Error::Truncated("eof".to_string()));
+        assert!(matches!(Error::from(e), Error::Truncated(ref e) if e == "eof"));
+        let e = io::Error::from(Error::Cancelled);
+        assert!(matches!(Error::from(e), Error::Cancelled));
+        let e = io::Error::new(io::ErrorKind::Other, "other");
+        assert!(matches!(Error::from(e), Error::IoError(_)));
     }
 }
@@ -0,0 +1,272 @@
+//! Compression and decompression of single `.xz` files.
+//!
+//! Like the `xz` tool, the helpers write the output to a temporary file in
+//! the same directory, and rename it to the output path once it's complete
+//! and synced to disk: the output path never holds partial data, even if the
+//! process is interrupted, and the temporary file is removed on errors.
+//!
+//! Unless overwriting is allowed, the temporary file is hard-linked to the
+//! output path instead of renamed, so that a file created there in the
+//! meantime is never replaced. The file system must then support hard links.
+
+use crate::{compress, decompress, error, CompressedSize, UncompressedSize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Size of the buffers of the input and output files.
+const BUF_SIZE: usize = 1 << 16;
+
+/// File handling options of [`compress_file`] and [`decompress_file`].
+#[derive(Clone, Copy, Debug)]
+pub struct FileOptions {
+    /// Whether to replace the output file if it exists, instead of failing
+    /// with [`io::ErrorKind::AlreadyExists`]. The default is false.
+    pub overwrite: bool,
+    /// Whether to give the output file the permissions of the input file.
+    /// The default is true.
+    pub preserve_permissions: bool,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            preserve_permissions: true,
+        }
+    }
+}
+
+/// Sizes and metadata of a file compressed or decompressed by the helpers of
+/// this module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileSummary {
+    /// Size of the uncompressed file, i.e. of the input of [`compress_file`]
+    /// or of the output of [`decompress_file`].
+    pub uncompressed_size: UncompressedSize,
+    /// Size of the `.xz` file, i.e. of the output of [`compress_file`] or of
+    /// the input of [`decompress_file`].
+    pub compressed_size: CompressedSize,
+    /// Modification time of the input file, if the platform provides it.
+    ///
+    /// The output file is left with the current time, as the minimum
+    /// supported Rust version of this crate can't set it. To preserve it as
+    /// `xz` does, set this one on the output file, e.g. with
+    /// `File::set_modified()` from Rust 1.75.
+    pub modified: Option<SystemTime>,
+}
+
+/// Compress the file at `input` to an `.xz` file at `output`.
+pub fn compress_file(
+    input: &Path,
+    output: &Path,
+    options: &compress::Options,
+    file_options: &FileOptions,
+) -> error::Result<FileSummary> {
+    compress_file_with_progress(input, output, options, file_options, |_, _| ())
+}
+
+/// Compress the file at `input` to an `.xz` file at `output` like
+/// [`compress_file`], calling `progress` with the number of bytes read from
+/// the input so far and the size of the input, as they are read.
+pub fn compress_file_with_progress<F>(
+    input: &Path,
+    output: &Path,
+    options: &compress::Options,
+    file_options: &FileOptions,
+    progress: F,
+) -> error::Result<FileSummary>
+where
+    F: FnMut(u64, u64),
+{
+    options.validate()?;
+    let (input_size, output_size, modified) = transform_file(
+        input,
+        output,
+        file_options,
+        progress,
+        |mut reader, mut writer| crate::xz_compress_with_options(&mut reader, &mut writer, options),
+    )?;
+    Ok(FileSummary {
+        uncompressed_size: UncompressedSize(input_size),
+        compressed_size: CompressedSize(output_size),
+        modified,
+    })
+}
+
+/// Decompress the `.xz` file at `input` to a file at `output`.
+pub fn decompress_file(
+    input: &Path,
+    output: &Path,
+    options: &decompress::Options,
+    file_options: &FileOptions,
+) -> error::Result<FileSummary> {
+    decompress_file_with_progress(input, output, options, file_options, |_, _| ())
+}
+
+/// Decompress the `.xz` file at `input` to a file at `output` like
+/// [`decompress_file`], calling `progress` with the number of bytes read from
+/// the input so far and the size of the input, as they are read.
+pub fn decompress_file_with_progress<F>(
+    input: &Path,
+    output: &Path,
+    options: &decompress::Options,
+    file_options: &FileOptions,
+    progress: F,
+) -> error::Result<FileSummary>
+where
+    F: FnMut(u64, u64),
+{
+    let (input_size, output_size, modified) = transform_file(
+        input,
+        output,
+        file_options,
+        progress,
+        |mut reader, mut writer| {
+            crate::xz_decompress_with_options(&mut reader, &mut writer, options)?;
+            Ok(())
+        },
+    )?;
+    Ok(FileSummary {
+        uncompressed_size: UncompressedSize(output_size),
+        compressed_size: CompressedSize(input_size),
+        modified,
+    })
+}
+
+/// Reader calling a progress callback after each read.
+struct ProgressReader<R, F> {
+    inner: R,
+    /// Number of bytes read so far
+    read: u64,
+    /// Size of the input
+    total: u64,
+    progress: F,
+}
+
+impl<R, F> Read for ProgressReader<R, F>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.read += n as u64;
+            (self.progress)(self.read, self.total);
+        }
+        Ok(n)
+    }
+}
+
+/// Temporary file next to `output`, renamed to it once complete.
+fn temp_path(output: &Path) -> io::Result<PathBuf> {
+    let name = output.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Output path {} isn't a file name", output.display()),
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(output.with_file_name(temp_name))
+}
+
+/// Run `code` from the file at `input` to a temporary file, and rename it to
+/// `output` if it succeeds. Returns the sizes of the input and of the output,
+/// and the modification time of the input.
+fn transform_file<F, C>(
+    input: &Path,
+    output: &Path,
+    file_options: &FileOptions,
+    progress: F,
+    code: C,
+) -> error::Result<(u64, u64, Option<SystemTime>)>
+where
+    F: FnMut(u64, u64),
+    C: FnOnce(&mut dyn io::BufRead, &mut dyn io::Write) -> error::Result<()>,
+{
+    if !file_options.overwrite && output.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Output file {} already exists", output.display()),
+        )
+        .into());
+    }
+    let input_file = File::open(input)?;
+    let metadata = input_file.metadata()?;
+    let temp = temp_path(output)?;
+    let temp_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)?;
+
+    let mut reader = BufReader::with_capacity(
+        BUF_SIZE,
+        ProgressReader {
+            inner: input_file,
+            read: 0,
+            total: metadata.len(),
+            progress,
+        },
+    );
+    let result =
+        write_temp(&mut reader, temp_file, &metadata, file_options, code).and_then(|output_size| {
+            publish(&temp, output, file_options.overwrite)?;
+            Ok(output_size)
+        });
+    let output_size = match result {
+        Ok(output_size) => output_size,
+        Err(e) => {
+            lzma_info!("Removing {} after error: {}", temp.display(), e);
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+    };
+    lzma_info!(
+        "{} ({} bytes) -> {} ({} bytes)",
+        input.display(),
+        metadata.len(),
+        output.display(),
+        output_size
+    );
+
+    Ok((metadata.len(), output_size, metadata.modified().ok()))
+}
+
+/// Move the complete temporary file to `output`. Without `overwrite`, it's
+/// hard-linked there, which fails with [`io::ErrorKind::AlreadyExists`] if
+/// `output` exists, and then removed: renaming would silently replace a file
+/// created since the check of [`transform_file`].
+fn publish(temp: &Path, output: &Path, overwrite: bool) -> io::Result<()> {
+    if overwrite {
+        fs::rename(temp, output)
+    } else {
+        fs::hard_link(temp, output)?;
+        fs::remove_file(temp)
+    }
+}
+
+/// Write the output of `code` to `temp_file`, and sync it to disk. Returns
+/// the size of the output.
+fn write_temp<C>(
+    reader: &mut dyn io::BufRead,
+    temp_file: File,
+    metadata: &fs::Metadata,
+    file_options: &FileOptions,
+    code: C,
+) -> error::Result<u64>
+where
+    C: FnOnce(&mut dyn io::BufRead, &mut dyn io::Write) -> error::Result<()>,
+{
+    let mut writer = BufWriter::with_capacity(BUF_SIZE, temp_file);
+    code(reader, &mut writer)?;
+    let temp_file = writer.into_inner().map_err(|e| e.into_error())?;
+    if file_options.preserve_permissions {
+        temp_file.set_permissions(metadata.permissions())?;
+    }
+    temp_file.sync_all()?;
+    Ok(temp_file.metadata()?.len())
+}
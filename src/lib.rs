@@ -1,4 +1,23 @@
 //! Pure-Rust codecs for LZMA, LZMA2, and XZ.
+//!
+//! The crate has no global mutable state: encoders and decoders own their
+//! buffers, and are `Send` and `Sync` whenever their reader or writer is. Use
+//! one encoder or decoder per thread, without any locking.
+//!
+//! Compression is deterministic with the default
+//! [`deterministic`](compress::Options::deterministic) option: the same input
+//! and options always give the same output, byte for byte, whatever the
+//! host, the number of threads of [`xz_compress_parallel`], or the way the
+//! input is split across reads. The encoders only use integer arithmetic to
+//! take their decisions. Only the durations in the statistics vary.
+//! Reproducible builds can rely on this, as long as the crate version stays
+//! the same.
+//!
+//! The internal invariants of the range coders, dictionary buffers and match
+//! finders are asserted in debug builds. The `checked` feature keeps these
+//! assertions in release builds, for users who'd rather have a bug end in a
+//! panic than in corrupt data. Malformed input never trips them: it's
+//! reported as an error.
 #![cfg_attr(docsrs, feature(doc_cfg, doc_cfg_hide))]
 #![deny(missing_docs)]
 #![deny(missing_debug_implementations)]
@@ -7,36 +26,95 @@
 #[macro_use]
 mod macros;
 
+#[cfg(feature = "bench")]
+#[cfg_attr(docsrs, doc(cfg(bench)))]
+pub mod bench;
+mod capabilities;
+pub mod codec;
 mod decode;
 mod encode;
 
 pub mod error;
+pub mod fs;
+pub mod magic;
 
+mod size;
+#[cfg(feature = "test_support")]
+#[cfg_attr(docsrs, doc(cfg(test_support)))]
+pub mod test_support;
 mod util;
-mod xz;
+pub mod xz;
+
+pub use capabilities::{capabilities, Capabilities};
+pub use size::{CompressedSize, UncompressedSize};
 
 use std::io;
+use std::io::Write;
+
+/// Container formats handled by this crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// Legacy `.lzma` format, also known as LZMA-alone.
+    Lzma,
+    /// Raw LZMA2 stream, without any container.
+    Lzma2,
+    /// `.xz` container.
+    Xz,
+}
 
 /// Compression helpers.
 pub mod compress {
+    pub use crate::encode::analyze::{analyze, Analysis, Executable, ExecutableFormat};
+    pub use crate::encode::dumbencoder::Encoder as LzmaEncoder;
+    pub use crate::encode::frame::FrameEncoder;
     pub use crate::encode::options::*;
+    pub use crate::encode::slices::SliceReader;
+    pub use crate::encode::stats::CompressStats;
+    pub use crate::encode::xz::XzWriter;
+
+    #[cfg(feature = "raw_decoder")]
+    #[cfg_attr(docsrs, doc(cfg(raw_decoder)))]
+    pub mod raw {
+        //! Raw encoding primitives for LZMA-style range coding.
+        pub use crate::decode::lzma::{
+            InitialProbs, LzmaParams, LzmaProperties, UnpackedSizeField,
+        };
+        pub use crate::encode::rangecoder::{LenEncoder, RangeEncoder};
+    }
 }
 
 /// Decompression helpers.
 pub mod decompress {
+    pub use crate::decode::carve::{find_embedded_streams, EmbeddedStream};
+    pub use crate::decode::fixed_block::FixedBlockDecoder;
+    pub use crate::decode::frame::FrameDecoder;
+    pub use crate::decode::lzbuffer::OutputBuffer;
+    pub use crate::decode::lzma2::dict_reset_points as lzma2_dict_reset_points;
     pub use crate::decode::options::*;
+    pub use crate::decode::pending::PendingLzma;
+    pub use crate::decode::pool::DecompressorPool;
+    pub use crate::decode::verify::{verify_lzma2_matches, verify_matches};
+    pub use crate::decode::xz::{XzBlockHeader, XzBlockInfo, XzSummary};
 
     #[cfg(feature = "raw_decoder")]
     #[cfg_attr(docsrs, doc(cfg(raw_decoder)))]
     pub mod raw {
         //! Raw decoding primitives for LZMA/LZMA2 streams.
-        pub use crate::decode::lzma::{LzmaDecoder, LzmaParams, LzmaProperties};
+        pub use crate::decode::batch::decode_batch;
+        pub use crate::decode::header_parser::HeaderParser;
+        pub use crate::decode::lzma::{
+            InitialProbs, LzmaDecoder, LzmaParams, LzmaProperties, UnpackedSizeField,
+        };
         pub use crate::decode::lzma2::Lzma2Decoder;
+        pub use crate::decode::rangecoder::{LenDecoder, RangeDecoder};
     }
 
     #[cfg(feature = "stream")]
     #[cfg_attr(docsrs, doc(cfg(stream)))]
-    pub use crate::decode::stream::Stream;
+    pub use crate::decode::reader::{LzmaReader, XzReader};
+    #[cfg(feature = "stream")]
+    #[cfg_attr(docsrs, doc(cfg(stream)))]
+    pub use crate::decode::stream::{Stream, STREAM_MAX_BUFFERED_INPUT};
 }
 
 /// Decompress LZMA data with default
@@ -55,16 +133,139 @@ pub fn lzma_decompress_with_options<R: io::BufRead, W: io::Write>(
     options: &decompress::Options,
 ) -> error::Result<()> {
     let params = decode::lzma::LzmaParams::read_header(input, options)?;
-    let mut decoder = decode::lzma::LzmaDecoder::new(params, options.memlimit)?;
+    let mut decoder = decode::lzma::LzmaDecoder::with_options(params, options)?;
     decoder.decompress(input, output)
 }
 
+/// Decompress LZMA data with the provided options, also returning the
+/// content of the dictionary at the end of the stream: the last decompressed
+/// bytes, up to the dictionary size declared in the header.
+///
+/// This is the history a follow-up stream compressed against this output
+/// would refer to, e.g. in delta chains of successive versions.
+pub fn lzma_decompress_with_dictionary<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+) -> error::Result<Vec<u8>> {
+    let params = decode::lzma::LzmaParams::read_header(input, options)?;
+    let mut decoder = decode::lzma::LzmaDecoder::with_options(params, options)?;
+    decoder.decompress_with_dictionary(input, output)
+}
+
+/// Decompress LZMA data compressed by [`lzma_compress_with_preset_dict`]
+/// with the same `preset_dict`.
+///
+/// Decompressing with another preset dictionary, or without one, fails or
+/// produces garbage.
+pub fn lzma_decompress_with_preset_dict<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+    preset_dict: &[u8],
+) -> error::Result<()> {
+    let params = decode::lzma::LzmaParams::read_header(input, options)?;
+    let mut decoder = decode::lzma::LzmaDecoder::with_options(params, options)?;
+    decoder.decompress_with_preset_dict(input, output, preset_dict)
+}
+
+/// Decompress LZMA data with the provided options, appending it to `output`,
+/// e.g. a ring buffer the decompressed data is consumed from, which also
+/// holds the dictionary.
+///
+/// Nothing is copied through an intermediate buffer, so the
+/// [`memlimit`](decompress::Options::memlimit) option doesn't apply, but
+/// `output` must keep at least as many of its last bytes as the dictionary
+/// size declared in the header. See [`OutputBuffer`](decompress::OutputBuffer).
+pub fn lzma_decompress_into_buffer<R, B>(
+    input: &mut R,
+    output: &mut B,
+    options: &decompress::Options,
+) -> error::Result<()>
+where
+    R: io::BufRead,
+    B: decompress::OutputBuffer + ?Sized,
+{
+    let params = decode::lzma::LzmaParams::read_header(input, options)?;
+    let mut decoder = decode::lzma::LzmaDecoder::with_options(params, options)?;
+    decoder.decompress_into_buffer(input, output)
+}
+
+/// Decompress LZMA data with the provided options, returning the number of
+/// input bytes consumed.
+///
+/// Combined with [`allow_trailing_data`](decompress::Options), this tells
+/// where the LZMA stream ended within the input, see
+/// [`Options::kernel_image`](decompress::Options::kernel_image).
+pub fn lzma_decompress_counted<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+) -> error::Result<u64> {
+    let mut count_input = decode::util::CountBufRead::new(input);
+    lzma_decompress_with_options(&mut count_input, output, options)?;
+    Ok(count_input.count() as u64)
+}
+
+/// Decompress LZMA data with the provided options, keeping at most about
+/// `window_size` bytes of the dictionary in memory.
+///
+/// Matches reaching farther back than the window are copied from the bytes
+/// already written to `output`, which must be readable and seekable, such as
+/// a [`File`](std::fs::File) opened for both reading and writing. This lets
+/// hosts short on memory decompress streams with dictionaries of hundreds of
+/// megabytes, at the cost of a seek and a read for each such match. The
+/// window is at least 4 KiB, and at most the dictionary size.
+///
+/// Decompression starts at the current position of `output`, and the
+/// [`memlimit`](decompress::Options::memlimit) option doesn't apply.
+pub fn lzma_decompress_low_memory<R, W>(
+    input: &mut R,
+    output: &mut W,
+    window_size: usize,
+    options: &decompress::Options,
+) -> error::Result<()>
+where
+    R: io::BufRead,
+    W: io::Read + io::Write + io::Seek,
+{
+    let params = decode::lzma::LzmaParams::read_header(input, options)?;
+    let mut decoder = decode::lzma::LzmaDecoder::with_options(params, options)?;
+    decoder.decompress_windowed(input, output, window_size)
+}
+
+/// Decompress LZMA data from a slice into a new vector, with the provided
+/// options.
+///
+/// The vector is pre-allocated from the unpacked size declared in the
+/// header, within the [`memlimit`](decompress::Options::memlimit) if any,
+/// unless the [`declared_size`](decompress::Options::declared_size) policy
+/// says otherwise.
+pub fn lzma_decompress_to_vec(
+    input: &[u8],
+    options: &decompress::Options,
+) -> error::Result<Vec<u8>> {
+    let mut input = input;
+    let params = decode::lzma::LzmaParams::read_header(&mut input, options)?;
+    let declared_size = match options.declared_size {
+        decompress::DeclaredSize::Hint | decompress::DeclaredSize::Max(_) => params.unpacked_size,
+        decompress::DeclaredSize::Ignore => None,
+    };
+    let capacity = declared_size.map_or(input.len(), |size| {
+        util::capacity_hint(size, options.memlimit.map(|limit| limit as u64))
+    });
+    let mut output = Vec::with_capacity(capacity);
+    let mut decoder = decode::lzma::LzmaDecoder::with_options(params, options)?;
+    decoder.decompress(&mut input, &mut output)?;
+    Ok(output)
+}
+
 /// Compresses data with LZMA and default
 /// [`Options`](compress/struct.Options.html).
 pub fn lzma_compress<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
-) -> io::Result<()> {
+) -> error::Result<()> {
     lzma_compress_with_options(input, output, &compress::Options::default())
 }
 
@@ -73,9 +274,102 @@ pub fn lzma_compress_with_options<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
     options: &compress::Options,
-) -> io::Result<()> {
-    let encoder = encode::dumbencoder::Encoder::from_stream(output, options)?;
-    encoder.process(input)
+) -> error::Result<()> {
+    let output = encode::util::LimitWrite::new(output, options.max_output_size);
+    let mut encoder = compress::LzmaEncoder::new(output, options)?;
+    encoder.process(input)?;
+    Ok(())
+}
+
+/// Compress LZMA data with the provided options to a seekable `output`,
+/// writing the actual unpacked size in the header instead of ending the
+/// stream with an end-of-stream marker.
+///
+/// The header is written at the current position of `output` with the
+/// unknown size as a placeholder, then patched once the input is consumed,
+/// leaving `output` positioned after the stream. The
+/// [`unpacked_size`](compress::Options::unpacked_size) option is ignored.
+/// Returns the unpacked size.
+pub fn lzma_compress_seekable<R, W>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<u64>
+where
+    R: io::BufRead,
+    W: io::Write + io::Seek,
+{
+    let start = output.stream_position()?;
+    // A known size of all ones writes the same field as an unknown size,
+    // without the end-of-stream marker.
+    let options = compress::Options {
+        unpacked_size: compress::UnpackedSize::WriteToHeader(Some(u64::MAX)),
+        ..*options
+    };
+    let limited = encode::util::LimitWrite::new(&mut *output, options.max_output_size);
+    let mut encoder = compress::LzmaEncoder::new(limited, &options)?;
+    encoder.process(input)?;
+    let unpacked_size = encoder.stats().in_bytes.get();
+
+    let end = output.stream_position()?;
+    output.seek(io::SeekFrom::Start(start + 5))?;
+    output.write_all(&unpacked_size.to_le_bytes())?;
+    output.seek(io::SeekFrom::Start(end))?;
+    Ok(unpacked_size)
+}
+
+/// Compress LZMA data with the provided options, against a preset
+/// dictionary shared with the decompressing side.
+///
+/// The dictionary is filled with the last bytes of `preset_dict`, up to the
+/// dictionary size, before the first byte of the input, so that small
+/// messages resembling the preset, e.g. in a protocol with a static
+/// dictionary agreed on by both peers, compress to a few bytes. The output
+/// must be decompressed with [`lzma_decompress_with_preset_dict`].
+pub fn lzma_compress_with_preset_dict<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+    preset_dict: &[u8],
+) -> error::Result<()> {
+    let output = encode::util::LimitWrite::new(output, options.max_output_size);
+    let mut encoder = compress::LzmaEncoder::new(output, options)?;
+    encoder.set_preset_dict(preset_dict);
+    encoder.process(input)?;
+    Ok(())
+}
+
+/// Compress a slice with LZMA and the provided options into a new vector.
+pub fn lzma_compress_to_vec(input: &[u8], options: &compress::Options) -> error::Result<Vec<u8>> {
+    // Uncompressible data takes a bit more space than its raw size, far from
+    // the worst-case bound.
+    let expected = (input.len() as u64 + input.len() as u64 / 8)
+        .min(lzma_compress_bound(input.len() as u64, options));
+    let mut output = Vec::with_capacity(util::capacity_hint(expected, options.max_output_size));
+    let options = options.fit_to(input.len() as u64);
+    lzma_compress_with_options(&mut &input[..], &mut output, &options)?;
+    Ok(output)
+}
+
+/// Compress the concatenation of `slices` with LZMA and the provided
+/// options, without concatenating them into a single buffer first: the
+/// encoder copies them into its window as it goes, as for any input.
+pub fn lzma_compress_slices<'a, I, W>(
+    slices: I,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<()>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    W: io::Write,
+{
+    lzma_compress_with_options(&mut compress::SliceReader::new(slices), output, options)
+}
+
+/// Upper bound on the size of the output of [`lzma_compress_with_options`]
+/// for `len` bytes of input, to pre-allocate output buffers.
+pub fn lzma_compress_bound(len: u64, options: &compress::Options) -> u64 {
+    encode::dumbencoder::compress_bound(len, options)
 }
 
 /// Decompress LZMA2 data with default
@@ -87,13 +381,108 @@ pub fn lzma2_decompress<R: io::BufRead, W: io::Write>(
     decode::lzma2::Lzma2Decoder::new().decompress(input, output)
 }
 
+/// Decompress LZMA2 data, also returning the content of the dictionary at
+/// the end of the stream: the last bytes decompressed since the last
+/// dictionary reset, up to `dict_size`.
+///
+/// The raw stream doesn't declare its dictionary size, so it must be
+/// provided, e.g. from the container.
+pub fn lzma2_decompress_with_dictionary<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    dict_size: u32,
+) -> error::Result<Vec<u8>> {
+    decode::lzma2::Lzma2Decoder::new().decompress_with_dictionary(input, output, dict_size)
+}
+
+/// Decompress LZMA2 data, keeping at most about `window_size` bytes of the
+/// dictionary in memory, like [`lzma_decompress_low_memory`].
+///
+/// The raw stream doesn't declare its dictionary size, so matches may reach
+/// as far back as
+/// [`MAX_DICT_SIZE`](decompress::MAX_DICT_SIZE) bytes, read from `output`.
+pub fn lzma2_decompress_low_memory<R, W>(
+    input: &mut R,
+    output: &mut W,
+    window_size: usize,
+) -> error::Result<()>
+where
+    R: io::BufRead,
+    W: io::Read + io::Write + io::Seek,
+{
+    decode::lzma2::Lzma2Decoder::new().decompress_windowed(input, output, window_size)
+}
+
+/// Decompress LZMA2 data from a slice into a new vector.
+pub fn lzma2_decompress_to_vec(input: &[u8]) -> error::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(util::capacity_hint(input.len() as u64, None));
+    lzma2_decompress(&mut &input[..], &mut output)?;
+    Ok(output)
+}
+
 /// Compress data with LZMA2 and default
 /// [`Options`](compress/struct.Options.html).
 pub fn lzma2_compress<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
-) -> io::Result<()> {
-    encode::lzma2::encode_stream(input, output)
+) -> error::Result<()> {
+    encode::lzma2::encode_stream(input, output)?;
+    Ok(())
+}
+
+/// Compress data with LZMA2 and the provided options.
+///
+/// The raw stream has no header, so only
+/// [`max_output_size`](compress::Options::max_output_size),
+/// [`match_finder`](compress::Options::match_finder),
+/// [`mode`](compress::Options::mode) and the literal coder properties
+/// [`lc`](compress::Options::lc), [`lp`](compress::Options::lp) and
+/// [`pb`](compress::Options::pb) apply; the dictionary
+/// size must be conveyed by the container.
+pub fn lzma2_compress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<()> {
+    options.validate_lzma2()?;
+    let mut output = encode::util::LimitWrite::new(output, options.max_output_size);
+    encode::lzma2::encode_stream_with_stats(input, &mut output, options, &mut Default::default())?;
+    Ok(())
+}
+
+/// Compress data with LZMA2, also returning the integrity check of the
+/// uncompressed data computed with `check_method`.
+///
+/// This is meant for building custom containers around raw LZMA2 streams,
+/// without hashing the input a second time.
+///
+/// [`CheckMethod::Sha256`](xz::CheckMethod::Sha256) is rejected with
+/// [`Error::InvalidOptions`](error::Error::InvalidOptions) before reading any
+/// input: the crate has no SHA-256 implementation, as it doesn't depend on a
+/// cryptography crate, and its `.xz` encoder and decoder don't support that
+/// check either. A SHA-256 of the input has to be computed separately.
+pub fn lzma2_compress_with_check<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    check_method: xz::CheckMethod,
+) -> error::Result<xz::Check> {
+    encode::lzma2::encode_stream_with_check(input, output, check_method)
+}
+
+/// Compress a slice with LZMA2 into a new vector.
+pub fn lzma2_compress_to_vec(input: &[u8]) -> error::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(util::capacity_hint(
+        lzma2_compress_bound(input.len() as u64),
+        None,
+    ));
+    lzma2_compress(&mut &input[..], &mut output)?;
+    Ok(output)
+}
+
+/// Upper bound on the size of the output of [`lzma2_compress`] for `len`
+/// bytes of input, to pre-allocate output buffers.
+pub fn lzma2_compress_bound(len: u64) -> u64 {
+    encode::lzma2::compress_bound(len)
 }
 
 /// Decompress XZ data with default [`Options`](decompress/struct.Options.html).
@@ -101,10 +490,227 @@ pub fn xz_decompress<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
 ) -> error::Result<()> {
-    decode::xz::decode_stream(input, output)
+    xz_decompress_with_options(input, output, &decompress::Options::default())?;
+    Ok(())
+}
+
+/// Decompress XZ data with the provided options, returning a summary of the
+/// stream.
+///
+/// Of the options, only
+/// [`unsupported_check`](decompress::Options::unsupported_check) applies to
+/// `.xz` streams.
+pub fn xz_decompress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+) -> error::Result<decompress::XzSummary> {
+    xz_decompress_with_block_callback(input, output, options, |_| ())
+}
+
+/// Decompress XZ data like [`xz_decompress_with_options`], calling
+/// `on_block` after each block is decompressed and verified.
+///
+/// The callback gets the header of the block, its sizes and its offsets in
+/// the compressed and decompressed data, which is enough for indexing tools
+/// to build their own index of the stream while extracting it in one pass.
+pub fn xz_decompress_with_block_callback<R, W, F>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+    mut on_block: F,
+) -> error::Result<decompress::XzSummary>
+where
+    R: io::BufRead,
+    W: io::Write,
+    F: FnMut(&decompress::XzBlockInfo),
+{
+    options.validate()?;
+    decode::xz::decode_stream(input, output, options.unsupported_check, &mut on_block)
+}
+
+/// Decompress XZ data from a slice into a new vector.
+///
+/// The vector is pre-allocated from the unpacked size recorded in the index,
+/// or from the size of the input if the index can't be read.
+pub fn xz_decompress_to_vec(input: &[u8]) -> error::Result<Vec<u8>> {
+    let capacity = xz::Index::read(&mut io::Cursor::new(input))
+        .ok()
+        .and_then(|index| index.checked_unpacked_size())
+        .map_or(input.len(), |size| util::capacity_hint(size.get(), None));
+    let mut output = Vec::with_capacity(capacity);
+    xz_decompress(&mut &input[..], &mut output)?;
+    Ok(output)
 }
 
 /// Compress data with XZ and default [`Options`](compress/struct.Options.html).
-pub fn xz_compress<R: io::BufRead, W: io::Write>(input: &mut R, output: &mut W) -> io::Result<()> {
-    encode::xz::encode_stream(input, output)
+pub fn xz_compress<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+) -> error::Result<()> {
+    xz_compress_with_options(input, output, &compress::Options::default())
+}
+
+/// Compress data with XZ and the provided options.
+pub fn xz_compress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<()> {
+    encode::xz::encode_stream(input, output, options)
+}
+
+/// Compress a slice with XZ and the provided options into a new vector.
+pub fn xz_compress_to_vec(input: &[u8], options: &compress::Options) -> error::Result<Vec<u8>> {
+    let options = &options.fit_to(input.len() as u64);
+    let mut output = Vec::with_capacity(util::capacity_hint(
+        xz_compress_bound(input.len() as u64, options),
+        options.max_output_size,
+    ));
+    let mut writer = compress::XzWriter::new(&mut output, options)?;
+    writer.write_all(input)?;
+    writer.finish()?;
+    Ok(output)
+}
+
+/// Compress a slice with XZ and the provided options into a new vector,
+/// splitting it into shards compressed independently on `parallelism`
+/// threads.
+///
+/// Each shard becomes one block of
+/// [`block_size`](compress::Options::block_size) bytes, or by default of 3
+/// times the dictionary size but at least 1 MiB, as done by xz-utils in
+/// multi-threaded mode. Without the
+/// [`deterministic`](compress::Options::deterministic) option, the default
+/// shards are made smaller, down to 256 KiB, so that each thread gets one. The
+/// shards are compressed straight from `input`, which is meant to be a large
+/// buffer such as a memory-mapped file, so each thread only holds the encoder
+/// of its current shard besides the compressed output. If `parallelism` is 0,
+/// the available parallelism of the host is used.
+///
+/// The output is the same as that of [`compress::XzWriter`] with the block
+/// size of the shards, which only depends on `parallelism` without the
+/// `deterministic` option: by default, any number of threads gives the same
+/// output.
+pub fn xz_compress_parallel(
+    input: &[u8],
+    options: &compress::Options,
+    parallelism: usize,
+) -> error::Result<Vec<u8>> {
+    encode::parallel::compress_parallel(input, options, parallelism)
+}
+
+/// Compress the concatenation of `slices` with XZ and the provided options,
+/// without copying them into a single buffer.
+pub fn xz_compress_slices<'a, I, W>(
+    slices: I,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<()>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    W: io::Write,
+{
+    xz_compress_with_options(&mut compress::SliceReader::new(slices), output, options)
+}
+
+/// Upper bound on the size of the output of [`xz_compress_with_options`] and
+/// [`compress::XzWriter`] for `len` bytes of input with the given `options`,
+/// to pre-allocate output buffers.
+///
+/// The bound assumes that chunks and blocks end only where `options` puts
+/// them. Each call to
+/// [`XzWriter::flush_sync()`](compress::XzWriter::flush_sync) that has data
+/// to compress ends a chunk early, which adds up to 3 bytes, and each
+/// boundary given to
+/// [`XzWriter::with_block_list()`](compress::XzWriter::with_block_list)
+/// also starts a block early, which adds the header, padding, check and
+/// index record of that block as well.
+pub fn xz_compress_bound(len: u64, options: &compress::Options) -> u64 {
+    encode::xz::compress_bound(len, options)
+}
+
+/// Compress data in the given `format` with the provided options, returning
+/// statistics of the run, e.g. to log the compression ratio.
+pub fn compress_with_stats<R: io::BufRead, W: io::Write>(
+    format: Format,
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<compress::CompressStats> {
+    match format {
+        Format::Lzma => {
+            let output = encode::util::LimitWrite::new(output, options.max_output_size);
+            let mut encoder = compress::LzmaEncoder::new(output, options)?;
+            encoder.process(input)?;
+            Ok(encoder.stats())
+        }
+        Format::Lzma2 => {
+            options.validate_lzma2()?;
+            let start = std::time::Instant::now();
+            let mut output = encode::util::LimitWrite::new(output, options.max_output_size);
+            let mut stats = compress::CompressStats::default();
+            encode::lzma2::encode_stream_with_stats(input, &mut output, options, &mut stats)?;
+            stats.out_bytes = CompressedSize(output.count());
+            stats.duration = start.elapsed();
+            Ok(stats)
+        }
+        Format::Xz => {
+            let mut writer = compress::XzWriter::new(output, options)?;
+            io::copy(input, &mut writer)?;
+            writer.try_finish()?;
+            let stats = writer.stats();
+            writer.finish()?;
+            Ok(stats)
+        }
+    }
+}
+
+/// Compressed stream holding no data in the given `format`, with the
+/// provided options.
+///
+/// For LZMA, the smallest stream is obtained by writing a known unpacked size
+/// of zero in the header, which avoids the end-of-stream marker, unless the
+/// options skip writing the size. For XZ, the stream has no block. The
+/// options otherwise apply as in [`lzma_compress_with_options`],
+/// [`lzma2_compress_with_options`] and [`xz_compress_with_options`].
+pub fn empty_stream(format: Format, options: &compress::Options) -> error::Result<Vec<u8>> {
+    match format {
+        Format::Lzma => {
+            let options = match options.unpacked_size {
+                compress::UnpackedSize::SkipWritingToHeader => *options,
+                compress::UnpackedSize::WriteToHeader(_) => compress::Options {
+                    unpacked_size: compress::UnpackedSize::WriteToHeader(Some(0)),
+                    ..*options
+                },
+            };
+            lzma_compress_to_vec(&[], &options)
+        }
+        Format::Lzma2 => {
+            let mut output = Vec::new();
+            lzma2_compress_with_options(&mut &[][..], &mut output, options)?;
+            Ok(output)
+        }
+        Format::Xz => xz_compress_to_vec(&[], options),
+    }
+}
+
+/// Decompress XZ data and compress it again with the provided options.
+///
+/// Decompressed data is fed to the compressor as it is produced, so that the
+/// whole decompressed content is never held in memory.
+pub fn transcode_xz<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+) -> error::Result<()> {
+    let mut writer = compress::XzWriter::new(output, options)?;
+    decode::xz::decode_stream(
+        input,
+        &mut writer,
+        decompress::UnsupportedCheck::Error,
+        &mut |_| (),
+    )?;
+    writer.finish()?;
+    Ok(())
 }
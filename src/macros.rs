@@ -22,6 +22,14 @@ macro_rules! lzma_info {
     }
 }
 
+/// Log warning message (feature: enabled).
+#[cfg(feature = "enable_logging")]
+macro_rules! lzma_warn {
+    ($($arg:tt)+) => {
+        log::warn!($($arg)+);
+    }
+}
+
 /// Log trace message (feature: disabled).
 #[cfg(not(feature = "enable_logging"))]
 macro_rules! lzma_trace {
@@ -39,3 +47,27 @@ macro_rules! lzma_debug {
 macro_rules! lzma_info {
     ($($arg:tt)+) => {};
 }
+
+/// Log warning message (feature: disabled).
+#[cfg(not(feature = "enable_logging"))]
+macro_rules! lzma_warn {
+    ($($arg:tt)+) => {};
+}
+
+/// Check an internal invariant of the coders (feature: enabled), in release
+/// builds as well.
+#[cfg(feature = "checked")]
+macro_rules! lzma_check {
+    ($($arg:tt)+) => {
+        assert!($($arg)+);
+    }
+}
+
+/// Check an internal invariant of the coders (feature: disabled), in debug
+/// builds only.
+#[cfg(not(feature = "checked"))]
+macro_rules! lzma_check {
+    ($($arg:tt)+) => {
+        debug_assert!($($arg)+);
+    }
+}
@@ -0,0 +1,191 @@
+//! Magic numbers of the container formats, and format detection.
+
+use crate::decode::carve;
+use crate::xz::crc::CRC32;
+use crate::xz::StreamFlags;
+use crate::Format;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+pub use crate::xz::footer::XZ_MAGIC_FOOTER;
+pub use crate::xz::header::XZ_MAGIC;
+
+/// Magic bytes at the start of `.lz` (lzip) files.
+///
+/// lzip files are not decoded by this crate, and [`sniff`] does not report
+/// them; the constant helps multiplexers tell them apart from `.lzma` files.
+pub const LZIP_MAGIC: &[u8] = b"LZIP";
+
+/// Guess the format of the compressed data starting with `data`.
+///
+/// `.xz` streams are recognized by their magic bytes. `.lzma` streams have no
+/// magic, so their header fields are checked against the values written by
+/// common encoders, as done by
+/// [`find_embedded_streams`](crate::decompress::find_embedded_streams); at
+/// least 14 bytes are needed. Raw LZMA2 streams cannot be recognized.
+pub fn sniff(data: &[u8]) -> Option<Format> {
+    if data.starts_with(XZ_MAGIC) {
+        Some(Format::Xz)
+    } else if data.starts_with(LZIP_MAGIC) {
+        None
+    } else if carve::is_plausible_lzma_header(data, usize::MAX) {
+        Some(Format::Lzma)
+    } else {
+        None
+    }
+}
+
+/// Subformat of a `.lzma` stream, told by the unpacked size in its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LzmaSubformat {
+    /// The header declares the size of the decompressed data, so it is
+    /// known before decoding; the stream may still end with a marker.
+    Sized(u64),
+    /// The header leaves the size unknown, and the stream ends with an
+    /// end-of-stream marker.
+    Streamed,
+}
+
+impl LzmaSubformat {
+    /// Subformat declared by the 13-byte `.lzma` header at the start of
+    /// `header`, or `None` if it is too short.
+    pub fn from_header(header: &[u8]) -> Option<Self> {
+        let size = header.get(5..13)?;
+        Some(match LittleEndian::read_u64(size) {
+            u64::MAX => LzmaSubformat::Streamed,
+            size => LzmaSubformat::Sized(size),
+        })
+    }
+
+    /// Size of the decompressed data, if declared.
+    pub fn unpacked_size(self) -> Option<u64> {
+        match self {
+            LzmaSubformat::Sized(size) => Some(size),
+            LzmaSubformat::Streamed => None,
+        }
+    }
+}
+
+/// Like [`sniff`], but only recognize `.lzma` streams, and tell whether
+/// their header declares the unpacked size.
+pub fn sniff_lzma(data: &[u8]) -> Option<LzmaSubformat> {
+    match sniff(data) {
+        Some(Format::Lzma) => LzmaSubformat::from_header(data),
+        _ => None,
+    }
+}
+
+/// Check whether `prefix` could be the start of a valid stream of the given
+/// `format`, without decoding it.
+///
+/// Only the fields covered by `prefix` are checked: the magic bytes, stream
+/// flags and header CRC32 of `.xz` streams, the properties byte and first
+/// range coder byte of `.lzma` streams (assuming the unpacked size is in the
+/// header), and the first chunk header of LZMA2 streams. An empty prefix is
+/// always valid.
+pub fn is_valid_prefix(prefix: &[u8], format: Format) -> bool {
+    match format {
+        Format::Xz => is_valid_xz_prefix(prefix),
+        Format::Lzma => is_valid_lzma_prefix(prefix),
+        Format::Lzma2 => is_valid_lzma2_prefix(prefix),
+    }
+}
+
+fn is_valid_xz_prefix(prefix: &[u8]) -> bool {
+    let magic_len = std::cmp::min(prefix.len(), XZ_MAGIC.len());
+    if prefix[..magic_len] != XZ_MAGIC[..magic_len] {
+        return false;
+    }
+    // Stream Flags, then their CRC32.
+    if prefix.len() >= 8 && StreamFlags::parse(BigEndian::read_u16(&prefix[6..8])).is_err() {
+        return false;
+    }
+    if prefix.len() >= 12 && CRC32.checksum(&prefix[6..8]) != LittleEndian::read_u32(&prefix[8..12])
+    {
+        return false;
+    }
+    true
+}
+
+fn is_valid_lzma_prefix(prefix: &[u8]) -> bool {
+    // Properties byte, then after the 13 bytes of header the first byte of
+    // the range coder, which is always null.
+    !(prefix.first().map_or(false, |&props| props >= 225)
+        || prefix.get(13).map_or(false, |&byte| byte != 0))
+}
+
+fn is_valid_lzma2_prefix(prefix: &[u8]) -> bool {
+    match prefix.first() {
+        None => true,
+        // End of stream, or uncompressed chunk resetting the dictionary.
+        Some(0x00) | Some(0x01) => true,
+        // LZMA chunk resetting the dictionary, with new properties.
+        Some(0xE0..=0xFF) => match prefix.get(5) {
+            Some(&props) => {
+                let lc = props % 9;
+                let lp = (props / 9) % 5;
+                props < 225 && lc + lp <= 4
+            }
+            None => true,
+        },
+        // Other chunks need a dictionary from a previous chunk.
+        Some(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        let xz = crate::xz_compress_to_vec(b"abc", &Default::default()).unwrap();
+        assert_eq!(sniff(&xz), Some(Format::Xz));
+        let lzma = crate::lzma_compress_to_vec(b"abc", &Default::default()).unwrap();
+        assert_eq!(sniff(&lzma), Some(Format::Lzma));
+        let lzma2 = crate::lzma2_compress_to_vec(b"abc").unwrap();
+        assert_eq!(sniff(&lzma2), None);
+        assert_eq!(sniff(b"LZIP\x01\x0c"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn test_sniff_lzma() {
+        let streamed = crate::lzma_compress_to_vec(b"abc", &Default::default()).unwrap();
+        assert_eq!(sniff_lzma(&streamed), Some(LzmaSubformat::Streamed));
+
+        let options = crate::compress::Options {
+            unpacked_size: crate::compress::UnpackedSize::WriteToHeader(Some(3)),
+            ..Default::default()
+        };
+        let sized = crate::lzma_compress_to_vec(b"abc", &options).unwrap();
+        assert_eq!(sniff_lzma(&sized), Some(LzmaSubformat::Sized(3)));
+        assert_eq!(LzmaSubformat::Sized(3).unpacked_size(), Some(3));
+
+        let xz = crate::xz_compress_to_vec(b"abc", &Default::default()).unwrap();
+        assert_eq!(sniff_lzma(&xz), None);
+        assert_eq!(LzmaSubformat::from_header(&sized[..12]), None);
+    }
+
+    #[test]
+    fn test_is_valid_prefix() {
+        for &format in [Format::Lzma, Format::Lzma2, Format::Xz].iter() {
+            let stream = crate::empty_stream(format, &Default::default()).unwrap();
+            for len in 0..=stream.len() {
+                assert!(is_valid_prefix(&stream[..len], format), "{:?}", format);
+            }
+        }
+
+        let xz = crate::xz_compress_to_vec(b"abc", &Default::default()).unwrap();
+        let mut corrupted = xz.clone();
+        corrupted[8] ^= 1;
+        assert!(!is_valid_prefix(&corrupted[..12], Format::Xz));
+        assert!(is_valid_prefix(&corrupted[..8], Format::Xz));
+        assert!(is_valid_prefix(b"\xFD7zX", Format::Xz));
+        assert!(!is_valid_prefix(b"\xFD7zY", Format::Xz));
+
+        assert!(!is_valid_prefix(&[225], Format::Lzma));
+        assert!(!is_valid_prefix(&[0x02], Format::Lzma2));
+        assert!(!is_valid_prefix(&[0xE0, 0, 0, 0, 0, 13], Format::Lzma2));
+        assert!(is_valid_prefix(&[0xE0, 0, 0, 0, 0, 0x5D], Format::Lzma2));
+    }
+}
@@ -0,0 +1,65 @@
+//! Sizes of compressed and uncompressed data.
+//!
+//! Containers record both kinds of sizes side by side, and mixing them up
+//! compiles fine when both are plain integers. These wrappers keep them
+//! apart, while converting from and to `u64` where a raw value is needed.
+
+use std::fmt;
+
+macro_rules! size_type {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u64);
+
+        impl $name {
+            /// Number of bytes.
+            pub fn get(self) -> u64 {
+                self.0
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(size: u64) -> Self {
+                Self(size)
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(size: $name) -> u64 {
+                size.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(fmt)
+            }
+        }
+    };
+}
+
+size_type!(
+    /// Size in bytes of compressed data, as stored in the stream.
+    CompressedSize
+);
+
+size_type!(
+    /// Size in bytes of uncompressed data, as produced by the decoder.
+    UncompressedSize
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let size = CompressedSize::from(42);
+        assert_eq!(size.get(), 42);
+        assert_eq!(u64::from(size), 42);
+        assert_eq!(size.to_string(), "42");
+        assert_eq!(UncompressedSize::from(7), UncompressedSize(7));
+        assert!(UncompressedSize(1) < UncompressedSize(2));
+    }
+}
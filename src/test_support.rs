@@ -0,0 +1,175 @@
+//! Utilities to derive corrupt variants of valid streams, for robustness tests
+//! of code built on top of this crate.
+//!
+//! Each [`Variant`] records the mutation applied and the range of bytes it
+//! affects, and [`assert_rejected()`] checks that decoding fails with the
+//! expected [`ErrorClass`].
+
+use crate::{error, Format};
+use std::ops::Range;
+
+/// Change applied to a valid stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    /// The bit at the given index, counting from the least significant bit
+    /// of the first byte, is inverted.
+    FlipBit(usize),
+    /// The stream is cut to the given length.
+    Truncate(usize),
+    /// The stream is cut at `at` and continued with the bytes of another
+    /// stream starting at `from`.
+    Splice {
+        /// Length kept from the first stream.
+        at: usize,
+        /// Offset of the continuation in the second stream.
+        from: usize,
+    },
+}
+
+/// Corrupt variant of a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variant {
+    /// Mutated stream.
+    pub data: Vec<u8>,
+    /// Mutation applied to the original stream.
+    pub mutation: Mutation,
+    /// Bytes of `data` that differ from the original stream, or the empty
+    /// range at its end if bytes were removed.
+    pub corrupt: Range<usize>,
+}
+
+/// Copy of `data` with the bit at index `bit` inverted.
+pub fn flip_bit(data: &[u8], bit: usize) -> Variant {
+    let mut mutated = data.to_vec();
+    mutated[bit / 8] ^= 1 << (bit % 8);
+    Variant {
+        data: mutated,
+        mutation: Mutation::FlipBit(bit),
+        corrupt: bit / 8..bit / 8 + 1,
+    }
+}
+
+/// Copy of the first `len` bytes of `data`.
+pub fn truncate(data: &[u8], len: usize) -> Variant {
+    Variant {
+        data: data[..len].to_vec(),
+        mutation: Mutation::Truncate(len),
+        corrupt: len..len,
+    }
+}
+
+/// The first `at` bytes of `head` followed by `tail` from offset `from`.
+pub fn splice(head: &[u8], at: usize, tail: &[u8], from: usize) -> Variant {
+    let mut data = head[..at].to_vec();
+    data.extend_from_slice(&tail[from..]);
+    let corrupt = at..data.len();
+    Variant {
+        data,
+        mutation: Mutation::Splice { at, from },
+        corrupt,
+    }
+}
+
+/// Every single-bit flip within the `bytes` of `data`.
+pub fn bit_flips(data: &[u8], bytes: Range<usize>) -> impl Iterator<Item = Variant> + '_ {
+    (bytes.start * 8..bytes.end * 8).map(move |bit| flip_bit(data, bit))
+}
+
+/// Every strict prefix of `data`.
+pub fn truncations(data: &[u8]) -> impl Iterator<Item = Variant> + '_ {
+    (0..data.len()).map(move |len| truncate(data, len))
+}
+
+/// Coarse classification of [`Error`](error::Error) variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// [`Error::IoError`](error::Error::IoError).
+    Io,
+    /// [`Error::HeaderTooShort`](error::Error::HeaderTooShort).
+    HeaderTooShort,
+    /// [`Error::LzmaError`](error::Error::LzmaError).
+    Lzma,
+    /// [`Error::XzError`](error::Error::XzError).
+    Xz,
+    /// [`Error::Truncated`](error::Error::Truncated).
+    Truncated,
+    /// [`Error::MemoryLimitExceeded`](error::Error::MemoryLimitExceeded).
+    MemoryLimitExceeded,
+    /// [`Error::InvalidOptions`](error::Error::InvalidOptions).
+    InvalidOptions,
+    /// [`Error::Cancelled`](error::Error::Cancelled).
+    Cancelled,
+}
+
+impl ErrorClass {
+    /// Class of the given error.
+    pub fn of(error: &error::Error) -> Self {
+        match error {
+            error::Error::IoError(_) => ErrorClass::Io,
+            error::Error::HeaderTooShort(_) => ErrorClass::HeaderTooShort,
+            error::Error::LzmaError(_) => ErrorClass::Lzma,
+            error::Error::XzError(_) => ErrorClass::Xz,
+            error::Error::Truncated(_) => ErrorClass::Truncated,
+            error::Error::MemoryLimitExceeded(_) => ErrorClass::MemoryLimitExceeded,
+            error::Error::InvalidOptions(_) => ErrorClass::InvalidOptions,
+            error::Error::Cancelled => ErrorClass::Cancelled,
+        }
+    }
+}
+
+/// Decompress `data` in the given `format` with the default options.
+pub fn decode(data: &[u8], format: Format) -> error::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut input = data;
+    match format {
+        Format::Lzma => crate::lzma_decompress(&mut input, &mut output)?,
+        Format::Lzma2 => crate::lzma2_decompress(&mut input, &mut output)?,
+        Format::Xz => crate::xz_decompress(&mut input, &mut output)?,
+    }
+    Ok(output)
+}
+
+/// Panic unless decompressing `data` in the given `format` fails with an
+/// error of one of the `expected` classes.
+#[track_caller]
+pub fn assert_rejected(data: &[u8], format: Format, expected: &[ErrorClass]) {
+    match decode(data, format) {
+        Ok(output) => panic!(
+            "{:?} stream of {} bytes was accepted, decoding to {} bytes",
+            format,
+            data.len(),
+            output.len()
+        ),
+        Err(e) => assert!(
+            expected.contains(&ErrorClass::of(&e)),
+            "{:?} stream of {} bytes was rejected with {:?} ({}), expected one of {:?}",
+            format,
+            data.len(),
+            ErrorClass::of(&e),
+            e,
+            expected
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mutations() {
+        let data = [0u8, 1, 2, 3];
+        let variant = flip_bit(&data, 9);
+        assert_eq!(variant.data, [0, 3, 2, 3]);
+        assert_eq!(variant.corrupt, 1..2);
+
+        assert_eq!(truncate(&data, 1).data, [0]);
+        assert_eq!(truncations(&data).count(), 4);
+        assert_eq!(bit_flips(&data, 1..3).count(), 16);
+
+        let variant = splice(&data, 2, &[7, 8, 9], 1);
+        assert_eq!(variant.data, [0, 1, 8, 9]);
+        assert_eq!(variant.corrupt, 2..4);
+        assert_eq!(variant.mutation, Mutation::Splice { at: 2, from: 1 });
+    }
+}
@@ -1,5 +1,16 @@
 pub mod vec2d;
 
+/// Largest initial capacity reserved by the `*_to_vec` functions, so that a
+/// size declared by untrusted input cannot trigger a huge allocation upfront.
+const MAX_CAPACITY_HINT: u64 = 1 << 26;
+
+/// Initial capacity of an output buffer expected to hold `expected` bytes,
+/// bounded by the optional `limit` and by [`MAX_CAPACITY_HINT`].
+pub(crate) fn capacity_hint(expected: u64, limit: Option<u64>) -> usize {
+    let hint = expected.min(MAX_CAPACITY_HINT);
+    limit.map_or(hint, |limit| hint.min(limit)) as usize
+}
+
 /// macro for compile-time const assertions
 macro_rules! const_assert {
     ($message:expr, $($list:ident : $ty:ty),* => $expr:expr) => {{
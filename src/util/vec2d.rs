@@ -30,6 +30,29 @@ impl<T> Vec2D<T> {
     {
         self.data.fill(value)
     }
+
+    /// All the elements, row after row.
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    /// All the elements, row after row.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    /// Copies `row`, which must have as many elements as the columns, into
+    /// every row of the grid.
+    #[cfg(feature = "raw_decoder")]
+    pub fn fill_rows(&mut self, row: &[T])
+    where
+        T: Clone,
+    {
+        assert_eq!(row.len(), self.cols);
+        for chunk in self.data.chunks_exact_mut(self.cols) {
+            chunk.clone_from_slice(row);
+        }
+    }
 }
 
 impl<T> Index<usize> for Vec2D<T> {
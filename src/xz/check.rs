@@ -0,0 +1,78 @@
+//! Integrity checks of the uncompressed data, see spec section 3.4.
+
+use crate::xz::crc::{CRC32, CRC64};
+use crate::xz::CheckMethod;
+
+/// Value of an integrity check computed on uncompressed data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Check {
+    /// No check.
+    None,
+    /// CRC32 of the uncompressed data.
+    Crc32(u32),
+    /// CRC64 of the uncompressed data.
+    Crc64(u64),
+}
+
+impl Check {
+    /// Method used to compute this check.
+    pub fn method(&self) -> CheckMethod {
+        match self {
+            Check::None => CheckMethod::None,
+            Check::Crc32(_) => CheckMethod::Crc32,
+            Check::Crc64(_) => CheckMethod::Crc64,
+        }
+    }
+
+    /// Serialize the check as in the "Block Check" field of `.xz` blocks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Check::None => vec![],
+            Check::Crc32(crc32) => crc32.to_le_bytes().to_vec(),
+            Check::Crc64(crc64) => crc64.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+/// Hasher computing a [`Check`] incrementally.
+pub(crate) enum CheckDigest {
+    None,
+    Crc32(crc::Digest<'static, u32>),
+    Crc64(crc::Digest<'static, u64>),
+    Sha256,
+}
+
+impl CheckDigest {
+    pub(crate) fn new(check_method: CheckMethod) -> Self {
+        match check_method {
+            CheckMethod::None => CheckDigest::None,
+            CheckMethod::Crc32 => CheckDigest::Crc32(CRC32.digest()),
+            CheckMethod::Crc64 => CheckDigest::Crc64(CRC64.digest()),
+            CheckMethod::Sha256 => CheckDigest::Sha256,
+        }
+    }
+
+    /// Whether checks of the given method can be computed.
+    pub(crate) fn is_supported(check_method: CheckMethod) -> bool {
+        check_method != CheckMethod::Sha256
+    }
+
+    pub(crate) fn update(&mut self, buf: &[u8]) {
+        match self {
+            CheckDigest::None | CheckDigest::Sha256 => (),
+            CheckDigest::Crc32(digest) => digest.update(buf),
+            CheckDigest::Crc64(digest) => digest.update(buf),
+        }
+    }
+
+    /// Get the check of the data hashed so far, or `None` if the check
+    /// method isn't supported.
+    pub(crate) fn finalize(self) -> Option<Check> {
+        match self {
+            CheckDigest::None => Some(Check::None),
+            CheckDigest::Crc32(digest) => Some(Check::Crc32(digest.finalize())),
+            CheckDigest::Crc64(digest) => Some(Check::Crc64(digest.finalize())),
+            CheckDigest::Sha256 => None,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Delta filter, see spec section 5.3.2.
+
+/// State of the delta filter, shared by the encoder and the decoder.
+#[derive(Clone)]
+pub(crate) struct DeltaState {
+    /// Distance between the subtracted bytes, in 1..=256
+    distance: usize,
+    /// Last 256 unfiltered bytes
+    history: [u8; 256],
+    /// Position of the next byte in the history
+    pos: usize,
+}
+
+impl DeltaState {
+    pub(crate) fn new(distance: usize) -> Self {
+        debug_assert!((1..=256).contains(&distance));
+        Self {
+            distance,
+            history: [0; 256],
+            pos: 0,
+        }
+    }
+
+    /// Replace each byte with its difference to the byte `distance` positions
+    /// before.
+    pub(crate) fn encode(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            let original = *byte;
+            *byte = original.wrapping_sub(self.history[(self.pos + 256 - self.distance) & 0xFF]);
+            self.push(original);
+        }
+    }
+
+    /// Revert [`DeltaState::encode()`].
+    pub(crate) fn decode(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = byte.wrapping_add(self.history[(self.pos + 256 - self.distance) & 0xFF]);
+            self.push(*byte);
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.history[self.pos] = byte;
+        self.pos = (self.pos + 1) & 0xFF;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * i % 253) as u8).collect();
+        for &distance in [1, 2, 7, 255, 256].iter() {
+            let mut encoder = DeltaState::new(distance);
+            let mut decoder = DeltaState::new(distance);
+            let mut buf = data.clone();
+            // Filter in uneven pieces, as the state carries over.
+            for piece in buf.chunks_mut(77) {
+                encoder.encode(piece);
+            }
+            assert_eq!(buf[..distance], data[..distance]);
+            assert_eq!(buf[distance], data[distance].wrapping_sub(data[0]));
+            for piece in buf.chunks_mut(100) {
+                decoder.decode(piece);
+            }
+            assert_eq!(buf, data, "distance = {}", distance);
+        }
+    }
+}
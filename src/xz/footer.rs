@@ -1,4 +1,63 @@
 //! XZ footer.
 
+use crate::decode::util;
+use crate::error;
+use crate::xz::crc::CRC32;
+use crate::xz::StreamFlags;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+
 /// File format trailing terminator, see sect. 2.1.2.4.
-pub(crate) const XZ_MAGIC_FOOTER: &[u8] = &[0x59, 0x5A];
+pub const XZ_MAGIC_FOOTER: &[u8] = &[0x59, 0x5A];
+
+/// Size of a Stream Footer, see sect. 2.1.2.
+pub(crate) const XZ_FOOTER_SIZE: u64 = 12;
+
+/// Stream Footer, see sect. 2.1.2.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StreamFooter {
+    /// Backward Size, as stored in the footer.
+    pub(crate) backward_size: u32,
+    pub(crate) stream_flags: StreamFlags,
+}
+
+impl StreamFooter {
+    /// Parse a Stream Footer from a buffered reader.
+    pub(crate) fn parse<BR>(input: &mut BR) -> error::Result<Self>
+    where
+        BR: std::io::BufRead,
+    {
+        let crc32 = input.read_u32::<LittleEndian>()?;
+        let (backward_size, flags, digested) = {
+            let mut digest = CRC32.digest();
+            let mut digest_rd = util::CrcDigestRead::new(input, &mut digest);
+            let backward_size = digest_rd.read_u32::<LittleEndian>()?;
+            let flags = digest_rd.read_u16::<BigEndian>()?;
+            (backward_size, flags, digest.finalize())
+        };
+
+        if crc32 != digested {
+            return Err(error::Error::XzError(format!(
+                "Invalid footer CRC32: expected 0x{:08x} but got 0x{:08x}",
+                crc32, digested
+            )));
+        }
+
+        if !util::read_tag(input, XZ_MAGIC_FOOTER)? {
+            return Err(error::Error::XzError(format!(
+                "Invalid footer magic, expected {:?}",
+                XZ_MAGIC_FOOTER
+            )));
+        }
+
+        let stream_flags = StreamFlags::parse(flags)?;
+        Ok(Self {
+            backward_size,
+            stream_flags,
+        })
+    }
+
+    /// Size of the Index described by this footer, in bytes.
+    pub(crate) fn index_size(&self) -> u64 {
+        (self.backward_size as u64 + 1) << 2
+    }
+}
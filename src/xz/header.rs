@@ -7,7 +7,10 @@ use crate::xz::StreamFlags;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 
 /// File format magic header signature, see sect. 2.1.1.1.
-pub(crate) const XZ_MAGIC: &[u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+pub const XZ_MAGIC: &[u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+
+/// Size of a Stream Header, see sect. 2.1.1.
+pub(crate) const XZ_HEADER_SIZE: u64 = 12;
 
 /// Stream Header, see sect. 2.1.1.
 #[derive(Clone, Copy, Debug)]
@@ -0,0 +1,274 @@
+//! XZ index.
+
+use crate::decode::util;
+use crate::decode::xz::get_multibyte;
+use crate::xz::crc::CRC32;
+use crate::xz::footer::{StreamFooter, XZ_FOOTER_SIZE};
+use crate::xz::header::{StreamHeader, XZ_HEADER_SIZE};
+use crate::xz::{CheckMethod, StreamFlags};
+use crate::{error, CompressedSize, UncompressedSize};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Smallest valid Unpadded Size, see sect. 4.3.2.
+const UNPADDED_SIZE_MIN: u64 = 5;
+
+/// Largest valid Unpadded Size: the largest multi-byte integer, see sect.
+/// 1.2, rounded down to a multiple of four.
+const UNPADDED_SIZE_MAX: u64 = (u64::MAX >> 1) & !3;
+
+/// Index Record, see sect. 4.3.
+///
+/// Describes the size of one block of the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexRecord {
+    /// Size of the block header, compressed data and check, without the
+    /// block padding.
+    pub unpadded_size: CompressedSize,
+    /// Size of the decompressed data of the block.
+    pub unpacked_size: UncompressedSize,
+}
+
+impl IndexRecord {
+    /// Size of the block in the stream, including the block padding.
+    pub fn padded_size(&self) -> CompressedSize {
+        CompressedSize((self.unpadded_size.0 + 3) & !3)
+    }
+}
+
+/// Index of an `.xz` stream, see sect. 4.
+///
+/// The index lists every block of the stream, which allows locating a block
+/// without decoding the blocks before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Index {
+    pub(crate) stream_flags: StreamFlags,
+    pub(crate) records: Vec<IndexRecord>,
+}
+
+impl Index {
+    /// Read the index of a single-stream `.xz` file, seeking to the footer
+    /// and then to the index, without decoding any block.
+    ///
+    /// The stream header is also read to check that it matches the footer.
+    /// Trailing stream padding and concatenated streams are not supported.
+    pub fn read<R>(input: &mut R) -> error::Result<Self>
+    where
+        R: Read + Seek,
+    {
+        input.seek(SeekFrom::Start(0))?;
+        let header = StreamHeader::parse(&mut io::BufReader::new(&mut *input))?;
+
+        let file_size = input.seek(SeekFrom::End(0))?;
+        if file_size < XZ_HEADER_SIZE + XZ_FOOTER_SIZE {
+            return Err(error::Error::Truncated(format!(
+                "File too short for an XZ stream: {} bytes",
+                file_size
+            )));
+        }
+        input.seek(SeekFrom::Start(file_size - XZ_FOOTER_SIZE))?;
+        let footer = StreamFooter::parse(&mut io::BufReader::new(&mut *input))?;
+        if header.stream_flags != footer.stream_flags {
+            return Err(error::Error::XzError(format!(
+                "Flags in header ({:?}) does not match footer ({:?})",
+                header.stream_flags, footer.stream_flags
+            )));
+        }
+
+        let index_size = footer.index_size();
+        if index_size > file_size - XZ_HEADER_SIZE - XZ_FOOTER_SIZE {
+            return Err(error::Error::XzError(format!(
+                "Invalid index size: {} bytes do not fit in the stream",
+                index_size
+            )));
+        }
+        let index_start = file_size - XZ_FOOTER_SIZE - index_size;
+        input.seek(SeekFrom::Start(index_start))?;
+
+        let mut buf_input = io::BufReader::new((&mut *input).take(index_size));
+        let mut count_input = util::CountBufRead::new(&mut buf_input);
+        if count_input.read_u8()? != 0 {
+            return Err(error::Error::XzError(
+                "Invalid index indicator, expected a null byte".to_string(),
+            ));
+        }
+        let records = parse_records(&mut count_input)?;
+        check_records(&records)?;
+        if count_input.count() as u64 != index_size {
+            return Err(error::Error::XzError(format!(
+                "Invalid index size: expected {} but got {}",
+                index_size,
+                count_input.count()
+            )));
+        }
+
+        let index = Self {
+            stream_flags: header.stream_flags,
+            records,
+        };
+        let blocks_size = index
+            .records
+            .iter()
+            .try_fold(0u64, |acc, r| acc.checked_add(r.padded_size().0));
+        if blocks_size != Some(index_start - XZ_HEADER_SIZE) {
+            return Err(error::Error::XzError(format!(
+                "Index records do not match the {} byte(s) of blocks in the stream",
+                index_start - XZ_HEADER_SIZE
+            )));
+        }
+
+        lzma_info!("XZ index: {:?}", index);
+        Ok(index)
+    }
+
+    /// Check method used by the blocks of the stream.
+    pub fn check_method(&self) -> CheckMethod {
+        self.stream_flags.check_method
+    }
+
+    /// Records of the index, one per block.
+    pub fn records(&self) -> &[IndexRecord] {
+        &self.records
+    }
+
+    /// Total size of the decompressed data of the stream.
+    pub fn unpacked_size(&self) -> UncompressedSize {
+        self.checked_unpacked_size()
+            .expect("the total size of an index is checked when read")
+    }
+
+    /// Total size of the decompressed data of the stream, or `None` if it
+    /// overflows.
+    pub(crate) fn checked_unpacked_size(&self) -> Option<UncompressedSize> {
+        self.records
+            .iter()
+            .try_fold(0u64, |acc, r| acc.checked_add(r.unpacked_size.0))
+            .map(UncompressedSize)
+    }
+
+    /// Offset of the given block from the start of the stream, or `None` if
+    /// there is no such block.
+    pub fn block_offset(&self, block: usize) -> Option<u64> {
+        if block >= self.records.len() {
+            return None;
+        }
+        Some(
+            self.records[..block]
+                .iter()
+                .fold(XZ_HEADER_SIZE, |acc, r| acc + r.padded_size().0),
+        )
+    }
+}
+
+/// Check the sizes of the records read from an untrusted Index: each
+/// Unpadded Size must be valid, and the total uncompressed size must fit in
+/// a u64.
+fn check_records(records: &[IndexRecord]) -> error::Result<()> {
+    let mut unpacked_size = 0u64;
+    for (i, record) in records.iter().enumerate() {
+        if !(UNPADDED_SIZE_MIN..=UNPADDED_SIZE_MAX).contains(&record.unpadded_size.0) {
+            return Err(error::Error::XzError(format!(
+                "Invalid index for record {}: unpadded size {} is out of range",
+                i, record.unpadded_size
+            )));
+        }
+        unpacked_size = unpacked_size
+            .checked_add(record.unpacked_size.0)
+            .ok_or_else(|| {
+                error::Error::XzError(format!(
+                    "Invalid index for record {}: total unpacked size overflows",
+                    i
+                ))
+            })?;
+    }
+    Ok(())
+}
+
+/// Records of an Index as read, before its padding and CRC32 are checked.
+pub(crate) struct RawIndex {
+    pub(crate) records: Vec<IndexRecord>,
+    /// Whether the Index Padding only contains null bytes.
+    pub(crate) null_padding: bool,
+    /// CRC32 stored at the end of the Index.
+    pub(crate) crc32: u32,
+    /// CRC32 computed over the Index.
+    pub(crate) digest_crc32: u32,
+}
+
+/// Parse the records, padding and CRC32 of an Index whose Index Indicator
+/// has already been read from `count_input`.
+pub(crate) fn parse_records<R>(
+    count_input: &mut util::CountBufRead<'_, R>,
+) -> error::Result<Vec<IndexRecord>>
+where
+    R: io::BufRead,
+{
+    let raw = read_records(count_input)?;
+    if !raw.null_padding {
+        return Err(error::Error::XzError(
+            "Invalid index padding, must be null bytes".to_string(),
+        ));
+    }
+    if raw.crc32 != raw.digest_crc32 {
+        return Err(error::Error::XzError(format!(
+            "Invalid index CRC32: expected 0x{:08x} but got 0x{:08x}",
+            raw.crc32, raw.digest_crc32
+        )));
+    }
+    Ok(raw.records)
+}
+
+/// Like [`parse_records`], but leave checking the padding and CRC32 to the
+/// caller.
+pub(crate) fn read_records<R>(
+    count_input: &mut util::CountBufRead<'_, R>,
+) -> error::Result<RawIndex>
+where
+    R: io::BufRead,
+{
+    let mut digest = CRC32.digest();
+    let index_tag = 0u8;
+    digest.update(&[index_tag]);
+
+    let mut records = vec![];
+    {
+        let mut digested = util::CrcDigestRead::new(count_input, &mut digest);
+        let num_records = get_multibyte(&mut digested)?;
+        for _ in 0..num_records {
+            let unpadded_size = get_multibyte(&mut digested)?;
+            let unpacked_size = get_multibyte(&mut digested)?;
+            records.push(IndexRecord {
+                unpadded_size: CompressedSize(unpadded_size),
+                unpacked_size: UncompressedSize(unpacked_size),
+            });
+        }
+    }
+
+    let count = count_input.count();
+    let padding_size = ((count ^ 0x03) + 1) & 0x03;
+    lzma_info!(
+        "XZ index: {} byte(s) read, {} byte(s) of padding",
+        count,
+        padding_size
+    );
+
+    let mut null_padding = true;
+    {
+        let mut digested = util::CrcDigestRead::new(count_input, &mut digest);
+        for _ in 0..padding_size {
+            null_padding &= digested.read_u8()? == 0;
+        }
+    }
+
+    let digest_crc32 = digest.finalize();
+    lzma_info!("XZ index checking digest 0x{:08x}", digest_crc32);
+
+    let crc32 = count_input.read_u32::<LittleEndian>()?;
+    Ok(RawIndex {
+        records,
+        null_padding,
+        crc32,
+        digest_crc32,
+    })
+}
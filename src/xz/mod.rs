@@ -7,9 +7,23 @@
 use crate::error;
 use std::io;
 
+pub(crate) mod check;
 pub(crate) mod crc;
+pub(crate) mod delta;
 pub(crate) mod footer;
 pub(crate) mod header;
+pub(crate) mod index;
+mod recheck;
+mod repair;
+mod split;
+mod validate;
+
+pub use check::Check;
+pub use index::{Index, IndexRecord};
+pub use recheck::rewrite_check;
+pub use repair::repair;
+pub use split::extract_block;
+pub use validate::{validate_container, ContainerReport, Inconsistency};
 
 /// Stream flags, see sect. 2.1.1.2.
 ///
@@ -43,9 +57,7 @@ impl StreamFlags {
         W: io::Write,
     {
         // First byte is currently unused and hard-coded to null.
-        writer
-            .write(&[0x00, self.check_method as u8])
-            .map_err(Into::into)
+        writer.write(&[0x00, self.check_method as u8])
     }
 }
 
@@ -53,9 +65,13 @@ impl StreamFlags {
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
 pub enum CheckMethod {
+    /// No check.
     None = 0x00,
+    /// CRC32 of the uncompressed data.
     Crc32 = 0x01,
+    /// CRC64 of the uncompressed data.
     Crc64 = 0x04,
+    /// SHA-256 of the uncompressed data.
     Sha256 = 0x0A,
 }
 
@@ -73,6 +89,16 @@ impl CheckMethod {
             ))),
         }
     }
+
+    /// Size of the "Block Check" field for this method.
+    pub(crate) fn check_size(self) -> usize {
+        match self {
+            CheckMethod::None => 0,
+            CheckMethod::Crc32 => 4,
+            CheckMethod::Crc64 => 8,
+            CheckMethod::Sha256 => 32,
+        }
+    }
 }
 
 impl From<CheckMethod> for u8 {
@@ -81,6 +107,15 @@ impl From<CheckMethod> for u8 {
     }
 }
 
+/// Filter ID of a block, see sect. 5.3.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilterId {
+    /// Delta filter.
+    Delta = 0x03,
+    /// LZMA2 compression.
+    Lzma2 = 0x21,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -90,7 +125,7 @@ mod test {
     #[test]
     fn test_checkmethod_roundtrip() {
         let mut count_valid = 0;
-        for input in 0..std::u8::MAX {
+        for input in 0..u8::MAX {
             if let Ok(check) = CheckMethod::try_from(input) {
                 let output: u8 = check.into();
                 assert_eq!(input, output);
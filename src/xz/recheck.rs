@@ -0,0 +1,214 @@
+//! Rewriting of the integrity checks of XZ streams.
+
+use crate::decode::options::UnsupportedCheck;
+use crate::decode::util;
+use crate::decode::xz::{check_footer, check_index, read_block};
+use crate::encode::xz::{write_footer, write_header, write_index};
+use crate::xz::check::CheckDigest;
+use crate::xz::header::StreamHeader;
+use crate::xz::index::{Index, IndexRecord};
+use crate::xz::{CheckMethod, StreamFlags};
+use crate::{error, CompressedSize};
+use byteorder::ReadBytesExt;
+use std::io;
+
+/// Copy an `.xz` stream into `output` with the integrity checks of its blocks
+/// computed with `check_method`, without compressing the data again.
+///
+/// The blocks are decoded to verify their current checks and compute the new
+/// ones, but their headers and compressed data are copied verbatim. The
+/// index and footer are rewritten to account for the new size of the checks.
+/// This is much cheaper than recompressing, e.g. to add CRC64 checks to
+/// archives written without any.
+///
+/// Returns the index of the new stream. Only the first stream of the input is
+/// rewritten, and any data following it is left unread. SHA-256 checks can
+/// be neither verified nor computed.
+pub fn rewrite_check<R, W>(
+    input: &mut R,
+    output: &mut W,
+    check_method: CheckMethod,
+) -> error::Result<Index>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    if !CheckDigest::is_supported(check_method) {
+        return Err(error::OptionsError::new(
+            "check_method",
+            format!("Unsupported check method {:?}", check_method),
+        )
+        .into());
+    }
+    let header = StreamHeader::parse(input)?;
+    let old_method = header.stream_flags.check_method;
+    let stream_flags = StreamFlags { check_method };
+    write_header(output, stream_flags)?;
+
+    let mut old_records = Vec::new();
+    let mut records = Vec::new();
+    loop {
+        let header_size = match input.fill_buf()?.first() {
+            Some(&header_size) => header_size,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+        if header_size == 0 {
+            break;
+        }
+
+        // The old check comes last, and is dropped by the tee.
+        let mut tee = TeeBufRead::new(input, output, old_method.check_size());
+        let mut digest = DigestWrite(CheckDigest::new(check_method));
+        {
+            let mut count_input = util::CountBufRead::new(&mut tee);
+            count_input.read_u8()?;
+            read_block(
+                &mut count_input,
+                &mut digest,
+                old_method,
+                UnsupportedCheck::Error,
+                &mut old_records,
+                header_size,
+            )?;
+        }
+        tee.finish()?;
+        let check = match digest.0.finalize() {
+            Some(check) => check,
+            None => unreachable!("the check method is supported"),
+        };
+        output.write_all(&check.to_bytes())?;
+
+        let old_record = old_records[old_records.len() - 1];
+        let unpadded_size = old_record.unpadded_size.0 - old_method.check_size() as u64
+            + check_method.check_size() as u64;
+        records.push(IndexRecord {
+            unpadded_size: CompressedSize(unpadded_size),
+            unpacked_size: old_record.unpacked_size,
+        });
+    }
+
+    let index_size = {
+        let mut count_input = util::CountBufRead::new(input);
+        count_input.read_u8()?;
+        check_index(&mut count_input, &old_records)?;
+        count_input.count()
+    };
+    check_footer(input, header.stream_flags, index_size)?;
+
+    let index_size = write_index(output, &records)?;
+    write_footer(output, stream_flags, index_size)?;
+    Ok(Index {
+        stream_flags,
+        records,
+    })
+}
+
+/// An [`io::Write`] computing a check on the bytes written.
+struct DigestWrite(CheckDigest);
+
+impl io::Write for DigestWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An [`io::BufRead`] copying the bytes consumed from `input` to `output`,
+/// except for the last `hold_back` ones, which are dropped at the end.
+struct TeeBufRead<'a, R, W> {
+    input: &'a mut R,
+    output: &'a mut W,
+    held: Vec<u8>,
+    hold_back: usize,
+    /// First error writing to `output`, reported by `finish()`
+    error: Option<io::Error>,
+}
+
+impl<'a, R, W> TeeBufRead<'a, R, W>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    fn new(input: &'a mut R, output: &'a mut W, hold_back: usize) -> Self {
+        Self {
+            input,
+            output,
+            held: Vec::new(),
+            hold_back,
+            error: None,
+        }
+    }
+
+    /// Drop the bytes held back, returning any error met writing the others.
+    fn finish(self) -> io::Result<()> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, R, W> io::Read for TeeBufRead<'a, R, W>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let available = io::BufRead::fill_buf(self)?;
+            let n = std::cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        io::BufRead::consume(self, n);
+        Ok(n)
+    }
+}
+
+impl<'a, R, W> io::BufRead for TeeBufRead<'a, R, W>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.input.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The buffer is unchanged until consumed.
+        if let Ok(buf) = self.input.fill_buf() {
+            self.held.extend_from_slice(&buf[..amt]);
+        }
+        self.input.consume(amt);
+        if self.held.len() > self.hold_back {
+            let released = self.held.len() - self.hold_back;
+            if self.error.is_none() {
+                self.error = self.output.write_all(&self.held[..released]).err();
+            }
+            self.held.drain(..released);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tee_holds_back() {
+        let mut input = &b"0123456789"[..];
+        let mut output = Vec::new();
+        let mut tee = TeeBufRead::new(&mut input, &mut output, 4);
+        let mut buf = [0; 3];
+        io::Read::read_exact(&mut tee, &mut buf).unwrap();
+        io::Read::read_exact(&mut tee, &mut buf).unwrap();
+        let mut rest = Vec::new();
+        io::Read::read_to_end(&mut tee, &mut rest).unwrap();
+        tee.finish().unwrap();
+        assert_eq!(output, b"012345");
+    }
+}
@@ -0,0 +1,72 @@
+//! Recovery of XZ streams with a missing or corrupt index.
+
+use crate::decode::options::UnsupportedCheck;
+use crate::decode::util;
+use crate::decode::xz::read_block;
+use crate::encode::xz::{write_footer, write_header, write_index};
+use crate::error;
+use crate::xz::header::{StreamHeader, XZ_HEADER_SIZE};
+use crate::xz::index::Index;
+use byteorder::ReadBytesExt;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Rebuild an `.xz` file whose index or footer is missing or corrupt.
+///
+/// The blocks are scanned from the start of the stream and decoded to
+/// recompute their sizes and verify their checks. Scanning stops at the first
+/// block that cannot be read, e.g. because the file was truncated in the
+/// middle of it. The valid blocks are then copied verbatim into `output`,
+/// followed by a new index and footer describing them.
+///
+/// Returns the index of the repaired stream. Only the first stream of the
+/// file is recovered.
+pub fn repair<R, W>(input: &mut R, output: &mut W) -> error::Result<Index>
+where
+    R: Read + Seek,
+    W: io::Write,
+{
+    input.seek(SeekFrom::Start(0))?;
+    let (stream_flags, records) = {
+        let mut buf_input = io::BufReader::new(&mut *input);
+        let header = StreamHeader::parse(&mut buf_input)?;
+
+        let mut records = vec![];
+        loop {
+            let mut count_input = util::CountBufRead::new(&mut buf_input);
+            let header_size = match count_input.read_u8() {
+                Ok(0) => break,
+                Ok(header_size) => header_size,
+                Err(_) => break,
+            };
+
+            if let Err(_e) = read_block(
+                &mut count_input,
+                &mut io::sink(),
+                header.stream_flags.check_method,
+                UnsupportedCheck::Error,
+                &mut records,
+                header_size,
+            ) {
+                lzma_info!("XZ repair: dropping block {}: {}", records.len(), _e);
+                break;
+            }
+        }
+        (header.stream_flags, records)
+    };
+    let index = Index {
+        stream_flags,
+        records,
+    };
+    lzma_info!("XZ repair: recovered {:?}", index);
+
+    write_header(output, index.stream_flags)?;
+
+    let blocks_size: u64 = index.records.iter().map(|r| r.padded_size().0).sum();
+    input.seek(SeekFrom::Start(XZ_HEADER_SIZE))?;
+    io::copy(&mut input.take(blocks_size), output)?;
+
+    let index_size = write_index(output, &index.records)?;
+    write_footer(output, index.stream_flags, index_size)?;
+    Ok(index)
+}
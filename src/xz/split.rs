@@ -0,0 +1,58 @@
+//! Extraction of single blocks from an XZ stream.
+
+use crate::encode::xz::{write_footer, write_header, write_index};
+use crate::error;
+use crate::xz::index::Index;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Copy one block of an `.xz` file into `output` as a standalone,
+/// single-block `.xz` stream.
+///
+/// The compressed bytes of the block are copied verbatim, using the `index`
+/// previously read with [`Index::read()`] to locate them; the other blocks are
+/// neither read nor decoded. A stream header, an index and a footer matching
+/// the block are synthesized around it, so that the output can be decoded on
+/// its own.
+pub fn extract_block<R, W>(
+    input: &mut R,
+    index: &Index,
+    block: usize,
+    output: &mut W,
+) -> error::Result<()>
+where
+    R: Read + Seek,
+    W: io::Write,
+{
+    let offset = index.block_offset(block).ok_or_else(|| {
+        error::Error::XzError(format!(
+            "Block {} out of range, the stream has {} block(s)",
+            block,
+            index.records().len()
+        ))
+    })?;
+    let record = index.records()[block];
+    lzma_info!(
+        "XZ extracting block {} at offset {}: {:?}",
+        block,
+        offset,
+        record
+    );
+
+    write_header(output, index.stream_flags)?;
+
+    input.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut input.take(record.padded_size().0), output)?;
+    if copied != record.padded_size().0 {
+        return Err(error::Error::XzError(format!(
+            "Block {} truncated: expected {} bytes but got {}",
+            block,
+            record.padded_size().0,
+            copied
+        )));
+    }
+
+    let index_size = write_index(output, &[record])?;
+    write_footer(output, index.stream_flags, index_size)?;
+    Ok(())
+}
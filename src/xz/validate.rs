@@ -0,0 +1,287 @@
+//! Cross-validation of the structures of an XZ stream.
+
+use crate::decode::options::UnsupportedCheck;
+use crate::decode::util;
+use crate::decode::xz::read_block;
+use crate::error;
+use crate::xz::footer::{StreamFooter, XZ_FOOTER_SIZE};
+use crate::xz::header::{StreamHeader, XZ_HEADER_SIZE};
+use crate::xz::index::{read_records, IndexRecord};
+use crate::xz::CheckMethod;
+use byteorder::ReadBytesExt;
+use std::io::{Read, Seek, SeekFrom};
+use std::{fmt, io};
+
+/// Disagreement between the structures of an `.xz` stream, found by
+/// [`validate_container()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The check methods of the stream header and footer differ.
+    FlagsMismatch {
+        /// Check method of the stream header.
+        header: CheckMethod,
+        /// Check method of the stream footer.
+        footer: CheckMethod,
+    },
+    /// The backward size of the footer does not match the size of the index.
+    BackwardSize {
+        /// Index size declared by the footer, in bytes.
+        footer: u64,
+        /// Size of the index as read, in bytes.
+        index: u64,
+    },
+    /// Bytes that belong to no structure lie between the index and the
+    /// footer.
+    UnexpectedData {
+        /// Offset of the bytes from the start of the stream.
+        offset: u64,
+        /// Number of bytes.
+        size: u64,
+    },
+    /// The index padding contains non-null bytes.
+    IndexPadding,
+    /// The CRC32 stored after the index does not match its contents.
+    IndexCrc {
+        /// CRC32 stored in the stream.
+        stored: u32,
+        /// CRC32 computed over the index.
+        computed: u32,
+    },
+    /// A block could not be decoded, so neither it nor the blocks after it
+    /// were compared to the index.
+    UnreadableBlock {
+        /// Number of the block, from zero.
+        block: usize,
+        /// Reason why decoding failed.
+        reason: String,
+    },
+    /// The number of records of the index differs from the number of blocks.
+    RecordCount {
+        /// Number of records in the index.
+        index: usize,
+        /// Number of blocks in the stream.
+        blocks: usize,
+    },
+    /// The sizes recorded in the index for a block differ from its actual
+    /// sizes.
+    RecordSize {
+        /// Number of the block, from zero.
+        block: usize,
+        /// Record of the index.
+        index: IndexRecord,
+        /// Sizes of the block as decoded.
+        actual: IndexRecord,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::FlagsMismatch { header, footer } => write!(
+                fmt,
+                "check method in header ({:?}) does not match footer ({:?})",
+                header, footer
+            ),
+            Inconsistency::BackwardSize { footer, index } => write!(
+                fmt,
+                "footer declares a {} byte index but the index takes {} bytes",
+                footer, index
+            ),
+            Inconsistency::UnexpectedData { offset, size } => write!(
+                fmt,
+                "{} unexpected byte(s) at offset {} between index and footer",
+                size, offset
+            ),
+            Inconsistency::IndexPadding => write!(fmt, "index padding is not null"),
+            Inconsistency::IndexCrc { stored, computed } => write!(
+                fmt,
+                "index CRC32 is 0x{:08x} but the index hashes to 0x{:08x}",
+                stored, computed
+            ),
+            Inconsistency::UnreadableBlock { block, reason } => {
+                write!(fmt, "block {} cannot be decoded: {}", block, reason)
+            }
+            Inconsistency::RecordCount { index, blocks } => write!(
+                fmt,
+                "index has {} record(s) but the stream has {} block(s)",
+                index, blocks
+            ),
+            Inconsistency::RecordSize {
+                block,
+                index,
+                actual,
+            } => write!(
+                fmt,
+                "block {} is recorded as {}/{} bytes (unpadded/unpacked) but is {}/{}",
+                block,
+                index.unpadded_size,
+                index.unpacked_size,
+                actual.unpadded_size,
+                actual.unpacked_size
+            ),
+        }
+    }
+}
+
+/// Verdict of [`validate_container()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContainerReport {
+    inconsistencies: Vec<Inconsistency>,
+}
+
+impl ContainerReport {
+    /// Whether no inconsistency was found.
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+
+    /// Inconsistencies found, in the order of the checks.
+    pub fn inconsistencies(&self) -> &[Inconsistency] {
+        &self.inconsistencies
+    }
+}
+
+/// Cross-check the header, blocks, index and footer of a single-stream
+/// `.xz` file, for auditing archives.
+///
+/// Unlike the decoders and [`Index::read()`](crate::xz::Index::read), which
+/// stop at the first problem, this collects every disagreement between the
+/// structures of the stream: the flags of the header and footer, the
+/// backward size of the footer and the actual size of the index, the CRC32
+/// and padding of the index, and the records of the index and the sizes of
+/// the blocks. The blocks are decoded to measure them, so this takes as
+/// long as decompressing the file.
+///
+/// The index is looked for after the last block that can be decoded, or
+/// where the footer places it if a block cannot be. An error is returned if
+/// the stream header, the footer or the index cannot be parsed at all, as
+/// there is nothing to compare them to.
+pub fn validate_container<R>(input: &mut R) -> error::Result<ContainerReport>
+where
+    R: Read + Seek,
+{
+    let mut report = ContainerReport::default();
+
+    input.seek(SeekFrom::Start(0))?;
+    let (header, blocks, blocks_complete) = {
+        let mut buf_input = io::BufReader::new(&mut *input);
+        let header = StreamHeader::parse(&mut buf_input)?;
+
+        let mut blocks = vec![];
+        let complete = loop {
+            let mut count_input = util::CountBufRead::new(&mut buf_input);
+            let header_size = count_input.read_u8()?;
+            if header_size == 0 {
+                break true;
+            }
+            if let Err(e) = read_block(
+                &mut count_input,
+                &mut io::sink(),
+                header.stream_flags.check_method,
+                UnsupportedCheck::Error,
+                &mut blocks,
+                header_size,
+            ) {
+                report.inconsistencies.push(Inconsistency::UnreadableBlock {
+                    block: blocks.len(),
+                    reason: e.to_string(),
+                });
+                break false;
+            }
+        };
+        (header, blocks, complete)
+    };
+
+    let file_size = input.seek(SeekFrom::End(0))?;
+    if file_size < XZ_HEADER_SIZE + XZ_FOOTER_SIZE {
+        return Err(error::Error::Truncated(format!(
+            "File too short for an XZ stream: {} bytes",
+            file_size
+        )));
+    }
+    let footer_start = file_size - XZ_FOOTER_SIZE;
+    input.seek(SeekFrom::Start(footer_start))?;
+    let footer = StreamFooter::parse(&mut io::BufReader::new(&mut *input))?;
+    if header.stream_flags != footer.stream_flags {
+        report.inconsistencies.push(Inconsistency::FlagsMismatch {
+            header: header.stream_flags.check_method,
+            footer: footer.stream_flags.check_method,
+        });
+    }
+
+    let index_start = if blocks_complete {
+        blocks
+            .iter()
+            .fold(XZ_HEADER_SIZE, |acc, r| acc + r.padded_size().0)
+    } else {
+        footer_start
+            .checked_sub(footer.index_size())
+            .ok_or_else(|| {
+                error::Error::XzError(format!(
+                    "Invalid index size: {} bytes do not fit in the stream",
+                    footer.index_size()
+                ))
+            })?
+    };
+    if index_start >= footer_start {
+        return Err(error::Error::XzError(
+            "No room for an index before the footer".to_string(),
+        ));
+    }
+    input.seek(SeekFrom::Start(index_start))?;
+    let (raw, index_size) = {
+        let mut buf_input = io::BufReader::new((&mut *input).take(footer_start - index_start));
+        let mut count_input = util::CountBufRead::new(&mut buf_input);
+        if count_input.read_u8()? != 0 {
+            return Err(error::Error::XzError(format!(
+                "Invalid index indicator at offset {}, expected a null byte",
+                index_start
+            )));
+        }
+        let raw = read_records(&mut count_input)?;
+        (raw, count_input.count() as u64)
+    };
+    lzma_info!("XZ validate: index of {} byte(s)", index_size);
+
+    if footer.index_size() != index_size {
+        report.inconsistencies.push(Inconsistency::BackwardSize {
+            footer: footer.index_size(),
+            index: index_size,
+        });
+    }
+    let index_end = index_start + index_size;
+    if index_end < footer_start {
+        report.inconsistencies.push(Inconsistency::UnexpectedData {
+            offset: index_end,
+            size: footer_start - index_end,
+        });
+    }
+    if !raw.null_padding {
+        report.inconsistencies.push(Inconsistency::IndexPadding);
+    }
+    if raw.crc32 != raw.digest_crc32 {
+        report.inconsistencies.push(Inconsistency::IndexCrc {
+            stored: raw.crc32,
+            computed: raw.digest_crc32,
+        });
+    }
+
+    if blocks_complete && raw.records.len() != blocks.len() {
+        report.inconsistencies.push(Inconsistency::RecordCount {
+            index: raw.records.len(),
+            blocks: blocks.len(),
+        });
+    }
+    for (block, (index, actual)) in raw.records.iter().zip(&blocks).enumerate() {
+        if index != actual {
+            report.inconsistencies.push(Inconsistency::RecordSize {
+                block,
+                index: *index,
+                actual: *actual,
+            });
+        }
+    }
+
+    lzma_info!("XZ validate: {:?}", report);
+    Ok(report)
+}
@@ -19,6 +19,7 @@ fn round_trip(x: &[u8]) {
     // Do another round trip, but this time also write it to the header
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(x.len() as u64)),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadFromHeader,
@@ -34,6 +35,10 @@ fn round_trip_no_options(x: &[u8]) {
     info!("Compressed {} -> {} bytes", x.len(), compressed.len());
     #[cfg(feature = "enable_logging")]
     debug!("Compressed content: {:?}", compressed);
+    assert!(
+        compressed.len() as u64
+            <= lzma_rs::lzma_compress_bound(x.len() as u64, &Default::default())
+    );
 
     assert_decomp_eq(&compressed, x, /* compare_to_liblzma */ true);
 }
@@ -54,6 +59,9 @@ fn assert_round_trip_with_options(
     info!("Compressed {} -> {} bytes", x.len(), compressed.len());
     #[cfg(feature = "enable_logging")]
     debug!("Compressed content: {:?}", compressed);
+    assert!(
+        compressed.len() as u64 <= lzma_rs::lzma_compress_bound(x.len() as u64, encode_options)
+    );
 
     // test non-streaming decompression
     {
@@ -238,6 +246,7 @@ fn unpacked_size_write_to_header() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadFromHeader,
@@ -251,6 +260,7 @@ fn unpacked_size_provided_outside() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(data.len() as u64)),
@@ -264,6 +274,7 @@ fn unpacked_size_write_some_to_header_but_use_provided_on_read() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(Some(
@@ -279,6 +290,7 @@ fn unpacked_size_write_none_to_header_and_use_provided_on_read() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(None),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(Some(
@@ -289,11 +301,49 @@ fn unpacked_size_write_none_to_header_and_use_provided_on_read() {
     assert_round_trip_with_options(&data[..], &encode_options, &decode_options);
 }
 
+#[test]
+fn max_write_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+
+    let mut sink = MaxWriteSink::default();
+    lzma_rs::lzma_decompress(&mut compressed.as_slice(), &mut sink).unwrap();
+    assert_eq!(sink.max_write, data.len());
+
+    let options = lzma_rs::decompress::Options {
+        max_write_size: Some(1000),
+        ..Default::default()
+    };
+    let mut sink = MaxWriteSink::default();
+    lzma_rs::lzma_decompress_with_options(&mut compressed.as_slice(), &mut sink, &options).unwrap();
+    assert_eq!(sink.data, data);
+    assert_eq!(sink.max_write, 1000);
+
+    #[cfg(feature = "stream")]
+    {
+        let mut stream =
+            lzma_rs::decompress::Stream::new_with_options(&options, MaxWriteSink::default());
+        stream.write_all(&compressed).unwrap();
+        let sink = stream.finish().unwrap();
+        assert_eq!(sink.data, data);
+        assert_eq!(sink.max_write, 1000);
+    }
+
+    let options = lzma_rs::decompress::Options {
+        max_write_size: Some(0),
+        ..Default::default()
+    };
+    assert!(options.validate().is_err());
+}
+
 #[test]
 fn unpacked_size_write_none_to_header_and_use_provided_none_on_read() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(None),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(None),
@@ -302,11 +352,80 @@ fn unpacked_size_write_none_to_header_and_use_provided_none_on_read() {
     assert_round_trip_with_options(&data[..], &encode_options, &decode_options);
 }
 
+#[test]
+fn unknown_size_zero_sentinel() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::UnknownSizeSentinel;
+
+    let data = b"Some data";
+    let encode_options = lzma_rs::compress::Options {
+        unknown_size_sentinel: UnknownSizeSentinel::Zero,
+        ..Default::default()
+    };
+    let decode_options = lzma_rs::decompress::Options {
+        zero_size_is_unknown: true,
+        ..Default::default()
+    };
+    assert_round_trip_with_options(&data[..], &encode_options, &decode_options);
+    // The usual sentinel is still understood.
+    assert_round_trip_with_options(&data[..], &Default::default(), &decode_options);
+
+    let compressed = lzma_rs::lzma_compress_to_vec(data, &encode_options).unwrap();
+    assert_eq!(compressed[5..13], [0; 8]);
+    // Without the option, the stream is taken as empty.
+    let mut decomp = Vec::new();
+    let result = lzma_rs::lzma_decompress(&mut compressed.as_slice(), &mut decomp);
+    assert!(result.is_err() || decomp.is_empty(), "{:?}", result);
+}
+
+#[test]
+fn unpacked_size_zero() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let empty = lzma_rs::lzma_compress_to_vec(b"", &Default::default()).unwrap();
+    assert_decomp_eq(&empty, b"", /* compare_to_liblzma */ true);
+
+    // An empty stream of unknown size declaring a size of zero, as some
+    // encoders write metadata-only members: the marker is consumed.
+    let mut with_marker = empty.clone();
+    with_marker[5..13].copy_from_slice(&[0; 8]);
+    let mut decomp = Vec::new();
+    let consumed = lzma_rs::lzma_decompress_counted(
+        &mut with_marker.as_slice(),
+        &mut decomp,
+        &Default::default(),
+    )
+    .unwrap();
+    assert!(decomp.is_empty());
+    assert_eq!(consumed, with_marker.len() as u64);
+
+    #[cfg(feature = "stream")]
+    {
+        let mut stream = lzma_rs::decompress::Stream::new(Vec::new());
+        stream.write_all(&with_marker).unwrap();
+        assert!(stream.finish().unwrap().is_empty());
+
+        let mut reader = lzma_rs::decompress::LzmaReader::new(with_marker.as_slice());
+        let mut decomp = Vec::new();
+        reader.read_to_end(&mut decomp).unwrap();
+        assert!(decomp.is_empty());
+    }
+
+    // Any other symbol contradicts the declared size.
+    let mut garbage = with_marker[..18].to_vec();
+    garbage.extend_from_slice(b"xyz");
+    let mut decomp = Vec::new();
+    let result = lzma_rs::lzma_decompress(&mut garbage.as_slice(), &mut decomp);
+    assert!(result.is_err(), "{:?}", decomp);
+}
+
 #[test]
 fn memlimit() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(None),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(None),
@@ -354,3 +473,2045 @@ fn memlimit() {
         );
     }
 }
+
+#[test]
+fn kernel_image_trailing_data() {
+    let data = b"Some data";
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress(&mut std::io::BufReader::new(&data[..]), &mut compressed).unwrap();
+    let stream_len = compressed.len() as u64;
+
+    // Zero out the unpacked size and append some padding.
+    compressed[5..13].copy_from_slice(&[0; 8]);
+    compressed.extend_from_slice(&[0; 16]);
+    compressed.extend_from_slice(b"trailing junk");
+
+    // Without allowing trailing data, the stream is rejected.
+    {
+        let decode_options = lzma_rs::decompress::Options {
+            unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(None),
+            ..Default::default()
+        };
+        let mut bf = std::io::BufReader::new(compressed.as_slice());
+        let mut decomp: Vec<u8> = Vec::new();
+        let error = lzma_rs::lzma_decompress_with_options(&mut bf, &mut decomp, &decode_options)
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("more bytes are available"),
+            "{}",
+            error
+        );
+    }
+
+    let mut bf = std::io::BufReader::new(compressed.as_slice());
+    let mut decomp: Vec<u8> = Vec::new();
+    let consumed = lzma_rs::lzma_decompress_counted(
+        &mut bf,
+        &mut decomp,
+        &lzma_rs::decompress::Options::kernel_image(),
+    )
+    .unwrap();
+    assert_eq!(decomp, data);
+    assert_eq!(consumed, stream_len);
+}
+
+#[test]
+fn end_marker_with_known_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{Options, UnpackedSize};
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    for &unpacked_size in [
+        UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        UnpackedSize::WriteToHeader(None),
+    ]
+    .iter()
+    {
+        let options = Options {
+            unpacked_size,
+            ..Default::default()
+        };
+        let with_marker = Options {
+            end_marker: true,
+            ..options
+        };
+        let compressed = lzma_rs::lzma_compress_to_vec(&data, &with_marker).unwrap();
+        let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &Default::default()).unwrap();
+        assert_eq!(decomp, data);
+
+        let without = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+        match unpacked_size {
+            UnpackedSize::WriteToHeader(None) => assert_eq!(compressed, without),
+            _ => {
+                assert!(compressed.len() > without.len());
+                // Only the marker is left after the data.
+                let strict = lzma_rs::decompress::Options {
+                    strict_end: true,
+                    ..Default::default()
+                };
+                lzma_rs::lzma_decompress_to_vec(&compressed, &strict).unwrap_err();
+                assert_eq!(
+                    lzma_rs::lzma_decompress_to_vec(&without, &strict).unwrap(),
+                    data
+                );
+            }
+        }
+    }
+
+    // The marker follows the data when the size is stored elsewhere too.
+    let options = Options {
+        unpacked_size: UnpackedSize::SkipWritingToHeader,
+        end_marker: true,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+    let decode_options = lzma_rs::decompress::Options {
+        unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(None),
+        ..Default::default()
+    };
+    let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &decode_options).unwrap();
+    assert_eq!(decomp, data);
+}
+
+#[test]
+fn firmware_strict_end() {
+    let data = b"Some firmware data";
+    let firmware_options = lzma_rs::decompress::Options::firmware(data.len() as u64, 0x1000);
+
+    // Sized stream without end-of-stream marker.
+    let encode_options = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress_with_options(
+        &mut std::io::BufReader::new(&data[..]),
+        &mut compressed,
+        &encode_options,
+    )
+    .unwrap();
+    {
+        let mut bf = std::io::BufReader::new(compressed.as_slice());
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::lzma_decompress_with_options(&mut bf, &mut decomp, &firmware_options).unwrap();
+        assert_eq!(decomp, data);
+    }
+
+    // Trailing bytes are rejected.
+    {
+        let mut trailing = compressed.clone();
+        trailing.push(0);
+        let mut bf = std::io::BufReader::new(trailing.as_slice());
+        let mut decomp: Vec<u8> = Vec::new();
+        let error = lzma_rs::lzma_decompress_with_options(&mut bf, &mut decomp, &firmware_options)
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("more bytes are available"),
+            "{}",
+            error
+        );
+    }
+
+    // Streams with an end-of-stream marker are rejected.
+    {
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::lzma_compress(&mut std::io::BufReader::new(&data[..]), &mut compressed).unwrap();
+        let mut bf = std::io::BufReader::new(compressed.as_slice());
+        let mut decomp: Vec<u8> = Vec::new();
+        let error = lzma_rs::lzma_decompress_with_options(&mut bf, &mut decomp, &firmware_options)
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("more bytes are available"),
+            "{}",
+            error
+        );
+    }
+}
+
+#[test]
+fn max_output_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress(&mut std::io::BufReader::new(&data[..]), &mut compressed).unwrap();
+
+    // An exact budget is enough.
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(compressed.len() as u64),
+        ..Default::default()
+    };
+    let mut limited: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress_with_options(&mut &data[..], &mut limited, &options).unwrap();
+    assert_eq!(limited, compressed);
+
+    // One byte less aborts without writing past the budget.
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(compressed.len() as u64 - 1),
+        ..Default::default()
+    };
+    let mut limited: Vec<u8> = Vec::new();
+    let error =
+        lzma_rs::lzma_compress_with_options(&mut &data[..], &mut limited, &options).unwrap_err();
+    let exceeded = match &error {
+        lzma_rs::error::Error::IoError(e) => e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<lzma_rs::error::OutputSizeExceeded>())
+            .unwrap(),
+        _ => panic!("unexpected error {:?}", error),
+    };
+    assert_eq!(exceeded.limit, compressed.len() as u64 - 1);
+    assert!(limited.len() < compressed.len());
+}
+
+#[test]
+fn compress_bound_incompressible() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    // Xorshift noise, which the encoder cannot compress.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let data: Vec<u8> = (0..100_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        })
+        .collect();
+    for len in [0, 1, 100, data.len()] {
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::lzma_compress(&mut &data[..len], &mut compressed).unwrap();
+        let bound = lzma_rs::lzma_compress_bound(len as u64, &Default::default());
+        assert!(
+            compressed.len() as u64 <= bound,
+            "{} > {}",
+            compressed.len(),
+            bound
+        );
+    }
+}
+
+#[test]
+fn compress_matches() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let mut compressed = Vec::new();
+    lzma_rs::lzma_compress(&mut text.as_slice(), &mut compressed).unwrap();
+    assert!(
+        compressed.len() < text.len() / 2,
+        "{} bytes compressed to {}",
+        text.len(),
+        compressed.len()
+    );
+    assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+
+    // Noise repeated at distances covering all the distance slots, with
+    // short runs in between to exercise the literals following a match.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut noise = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 32) as u8
+    };
+    let mut data: Vec<u8> = (0..300_000).map(|_| noise()).collect();
+    for &dist in [1usize, 2, 5, 17, 200, 3000, 70_000, 250_000].iter() {
+        for _ in 0..3 {
+            let start = data.len() - dist;
+            data.extend_from_within(start..start + std::cmp::min(dist, 500));
+            data.push(noise());
+        }
+    }
+    round_trip(&data);
+}
+
+#[test]
+fn compress_match_lengths() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let mut state = 0x2545_f491_u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    };
+    // Matches at the edges of the low, mid and high length trees, each
+    // followed by fresh bytes ending it.
+    let source = noise(300);
+    let mut data = source.clone();
+    for &len in [3, 9, 10, 17, 18, 100, 272, 273].iter() {
+        data.extend_from_slice(&source[..len]);
+        data.extend(noise(16));
+    }
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+    assert!(compressed.len() < data.len() / 2);
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+}
+
+#[test]
+fn compress_rep_matches() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    // Fixed-size records, where the same distances come back after each
+    // varying field.
+    let mut state = 1u32;
+    let mut data = Vec::new();
+    for i in 0..20_000u32 {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        data.extend_from_slice(&i.to_le_bytes());
+        data.extend_from_slice(b"RECORD__");
+        data.extend_from_slice(&[(state >> 30) as u8, (state >> 16) as u8, 0, 1]);
+    }
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+    assert!(
+        compressed.len() < data.len() / 5,
+        "{} bytes compressed to {}",
+        data.len(),
+        compressed.len()
+    );
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+}
+
+#[test]
+fn compress_short_reps() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let mut state = 0x2545_f491_u32;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    };
+    // Blocks repeated once, then with every other byte replaced: the bytes
+    // kept are single bytes at the last distance.
+    let mut data = Vec::new();
+    for _ in 0..2000 {
+        let block: Vec<u8> = (0..32).map(|_| next()).collect();
+        data.extend_from_slice(&block);
+        data.extend_from_slice(&block);
+        for (i, &byte) in block.iter().enumerate() {
+            data.push(if i % 2 == 0 { byte } else { next() });
+        }
+    }
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+    assert!(
+        compressed.len() < data.len() * 3 / 5,
+        "{} bytes compressed to {}",
+        data.len(),
+        compressed.len()
+    );
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+}
+
+#[test]
+fn bt4_match_finder() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::MatchFinder;
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let mut sizes = Vec::new();
+    for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+        let encode_options = lzma_rs::compress::Options {
+            match_finder,
+            ..Default::default()
+        };
+        let compressed = lzma_rs::lzma_compress_to_vec(&text, &encode_options).unwrap();
+        assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+        sizes.push(compressed.len());
+
+        // Runs longer than the nice length, and far repetitions.
+        let mut data = vec![0u8; 5000];
+        data.extend(text[..100_000].iter());
+        data.extend_from_within(..60_000);
+        assert_round_trip_with_options(&data, &encode_options, &Default::default());
+    }
+    assert!(sizes[1] < sizes[0], "{:?}", sizes);
+}
+
+#[test]
+fn normal_mode() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, MatchFinder};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+        let fast_options = lzma_rs::compress::Options {
+            match_finder,
+            ..Default::default()
+        };
+        let normal_options = lzma_rs::compress::Options {
+            mode: EncodeMode::Normal,
+            ..fast_options
+        };
+        let fast = lzma_rs::lzma_compress_to_vec(&text, &fast_options).unwrap();
+        let normal = lzma_rs::lzma_compress_to_vec(&text, &normal_options).unwrap();
+        assert!(
+            normal.len() < fast.len() * 19 / 20,
+            "{:?}: {} bytes in normal mode, {} in fast mode",
+            match_finder,
+            normal.len(),
+            fast.len()
+        );
+        assert_decomp_eq(&normal, &text, /* compare_to_liblzma */ true);
+
+        // Reading a few bytes at a time doesn't change the parse.
+        let mut streamed = Vec::new();
+        let mut encoder =
+            lzma_rs::compress::LzmaEncoder::new(&mut streamed, &normal_options).unwrap();
+        encoder
+            .process(&mut std::io::BufReader::with_capacity(7, &text[..]))
+            .unwrap();
+        assert_eq!(streamed, normal);
+
+        let mut data = vec![0u8; 5000];
+        data.extend(text[..100_000].iter());
+        data.extend_from_within(..60_000);
+        assert_round_trip_with_options(&data, &normal_options, &Default::default());
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress_with_options(&mut &data[..], &mut xz, &normal_options).unwrap();
+        let mut decompressed = Vec::new();
+        lzma_rs::xz_decompress(&mut xz.as_slice(), &mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
+
+#[test]
+fn compress_seekable() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options::default();
+
+    let mut output = std::io::Cursor::new(b"prefix".to_vec());
+    output.set_position(6);
+    let size = lzma_rs::lzma_compress_seekable(&mut &text[..], &mut output, &options).unwrap();
+    assert_eq!(size, text.len() as u64);
+    assert_eq!(output.position(), output.get_ref().len() as u64);
+    let compressed = &output.get_ref()[6..];
+
+    // Same stream as with the size known upfront.
+    let known = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(size)),
+        ..options
+    };
+    assert_eq!(
+        compressed,
+        &lzma_rs::lzma_compress_to_vec(&text, &known).unwrap()[..]
+    );
+    assert_decomp_eq(compressed, &text, /* compare_to_liblzma */ true);
+}
+
+#[test]
+fn store_mode() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, Options};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let options = Options {
+        mode: EncodeMode::Store,
+        ..Default::default()
+    };
+    for &format in &[
+        lzma_rs::Format::Lzma,
+        lzma_rs::Format::Lzma2,
+        lzma_rs::Format::Xz,
+    ] {
+        let mut compressed = Vec::new();
+        let stats =
+            lzma_rs::compress_with_stats(format, &mut text.as_slice(), &mut compressed, &options)
+                .unwrap();
+        assert_eq!(stats.matches, 0, "{:?}", format);
+        if format == lzma_rs::Format::Lzma {
+            assert_eq!(stats.literals, text.len() as u64);
+            // The literals of text still take fewer than 8 bits.
+            assert!(compressed.len() < text.len());
+            assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+        }
+    }
+    assert!(options.mem_usage() < Options::default().mem_usage() - (1 << 20));
+}
+
+#[test]
+fn lazy_mode() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, MatchFinder};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+        let fast_options = lzma_rs::compress::Options {
+            match_finder,
+            ..Default::default()
+        };
+        let lazy_options = lzma_rs::compress::Options {
+            mode: EncodeMode::Lazy,
+            ..fast_options
+        };
+        let fast = lzma_rs::lzma_compress_to_vec(&text, &fast_options).unwrap();
+        let lazy = lzma_rs::lzma_compress_to_vec(&text, &lazy_options).unwrap();
+        assert!(
+            lazy.len() < fast.len(),
+            "{:?}: {} bytes in lazy mode, {} in fast mode",
+            match_finder,
+            lazy.len(),
+            fast.len()
+        );
+        assert_decomp_eq(&lazy, &text, /* compare_to_liblzma */ true);
+
+        // Reading a few bytes at a time doesn't change the parse.
+        let mut streamed = Vec::new();
+        let mut encoder =
+            lzma_rs::compress::LzmaEncoder::new(&mut streamed, &lazy_options).unwrap();
+        encoder
+            .process(&mut std::io::BufReader::with_capacity(7, &text[..]))
+            .unwrap();
+        assert_eq!(streamed, lazy);
+
+        let mut data = vec![0u8; 5000];
+        data.extend(text[..100_000].iter());
+        data.extend_from_within(..60_000);
+        assert_round_trip_with_options(&data, &lazy_options, &Default::default());
+    }
+}
+
+/// A fixed-size ring buffer, handing the bytes it drops to a consumer.
+struct RingBuffer {
+    ring: Vec<u8>,
+    cursor: usize,
+    len: usize,
+    consumed: Vec<u8>,
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ring: vec![0; capacity],
+            cursor: 0,
+            len: 0,
+            consumed: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == self.ring.len() {
+            self.consumed.push(self.ring[self.cursor]);
+        } else {
+            self.len += 1;
+        }
+        self.ring[self.cursor] = byte;
+        self.cursor = (self.cursor + 1) % self.ring.len();
+    }
+
+    fn into_data(mut self) -> Vec<u8> {
+        let start = (self.cursor + self.ring.len() - self.len) % self.ring.len();
+        for i in 0..self.len {
+            self.consumed.push(self.ring[(start + i) % self.ring.len()]);
+        }
+        self.consumed
+    }
+}
+
+impl lzma_rs::decompress::OutputBuffer for RingBuffer {
+    fn append(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        for &byte in bytes {
+            self.push(byte);
+        }
+        Ok(())
+    }
+
+    fn copy_from_history(&mut self, dist: usize, len: usize) -> std::io::Result<()> {
+        for _ in 0..len {
+            let byte = self.history(dist).unwrap();
+            self.push(byte);
+        }
+        Ok(())
+    }
+
+    fn history(&self, dist: usize) -> Option<u8> {
+        if dist == 0 || dist > self.len {
+            return None;
+        }
+        Some(self.ring[(self.cursor + self.ring.len() - dist) % self.ring.len()])
+    }
+}
+
+#[test]
+fn decompress_into_buffer() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options {
+        dict_size: 4096,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(&expected, &options).unwrap();
+
+    let mut vec = Vec::new();
+    lzma_rs::lzma_decompress_into_buffer(&mut &compressed[..], &mut vec, &Default::default())
+        .unwrap();
+    assert_eq!(vec, expected);
+
+    let mut ring = RingBuffer::new(4096);
+    lzma_rs::lzma_decompress_into_buffer(&mut &compressed[..], &mut ring, &Default::default())
+        .unwrap();
+    assert_eq!(ring.into_data(), expected);
+
+    // A ring smaller than the dictionary loses the history of far matches.
+    let mut ring = RingBuffer::new(100);
+    let err =
+        lzma_rs::lzma_decompress_into_buffer(&mut &compressed[..], &mut ring, &Default::default())
+            .unwrap_err();
+    assert!(err.to_string().contains("history"), "{}", err);
+}
+
+#[test]
+fn encoder_reset() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, LzmaEncoder, MatchFinder, Options};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let payloads: Vec<&[u8]> = vec![&text[..5000], b"", &text[3000..], b"abcabcabc", &text];
+    for &mode in [EncodeMode::Fast, EncodeMode::Lazy, EncodeMode::Normal].iter() {
+        for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+            let options = Options {
+                mode,
+                match_finder,
+                ..Default::default()
+            };
+            let mut encoder = LzmaEncoder::new(Vec::new(), &options).unwrap();
+            // Leave a stream unfinished, to be dropped by the first reset.
+            let mut input = InterruptedSource {
+                data: &text,
+                interrupt: false,
+            };
+            for _ in 0..100 {
+                assert!(encoder.process(&mut input).is_err());
+            }
+            for payload in payloads.iter() {
+                encoder.reset();
+                encoder.get_mut().clear();
+                encoder.process(&mut &payload[..]).unwrap();
+                let compressed = std::mem::take(encoder.get_mut());
+                assert_eq!(
+                    compressed,
+                    lzma_rs::lzma_compress_to_vec(payload, &options).unwrap(),
+                    "{:?} {:?}",
+                    mode,
+                    match_finder
+                );
+                assert_eq!(encoder.stats().in_bytes.get(), payload.len() as u64);
+            }
+        }
+    }
+}
+
+#[test]
+fn encoder_progress() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::LzmaEncoder;
+    use std::sync::{Arc, Mutex};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let data = text.repeat(20);
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let mut encoder = LzmaEncoder::new(Vec::new(), &Default::default()).unwrap();
+    let recorded = Arc::clone(&calls);
+    encoder.set_progress(1 << 20, move |bytes_in, bytes_out| {
+        recorded.lock().unwrap().push((bytes_in, bytes_out))
+    });
+    encoder.process(&mut data.as_slice()).unwrap();
+    // Nothing is left to report.
+    encoder.process(&mut data.as_slice()).unwrap();
+
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.len(), 4, "{:?}", calls);
+    for pair in calls.windows(2) {
+        assert!(
+            pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1,
+            "{:?}",
+            calls
+        );
+    }
+    for (i, &(bytes_in, _)) in calls[..3].iter().enumerate() {
+        assert!(bytes_in >= (i as u64 + 1) << 20, "{:?}", calls);
+    }
+    let compressed = encoder.into_inner();
+    assert_eq!(
+        calls.last(),
+        Some(&(data.len() as u64, compressed.len() as u64))
+    );
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+}
+
+#[test]
+fn encoder_cancel() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::LzmaEncoder;
+    use lzma_rs::error::Error;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let data = text.repeat(20);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut encoder = LzmaEncoder::new(Vec::new(), &Default::default()).unwrap();
+    encoder.set_cancel(Arc::clone(&cancel));
+    let flag = Arc::clone(&cancel);
+    encoder.set_progress(1 << 20, move |_, _| flag.store(true, Ordering::Relaxed));
+    let err = encoder.process(&mut data.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Other);
+    assert!(matches!(Error::from(err), Error::Cancelled));
+    assert_eq!(encoder.stats().in_bytes.get(), 1 << 20);
+
+    cancel.store(false, Ordering::Relaxed);
+    encoder.reset();
+    encoder.get_mut().clear();
+    encoder.process(&mut &text[..]).unwrap();
+    assert_eq!(
+        encoder.into_inner(),
+        lzma_rs::lzma_compress_to_vec(&text, &Default::default()).unwrap()
+    );
+}
+
+#[test]
+fn encoder_save_state() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, LzmaEncoder, MatchFinder, Options};
+    use std::io::Read;
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let data = text.repeat(3);
+    for &mode in [EncodeMode::Fast, EncodeMode::Lazy, EncodeMode::Normal].iter() {
+        for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+            let options = Options {
+                mode,
+                match_finder,
+                dict_size: 0x1_0000,
+                ..Default::default()
+            };
+            let (head, tail) = data.split_at(200_000);
+            // The source fails once the head is consumed, leaving the
+            // stream unfinished.
+            let mut input = head.chain(InterruptedSource {
+                data: &[],
+                interrupt: false,
+            });
+            let mut encoder = LzmaEncoder::new(Vec::new(), &options).unwrap();
+            assert!(encoder.process(&mut input).is_err());
+            let state = encoder.save_state();
+            let mut compressed = encoder.into_inner();
+
+            let mut encoder = LzmaEncoder::restore_state(Vec::new(), &options, &state).unwrap();
+            assert_eq!(encoder.stats().in_bytes.get(), head.len() as u64);
+            encoder.process(&mut &tail[..]).unwrap();
+            compressed.extend(encoder.into_inner());
+            assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+            // Here the rebuilt match finders give the same matches to the
+            // greedy parsers, while the optimal one updates its prices at
+            // other times.
+            if mode != EncodeMode::Normal {
+                let uninterrupted = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+                assert!(compressed == uninterrupted, "{:?} {:?}", mode, match_finder);
+            }
+        }
+    }
+
+    let options = Options::default();
+    let state = LzmaEncoder::new(Vec::new(), &options).unwrap().save_state();
+    let other = Options { lc: 0, ..options };
+    assert!(LzmaEncoder::restore_state(Vec::new(), &other, &state).is_err());
+    assert!(LzmaEncoder::restore_state(Vec::new(), &options, &state[..state.len() - 1]).is_err());
+    assert!(LzmaEncoder::restore_state(Vec::new(), &options, &state).is_ok());
+}
+
+#[test]
+fn presets() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, MatchFinder, Options};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let mut sizes = Vec::new();
+    for preset in 0..=9 {
+        let options = Options::with_preset(preset).unwrap();
+        let compressed = lzma_rs::lzma_compress_to_vec(&text, &options).unwrap();
+        assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+        sizes.push(compressed.len());
+    }
+    assert!(sizes[9] < sizes[3] && sizes[3] < sizes[0], "{:?}", sizes);
+
+    let options = Options::with_preset(6).unwrap();
+    assert_eq!(options.dict_size, 8 << 20);
+    assert_eq!(options.match_finder, MatchFinder::Bt4);
+    assert_eq!(options.mode, EncodeMode::Normal);
+    assert_eq!(Options::with_preset(0).unwrap().mode, EncodeMode::Fast);
+    assert!(Options::with_preset(10).is_err());
+    let options = Options {
+        nice_len: 274,
+        ..Default::default()
+    };
+    assert!(options.validate().is_err());
+}
+
+#[test]
+fn nice_len_and_depth() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{MatchFinder, Options};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+        let sizes: Vec<usize> = [(8, 1), (64, 1), (64, 0), (273, 200)]
+            .iter()
+            .map(|&(nice_len, depth)| {
+                let options = Options {
+                    match_finder,
+                    nice_len,
+                    depth,
+                    ..Default::default()
+                };
+                let compressed = lzma_rs::lzma_compress_to_vec(&text, &options).unwrap();
+                assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+                compressed.len()
+            })
+            .collect();
+        // Deeper searches and longer nice lengths find longer matches.
+        assert!(
+            sizes[3] <= sizes[2] && sizes[2] < sizes[1] && sizes[1] <= sizes[0],
+            "{:?}",
+            sizes
+        );
+    }
+    let options = Options {
+        nice_len: 2,
+        ..Default::default()
+    };
+    assert!(options.validate().is_err());
+}
+
+#[test]
+fn max_match_len() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, Options};
+
+    let mut data = vec![0; 10_000];
+    data.extend(read_all_file("tests/files/foo.txt").unwrap());
+    for &mode in &[EncodeMode::Fast, EncodeMode::Lazy, EncodeMode::Normal] {
+        for &max_match_len in &[2, 10, 273] {
+            let options = Options {
+                mode,
+                max_match_len,
+                ..Default::default()
+            };
+            let mut compressed = Vec::new();
+            let stats = lzma_rs::compress_with_stats(
+                lzma_rs::Format::Lzma,
+                &mut data.as_slice(),
+                &mut compressed,
+                &options,
+            )
+            .unwrap();
+            // No match covers more than the maximum.
+            assert!(
+                stats.literals + stats.matches * max_match_len as u64 >= data.len() as u64,
+                "{:?} {}: {:?}",
+                mode,
+                max_match_len,
+                stats
+            );
+            assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+        }
+    }
+    for &max_match_len in &[1, 274] {
+        let options = Options {
+            max_match_len,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+}
+
+#[test]
+fn match_finder_hash_bits() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{MatchFinder, Options};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+        let auto = Options {
+            match_finder,
+            dict_size: 0x10000,
+            ..Default::default()
+        };
+        let small = Options {
+            hash_bits: 8,
+            ..auto
+        };
+        for options in [auto, small].iter() {
+            let compressed = lzma_rs::lzma_compress_to_vec(&text, options).unwrap();
+            assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+        }
+        // 17 bits for a 64 KiB dictionary.
+        let entry_size = if match_finder == MatchFinder::Hc4 {
+            8
+        } else {
+            4
+        };
+        assert_eq!(
+            auto.match_finder_mem_usage() - small.match_finder_mem_usage(),
+            entry_size * ((1 << 17) - (1 << 8))
+        );
+    }
+    assert_eq!(
+        Options::default().match_finder_mem_usage(),
+        (8 << 20) + 4 * 0x0080_0000
+    );
+
+    let fast = Options::default();
+    let normal = Options {
+        mode: lzma_rs::compress::EncodeMode::Normal,
+        ..fast
+    };
+    assert!(normal.mem_usage() > fast.mem_usage());
+    assert!(fast.mem_usage() > normal.match_finder_mem_usage() + normal.dict_size as u64);
+    let more_literal_bits = Options { lc: 8, ..fast };
+    assert_eq!(
+        more_literal_bits.mem_usage() - fast.mem_usage(),
+        2 * ((0x300 << 8) - (0x300 << 3))
+    );
+
+    for &hash_bits in &[7, 25] {
+        let options = Options {
+            hash_bits,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+}
+
+#[test]
+fn literal_coder_properties() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::Options;
+
+    // Little-endian u32 values, whose high bytes are mostly zero.
+    let data: Vec<u8> = (0..20_000u32)
+        .flat_map(|i| (i * i % 1000).to_le_bytes())
+        .collect();
+    let default = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+    assert_eq!(default[0], 0x5D);
+
+    let options = Options {
+        lc: 0,
+        lp: 2,
+        pb: 2,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+    assert_eq!(compressed[0], 9 * (2 + 5 * 2));
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+    assert!(compressed.len() < default.len());
+
+    let options = Options {
+        lc: 1,
+        lp: 3,
+        pb: 0,
+        ..Default::default()
+    };
+    let mut xz = Vec::new();
+    lzma_rs::xz_compress_with_options(&mut &data[..], &mut xz, &options).unwrap();
+    let mut decompressed = Vec::new();
+    lzma_rs::xz_decompress(&mut xz.as_slice(), &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+
+    // LZMA allows lc + lp > 4, but not LZMA2.
+    let options = Options {
+        lc: 8,
+        lp: 4,
+        pb: 4,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+    let mut xz = Vec::new();
+    assert!(lzma_rs::xz_compress_with_options(&mut &data[..], &mut xz, &options).is_err());
+    assert!(Options {
+        lc: 9,
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+    assert!(Options {
+        pb: 5,
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+}
+
+#[test]
+fn position_bits() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{Options, UnpackedSize};
+
+    // Records of 16 bytes, so that each position state sees its own bytes.
+    let data: Vec<u8> = (0..4_000u32)
+        .flat_map(|i| {
+            let mut record = [0u8; 16];
+            record[..4].copy_from_slice(&i.to_le_bytes());
+            record[8] = (i % 7) as u8;
+            record
+        })
+        .collect();
+    for pb in 0..=4 {
+        for &unpacked_size in &[
+            UnpackedSize::WriteToHeader(None),
+            UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ] {
+            let options = Options {
+                pb,
+                unpacked_size,
+                ..Default::default()
+            };
+            let compressed = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+            assert_eq!(compressed[0] as u32, 3 + 9 * 5 * pb);
+            assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+        }
+    }
+}
+
+#[test]
+fn to_vec() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let encode_options = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &encode_options).unwrap();
+    let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &Default::default()).unwrap();
+    assert_eq!(decomp, data);
+
+    // A bogus declared size doesn't allocate 1 TiB upfront.
+    let mut bogus = compressed.clone();
+    bogus[5..13].copy_from_slice(&(1u64 << 40).to_le_bytes()[..]);
+    let decode_options = lzma_rs::decompress::Options {
+        memlimit: Some(1 << 20),
+        ..Default::default()
+    };
+    lzma_rs::lzma_decompress_to_vec(&bogus, &decode_options).unwrap_err();
+}
+
+#[test]
+fn cap_dict_to_unpacked_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/small.txt").unwrap();
+    let encode_options = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
+    };
+    // The encoder declares an 8 MiB dictionary.
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &encode_options).unwrap();
+
+    let decode_options = lzma_rs::decompress::Options {
+        memlimit: Some(data.len()),
+        cap_dict_to_unpacked_size: true,
+        ..Default::default()
+    };
+    let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &decode_options).unwrap();
+    assert_eq!(decomp, data);
+
+    #[cfg(feature = "stream")]
+    {
+        let mut stream = lzma_rs::decompress::Stream::new_with_options(&decode_options, Vec::new());
+        stream.write_all(&compressed).unwrap();
+        assert_eq!(stream.finish().unwrap(), data);
+    }
+}
+
+#[test]
+fn find_embedded_streams() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let hello = read_all_file("tests/files/hello.txt").unwrap();
+    let lzma = read_all_file("tests/files/hello.txt.lzma").unwrap();
+    let xz = read_all_file("tests/files/hello.txt.xz").unwrap();
+
+    // Bury the streams in pseudo-random bytes.
+    let mut state = 0x9e37_79b9u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    };
+    let mut data = noise(1000);
+    let lzma_offset = data.len();
+    data.extend_from_slice(&lzma);
+    data.extend(noise(500));
+    let xz_offset = data.len();
+    data.extend_from_slice(&xz);
+    data.extend(noise(500));
+
+    let found = lzma_rs::decompress::find_embedded_streams(&data, 1 << 20);
+    assert_eq!(found.len(), 2, "{:?}", found);
+
+    assert_eq!(found[0].offset, lzma_offset);
+    assert_eq!(found[0].format, lzma_rs::Format::Lzma);
+    assert_eq!(found[0].properties, Some(lzma[0]));
+    assert_eq!(found[0].unpacked_size.get(), hello.len() as u64);
+    assert_eq!(
+        found[0].lzma_subformat,
+        lzma_rs::magic::LzmaSubformat::from_header(&lzma)
+    );
+    assert!(found[0].length <= lzma.len());
+
+    assert_eq!(found[1].offset, xz_offset);
+    assert_eq!(found[1].format, lzma_rs::Format::Xz);
+    assert_eq!(found[1].length, xz.len());
+    assert_eq!(found[1].lzma_subformat, None);
+    assert_eq!(found[1].unpacked_size.get(), hello.len() as u64);
+}
+
+#[test]
+fn empty_stream() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let options = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(0)),
+        ..Default::default()
+    };
+    let empty = lzma_rs::empty_stream(lzma_rs::Format::Lzma, &options).unwrap();
+    // Header and range coder flush only.
+    assert_eq!(empty.len(), 13 + 5);
+    assert_decomp_eq(&empty, b"", /* compare_to_liblzma */ true);
+
+    // The size of zero is written with the default options too.
+    let empty = lzma_rs::empty_stream(lzma_rs::Format::Lzma, &Default::default()).unwrap();
+    assert_eq!(empty.len(), 13 + 5);
+    assert_decomp_eq(&empty, b"", /* compare_to_liblzma */ true);
+
+    // Without a size in the header, an end-of-stream marker is needed.
+    let options = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        ..Default::default()
+    };
+    let empty = lzma_rs::empty_stream(lzma_rs::Format::Lzma, &options).unwrap();
+    assert_eq!(empty, lzma_rs::lzma_compress_to_vec(&[], &options).unwrap());
+
+    // The options apply to LZMA2 as well.
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(0),
+        ..Default::default()
+    };
+    lzma_rs::empty_stream(lzma_rs::Format::Lzma2, &options).unwrap_err();
+    let empty = lzma_rs::empty_stream(lzma_rs::Format::Lzma2, &Default::default()).unwrap();
+    assert_eq!(empty, [0x00]);
+}
+
+#[test]
+fn compress_dict_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let options = lzma_rs::compress::Options {
+        dict_size: 0x1_0000,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(b"abc", &options).unwrap();
+    assert_eq!(compressed[1..5], [0x00, 0x00, 0x01, 0x00]);
+    let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &Default::default()).unwrap();
+    assert_eq!(decomp, b"abc");
+
+    let options = lzma_rs::compress::Options {
+        dict_size: 0xFFF,
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    let err_msg = lzma_rs::lzma_compress_with_options(&mut &b"abc"[..], &mut compressed, &options)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "invalid options: `dict_size`: Dictionary size 4095 is below the minimum of 4096"
+    );
+    assert!(compressed.is_empty());
+}
+
+#[test]
+fn fit_dict_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{Options, UnpackedSize};
+
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let options = Options {
+        fit_dict_size: true,
+        ..Default::default()
+    };
+    assert_eq!(options.dict_size_for(text.len() as u64), 0x4_0000);
+    assert_eq!(options.dict_size_for(3), 0x1000);
+    assert_eq!(options.dict_size_for(u64::MAX), options.dict_size);
+    assert_eq!(Options::default().dict_size_for(3), options.dict_size);
+
+    let compressed = lzma_rs::lzma_compress_to_vec(&text, &options).unwrap();
+    assert_eq!(compressed[1..5], [0x00, 0x00, 0x04, 0x00]);
+    assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+
+    // Streams of unknown size keep the whole dictionary, unlike those of
+    // known size.
+    let mut compressed = Vec::new();
+    lzma_rs::lzma_compress_with_options(&mut &text[..], &mut compressed, &options).unwrap();
+    assert_eq!(compressed[1..5], [0x00, 0x00, 0x80, 0x00]);
+    let options = Options {
+        unpacked_size: UnpackedSize::WriteToHeader(Some(text.len() as u64)),
+        ..options
+    };
+    let mut compressed = Vec::new();
+    lzma_rs::lzma_compress_with_options(&mut &text[..], &mut compressed, &options).unwrap();
+    assert_eq!(compressed[1..5], [0x00, 0x00, 0x04, 0x00]);
+    assert_decomp_eq(&compressed, &text, /* compare_to_liblzma */ true);
+
+    // The LZMA2 filter of the block: ID, size of the properties, and the
+    // dictionary size property of 256 KiB.
+    let compressed = lzma_rs::xz_compress_to_vec(&text, &options).unwrap();
+    assert_eq!(compressed[14..17], [0x21, 0x01, 12]);
+    assert_eq!(lzma_rs::xz_decompress_to_vec(&compressed).unwrap(), text);
+}
+
+/// A sink recording the size of the largest write.
+#[derive(Default)]
+struct MaxWriteSink {
+    data: Vec<u8>,
+    max_write: usize,
+}
+
+impl std::io::Write for MaxWriteSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.max_write = self.max_write.max(buf.len());
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink accepting at most one byte per call, and refusing every other call
+/// with `WouldBlock`.
+struct NonBlockingSink {
+    data: Vec<u8>,
+    calls: usize,
+}
+
+impl std::io::Write for NonBlockingSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.calls += 1;
+        if self.calls % 2 == 0 {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        self.data.extend_from_slice(&buf[..1]);
+        Ok(1)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A source yielding small chunks, interrupted between each chunk.
+struct InterruptedSource<'a> {
+    data: &'a [u8],
+    interrupt: bool,
+}
+
+impl<'a> std::io::Read for InterruptedSource<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = std::io::BufRead::fill_buf(self)?;
+        let len = std::cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        std::io::BufRead::consume(self, len);
+        Ok(len)
+    }
+}
+
+impl<'a> std::io::BufRead for InterruptedSource<'a> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(std::io::ErrorKind::Interrupted.into());
+        }
+        Ok(&self.data[..std::cmp::min(self.data.len(), 7)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.data = &self.data[amt..];
+    }
+}
+
+#[test]
+fn resumable_encoder() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let expected = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+
+    let sink = NonBlockingSink {
+        data: Vec::new(),
+        calls: 0,
+    };
+    let mut input = InterruptedSource {
+        data: &data,
+        interrupt: false,
+    };
+    let mut encoder = lzma_rs::compress::LzmaEncoder::new(sink, &Default::default()).unwrap();
+    let mut errors = 0;
+    while let Err(e) = encoder.process(&mut input) {
+        assert!(
+            e.kind() == std::io::ErrorKind::WouldBlock
+                || e.kind() == std::io::ErrorKind::Interrupted
+        );
+        errors += 1;
+    }
+    assert!(errors > 0);
+    assert_eq!(encoder.into_inner().data, expected);
+}
+
+#[test]
+fn encoder_process_slice() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let expected = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+
+    let mut encoder = lzma_rs::compress::LzmaEncoder::new(Vec::new(), &Default::default()).unwrap();
+    encoder.process_slice(&data).unwrap();
+    assert_eq!(encoder.into_inner(), expected);
+
+    // Resumed after the errors of the sink with the bytes not yet consumed.
+    let sink = NonBlockingSink {
+        data: Vec::new(),
+        calls: 0,
+    };
+    let mut encoder = lzma_rs::compress::LzmaEncoder::new(sink, &Default::default()).unwrap();
+    let mut errors = 0;
+    while let Err(e) = encoder.process_slice(&data[encoder.stats().in_bytes.get() as usize..]) {
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+        errors += 1;
+    }
+    assert!(errors > 0);
+    assert_eq!(encoder.into_inner().data, expected);
+}
+
+#[test]
+fn work_limit() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let compressed = read_all_file("tests/files/foo.txt.lzma").unwrap();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+
+    let options = lzma_rs::decompress::Options {
+        max_work_per_byte: Some(32),
+        ..Default::default()
+    };
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::lzma_decompress_with_options(&mut compressed.as_slice(), &mut decomp, &options)
+        .unwrap();
+    assert_eq!(decomp, expected);
+
+    // Less than one operation per byte can't decode anything but long matches.
+    let options = lzma_rs::decompress::Options {
+        max_work_per_byte: Some(1),
+        ..Default::default()
+    };
+    let error = lzma_rs::lzma_decompress_with_options(
+        &mut compressed.as_slice(),
+        &mut Vec::new(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(
+        error.to_string().contains("Work limit exceeded"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn conflicting_decompress_options() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let options = lzma_rs::decompress::Options {
+        strict_end: true,
+        allow_trailing_data: true,
+        ..Default::default()
+    };
+    let expected = lzma_rs::error::OptionsError {
+        field: "strict_end",
+        conflicts_with: Some("allow_trailing_data"),
+        reason: "Trailing data can't be both rejected and allowed".to_string(),
+    };
+    assert_eq!(options.validate(), Err(expected.clone()));
+
+    // Rejected before reading anything.
+    let compressed = read_all_file("tests/files/hello.txt.lzma").unwrap();
+    let mut input = compressed.as_slice();
+    match lzma_rs::lzma_decompress_with_options(&mut input, &mut Vec::new(), &options) {
+        Err(lzma_rs::error::Error::InvalidOptions(e)) => assert_eq!(e, expected),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(input.len(), compressed.len());
+}
+
+#[test]
+fn declared_size_policy() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::decompress::DeclaredSize;
+
+    // Stream declaring 1 TiB of data.
+    let mut compressed = lzma_rs::lzma_compress_to_vec(b"abc", &Default::default()).unwrap();
+    compressed[5..13].copy_from_slice(&(1u64 << 40).to_le_bytes());
+
+    for &policy in [DeclaredSize::Hint, DeclaredSize::Ignore].iter() {
+        let options = lzma_rs::decompress::Options {
+            declared_size: policy,
+            ..Default::default()
+        };
+        let err_msg = lzma_rs::lzma_decompress_to_vec(&compressed, &options)
+            .unwrap_err()
+            .to_string();
+        assert!(err_msg.contains("Expected unpacked size"), "{}", err_msg);
+    }
+
+    let options = lzma_rs::decompress::Options {
+        declared_size: DeclaredSize::Max(1 << 30),
+        ..Default::default()
+    };
+    let err_msg = lzma_rs::lzma_decompress_to_vec(&compressed, &options)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "lzma error: Declared unpacked size 1099511627776 exceeds the maximum of 1073741824"
+    );
+
+    // Sizes within the maximum are accepted.
+    let compressed = lzma_rs::lzma_compress_to_vec(
+        b"abc",
+        &lzma_rs::compress::Options {
+            unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(3)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        lzma_rs::lzma_decompress_to_vec(&compressed, &options).unwrap(),
+        b"abc"
+    );
+}
+
+#[test]
+fn pending_lzma() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::decompress::{Options, PendingLzma};
+
+    let options = lzma_rs::compress::Options {
+        dict_size: 0x10_0000,
+        ..Default::default()
+    };
+    let mut compressed = lzma_rs::lzma_compress_to_vec(b"Hello, world", &options).unwrap();
+    compressed.extend_from_slice(b"trailer");
+
+    // A reader that can't be rewound.
+    let input = std::io::BufReader::new(compressed.as_slice().chain(&[][..]));
+    let pending = PendingLzma::new(input, &Options::default()).unwrap();
+    assert_eq!(pending.dict_size(), 0x10_0000);
+    assert_eq!(pending.unpacked_size(), None);
+    let mut rest = Vec::new();
+    pending.into_inner().read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, &compressed[13..]);
+
+    let decompress_options = Options {
+        allow_trailing_data: true,
+        ..Default::default()
+    };
+    let pending = PendingLzma::new(compressed.as_slice(), &decompress_options).unwrap();
+    let mut output = Vec::new();
+    let rest = pending.decompress(&mut output).unwrap();
+    assert_eq!(output, b"Hello, world");
+    assert_eq!(rest, b"trailer");
+
+    // The options are checked before reading the header.
+    let invalid = Options {
+        allow_trailing_data: true,
+        strict_end: true,
+        ..Default::default()
+    };
+    assert!(PendingLzma::new(compressed.as_slice(), &invalid).is_err());
+}
+
+#[test]
+fn decompress_low_memory() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    // Blocks of noise repeated far beyond the window.
+    let mut state = 0x2545_f491_u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    };
+    let a = noise(20_000);
+    let b = noise(30_000);
+    let data = [&a[..], &b, &a, &b[..10_000], &a].concat();
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap();
+    assert!(compressed.len() < data.len() * 3 / 5);
+
+    // The output doesn't start at the beginning of the sink.
+    let mut output = std::io::Cursor::new(b"prefix".to_vec());
+    output.set_position(6);
+    lzma_rs::lzma_decompress_low_memory(
+        &mut compressed.as_slice(),
+        &mut output,
+        0x1000,
+        &Default::default(),
+    )
+    .unwrap();
+    let output = output.into_inner();
+    assert_eq!(&output[..6], b"prefix");
+    assert_eq!(&output[6..], &data[..]);
+}
+
+#[test]
+fn decompress_with_dictionary() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let options = lzma_rs::compress::Options {
+        dict_size: 0x1000,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+
+    let mut decomp: Vec<u8> = Vec::new();
+    let dictionary = lzma_rs::lzma_decompress_with_dictionary(
+        &mut compressed.as_slice(),
+        &mut decomp,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(decomp, data);
+    assert_eq!(dictionary, &data[data.len() - 0x1000..]);
+
+    // Short streams fill only part of the dictionary.
+    let compressed = lzma_rs::lzma_compress_to_vec(b"abc", &options).unwrap();
+    let dictionary = lzma_rs::lzma_decompress_with_dictionary(
+        &mut compressed.as_slice(),
+        &mut Vec::new(),
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(dictionary, b"abc");
+}
+
+#[test]
+fn preset_dict() {
+    use lzma_rs::compress::{EncodeMode, MatchFinder, Options, UnpackedSize};
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let preset: Vec<u8> = (0..6000u32).map(|i| (i * 7 % 253) as u8).collect();
+    let mut message = preset[1000..1500].to_vec();
+    message.extend_from_slice(b"tail");
+    message.extend_from_slice(&preset[5900..]);
+
+    for &mode in [EncodeMode::Fast, EncodeMode::Lazy, EncodeMode::Normal].iter() {
+        for &match_finder in [MatchFinder::Hc4, MatchFinder::Bt4].iter() {
+            for &unpacked_size in [
+                UnpackedSize::WriteToHeader(None),
+                UnpackedSize::WriteToHeader(Some(message.len() as u64)),
+            ]
+            .iter()
+            {
+                // The dictionary only holds the end of the preset.
+                let options = Options {
+                    dict_size: 0x1000,
+                    mode,
+                    match_finder,
+                    unpacked_size,
+                    ..Default::default()
+                };
+                let mut compressed = Vec::new();
+                lzma_rs::lzma_compress_with_preset_dict(
+                    &mut message.as_slice(),
+                    &mut compressed,
+                    &options,
+                    &preset,
+                )
+                .unwrap();
+                let plain = lzma_rs::lzma_compress_to_vec(&message, &options).unwrap();
+                assert!(
+                    compressed.len() < plain.len() / 2,
+                    "{} vs {} bytes",
+                    compressed.len(),
+                    plain.len()
+                );
+
+                let mut decomp = Vec::new();
+                lzma_rs::lzma_decompress_with_preset_dict(
+                    &mut compressed.as_slice(),
+                    &mut decomp,
+                    &Default::default(),
+                    &preset,
+                )
+                .unwrap();
+                assert_eq!(decomp, message);
+
+                // Without the preset, the matches reach before the start.
+                let result = lzma_rs::lzma_decompress(&mut compressed.as_slice(), &mut Vec::new());
+                assert!(result.is_err());
+            }
+        }
+    }
+
+    // An empty preset changes nothing.
+    let mut compressed = Vec::new();
+    lzma_rs::lzma_compress_with_preset_dict(
+        &mut message.as_slice(),
+        &mut compressed,
+        &Default::default(),
+        b"",
+    )
+    .unwrap();
+    assert_decomp_eq(&compressed, &message, /* compare_to_liblzma */ true);
+}
+
+#[test]
+#[cfg(feature = "stream")]
+fn reader_seek_forward() {
+    use std::io::{Seek, SeekFrom};
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = read_all_file("tests/files/foo.txt.lzma").unwrap();
+
+    let mut reader = lzma_rs::decompress::LzmaReader::new(std::io::BufReader::with_capacity(
+        64,
+        compressed.as_slice(),
+    ));
+    let mut decomp = Vec::new();
+    reader.read_to_end(&mut decomp).unwrap();
+    assert_eq!(decomp, expected);
+
+    let mut reader = lzma_rs::decompress::LzmaReader::new(compressed.as_slice());
+    let mut buf = [0u8; 100];
+    assert_eq!(reader.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &expected[1000..1100]);
+    assert_eq!(reader.seek(SeekFrom::Current(50_000)).unwrap(), 51_100);
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &expected[51_100..51_200]);
+    assert_eq!(reader.stream_position().unwrap(), 51_200);
+
+    // Only forward seeks are supported.
+    assert!(reader.seek(SeekFrom::Start(0)).is_err());
+    assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+    assert!(reader.seek(SeekFrom::End(0)).is_err());
+
+    // Seeking past the end leaves nothing to read.
+    reader.seek(SeekFrom::Start(1 << 20)).unwrap();
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+#[cfg(feature = "stream")]
+fn reader_truncated() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let compressed = read_all_file("tests/files/foo.txt.lzma").unwrap();
+
+    let mut reader = lzma_rs::decompress::LzmaReader::new(&compressed[..compressed.len() / 2]);
+    let mut decomp = Vec::new();
+    let err = reader.read_to_end(&mut decomp).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    // The reader keeps failing instead of reporting a clean end.
+    let mut buf = [0u8; 16];
+    for _ in 0..2 {
+        let again = reader.read(&mut buf).unwrap_err();
+        assert_eq!(again.kind(), err.kind());
+        assert_eq!(again.to_string(), err.to_string());
+    }
+}
+
+#[test]
+#[cfg(feature = "stream")]
+fn reader_buf_read() {
+    use std::io::BufRead;
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = read_all_file("tests/files/foo.txt.lzma").unwrap();
+
+    let reader = lzma_rs::decompress::LzmaReader::new(compressed.as_slice());
+    let lines: Vec<String> = reader.lines().map(Result::unwrap).collect();
+    let expected_lines: Vec<&str> = std::str::from_utf8(&expected).unwrap().lines().collect();
+    assert_eq!(lines, expected_lines);
+
+    let mut reader = lzma_rs::decompress::LzmaReader::new(compressed.as_slice());
+    let available = reader.fill_buf().unwrap().len();
+    assert!(available > 0);
+    reader.consume(available);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, &expected[available..]);
+}
+
+#[test]
+fn compress_slices() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{EncodeMode, Options};
+
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    for &mode in [EncodeMode::Fast, EncodeMode::Lazy, EncodeMode::Normal].iter() {
+        let options = Options {
+            mode,
+            ..Default::default()
+        };
+        let expected = lzma_rs::lzma_compress_to_vec(&data, &options).unwrap();
+        // Matches span the boundaries of the slices, however small.
+        for &size in [1, 7, 1000].iter() {
+            let slices: Vec<&[u8]> = data.chunks(size).chain(std::iter::once(&[][..])).collect();
+            let mut compressed = Vec::new();
+            lzma_rs::lzma_compress_slices(slices, &mut compressed, &options).unwrap();
+            assert_eq!(compressed, expected, "{:?} {}", mode, size);
+        }
+    }
+}
+
+#[test]
+fn compress_stats() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options::default();
+    for &format in &[
+        lzma_rs::Format::Lzma,
+        lzma_rs::Format::Lzma2,
+        lzma_rs::Format::Xz,
+    ] {
+        let mut compressed = Vec::new();
+        let stats =
+            lzma_rs::compress_with_stats(format, &mut data.as_slice(), &mut compressed, &options)
+                .unwrap();
+        assert_eq!(stats.in_bytes.get(), data.len() as u64, "{:?}", format);
+        assert_eq!(
+            stats.out_bytes.get(),
+            compressed.len() as u64,
+            "{:?}",
+            format
+        );
+        assert!(stats.matches > 0, "{:?}", format);
+        assert!(stats.rep_matches > 0, "{:?}", format);
+        assert!(stats.rep_matches < stats.matches, "{:?}", format);
+        assert!(stats.literals < data.len() as u64, "{:?}", format);
+        match format {
+            lzma_rs::Format::Lzma => assert_eq!(stats.chunks, 0),
+            lzma_rs::Format::Lzma2 | lzma_rs::Format::Xz => assert_eq!(stats.chunks, 3),
+        }
+    }
+}
+
+#[test]
+fn large_dict_policy() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::decompress::LargeDict;
+
+    // This stream declares a dictionary of 0x7F7F7F7F bytes, about 2 GiB.
+    let compressed = read_all_file("tests/files/hugedict.txt.lzma").unwrap();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    for &policy in [LargeDict::Clamp, LargeDict::Allow].iter() {
+        let options = lzma_rs::decompress::Options {
+            large_dict: policy,
+            ..Default::default()
+        };
+        let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &options).unwrap();
+        assert_eq!(decomp, expected, "{:?}", policy);
+    }
+
+    let options = lzma_rs::decompress::Options {
+        large_dict: LargeDict::Reject,
+        ..Default::default()
+    };
+    let err_msg = lzma_rs::lzma_decompress_to_vec(&compressed, &options)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "lzma error: Dictionary size 2139062143 exceeds the maximum of 1610612736"
+    );
+
+    // Sizes up to the maximum are used as declared.
+    let mut compressed = lzma_rs::lzma_compress_to_vec(b"abc", &Default::default()).unwrap();
+    compressed[1..5].copy_from_slice(&lzma_rs::decompress::MAX_DICT_SIZE.to_le_bytes());
+    let decomp = lzma_rs::lzma_decompress_to_vec(&compressed, &options).unwrap();
+    assert_eq!(decomp, b"abc");
+}
+
+#[test]
+#[cfg(feature = "raw_decoder")]
+fn raw_length_coder() {
+    use lzma_rs::compress::raw::{LenEncoder, RangeEncoder};
+    use lzma_rs::decompress::raw::{LenDecoder, RangeDecoder};
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let values: Vec<(usize, u32)> = (0..272).map(|len| (len as usize % 16, len)).collect();
+    let mut coded = Vec::new();
+    let mut encoder = RangeEncoder::new(&mut coded);
+    let mut len_encoder = LenEncoder::new();
+    let mut flag = 0x400;
+    for &(pos_state, len) in &values {
+        encoder.encode_bit(&mut flag, len % 3 == 0).unwrap();
+        len_encoder.encode(&mut encoder, pos_state, len).unwrap();
+    }
+    encoder.finish().unwrap();
+
+    let mut input = coded.as_slice();
+    let mut decoder = RangeDecoder::new(&mut input).unwrap();
+    let mut len_decoder = LenDecoder::default();
+    let mut flag = 0x400;
+    for &(pos_state, len) in &values {
+        assert_eq!(decoder.decode_bit(&mut flag, true).unwrap(), len % 3 == 0);
+        assert_eq!(
+            len_decoder.decode(&mut decoder, pos_state, true).unwrap(),
+            len as usize
+        );
+    }
+    assert!(decoder.is_finished_ok().unwrap());
+}
+
+#[test]
+#[cfg(feature = "raw_decoder")]
+fn raw_tiny_dict() {
+    use lzma_rs::decompress::raw::{LzmaDecoder, LzmaParams, LzmaProperties};
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let decompress = |compressed: &[u8], dict_size: u32| {
+        let properties = LzmaProperties {
+            lc: 3,
+            lp: 0,
+            pb: 2,
+        };
+        let params = LzmaParams::new(properties, dict_size, None);
+        let mut decoder = LzmaDecoder::new(params, None)?;
+        let mut decomp = Vec::new();
+        // Skip the header.
+        decoder.decompress(&mut &compressed[13..], &mut decomp)?;
+        Ok::<_, lzma_rs::error::Error>(decomp)
+    };
+
+    // A dictionary of 0 bytes used to trip an assertion on the first
+    // literal, see also the decompress_lzma_raw fuzz target.
+    let runs = lzma_rs::lzma_compress_to_vec(b"abbbbbbbbbbbbb", &Default::default()).unwrap();
+    for dict_size in 0..=2 {
+        assert_eq!(decompress(&runs, dict_size).unwrap(), b"abbbbbbbbbbbbb");
+    }
+
+    let pairs = lzma_rs::lzma_compress_to_vec(b"abababababab", &Default::default()).unwrap();
+    for dict_size in 0..=1 {
+        let err = decompress(&pairs, dict_size).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lzma error: LZ distance 2 is beyond dictionary size 1"
+        );
+    }
+    assert_eq!(decompress(&pairs, 2).unwrap(), b"abababababab");
+}
+
+#[test]
+#[cfg(feature = "raw_decoder")]
+fn encoder_with_params() {
+    use lzma_rs::compress::raw::{LzmaParams, LzmaProperties, UnpackedSizeField};
+    use lzma_rs::compress::LzmaEncoder;
+    use lzma_rs::decompress::raw::LzmaDecoder;
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let properties = LzmaProperties {
+        lc: 0,
+        lp: 2,
+        pb: 0,
+    };
+    let compress = |params: LzmaParams| {
+        let mut encoder = LzmaEncoder::with_params(Vec::new(), params, &Default::default())?;
+        encoder.process(&mut data.as_slice())?;
+        Ok::<_, lzma_rs::error::Error>(encoder.into_inner())
+    };
+
+    let params = LzmaParams::new(properties, 0x12345, Some(data.len() as u64));
+    let compressed = compress(params).unwrap();
+    assert_eq!(compressed[..5], [18, 0x45, 0x23, 0x01, 0x00]);
+    assert_eq!(compressed[5..13], (data.len() as u64).to_le_bytes());
+    assert_decomp_eq(&compressed, &data, /* compare_to_liblzma */ true);
+
+    // Without a size field nor a known size, the stream ends with a marker.
+    let params = LzmaParams::new(properties, 0x10000, None)
+        .with_unpacked_size_field(UnpackedSizeField::Absent);
+    let compressed = compress(params).unwrap();
+    assert_eq!(compressed[..5], [18, 0x00, 0x00, 0x01, 0x00]);
+    let mut decoder = LzmaDecoder::new(params, None).unwrap();
+    let mut decomp = Vec::new();
+    decoder
+        .decompress(&mut &compressed[5..], &mut decomp)
+        .unwrap();
+    assert_eq!(decomp, data);
+
+    let params = LzmaParams::new(properties, 0x100, None);
+    let err = compress(params).unwrap_err();
+    assert!(err.to_string().contains("`dict_size`"), "{}", err);
+}
+
+#[test]
+#[cfg(feature = "raw_decoder")]
+fn initial_probs() {
+    use lzma_rs::compress::LzmaEncoder;
+    use lzma_rs::decompress::raw::{InitialProbs, LzmaDecoder, LzmaParams};
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let compress = |probs: &InitialProbs| {
+        let mut encoder = LzmaEncoder::new(Vec::new(), &Default::default()).unwrap();
+        encoder.set_initial_probs(probs).unwrap();
+        encoder.process(&mut data.as_slice()).unwrap();
+        encoder.into_inner()
+    };
+    let decompress = |compressed: &[u8], probs: &InitialProbs| {
+        let mut input = compressed;
+        let params = LzmaParams::read_header(&mut input, &Default::default())?;
+        let mut decoder = LzmaDecoder::new(params, None)?;
+        decoder.set_initial_probs(probs)?;
+        let mut decomp = Vec::new();
+        decoder.decompress(&mut input, &mut decomp)?;
+        Ok::<_, lzma_rs::error::Error>(decomp)
+    };
+
+    // The probabilities of the specification change nothing.
+    let default_probs = InitialProbs::default();
+    let compressed = compress(&default_probs);
+    assert_eq!(
+        compressed,
+        lzma_rs::lzma_compress_to_vec(&data, &Default::default()).unwrap()
+    );
+    assert_eq!(decompress(&compressed, &default_probs).unwrap(), data);
+
+    // ASCII text: the high bit of the literals is almost always 0.
+    let mut ascii = InitialProbs::default();
+    ascii.literal[1] = 2000;
+    let compressed = compress(&ascii);
+    assert_eq!(decompress(&compressed, &ascii).unwrap(), data);
+    assert_ne!(
+        decompress(&compressed, &default_probs).ok(),
+        Some(data.clone())
+    );
+
+    // The probabilities are kept by reset, as by the decoder.
+    let mut encoder = LzmaEncoder::new(Vec::new(), &Default::default()).unwrap();
+    encoder.set_initial_probs(&ascii).unwrap();
+    encoder.process(&mut &data[..100]).unwrap();
+    let err = encoder.set_initial_probs(&ascii).unwrap_err();
+    assert!(matches!(err, lzma_rs::error::Error::InvalidOptions(_)));
+    encoder.reset();
+    encoder.get_mut().clear();
+    encoder.process(&mut data.as_slice()).unwrap();
+    assert_eq!(encoder.into_inner(), compressed);
+
+    ascii.is_match[0] = 0;
+    let err = decompress(&compressed, &ascii).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid options: `is_match`: probability 0 at index 0 is not within 1..=2047"
+    );
+}
+
+#[test]
+fn sink_error_reports_written_bytes() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    /// Sink running out of space after `capacity` bytes.
+    struct FullDisk {
+        data: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl std::io::Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let room = self.capacity - self.data.len();
+            if room == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "no space left on device",
+                ));
+            }
+            let n = std::cmp::min(std::cmp::min(room, buf.len()), 4000);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let compressed = read_all_file("tests/files/foo.txt.lzma").unwrap();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let mut sink = FullDisk {
+        data: Vec::new(),
+        capacity: 10_000,
+    };
+    let err = lzma_rs::lzma_decompress(&mut compressed.as_slice(), &mut sink).unwrap_err();
+    let io_err = match err {
+        lzma_rs::error::Error::IoError(e) => e,
+        e => panic!("unexpected error {:?}", e),
+    };
+    assert_eq!(io_err.kind(), std::io::ErrorKind::Other);
+    let sink_err = io_err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<lzma_rs::error::SinkError>())
+        .unwrap();
+    assert_eq!(sink_err.written, 10_000);
+    assert_eq!(sink_err.source.to_string(), "no space left on device");
+    assert_eq!(sink.data, &expected[..10_000]);
+}
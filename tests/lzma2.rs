@@ -16,6 +16,7 @@ fn round_trip(x: &[u8]) {
     info!("Compressed {} -> {} bytes", x.len(), compressed.len());
     #[cfg(feature = "enable_logging")]
     debug!("Compressed content: {:?}", compressed);
+    assert!(compressed.len() as u64 <= lzma_rs::lzma2_compress_bound(x.len() as u64));
     let mut bf = std::io::BufReader::new(compressed.as_slice());
     let mut decomp: Vec<u8> = Vec::new();
     lzma_rs::lzma2_decompress(&mut bf, &mut decomp).unwrap();
@@ -50,3 +51,244 @@ fn round_trip_files() {
     let _ = env_logger::try_init();
     round_trip_file("tests/files/foo.txt");
 }
+
+#[test]
+fn to_vec() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = lzma_rs::lzma2_compress_to_vec(&data).unwrap();
+    assert!(compressed.len() as u64 <= lzma_rs::lzma2_compress_bound(data.len() as u64));
+    let decomp = lzma_rs::lzma2_decompress_to_vec(&compressed).unwrap();
+    assert_eq!(decomp, data);
+}
+
+#[test]
+fn empty_stream() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let empty = lzma_rs::empty_stream(lzma_rs::Format::Lzma2, &Default::default()).unwrap();
+    assert_eq!(empty, [0x00]);
+    let decomp = lzma_rs::lzma2_decompress_to_vec(&empty).unwrap();
+    assert!(decomp.is_empty());
+}
+
+#[test]
+fn compress_with_check() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::xz::{Check, CheckMethod};
+
+    let expected = [
+        (CheckMethod::None, Check::None),
+        (CheckMethod::Crc32, Check::Crc32(0xCBF4_3926)),
+        (CheckMethod::Crc64, Check::Crc64(0x995D_C9BB_DF19_39FA)),
+    ];
+    for &(method, check) in expected.iter() {
+        let mut compressed = Vec::new();
+        let result =
+            lzma_rs::lzma2_compress_with_check(&mut &b"123456789"[..], &mut compressed, method)
+                .unwrap();
+        assert_eq!(result, check);
+        assert_eq!(result.method(), method);
+        assert_eq!(
+            lzma_rs::lzma2_decompress_to_vec(&compressed).unwrap(),
+            b"123456789"
+        );
+    }
+
+    // SHA-256 isn't implemented, and is rejected before compressing.
+    let mut compressed = Vec::new();
+    let err =
+        lzma_rs::lzma2_compress_with_check(&mut &b"123"[..], &mut compressed, CheckMethod::Sha256)
+            .unwrap_err();
+    assert!(matches!(err, lzma_rs::error::Error::InvalidOptions(_)));
+    assert!(compressed.is_empty());
+}
+
+#[test]
+fn compress_with_options() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let mut compressed = Vec::new();
+    let options = lzma_rs::compress::Options::default();
+    lzma_rs::lzma2_compress_with_options(&mut data.as_slice(), &mut compressed, &options).unwrap();
+    assert_eq!(lzma_rs::lzma2_decompress_to_vec(&compressed).unwrap(), data);
+
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(100),
+        ..Default::default()
+    };
+    let err = lzma_rs::lzma2_compress_with_options(&mut data.as_slice(), &mut Vec::new(), &options)
+        .unwrap_err();
+    match err {
+        lzma_rs::error::Error::IoError(e) => {
+            assert!(e
+                .get_ref()
+                .unwrap()
+                .is::<lzma_rs::error::OutputSizeExceeded>())
+        }
+        _ => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+fn dict_reset_points() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = lzma_rs::lzma2_compress_to_vec(&data).unwrap();
+    let points = lzma_rs::decompress::lzma2_dict_reset_points(&mut compressed.as_slice()).unwrap();
+    let expected: Vec<u64> = (0..data.len() as u64).step_by(0x10000).collect();
+    assert_eq!(points, expected);
+
+    for &(file, ref expected) in [
+        ("good-1-lzma2-1", vec![0]),
+        ("good-1-lzma2-2", vec![0]),
+        ("good-1-lzma2-3", vec![0]),
+        // LZMA chunk, then uncompressed chunk resetting the dictionary.
+        ("good-1-lzma2-4", vec![0, 188]),
+    ]
+    .iter()
+    {
+        // The LZMA2 data follows the 12-byte stream header and 12-byte block
+        // header.
+        let xz = read_all_file(&format!("tests/files/{}.xz", file)).unwrap();
+        let points = lzma_rs::decompress::lzma2_dict_reset_points(&mut &xz[24..]).unwrap();
+        assert_eq!(&points, expected, "{}", file);
+    }
+
+    // Truncated chunk.
+    lzma_rs::decompress::lzma2_dict_reset_points(&mut &compressed[..1000]).unwrap_err();
+    // The first chunk doesn't reset the dictionary.
+    lzma_rs::decompress::lzma2_dict_reset_points(&mut &[2, 0, 0, 0, 0][..]).unwrap_err();
+}
+
+#[test]
+fn frames() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let messages: Vec<Vec<u8>> = vec![
+        b"first message".to_vec(),
+        Vec::new(),
+        (0..200_000u32).map(|i| (i % 253) as u8).collect(),
+        b"last message".to_vec(),
+    ];
+
+    let mut encoder = lzma_rs::compress::FrameEncoder::new(Vec::new());
+    for message in messages.iter() {
+        encoder.write_frame(message).unwrap();
+    }
+    let framed = encoder.into_inner();
+    // Only the first chunk resets the dictionary, and the first compressed
+    // one sets the properties.
+    assert_eq!(&framed[1..4], &[1, 0, 12]);
+    assert_eq!(framed[17..20], [0, 1, 0]);
+    assert_eq!(framed[22], 0xC0);
+
+    let mut decoder = lzma_rs::decompress::FrameDecoder::new(framed.as_slice());
+    for message in messages.iter() {
+        assert_eq!(&decoder.read_frame().unwrap().unwrap(), message);
+    }
+    assert!(decoder.read_frame().unwrap().is_none());
+
+    // Frames cut in the middle are rejected.
+    let mut decoder = lzma_rs::decompress::FrameDecoder::new(&framed[..30]);
+    assert_eq!(decoder.read_frame().unwrap().unwrap(), messages[0]);
+    decoder.read_frame().unwrap();
+    decoder.read_frame().unwrap_err();
+}
+
+#[test]
+fn frames_share_dictionary() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let message = read_all_file("tests/files/foo.txt").unwrap();
+
+    let mut encoder = lzma_rs::compress::FrameEncoder::new(Vec::new());
+    encoder.write_frame(&message).unwrap();
+    let first_len = encoder.get_ref().len();
+    encoder.write_frame(&message).unwrap();
+    let framed = encoder.into_inner();
+    // The first frame is compressed, and the repeated one is a match into
+    // it.
+    assert!(first_len < message.len());
+    assert!(framed.len() - first_len < first_len / 4);
+
+    let mut decoder = lzma_rs::decompress::FrameDecoder::new(framed.as_slice());
+    assert_eq!(decoder.read_frame().unwrap().unwrap(), message);
+    assert_eq!(decoder.read_frame().unwrap().unwrap(), message);
+    assert!(decoder.read_frame().unwrap().is_none());
+}
+
+#[test]
+fn decompress_with_dictionary() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    // Uncompressed chunks of 64 KiB, each resetting the dictionary.
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let compressed = lzma_rs::lzma2_compress_to_vec(&data).unwrap();
+
+    let mut decomp: Vec<u8> = Vec::new();
+    let dictionary = lzma_rs::lzma2_decompress_with_dictionary(
+        &mut compressed.as_slice(),
+        &mut decomp,
+        0x1_0000,
+    )
+    .unwrap();
+    assert_eq!(decomp, data);
+    assert_eq!(dictionary, &data[0x1_0000..]);
+
+    let dictionary = lzma_rs::lzma2_decompress_with_dictionary(
+        &mut compressed.as_slice(),
+        &mut Vec::new(),
+        0x1000,
+    )
+    .unwrap();
+    assert_eq!(dictionary, &data[data.len() - 0x1000..]);
+}
+
+#[test]
+fn decompress_low_memory() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = lzma_rs::lzma2_compress_to_vec(&text).unwrap();
+
+    let mut output = std::io::Cursor::new(Vec::new());
+    lzma_rs::lzma2_decompress_low_memory(&mut compressed.as_slice(), &mut output, 0x1000).unwrap();
+    assert_eq!(output.into_inner(), text);
+}
+
+#[test]
+fn adaptive_chunks() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let mut state = 0x2545_f491_u32;
+    let noise: Vec<u8> = (0..0x10000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let mut data = text[..0x10000].to_vec();
+    data.extend_from_slice(&noise);
+    data.extend_from_slice(&text[..1000]);
+
+    let compressed = lzma_rs::lzma2_compress_to_vec(&data).unwrap();
+    assert_eq!(lzma_rs::lzma2_decompress_to_vec(&compressed).unwrap(), data);
+
+    // The text is compressed with its properties, the noise is stored.
+    assert_eq!(compressed[0], 0xE0);
+    assert_eq!(compressed[5], 0x5D);
+    let packed_size = u16::from_be_bytes([compressed[3], compressed[4]]) as usize + 1;
+    assert!(packed_size < 0x10000);
+    let stored = &compressed[6 + packed_size..];
+    assert_eq!(stored[..3], [1, 0xFF, 0xFF]);
+    assert_eq!(&stored[3..3 + 0x10000], &noise[..]);
+    assert_eq!(stored[3 + 0x10000] & 0xE0, 0xE0);
+}
@@ -0,0 +1,170 @@
+use std::io::Write;
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn send_sync() {
+    assert_send_sync::<lzma_rs::compress::Options>();
+    assert_send_sync::<lzma_rs::compress::LzmaEncoder<Vec<u8>>>();
+    assert_send_sync::<lzma_rs::compress::XzWriter<Vec<u8>>>();
+    assert_send_sync::<lzma_rs::decompress::Options>();
+    assert_send_sync::<lzma_rs::decompress::FixedBlockDecoder>();
+    assert_send_sync::<lzma_rs::decompress::DecompressorPool>();
+    assert_send_sync::<lzma_rs::compress::FrameEncoder<Vec<u8>>>();
+    assert_send_sync::<lzma_rs::decompress::FrameDecoder<&[u8]>>();
+    assert_send_sync::<lzma_rs::xz::Index>();
+    assert_send_sync::<lzma_rs::error::Error>();
+    assert_send_sync::<Box<dyn lzma_rs::codec::Decode>>();
+    assert_send_sync::<Box<dyn lzma_rs::codec::Encode>>();
+    #[cfg(feature = "raw_decoder")]
+    {
+        assert_send_sync::<lzma_rs::decompress::raw::LzmaDecoder>();
+        assert_send_sync::<lzma_rs::decompress::raw::Lzma2Decoder>();
+        assert_send_sync::<lzma_rs::decompress::raw::HeaderParser>();
+        assert_send_sync::<lzma_rs::decompress::raw::LenDecoder>();
+        assert_send_sync::<lzma_rs::compress::raw::LenEncoder>();
+    }
+    #[cfg(feature = "stream")]
+    {
+        assert_send_sync::<lzma_rs::decompress::Stream<Vec<u8>>>();
+        assert_send_sync::<lzma_rs::decompress::LzmaReader<&[u8]>>();
+    }
+}
+
+#[test]
+fn shared_decompressor_pool() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data: Vec<Vec<u8>> = (0..8u8)
+        .map(|i| (0..50_000u32).map(|j| (j % 251) as u8 ^ i).collect())
+        .collect();
+    let pool = lzma_rs::decompress::DecompressorPool::new(&Default::default(), 4);
+
+    std::thread::scope(|scope| {
+        for (i, expected) in data.iter().enumerate() {
+            let pool = &pool;
+            scope.spawn(move || {
+                let (format, compressed) = if i % 2 == 0 {
+                    let compressed =
+                        lzma_rs::lzma_compress_to_vec(expected, &Default::default()).unwrap();
+                    (lzma_rs::Format::Lzma, compressed)
+                } else {
+                    let compressed = lzma_rs::lzma2_compress_to_vec(expected).unwrap();
+                    (lzma_rs::Format::Lzma2, compressed)
+                };
+                for _ in 0..4 {
+                    let mut decomp: Vec<u8> = Vec::new();
+                    pool.decompress(format, &mut compressed.as_slice(), &mut decomp)
+                        .unwrap();
+                    assert_eq!(&decomp, expected);
+                }
+            });
+        }
+    });
+    assert!(pool.idle() >= 1 && pool.idle() <= 4);
+}
+
+#[test]
+fn decoder_per_thread() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data: Vec<Vec<u8>> = (0..8u8)
+        .map(|i| (0..50_000u32).map(|j| (j % 251) as u8 ^ i).collect())
+        .collect();
+    let codec: &dyn lzma_rs::codec::Decode = &lzma_rs::codec::Xz::default();
+
+    std::thread::scope(|scope| {
+        for expected in data.iter() {
+            scope.spawn(move || {
+                let mut writer =
+                    lzma_rs::compress::XzWriter::new(Vec::new(), &Default::default()).unwrap();
+                writer.write_all(expected).unwrap();
+                let compressed = writer.finish().unwrap();
+
+                let mut decomp: Vec<u8> = Vec::new();
+                codec
+                    .decode(&mut compressed.as_slice(), &mut decomp)
+                    .unwrap();
+                assert_eq!(&decomp, expected);
+            });
+        }
+    });
+}
+
+#[test]
+fn compress_parallel() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data: Vec<u8> = (0..300_000u32)
+        .map(|j| (j % 251) as u8 ^ (j >> 12) as u8)
+        .collect();
+    let options = lzma_rs::compress::Options {
+        block_size: Some(70_000),
+        check: lzma_rs::xz::CheckMethod::Crc32,
+        ..Default::default()
+    };
+
+    let compressed = lzma_rs::xz_compress_parallel(&data, &options, 3).unwrap();
+    // Same blocks as a sequential writer.
+    assert_eq!(
+        compressed,
+        lzma_rs::xz_compress_to_vec(&data, &options).unwrap()
+    );
+    for parallelism in 0..=8 {
+        assert_eq!(
+            lzma_rs::xz_compress_parallel(&data, &options, parallelism).unwrap(),
+            compressed,
+            "{} threads",
+            parallelism
+        );
+    }
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, data);
+
+    let empty = lzma_rs::xz_compress_parallel(&[], &options, 0).unwrap();
+    assert_eq!(empty, lzma_rs::xz_compress_to_vec(&[], &options).unwrap());
+
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(1000),
+        ..options
+    };
+    assert!(lzma_rs::xz_compress_parallel(&data, &options, 0).is_err());
+
+    // The default shard size doesn't depend on the number of threads either.
+    let default_block = lzma_rs::compress::Options {
+        block_size: None,
+        dict_size: 0x10000,
+        max_output_size: None,
+        ..options
+    };
+    let large: Vec<u8> = data.iter().cycle().take(3 << 20).copied().collect();
+    let sequential = lzma_rs::xz_compress_parallel(&large, &default_block, 1).unwrap();
+    assert_eq!(
+        lzma_rs::xz_compress_parallel(&large, &default_block, 0).unwrap(),
+        sequential
+    );
+    assert_eq!(
+        lzma_rs::xz_compress_parallel(&large, &default_block, 4).unwrap(),
+        sequential
+    );
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut sequential.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, large);
+
+    // Unless the output may depend on the number of threads, which then
+    // each get a smaller shard.
+    let threaded = lzma_rs::compress::Options {
+        deterministic: false,
+        ..default_block
+    };
+    assert_eq!(
+        lzma_rs::xz_compress_parallel(&large, &threaded, 1).unwrap(),
+        sequential
+    );
+    let split = lzma_rs::xz_compress_parallel(&large, &threaded, 4).unwrap();
+    assert_ne!(split, sequential);
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut split.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, large);
+}
@@ -16,6 +16,9 @@ fn round_trip(x: &[u8]) {
     info!("Compressed {} -> {} bytes", x.len(), compressed.len());
     #[cfg(feature = "enable_logging")]
     debug!("Compressed content: {:?}", compressed);
+    assert!(
+        compressed.len() as u64 <= lzma_rs::xz_compress_bound(x.len() as u64, &Default::default())
+    );
     let mut bf = BufReader::new(compressed.as_slice());
     let mut decomp: Vec<u8> = Vec::new();
     lzma_rs::xz_decompress(&mut bf, &mut decomp).unwrap();
@@ -80,6 +83,7 @@ fn big_file() {
         "tests/files/good-1-lzma2-4.xz",
         "tests/files/good-1-lzma2-4",
     );
+    decomp_big_file("tests/files/multi-block.txt.xz", "tests/files/foo.txt");
 }
 
 #[test]
@@ -119,6 +123,48 @@ fn test_xz_block_check_crc32() {
     );
 }
 
+#[test]
+fn test_xz_unsupported_check() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::decompress::{Options, UnsupportedCheck};
+    use lzma_rs::xz::CheckMethod;
+
+    let compressed = read_all_file("tests/files/hello-sha256.txt.xz").unwrap();
+    let expected = read_all_file("tests/files/hello.txt").unwrap();
+
+    let mut decomp = Vec::new();
+    let err_msg = lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(err_msg, "xz error: Unsupported check method Sha256");
+
+    for &unsupported_check in &[UnsupportedCheck::Warn, UnsupportedCheck::Ignore] {
+        let options = Options {
+            unsupported_check,
+            ..Default::default()
+        };
+        let mut decomp = Vec::new();
+        let summary =
+            lzma_rs::xz_decompress_with_options(&mut compressed.as_slice(), &mut decomp, &options)
+                .unwrap();
+        assert_eq!(decomp, expected);
+        assert_eq!(summary.check_method, CheckMethod::Sha256);
+        assert!(summary.check_skipped);
+    }
+
+    // Supported checks are verified regardless of the policy.
+    let compressed = read_all_file("tests/files/hello.txt.xz").unwrap();
+    let options = Options {
+        unsupported_check: UnsupportedCheck::Ignore,
+        ..Default::default()
+    };
+    let summary =
+        lzma_rs::xz_decompress_with_options(&mut compressed.as_slice(), &mut Vec::new(), &options)
+            .unwrap();
+    assert!(!summary.check_skipped);
+}
+
 #[test]
 fn test_xz_block_check_crc32_invalid() {
     #[cfg(feature = "enable_logging")]
@@ -144,3 +190,849 @@ fn test_xz_block_check_crc32_invalid() {
         "xz error: Invalid footer CRC32: expected 0x01234567 but got 0x8b0d303e"
     )
 }
+
+#[test]
+fn transcode() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let expected = read_all_file("tests/files/good-1-lzma2-1").unwrap();
+    let mut f = BufReader::new(std::fs::File::open("tests/files/good-1-lzma2-1.xz").unwrap());
+    let mut transcoded: Vec<u8> = Vec::new();
+    lzma_rs::transcode_xz(
+        &mut f,
+        &mut transcoded,
+        &lzma_rs::compress::Options::default(),
+    )
+    .unwrap();
+
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut transcoded.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, expected);
+}
+
+#[test]
+fn extract_blocks() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let mut f = std::fs::File::open("tests/files/multi-block.txt.xz").unwrap();
+    let index = lzma_rs::xz::Index::read(&mut f).unwrap();
+    assert_eq!(index.records().len(), 3);
+    assert_eq!(index.check_method(), lzma_rs::xz::CheckMethod::Crc64);
+    assert_eq!(index.unpacked_size().get(), expected.len() as u64);
+    assert_eq!(index.block_offset(0), Some(12));
+    assert_eq!(index.block_offset(3), None);
+
+    let mut decomp: Vec<u8> = Vec::new();
+    for (i, record) in index.records().iter().enumerate() {
+        let mut block: Vec<u8> = Vec::new();
+        lzma_rs::xz::extract_block(&mut f, &index, i, &mut block).unwrap();
+
+        let mut block_decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut block.as_slice(), &mut block_decomp).unwrap();
+        assert_eq!(block_decomp.len() as u64, record.unpacked_size.get());
+        decomp.extend_from_slice(&block_decomp);
+    }
+    assert_eq!(decomp, expected);
+
+    let err_msg = lzma_rs::xz::extract_block(&mut f, &index, 3, &mut Vec::new())
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "xz error: Block 3 out of range, the stream has 3 block(s)"
+    );
+}
+
+#[test]
+fn block_callback() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::xz::FilterId;
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = read_all_file("tests/files/multi-block.txt.xz").unwrap();
+    let index = lzma_rs::xz::Index::read(&mut Cursor::new(&compressed)).unwrap();
+
+    let mut blocks = Vec::new();
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress_with_block_callback(
+        &mut compressed.as_slice(),
+        &mut decomp,
+        &Default::default(),
+        |block| blocks.push(block.clone()),
+    )
+    .unwrap();
+    assert_eq!(decomp, expected);
+    assert_eq!(blocks.len(), 3);
+
+    let mut uncompressed_offset = 0;
+    for (i, block) in blocks.iter().enumerate() {
+        assert_eq!(block.number, i);
+        assert_eq!(block.record, index.records()[i]);
+        assert_eq!(Some(block.compressed_offset), index.block_offset(i));
+        assert_eq!(block.uncompressed_offset, uncompressed_offset);
+        assert_eq!(block.header.filters, [FilterId::Lzma2]);
+        uncompressed_offset = block.uncompressed_total();
+    }
+    assert_eq!(uncompressed_offset, expected.len() as u64);
+    let last = &blocks[2];
+    assert_eq!(
+        compressed[last.compressed_total() as usize],
+        0,
+        "index indicator"
+    );
+
+    // Header fields written by the encoder.
+    let options = lzma_rs::compress::Options {
+        filter: lzma_rs::compress::Filter::Delta(2),
+        ..Default::default()
+    };
+    let compressed = lzma_rs::xz_compress_to_vec(&expected, &options).unwrap();
+    let mut headers = Vec::new();
+    lzma_rs::xz_decompress_with_block_callback(
+        &mut compressed.as_slice(),
+        &mut Vec::new(),
+        &Default::default(),
+        |block| headers.push(block.header.clone()),
+    )
+    .unwrap();
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers[0].filters, [FilterId::Delta, FilterId::Lzma2]);
+    assert_eq!(headers[0].header_size, 4 * (compressed[12] as u64 + 1));
+}
+
+#[test]
+fn repair_truncated() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let original = read_all_file("tests/files/multi-block.txt.xz").unwrap();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+
+    // An intact file is rebuilt identically.
+    let mut repaired: Vec<u8> = Vec::new();
+    let index = lzma_rs::xz::repair(&mut Cursor::new(&original), &mut repaired).unwrap();
+    assert_eq!(index.records().len(), 3);
+    assert_eq!(repaired, original);
+
+    // Truncate the file in the middle of the last block.
+    let truncated = &original[..50_000];
+    assert!(lzma_rs::xz_decompress(&mut &truncated[..], &mut Vec::new()).is_err());
+
+    let mut repaired: Vec<u8> = Vec::new();
+    let index = lzma_rs::xz::repair(&mut Cursor::new(truncated), &mut repaired).unwrap();
+    assert_eq!(index.records().len(), 2);
+
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut repaired.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp.len() as u64, index.unpacked_size().get());
+    assert_eq!(decomp, &expected[..decomp.len()]);
+}
+
+#[test]
+fn rewrite_check() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::xz::CheckMethod;
+
+    let original = read_all_file("tests/files/multi-block.txt.xz").unwrap();
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+    let original_index = lzma_rs::xz::Index::read(&mut Cursor::new(&original)).unwrap();
+
+    let mut rewritten: Vec<u8> = Vec::new();
+    let index =
+        lzma_rs::xz::rewrite_check(&mut original.as_slice(), &mut rewritten, CheckMethod::None)
+            .unwrap();
+    assert_eq!(index.check_method(), CheckMethod::None);
+    assert_eq!(index.records().len(), 3);
+    assert_eq!(rewritten.len(), original.len() - 3 * 8);
+    assert_eq!(
+        lzma_rs::xz::Index::read(&mut Cursor::new(&rewritten))
+            .unwrap()
+            .records(),
+        index.records()
+    );
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut rewritten.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, expected);
+
+    // The compressed data is untouched, so going back gives the original.
+    for &check_method in &[CheckMethod::Crc32, CheckMethod::Crc64] {
+        let mut back: Vec<u8> = Vec::new();
+        lzma_rs::xz::rewrite_check(&mut rewritten.as_slice(), &mut back, check_method).unwrap();
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut back.as_slice(), &mut decomp).unwrap();
+        assert_eq!(decomp, expected);
+        if check_method == original_index.check_method() {
+            assert_eq!(back, original);
+        }
+    }
+
+    // Corrupt checks are not carried over.
+    let mut corrupted = read_all_file("tests/files/block-check-crc32.txt.xz").unwrap();
+    corrupted[0x54] ^= 1;
+    assert!(lzma_rs::xz::rewrite_check(
+        &mut corrupted.as_slice(),
+        &mut Vec::new(),
+        CheckMethod::Crc64
+    )
+    .is_err());
+    assert!(lzma_rs::xz::rewrite_check(
+        &mut original.as_slice(),
+        &mut Vec::new(),
+        CheckMethod::Sha256
+    )
+    .is_err());
+}
+
+#[test]
+fn validate_container() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::xz::Inconsistency;
+
+    let original = read_all_file("tests/files/multi-block.txt.xz").unwrap();
+    let report = lzma_rs::xz::validate_container(&mut Cursor::new(&original)).unwrap();
+    assert!(report.is_consistent());
+
+    let index = lzma_rs::xz::Index::read(&mut Cursor::new(&original)).unwrap();
+    let index_start =
+        (index.block_offset(2).unwrap() + index.records()[2].padded_size().get()) as usize;
+
+    // Change the unpadded size of the first record without fixing the CRC32.
+    let mut corrupt = original.clone();
+    corrupt[index_start + 2] ^= 1;
+    let report = lzma_rs::xz::validate_container(&mut Cursor::new(&corrupt)).unwrap();
+    assert!(!report.is_consistent());
+    let inconsistencies = report.inconsistencies();
+    assert_eq!(inconsistencies.len(), 2);
+    assert!(matches!(inconsistencies[0], Inconsistency::IndexCrc { .. }));
+    match &inconsistencies[1] {
+        Inconsistency::RecordSize {
+            block,
+            index: recorded,
+            actual,
+        } => {
+            assert_eq!(*block, 0);
+            assert_eq!(*actual, index.records()[0]);
+            assert_ne!(recorded.unpadded_size, actual.unpadded_size);
+            assert_eq!(recorded.unpacked_size, actual.unpacked_size);
+        }
+        other => panic!("unexpected inconsistency: {}", other),
+    }
+
+    // Garbage inserted between the index and the footer.
+    let mut padded = original.clone();
+    padded.splice(original.len() - 12..original.len() - 12, [0u8; 4]);
+    let report = lzma_rs::xz::validate_container(&mut Cursor::new(&padded)).unwrap();
+    assert_eq!(
+        report.inconsistencies(),
+        &[Inconsistency::UnexpectedData {
+            offset: original.len() as u64 - 12,
+            size: 4
+        }]
+    );
+}
+
+#[test]
+fn index_overflow() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    // Records with an unpadded size of 0 take no room in the stream.
+    let mut f = std::fs::File::open("tests/files/index-overflow.xz").unwrap();
+    let err = lzma_rs::xz::Index::read(&mut f).unwrap_err();
+    assert!(err.to_string().contains("unpadded size 0"), "{}", err);
+
+    // Valid unpadded sizes, but a total unpacked size beyond a u64.
+    let mut f = std::fs::File::open("tests/files/index-overflow-blocks.xz").unwrap();
+    let err = lzma_rs::xz::Index::read(&mut f).unwrap_err();
+    assert!(err.to_string().contains("overflows"), "{}", err);
+}
+
+#[test]
+fn transcode_max_output_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let mut f = BufReader::new(std::fs::File::open("tests/files/good-1-lzma2-1.xz").unwrap());
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(100),
+        ..Default::default()
+    };
+    let mut transcoded: Vec<u8> = Vec::new();
+    let error = lzma_rs::transcode_xz(&mut f, &mut transcoded, &options).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "io error: compressed output exceeds limit of 100 bytes"
+    );
+    assert!(transcoded.len() <= 100);
+}
+
+#[test]
+fn to_vec() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = lzma_rs::xz_compress_to_vec(&data, &Default::default()).unwrap();
+    let decomp = lzma_rs::xz_decompress_to_vec(&compressed).unwrap();
+    assert_eq!(decomp, data);
+
+    let compressed = read_all_file("tests/files/multi-block.txt.xz").unwrap();
+    let decomp = lzma_rs::xz_decompress_to_vec(&compressed).unwrap();
+    assert_eq!(decomp, data);
+
+    // A crafted index fails the decompression instead of the sizing.
+    for file in ["index-overflow.xz", "index-overflow-blocks.xz"].iter() {
+        let compressed = read_all_file(&format!("tests/files/{}", file)).unwrap();
+        lzma_rs::xz_decompress_to_vec(&compressed).unwrap_err();
+    }
+}
+
+#[test]
+fn empty_stream() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let empty = lzma_rs::empty_stream(lzma_rs::Format::Xz, &Default::default()).unwrap();
+    // Stream header, empty index and stream footer, like xz-utils.
+    assert_eq!(empty.len(), 32);
+    let index = lzma_rs::xz::Index::read(&mut Cursor::new(&empty)).unwrap();
+    assert!(index.records().is_empty());
+    let decomp = lzma_rs::xz_decompress_to_vec(&empty).unwrap();
+    assert!(decomp.is_empty());
+}
+
+#[test]
+fn compress_with_options() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::xz::CheckMethod;
+
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    for &check in [CheckMethod::None, CheckMethod::Crc32, CheckMethod::Crc64].iter() {
+        for &block_size in [None, Some(1000), Some(0x10000), Some(data.len() as u64)].iter() {
+            let options = lzma_rs::compress::Options {
+                dict_size: 0x1_0000,
+                check,
+                block_size,
+                ..Default::default()
+            };
+            let mut compressed = Vec::new();
+            lzma_rs::xz_compress_with_options(&mut data.as_slice(), &mut compressed, &options)
+                .unwrap();
+            assert!(
+                compressed.len() as u64 <= lzma_rs::xz_compress_bound(data.len() as u64, &options)
+            );
+            assert_eq!(lzma_rs::xz_decompress_to_vec(&compressed).unwrap(), data);
+
+            let index = lzma_rs::xz::Index::read(&mut Cursor::new(&compressed)).unwrap();
+            assert_eq!(index.check_method(), check);
+            let block_size = block_size.unwrap_or(data.len() as u64);
+            let blocks = (data.len() as u64 + block_size - 1) / block_size;
+            assert_eq!(index.records().len() as u64, blocks);
+            for record in &index.records()[..index.records().len() - 1] {
+                assert_eq!(record.unpacked_size.get(), block_size);
+            }
+        }
+    }
+
+    let options = lzma_rs::compress::Options {
+        check: CheckMethod::Sha256,
+        ..Default::default()
+    };
+    let err_msg = lzma_rs::xz_compress_with_options(&mut &b"abc"[..], &mut Vec::new(), &options)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "invalid options: `check`: Unsupported SHA-256 checksum (not yet implemented)"
+    );
+
+    let options = lzma_rs::compress::Options {
+        block_size: Some(0),
+        ..Default::default()
+    };
+    lzma_rs::xz_compress_with_options(&mut &b"abc"[..], &mut Vec::new(), &options).unwrap_err();
+}
+
+#[test]
+fn bt4_match_finder() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options {
+        match_finder: lzma_rs::compress::MatchFinder::Bt4,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::xz_compress_to_vec(&data, &options).unwrap();
+    assert_eq!(lzma_rs::xz_decompress_to_vec(&compressed).unwrap(), data);
+    let default = lzma_rs::xz_compress_to_vec(&data, &Default::default()).unwrap();
+    assert!(compressed.len() < default.len());
+}
+
+#[test]
+fn compress_dict_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    // The dictionary size is rounded up to 2^n or 3 * 2^n in the filter
+    // properties at offset 12 + 4.
+    let expected = [
+        (0x1000, 0),
+        (0x1001, 1),
+        (0x0080_0000, 22),
+        (0x00C0_0000, 23),
+        (0xFFFF_FFFF, 40),
+    ];
+    for &(dict_size, props) in expected.iter() {
+        let options = lzma_rs::compress::Options {
+            dict_size,
+            ..Default::default()
+        };
+        let compressed = lzma_rs::xz_compress_to_vec(b"abc", &options).unwrap();
+        assert_eq!(compressed[16], props, "dict_size = {}", dict_size);
+        assert_eq!(lzma_rs::xz_decompress_to_vec(&compressed).unwrap(), b"abc");
+    }
+}
+
+#[test]
+fn compress_xz_utils() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    // Reference files produced by `xz -T1 -<preset>` from xz-utils.
+    let data = read_all_file("tests/files/random-1000.bin").unwrap();
+    for &preset in [0, 6].iter() {
+        let expected =
+            read_all_file(&format!("tests/files/random-1000-preset{}.bin.xz", preset)).unwrap();
+        let options = lzma_rs::compress::Options::xz_utils(preset).unwrap();
+        let compressed = lzma_rs::xz_compress_to_vec(&data, &options).unwrap();
+        assert_eq!(compressed, expected, "preset = {}", preset);
+    }
+
+    let expected = read_all_file("tests/files/empty.txt.xz").unwrap();
+    let options = lzma_rs::compress::Options::xz_utils(6).unwrap();
+    assert_eq!(
+        lzma_rs::xz_compress_to_vec(&[], &options).unwrap(),
+        expected
+    );
+
+    match lzma_rs::compress::Options::xz_utils(10) {
+        Err(lzma_rs::error::Error::InvalidOptions(_)) => {}
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn verify_matches() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::decompress::verify_matches;
+    use lzma_rs::Format;
+
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let xz = read_all_file("tests/files/multi-block.txt.xz").unwrap();
+    let lzma = read_all_file("tests/files/foo.txt.lzma").unwrap();
+    let lzma2 = lzma_rs::lzma2_compress_to_vec(&data).unwrap();
+    for &(format, compressed) in [
+        (Format::Xz, &xz),
+        (Format::Lzma, &lzma),
+        (Format::Lzma2, &lzma2),
+    ]
+    .iter()
+    {
+        let verify =
+            |plain: &[u8]| verify_matches(&mut compressed.as_slice(), &mut &plain[..], format);
+        assert_eq!(verify(&data).unwrap(), None);
+
+        let mut modified = data.clone();
+        modified[70_000] ^= 1;
+        assert_eq!(verify(&modified).unwrap(), Some(70_000));
+        assert_eq!(verify(&data[..1000]).unwrap(), Some(1000));
+        modified = data.clone();
+        modified.push(b'\n');
+        assert_eq!(verify(&modified).unwrap(), Some(data.len() as u64));
+    }
+
+    verify_matches(&mut &xz[..100], &mut data.as_slice(), Format::Xz).unwrap_err();
+}
+
+#[test]
+fn verify_lzma2_matches() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::decompress::verify_lzma2_matches;
+
+    // The LZMA2 data of a file written by xz-utils, which only resets the
+    // dictionary in its first chunk.
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let xz = read_all_file("tests/files/foo.txt.xz").unwrap();
+    let block_header_size = (xz[12] as usize + 1) * 4;
+    let lzma2 = &xz[12 + block_header_size..];
+    let dict_reset_points = lzma_rs::decompress::lzma2_dict_reset_points(&mut &lzma2[..]).unwrap();
+    assert_eq!(dict_reset_points.len(), 1);
+
+    let verify =
+        |plain: &[u8], dict_size| verify_lzma2_matches(&mut &lzma2[..], &mut &plain[..], dict_size);
+    assert_eq!(verify(&data, 1 << 23).unwrap(), None);
+    let mut modified = data.clone();
+    modified[150_000] ^= 1;
+    assert_eq!(verify(&modified, 1 << 23).unwrap(), Some(150_000));
+
+    // The dictionary is bounded: matches farther back are errors.
+    verify(&data, 4096).unwrap_err();
+}
+
+#[test]
+fn compress_block_list() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use std::io::Write;
+
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options {
+        block_size: Some(50_000),
+        ..Default::default()
+    };
+    let block_list = [0, 10, 1000, 70_000, 1 << 40];
+    let mut writer =
+        lzma_rs::compress::XzWriter::with_block_list(Vec::new(), &options, &block_list).unwrap();
+    // Write in pieces that straddle the boundaries.
+    for piece in data.chunks(777) {
+        writer.write_all(piece).unwrap();
+    }
+    let compressed = writer.finish().unwrap();
+    assert_eq!(lzma_rs::xz_decompress_to_vec(&compressed).unwrap(), data);
+
+    let index = lzma_rs::xz::Index::read(&mut Cursor::new(&compressed)).unwrap();
+    let sizes: Vec<u64> = index
+        .records()
+        .iter()
+        .map(|r| r.unpacked_size.get())
+        .collect();
+    let mut expected = vec![10, 990, 50_000, 70_000 - 51_000];
+    let mut rest = data.len() as u64 - 70_000;
+    while rest > 0 {
+        expected.push(std::cmp::min(rest, 50_000));
+        rest -= std::cmp::min(rest, 50_000);
+    }
+    assert_eq!(sizes, expected);
+
+    lzma_rs::compress::XzWriter::with_block_list(Vec::new(), &options, &[10, 10]).unwrap_err();
+}
+
+#[test]
+#[cfg(feature = "stream")]
+fn xz_reader() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use std::io::{Seek, SeekFrom};
+
+    let data = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options {
+        block_size: Some(50_000),
+        ..Default::default()
+    };
+    let mut compressed = Vec::new();
+    lzma_rs::xz_compress_with_options(&mut data.as_slice(), &mut compressed, &options).unwrap();
+
+    let mut reader =
+        lzma_rs::decompress::XzReader::new(BufReader::with_capacity(64, compressed.as_slice()));
+    let mut decomp = Vec::new();
+    reader.read_to_end(&mut decomp).unwrap();
+    assert_eq!(decomp, data);
+
+    // Seeking skips whole blocks.
+    let mut reader = lzma_rs::decompress::XzReader::new(compressed.as_slice());
+    let mut buf = [0u8; 100];
+    assert_eq!(reader.seek(SeekFrom::Start(120_000)).unwrap(), 120_000);
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &data[120_000..120_100]);
+    assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+    assert!(reader.seek(SeekFrom::End(0)).is_err());
+
+    // Truncated input keeps failing.
+    let mut reader = lzma_rs::decompress::XzReader::new(&compressed[..compressed.len() - 1]);
+    let err = std::io::copy(&mut reader, &mut std::io::sink()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    let again = reader.read(&mut buf).unwrap_err();
+    assert_eq!(again.to_string(), err.to_string());
+
+    // So does trailing data.
+    compressed.push(0);
+    let mut reader = lzma_rs::decompress::XzReader::new(compressed.as_slice());
+    let err = std::io::copy(&mut reader, &mut std::io::sink()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(
+        reader.read(&mut buf).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+}
+
+#[test]
+fn delta_filter() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::Filter;
+
+    // Created with `xz --delta=dist=2 --lzma2=preset=6`.
+    let expected = read_all_file("tests/files/delta-2.bin").unwrap();
+    let compressed = read_all_file("tests/files/delta-2.bin.xz").unwrap();
+    assert_eq!(
+        lzma_rs::xz_decompress_to_vec(&compressed).unwrap(),
+        expected
+    );
+
+    for &filter in [Filter::Delta(2), Filter::Delta(256), Filter::Auto].iter() {
+        let options = lzma_rs::compress::Options {
+            filter,
+            block_size: Some(3000),
+            ..Default::default()
+        };
+        let compressed = lzma_rs::xz_compress_to_vec(&expected, &options).unwrap();
+        assert_eq!(
+            lzma_rs::xz_decompress_to_vec(&compressed).unwrap(),
+            expected,
+            "{:?}",
+            filter
+        );
+        let delta_props = match filter {
+            Filter::Delta(distance) => (distance - 1) as u8,
+            _ => 1,
+        };
+        // Delta filter flags in the first block header.
+        assert_eq!(compressed[12..17], [2, 1, 3, 1, delta_props]);
+    }
+
+    // Text gets no filter.
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    let options = lzma_rs::compress::Options {
+        filter: Filter::Auto,
+        ..Default::default()
+    };
+    let compressed = lzma_rs::xz_compress_to_vec(&text, &options).unwrap();
+    assert_eq!(compressed[12..15], [2, 0, 0x21]);
+
+    let options = lzma_rs::compress::Options {
+        filter: Filter::Delta(0),
+        ..Default::default()
+    };
+    lzma_rs::xz_compress_to_vec(&text, &options).unwrap_err();
+}
+
+#[test]
+fn writer_finalization() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use std::io::Write;
+    let data = b"Hello world";
+    let options = lzma_rs::compress::Options::default();
+    let expected = lzma_rs::xz_compress_to_vec(data, &options).unwrap();
+
+    // Dropping the writer finishes the stream.
+    let mut compressed: Vec<u8> = Vec::new();
+    {
+        let mut writer = lzma_rs::compress::XzWriter::new(&mut compressed, &options).unwrap();
+        writer.write_all(data).unwrap();
+    }
+    assert_eq!(compressed, expected);
+
+    // `try_finish` is idempotent and no data can be written afterwards.
+    let mut writer = lzma_rs::compress::XzWriter::new(Vec::new(), &options).unwrap();
+    writer.write_all(data).unwrap();
+    writer.try_finish().unwrap();
+    assert_eq!(writer.get_ref(), &expected);
+    writer.try_finish().unwrap();
+    writer.write_all(b"more").unwrap_err();
+    assert_eq!(writer.finish().unwrap(), expected);
+
+    // A failed write poisons the writer.
+    let options = lzma_rs::compress::Options {
+        max_output_size: Some(100),
+        ..Default::default()
+    };
+    let mut writer = lzma_rs::compress::XzWriter::new(Vec::new(), &options).unwrap();
+    writer.write_all(&[0; 100_000]).unwrap_err();
+    assert!(writer.is_poisoned());
+    writer.write_all(data).unwrap_err();
+    let err_msg = writer.finish().unwrap_err().to_string();
+    assert!(err_msg.contains("poisoned"), "{}", err_msg);
+}
+
+#[test]
+fn xz_writer_flush_sync() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use std::io::Write;
+
+    let request = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+    let mut writer = lzma_rs::compress::XzWriter::new(Vec::new(), &Default::default()).unwrap();
+    writer.write_all(request).unwrap();
+    writer.flush().unwrap();
+    // A plain flush keeps the data for a full chunk.
+    assert_eq!(writer.get_ref().len(), 12);
+    writer.flush_sync().unwrap();
+
+    // The LZMA2 chunks written so far, after the stream and block headers,
+    // decode to the request once terminated.
+    let flushed = writer.get_ref().clone();
+    let block_header_size = (flushed[12] as usize + 1) * 4;
+    let mut chunks = flushed[12 + block_header_size..].to_vec();
+    chunks.push(0);
+    let mut decoded = Vec::new();
+    lzma_rs::lzma2_decompress(&mut chunks.as_slice(), &mut decoded).unwrap();
+    assert_eq!(decoded, request);
+
+    // Flushing again adds nothing.
+    writer.flush_sync().unwrap();
+    assert_eq!(writer.get_ref(), &flushed);
+    writer.write_all(response).unwrap();
+    let compressed = writer.finish().unwrap();
+    let data = [&request[..], &response[..]].concat();
+    assert_eq!(lzma_rs::xz_decompress_to_vec(&compressed).unwrap(), data);
+
+    // The flush that ended a chunk early adds a chunk header to the bound.
+    let bound = lzma_rs::xz_compress_bound(data.len() as u64, &Default::default());
+    assert!(compressed.len() as u64 <= bound + 3);
+}
+
+#[cfg(feature = "test_support")]
+#[test]
+fn corrupt_variants() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::test_support::{self, ErrorClass};
+    use lzma_rs::Format;
+
+    let options = lzma_rs::compress::Options {
+        check: lzma_rs::xz::CheckMethod::Crc32,
+        ..Default::default()
+    };
+    let data = lzma_rs::xz_compress_to_vec(b"Hello world", &options).unwrap();
+
+    let rejected = [
+        ErrorClass::Io,
+        ErrorClass::Lzma,
+        ErrorClass::Xz,
+        ErrorClass::Truncated,
+    ];
+    for variant in test_support::truncations(&data) {
+        test_support::assert_rejected(&variant.data, Format::Xz, &rejected);
+    }
+    // Every field of the container is covered by a CRC32.
+    for variant in test_support::bit_flips(&data, 0..data.len()) {
+        test_support::assert_rejected(&variant.data, Format::Xz, &rejected);
+    }
+    // Chunk header followed by the data of another stream.
+    let other = lzma_rs::xz_compress_to_vec(b"Other data", &options).unwrap();
+    let variant = test_support::splice(&data, 27, &other, 27);
+    test_support::assert_rejected(&variant.data, Format::Xz, &rejected);
+}
+
+#[test]
+fn compress_slices() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let parts: [&[u8]; 3] = [b"Hello, ", b"", b"world!\n"];
+    let options = lzma_rs::compress::Options::default();
+
+    let mut compressed = Vec::new();
+    lzma_rs::xz_compress_slices(parts.iter().copied(), &mut compressed, &options).unwrap();
+    assert_eq!(
+        lzma_rs::xz_decompress_to_vec(&compressed).unwrap(),
+        b"Hello, world!\n"
+    );
+}
+
+#[test]
+fn file_helpers() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::fs::{compress_file_with_progress, decompress_file, FileOptions};
+
+    let dir = std::env::temp_dir().join(format!("lzma-rs-fs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = std::path::Path::new("tests/files/foo.txt");
+    let compressed = dir.join("foo.txt.xz");
+    let decompressed = dir.join("foo.txt");
+    let expected = read_all_file("tests/files/foo.txt").unwrap();
+
+    let mut calls = Vec::new();
+    let summary = compress_file_with_progress(
+        input,
+        &compressed,
+        &Default::default(),
+        &FileOptions::default(),
+        |read, total| calls.push((read, total)),
+    )
+    .unwrap();
+    let size = expected.len() as u64;
+    assert_eq!(summary.uncompressed_size.get(), size);
+    assert_eq!(calls.last(), Some(&(size, size)));
+    assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(
+        std::fs::metadata(&compressed).unwrap().len(),
+        summary.compressed_size.get()
+    );
+
+    let summary = decompress_file(
+        &compressed,
+        &decompressed,
+        &Default::default(),
+        &FileOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(summary.uncompressed_size.get(), size);
+    assert_eq!(std::fs::read(&decompressed).unwrap(), expected);
+    assert_eq!(
+        summary.modified,
+        std::fs::metadata(&compressed).unwrap().modified().ok()
+    );
+
+    // The output isn't replaced unless asked to.
+    let err = decompress_file(
+        &compressed,
+        &decompressed,
+        &Default::default(),
+        &FileOptions::default(),
+    )
+    .unwrap_err();
+    assert_eq!(err.io_kind(), std::io::ErrorKind::AlreadyExists);
+
+    // Nor if it's created while compressing.
+    let recompressed = dir.join("foo.txt.2.xz");
+    let err = compress_file_with_progress(
+        input,
+        &recompressed,
+        &Default::default(),
+        &FileOptions::default(),
+        |_, _| {
+            let _ = std::fs::write(&recompressed, b"other");
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err.io_kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read(&recompressed).unwrap(), b"other");
+    std::fs::remove_file(&recompressed).unwrap();
+
+    // On errors, neither the output nor the temporary file are left behind.
+    std::fs::remove_file(&decompressed).unwrap();
+    let mut corrupt = std::fs::read(&compressed).unwrap();
+    let len = corrupt.len();
+    corrupt[len / 2] ^= 1;
+    std::fs::write(&compressed, &corrupt).unwrap();
+    assert!(decompress_file(
+        &compressed,
+        &decompressed,
+        &Default::default(),
+        &FileOptions {
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .is_err());
+    let mut entries: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    entries.sort();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(entries, ["foo.txt.xz"]);
+}